@@ -0,0 +1,103 @@
+// Framing of the commands in an AOF, which are RESP arrays of bulk strings.
+// Unlike the parser for clients this is strict, so corruption is reported
+// where it starts. It only depends on std so the offline tools can share it.
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AofError {
+    /// The command starting at this offset is cut short, as happens when
+    /// the server stops in the middle of a write.
+    #[error("unexpected end of file in the command at offset {0}")]
+    Truncated(usize),
+    #[error("{message} at offset {offset}")]
+    Invalid { offset: usize, message: &'static str },
+}
+
+/// Reads the `*<count>` or `$<len>` line at `pos`. `start` is the offset of
+/// the command being read, reported when the file ends early.
+fn header(data: &[u8], pos: usize, start: usize, prefix: u8) -> Result<(usize, usize), AofError> {
+    match data.get(pos) {
+        None => return Err(AofError::Truncated(start)),
+        Some(byte) if *byte != prefix => {
+            let message = if prefix == b'*' { "expected '*'" } else { "expected '$'" };
+            return Err(AofError::Invalid { offset: pos, message });
+        }
+        Some(_) => {}
+    }
+    let end = match data[pos..].windows(2).position(|window| window == b"\r\n") {
+        Some(end) => pos + end,
+        None => return Err(AofError::Truncated(start)),
+    };
+    let value = std::str::from_utf8(&data[pos + 1..end]).ok().and_then(|value| value.parse::<usize>().ok());
+    match value {
+        Some(value) => Ok((value, end + 2)),
+        None => Err(AofError::Invalid { offset: pos + 1, message: "invalid length" }),
+    }
+}
+
+/// Reads the command starting at `start`, returning its arguments and the
+/// offset right after it.
+pub fn read_command(data: &[u8], start: usize) -> Result<(Vec<Vec<u8>>, usize), AofError> {
+    let (count, mut pos) = header(data, start, start, b'*')?;
+    if count == 0 {
+        return Err(AofError::Invalid { offset: start, message: "empty command" });
+    }
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let (len, begin) = header(data, pos, start, b'$')?;
+        let end = begin.checked_add(len).filter(|end| end + 2 <= data.len()).ok_or(AofError::Truncated(start))?;
+        if &data[end..end + 2] != b"\r\n" {
+            return Err(AofError::Invalid { offset: end, message: "expected CRLF after the argument" });
+        }
+        args.push(data[begin..end].to_vec());
+        pos = end + 2;
+    }
+    Ok((args, pos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(data: &[u8]) -> Result<Vec<Vec<Vec<u8>>>, AofError> {
+        let mut commands = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let (args, end) = read_command(data, pos)?;
+            commands.push(args);
+            pos = end;
+        }
+        Ok(commands)
+    }
+
+    #[test]
+    fn reads_commands_in_sequence() {
+        let data = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\na\r\nb\r\n";
+        let commands = read_all(data).unwrap();
+        assert_eq!(commands[0], [b"GET".to_vec(), b"k".to_vec()]);
+        // Arguments are binary safe.
+        assert_eq!(commands[1][2], b"a\r\nb");
+    }
+
+    #[test]
+    fn reports_where_commands_are_cut_short() {
+        let data = b"*1\r\n$4\r\nPING\r\n*2\r\n$3\r\nGET\r\n$1\r\n";
+        assert!(matches!(read_all(data), Err(AofError::Truncated(14))));
+        assert!(matches!(read_command(b"*1\r\n$4\r\nPI", 0), Err(AofError::Truncated(0))));
+        assert!(matches!(read_command(b"*1", 0), Err(AofError::Truncated(0))));
+    }
+
+    #[test]
+    fn rejects_invalid_framing() {
+        let invalid = |data: &[u8]| match read_command(data, 0) {
+            Err(AofError::Invalid { offset, message }) => (offset, message),
+            _ => panic!("{:?} is valid", String::from_utf8_lossy(data)),
+        };
+        assert_eq!(invalid(b"PING\r\n"), (0, "expected '*'"));
+        assert_eq!(invalid(b"*1\r\n+PING\r\n"), (4, "expected '$'"));
+        assert_eq!(invalid(b"*x\r\n"), (1, "invalid length"));
+        assert_eq!(invalid(b"*0\r\n"), (0, "empty command"));
+        assert_eq!(invalid(b"*1\r\n$2\r\nPING\r\n"), (10, "expected CRLF after the argument"));
+    }
+}
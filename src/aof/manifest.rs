@@ -0,0 +1,127 @@
+// Manifest of a multi-part AOF: the base file, holding the dataset as of the
+// last rewrite, and the incremental files with the writes made since, in
+// the format of Redis 7. Like `format`, it only depends on std.
+
+pub const BASE_RDB_SUFFIX: &str = ".base.rdb";
+
+#[derive(Clone)]
+pub struct ManifestFile {
+    pub name: String,
+    pub seq: u64,
+}
+
+#[derive(Default)]
+pub struct Manifest {
+    pub base: Option<ManifestFile>,
+    pub incrs: Vec<ManifestFile>,
+}
+
+impl Manifest {
+    /// Parses lines of `file <name> seq <seq> type <b|h|i>`. History files,
+    /// left over from a rewrite, are skipped.
+    pub fn parse(text: &str) -> Result<Manifest, String> {
+        let mut manifest = Manifest::default();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || format!("invalid manifest line {}: {}", idx + 1, line);
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if !tokens.chunks_exact(2).remainder().is_empty() {
+                return Err(invalid());
+            }
+            let (mut name, mut seq, mut kind) = (None, None, None);
+            for pair in tokens.chunks(2) {
+                match pair[0] {
+                    "file" => name = Some(pair[1].to_string()),
+                    "seq" => seq = pair[1].parse::<u64>().ok(),
+                    "type" => kind = Some(pair[1]),
+                    _ => {}
+                }
+            }
+            let (Some(name), Some(seq), Some(kind)) = (name, seq, kind) else {
+                return Err(invalid());
+            };
+            match kind {
+                "b" if manifest.base.is_none() => manifest.base = Some(ManifestFile { name, seq }),
+                "i" => manifest.incrs.push(ManifestFile { name, seq }),
+                "h" => {}
+                _ => return Err(invalid()),
+            }
+        }
+        manifest.incrs.sort_by_key(|file| file.seq);
+        Ok(manifest)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        if let Some(base) = &self.base {
+            text.push_str(&format!("file {} seq {} type b\n", base.name, base.seq));
+        }
+        for incr in &self.incrs {
+            text.push_str(&format!("file {} seq {} type i\n", incr.name, incr.seq));
+        }
+        text
+    }
+
+    /// Every file in the order it is loaded, the base first.
+    pub fn files(&self) -> impl Iterator<Item = &ManifestFile> {
+        self.base.iter().chain(self.incrs.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(manifest: &Manifest) -> Vec<&str> {
+        manifest.files().map(|file| file.name.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_redis_manifests() {
+        let text = "# comment\n\
+                    file appendonly.aof.3.incr.aof seq 3 type i\n\
+                    file appendonly.aof.1.base.rdb seq 1 type b\n\
+                    \n\
+                    file appendonly.aof.1.incr.aof seq 1 type h\n\
+                    file appendonly.aof.2.incr.aof seq 2 type i\n";
+        let manifest = Manifest::parse(text).unwrap();
+        assert_eq!(names(&manifest), ["appendonly.aof.1.base.rdb", "appendonly.aof.2.incr.aof", "appendonly.aof.3.incr.aof"]);
+        assert_eq!(manifest.base.as_ref().unwrap().seq, 1);
+        assert_eq!(manifest.incrs.iter().map(|file| file.seq).collect::<Vec<_>>(), [2, 3]);
+    }
+
+    #[test]
+    fn accepts_keys_in_any_order() {
+        let manifest = Manifest::parse("type b seq 7 file base.rdb\n").unwrap();
+        assert_eq!(manifest.base.unwrap().name, "base.rdb");
+        assert!(Manifest::parse("").unwrap().files().next().is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        for text in [
+            "file a.aof seq 1 type\n",
+            "file a.aof seq x type i\n",
+            "file a.aof type i\n",
+            "file a.aof seq 1 type z\n",
+            "file a.rdb seq 1 type b\nfile b.rdb seq 2 type b\n",
+        ] {
+            assert!(Manifest::parse(text).is_err(), "{:?}", text);
+        }
+        assert_eq!(Manifest::parse("\nfile a.aof seq 1\n").err().unwrap(), "invalid manifest line 2: file a.aof seq 1");
+    }
+
+    #[test]
+    fn writes_what_it_parses() {
+        let manifest = Manifest {
+            base: Some(ManifestFile { name: format!("appendonly.aof.2{}", BASE_RDB_SUFFIX), seq: 2 }),
+            incrs: vec![ManifestFile { name: "appendonly.aof.4.incr.aof".to_string(), seq: 4 }],
+        };
+        let text = manifest.to_text();
+        assert_eq!(text, "file appendonly.aof.2.base.rdb seq 2 type b\nfile appendonly.aof.4.incr.aof seq 4 type i\n");
+        assert_eq!(Manifest::parse(&text).unwrap().to_text(), text);
+    }
+}
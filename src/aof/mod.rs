@@ -0,0 +1,574 @@
+// Append-only file: every write propagated to replicas is appended to it as
+// well, and replayed at startup. Like in Redis 7 the AOF is a directory with
+// a base file, an incremental file per rewrite and a manifest listing them.
+// A rewrite replaces the base with a snapshot of the keyspace, and switches
+// to a new manifest only once the snapshot is on disk.
+
+mod format;
+mod manifest;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crate::commands::{execute, lock_execution};
+use crate::error::CommandError;
+use crate::parser::parse_command;
+use crate::persistence::{self, now_secs};
+use crate::rdb;
+use crate::resp;
+use crate::transaction::Transaction;
+use crate::util::parse_memory;
+use crate::{get_aof_instance, get_memory_instance, get_options_instance};
+
+use format::{read_command, AofError};
+use manifest::{Manifest, ManifestFile, BASE_RDB_SUFFIX};
+
+/// Seconds to wait before retrying a failed automatic rewrite.
+const REWRITE_RETRY_DELAY: u64 = 5;
+
+enum Fsync {
+    Always,
+    EverySec,
+    No,
+}
+
+pub struct Aof {
+    /// Incremental file receiving the writes, while AOF is on.
+    file: Option<File>,
+    /// Files of the AOF as of the manifest on disk, plus the incremental file
+    /// of a rewrite in progress.
+    manifest: Manifest,
+    /// Sequence number of the last incremental file opened.
+    incr_seq: u64,
+    /// Whether there were writes since the last fsync, under `everysec`.
+    unsynced: bool,
+    last_write_ok: bool,
+    /// First incremental file of the running rewrite, the writes before it
+    /// are in the new base.
+    rewrite_from: Option<u64>,
+    last_rewrite_ok: bool,
+    last_rewrite_try: u64,
+    /// Size of the AOF right after the last rewrite, the growth of
+    /// `current_size` over it triggers automatic rewrites.
+    base_size: u64,
+    current_size: u64,
+}
+
+impl Aof {
+    pub fn new() -> Self {
+        Aof {
+            file: None,
+            manifest: Manifest::default(),
+            incr_seq: 0,
+            unsynced: false,
+            last_write_ok: true,
+            rewrite_from: None,
+            last_rewrite_ok: true,
+            last_rewrite_try: 0,
+            base_size: 0,
+            current_size: 0,
+        }
+    }
+}
+
+fn option_enabled(name: &str) -> bool {
+    get_options_instance().get(name).map(|value| value == "yes").unwrap_or(false)
+}
+
+fn fsync_policy() -> Fsync {
+    match get_options_instance().get("appendfsync").map(String::as_str) {
+        Some("always") => Fsync::Always,
+        Some("no") => Fsync::No,
+        _ => Fsync::EverySec,
+    }
+}
+
+fn option(name: &str, default: &str) -> String {
+    get_options_instance().get(name).cloned().unwrap_or_else(|| default.to_string())
+}
+
+fn aof_filename() -> String {
+    option("appendfilename", "appendonly.aof")
+}
+
+/// The directory holding the AOF files, `appenddirname` under `dir`.
+pub fn aof_dir() -> PathBuf {
+    PathBuf::from(option("dir", ".")).join(option("appenddirname", "appendonlydir"))
+}
+
+fn manifest_path() -> PathBuf {
+    aof_dir().join(format!("{}.manifest", aof_filename()))
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0)
+}
+
+/// Syncs the manifest to disk through a rename, the switchover point of a
+/// rewrite.
+fn persist_manifest(manifest: &Manifest) -> io::Result<()> {
+    fs::create_dir_all(aof_dir())?;
+    persistence::write_atomically(manifest.to_text().as_bytes(), &manifest_path())
+}
+
+/// Creates the next incremental file and adds it to the manifest in memory.
+fn open_incr(aof: &mut Aof) -> io::Result<File> {
+    let seq = aof.incr_seq + 1;
+    let name = format!("{}.{}.incr.aof", aof_filename(), seq);
+    fs::create_dir_all(aof_dir())?;
+    let file = File::create(aof_dir().join(&name))?;
+    aof.incr_seq = seq;
+    aof.manifest.incrs.push(ManifestFile { name, seq });
+    Ok(file)
+}
+
+/// Appends a write, in the form replicas receive it, when AOF is enabled.
+/// Callers hold the execution lock.
+pub fn feed(frame: &[u8]) {
+    let aof = get_aof_instance();
+    let Some(file) = aof.file.as_mut() else {
+        return;
+    };
+    let result = file.write_all(frame).and_then(|_| match fsync_policy() {
+        Fsync::Always => file.sync_data(),
+        _ => Ok(()),
+    });
+    aof.unsynced = true;
+    aof.last_write_ok = result.is_ok();
+    match result {
+        Ok(()) => aof.current_size += frame.len() as u64,
+        Err(err) => eprintln!("[Rudis]: Error writing to the AOF: {}", err),
+    }
+}
+
+/// Flushes the writes of the last second to disk under `appendfsync
+/// everysec`. The fsync itself runs without the execution lock.
+pub fn fsync_every_second() {
+    let file = {
+        let _guard = lock_execution();
+        let aof = get_aof_instance();
+        if !matches!(fsync_policy(), Fsync::EverySec) || !aof.unsynced {
+            return;
+        }
+        aof.unsynced = false;
+        aof.file.as_ref().and_then(|file| file.try_clone().ok())
+    };
+    if let Some(Err(err)) = file.map(|file| file.sync_data()) {
+        eprintln!("[Rudis]: Error syncing the AOF: {}", err);
+    }
+}
+
+/// Starts rewriting the AOF in the background. Callers hold the execution
+/// lock: from here on writes go to a new incremental file, and the new base
+/// is the keyspace as it is now.
+fn start_rewrite() -> io::Result<()> {
+    let aof = get_aof_instance();
+    aof.last_rewrite_try = now_secs();
+    let from = aof.incr_seq + 1;
+    if option_enabled("appendonly") {
+        let file = open_incr(aof)?;
+        // While AOF was already on, loading the old files plus the new one
+        // gives the current keyspace until the rewrite is done. When it was
+        // just turned on, the old files are stale and the manifest only
+        // changes once the new base is written.
+        if aof.file.is_some() {
+            if let Err(err) = persist_manifest(&aof.manifest) {
+                if let Some(incr) = aof.manifest.incrs.pop() {
+                    _ = fs::remove_file(aof_dir().join(incr.name));
+                }
+                return Err(err);
+            }
+        }
+        if let Some(old) = aof.file.replace(file) {
+            _ = old.sync_data();
+        }
+    }
+    aof.rewrite_from = Some(from);
+
+    let snapshot = persistence::snapshot();
+    let base_seq = aof.manifest.base.as_ref().map(|base| base.seq).unwrap_or(0) + 1;
+    let base_name = format!("{}.{}{}", aof_filename(), base_seq, BASE_RDB_SUFFIX);
+    let base_path = aof_dir().join(&base_name);
+    thread::spawn(move || {
+        let result = fs::create_dir_all(aof_dir())
+            .and_then(|_| persistence::write_atomically(&persistence::serialize(&snapshot), &base_path));
+        let _guard = lock_execution();
+        finish_rewrite(result.map(|_| ManifestFile { name: base_name, seq: base_seq }), from);
+    });
+    Ok(())
+}
+
+/// Switches to the manifest with the new `base` and the incremental files
+/// opened since the rewrite started, then deletes the files it replaces.
+fn finish_rewrite(base: io::Result<ManifestFile>, from: u64) {
+    let aof = get_aof_instance();
+    aof.rewrite_from = None;
+    let result = base.and_then(|base| {
+        let base_path = aof_dir().join(&base.name);
+        let incrs = aof.manifest.incrs.iter().filter(|incr| incr.seq >= from).cloned().collect();
+        let manifest = Manifest { base: Some(base), incrs };
+        match persist_manifest(&manifest) {
+            Ok(()) => Ok(manifest),
+            Err(err) => {
+                _ = fs::remove_file(base_path);
+                Err(err)
+            }
+        }
+    });
+    aof.last_rewrite_ok = result.is_ok();
+    match result {
+        Ok(manifest) => {
+            let old = std::mem::replace(&mut aof.manifest, manifest);
+            for file in old.files() {
+                if !aof.manifest.files().any(|current| current.name == file.name) {
+                    _ = fs::remove_file(aof_dir().join(&file.name));
+                }
+            }
+            aof.current_size = aof.manifest.files().map(|file| file_size(&aof_dir().join(&file.name))).sum();
+            aof.base_size = aof.current_size;
+            println!("[Rudis]: Background AOF rewrite finished successfully");
+        }
+        Err(err) => eprintln!("[Rudis]: Background AOF rewrite failed: {}", err),
+    }
+}
+
+/// `BGREWRITEAOF`.
+fn bgrewriteaof() -> Result<Vec<u8>, CommandError> {
+    if get_aof_instance().rewrite_from.is_some() {
+        return Err(CommandError::Other("Background append only file rewriting already in progress".to_string()));
+    }
+    match start_rewrite() {
+        Ok(()) => Ok(resp::simple_string("Background append only file rewriting started")),
+        Err(err) => {
+            eprintln!("[Rudis]: Can't rewrite the append only file: {}", err);
+            Err(CommandError::Other("Can't execute an AOF background rewriting. Please check the server logs for more information.".to_string()))
+        }
+    }
+}
+
+/// Turns AOF on or off after `CONFIG SET appendonly`. Turning it on
+/// rewrites the AOF from the current keyspace.
+pub fn set_enabled(enabled: bool) -> Result<(), CommandError> {
+    let aof = get_aof_instance();
+    if !enabled {
+        if let Some(file) = aof.file.take() {
+            _ = file.sync_data();
+        }
+        return Ok(());
+    }
+    if aof.file.is_some() {
+        return Ok(());
+    }
+    if aof.rewrite_from.is_some() {
+        return Err(CommandError::Other("Background append only file rewriting already in progress".to_string()));
+    }
+    start_rewrite().map_err(|err| CommandError::Other(format!("Can't turn on the AOF: {}", err)))
+}
+
+/// Starts a rewrite once the AOF has grown by `auto-aof-rewrite-percentage`
+/// since the last one and is at least `auto-aof-rewrite-min-size`.
+pub fn cron() {
+    let _guard = lock_execution();
+    let aof = get_aof_instance();
+    if aof.file.is_none() || aof.rewrite_from.is_some() {
+        return;
+    }
+    if !aof.last_rewrite_ok && now_secs().saturating_sub(aof.last_rewrite_try) <= REWRITE_RETRY_DELAY {
+        return;
+    }
+    let percentage = option("auto-aof-rewrite-percentage", "100").parse::<u64>().unwrap_or(0);
+    let min_size = parse_memory(&option("auto-aof-rewrite-min-size", "64mb")).unwrap_or(0);
+    if percentage == 0 || aof.current_size < min_size {
+        return;
+    }
+    let base = aof.base_size.max(1);
+    let growth = aof.current_size.saturating_sub(base) * 100 / base;
+    if growth >= percentage {
+        println!("[Rudis]: Starting automatic rewriting of AOF on {}% growth", growth);
+        if let Err(err) = start_rewrite() {
+            aof.last_rewrite_ok = false;
+            eprintln!("[Rudis]: Can't rewrite the append only file: {}", err);
+        }
+    }
+}
+
+/// Runs a command read from the AOF.
+fn replay(frame: &[u8], transaction: &mut Transaction) {
+    let Ok(Some((command, _))) = parse_command(frame) else {
+        return;
+    };
+    match command.args[0].as_str() {
+        "MULTI" => {
+            transaction.begin();
+        }
+        "EXEC" => {
+            transaction.exec();
+        }
+        _ if transaction.is_active() => {
+            transaction.queue(command);
+        }
+        _ => {
+            if let Err(err) = execute(&command) {
+                eprintln!("[Rudis]: Error replaying {} from the AOF: {}", command.args[0], err);
+            }
+        }
+    }
+}
+
+/// Replays the commands of one AOF file and returns how many there were. A
+/// last command cut short, or a `MULTI` without its `EXEC`, is dropped from
+/// the last file when `aof-load-truncated` is on, other errors stop the
+/// server.
+fn replay_file(path: &Path, last: bool) -> usize {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("[Rudis]: Can't read the AOF file {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    };
+
+    let mut transaction = Transaction::new();
+    let mut multi_start = None;
+    let mut pos = 0;
+    let mut commands = 0;
+    let error = loop {
+        if pos == data.len() {
+            break multi_start.map(AofError::Truncated);
+        }
+        match read_command(&data, pos) {
+            Ok((args, end)) => {
+                if args[0].eq_ignore_ascii_case(b"MULTI") {
+                    multi_start = Some(pos);
+                } else if args[0].eq_ignore_ascii_case(b"EXEC") {
+                    multi_start = None;
+                }
+                replay(&data[pos..end], &mut transaction);
+                commands += 1;
+                pos = end;
+            }
+            Err(err) => break Some(err),
+        }
+    };
+
+    match error {
+        None => {}
+        Some(AofError::Truncated(offset)) if last && option_enabled("aof-load-truncated") => {
+            // Nothing of an unfinished transaction ran, drop it whole.
+            let valid = multi_start.unwrap_or(offset);
+            println!(
+                "[Rudis]: !!! Warning: short read while loading the AOF file {}, truncating it to {} bytes !!!",
+                path.display(),
+                valid
+            );
+            let truncated = OpenOptions::new().write(true).open(path).and_then(|file| file.set_len(valid as u64));
+            if let Err(err) = truncated {
+                eprintln!("[Rudis]: Failed to truncate the AOF {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        }
+        Some(AofError::Truncated(offset)) if last => {
+            eprintln!(
+                "[Rudis]: Unexpected end of file reading the AOF {} at offset {}. Set aof-load-truncated to yes to load it anyway.",
+                path.display(),
+                offset
+            );
+            std::process::exit(1);
+        }
+        Some(AofError::Truncated(offset)) => {
+            eprintln!(
+                "[Rudis]: Unexpected end of file reading the AOF {} at offset {}, which is not the last file of the AOF.",
+                path.display(),
+                offset
+            );
+            std::process::exit(1);
+        }
+        Some(err) => {
+            eprintln!("[Rudis]: Bad file format reading the AOF {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    }
+    commands
+}
+
+/// Loads a base file in RDB format and returns how many keys it had.
+fn load_rdb_base(path: &Path) -> usize {
+    let snapshot = match fs::read(path).map_err(|err| err.to_string()).and_then(|data| rdb::parse(&data).map_err(|err| err.to_string())) {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            eprintln!("[Rudis]: Failed to load the AOF base {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    };
+    persistence::load_snapshot(snapshot)
+}
+
+/// Moves an AOF of a single file, as `appendfilename` under `dir`, into the
+/// AOF directory as the base, in command format.
+fn upgrade() -> Option<Manifest> {
+    let legacy = PathBuf::from(option("dir", ".")).join(aof_filename());
+    if !legacy.is_file() {
+        return None;
+    }
+    let name = format!("{}.1.base.aof", aof_filename());
+    let manifest = Manifest { base: Some(ManifestFile { name: name.clone(), seq: 1 }), incrs: Vec::new() };
+    let result = fs::create_dir_all(aof_dir())
+        .and_then(|_| fs::rename(&legacy, aof_dir().join(&name)))
+        .and_then(|_| persist_manifest(&manifest));
+    if let Err(err) = result {
+        eprintln!("[Rudis]: Failed to move the AOF {} into {}: {}", legacy.display(), aof_dir().display(), err);
+        std::process::exit(1);
+    }
+    println!("[Rudis]: Successfully migrated an old-style AOF into the AOF directory {}", aof_dir().display());
+    Some(manifest)
+}
+
+/// Loads the AOF when AOF is enabled and there is one, returning whether it
+/// did.
+pub fn load() -> bool {
+    if !option_enabled("appendonly") {
+        return false;
+    }
+    let path = manifest_path();
+    let manifest = match fs::read_to_string(&path) {
+        Ok(text) => match Manifest::parse(&text) {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                eprintln!("[Rudis]: Failed to load the AOF manifest {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        },
+        Err(_) => match upgrade() {
+            Some(manifest) => manifest,
+            None => return false,
+        },
+    };
+
+    let count = manifest.files().count();
+    let mut size = 0;
+    for (idx, file) in manifest.files().enumerate() {
+        let path = aof_dir().join(&file.name);
+        if file.name.ends_with(BASE_RDB_SUFFIX) {
+            let keys = load_rdb_base(&path);
+            println!("[Rudis]: Loaded {} keys from the AOF base {}", keys, path.display());
+        } else {
+            let commands = replay_file(&path, idx + 1 == count);
+            println!("[Rudis]: Replayed {} commands from {}", commands, path.display());
+        }
+        size += file_size(&path);
+    }
+
+    // What was just loaded is already on disk.
+    let memory = get_memory_instance();
+    let dirty = memory.dirty();
+    memory.saved(dirty);
+    let aof = get_aof_instance();
+    aof.incr_seq = manifest.incrs.iter().map(|incr| incr.seq).max().unwrap_or(0);
+    aof.manifest = manifest;
+    aof.current_size = size;
+    aof.base_size = size;
+    true
+}
+
+/// Opens the AOF for appending when AOF is enabled. Without an AOF to
+/// continue, it is created from the keyspace loaded from the RDB.
+pub fn open() {
+    if !option_enabled("appendonly") {
+        return;
+    }
+    let _guard = lock_execution();
+    let aof = get_aof_instance();
+    let result = match aof.manifest.incrs.last() {
+        Some(incr) => OpenOptions::new().append(true).open(aof_dir().join(&incr.name)).map(|file| aof.file = Some(file)),
+        None if aof.manifest.base.is_some() => open_incr(aof).and_then(|file| {
+            aof.file = Some(file);
+            persist_manifest(&aof.manifest)
+        }),
+        None => start_rewrite(),
+    };
+    if let Err(err) = result {
+        eprintln!("[Rudis]: Can't open the AOF in {}: {}", aof_dir().display(), err);
+        std::process::exit(1);
+    }
+}
+
+/// Fields of the persistence section of `INFO` about the AOF.
+pub fn info() -> Vec<(&'static str, String)> {
+    let aof = get_aof_instance();
+    let mut fields = vec![
+        ("aof_enabled", (aof.file.is_some() as u8).to_string()),
+        ("aof_rewrite_in_progress", (aof.rewrite_from.is_some() as u8).to_string()),
+        ("aof_last_bgrewrite_status", if aof.last_rewrite_ok { "ok" } else { "err" }.to_string()),
+        ("aof_last_write_status", if aof.last_write_ok { "ok" } else { "err" }.to_string()),
+    ];
+    if aof.file.is_some() {
+        fields.push(("aof_current_size", aof.current_size.to_string()));
+        fields.push(("aof_base_size", aof.base_size.to_string()));
+    }
+    fields
+}
+
+pub fn process_command(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    match commands[0].as_str() {
+        "BGREWRITEAOF" => bgrewriteaof(),
+        _ => Err(CommandError::Other(format!("unknown command '{}'", commands[0]))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::run;
+
+    fn frame(args: &[&str]) -> Vec<u8> {
+        resp::bulk_string_array(args)
+    }
+
+    /// Writes `data` as an AOF file and replays it as the last file.
+    fn replay_data(name: &str, data: &[u8]) -> (usize, Vec<u8>) {
+        let path = std::env::temp_dir().join(format!("rudis-{}-{}.aof", name, std::process::id()));
+        fs::write(&path, data).unwrap();
+        {
+            let _guard = lock_execution();
+            get_options_instance().set("aof-load-truncated", "yes");
+        }
+        let commands = replay_file(&path, true);
+        let left = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        (commands, left)
+    }
+
+    #[test]
+    fn replays_commands_and_transactions() {
+        let mut data = frame(&["SET", "aof:plain", "1"]);
+        data.extend(frame(&["MULTI"]));
+        data.extend(frame(&["SET", "aof:multi", "1"]));
+        data.extend(frame(&["SADD", "aof:set", "a", "b"]));
+        data.extend(frame(&["EXEC"]));
+        assert_eq!(replay_data("replay", &data), (5, data));
+        assert_eq!(run(&["GET", "aof:plain"]), resp::bulk_string("1"));
+        assert_eq!(run(&["GET", "aof:multi"]), resp::bulk_string("1"));
+        assert_eq!(run(&["SCARD", "aof:set"]), resp::integer(2));
+    }
+
+    #[test]
+    fn truncates_a_last_command_cut_short() {
+        let valid = frame(&["SET", "aof:truncated", "1"]);
+        let mut data = valid.clone();
+        data.extend(&frame(&["SET", "aof:truncated", "2"])[..20]);
+        assert_eq!(replay_data("truncated", &data), (1, valid));
+        assert_eq!(run(&["GET", "aof:truncated"]), resp::bulk_string("1"));
+    }
+
+    #[test]
+    fn drops_an_unfinished_transaction_whole() {
+        let valid = frame(&["SET", "aof:unfinished", "1"]);
+        let mut data = valid.clone();
+        data.extend(frame(&["MULTI"]));
+        data.extend(frame(&["SET", "aof:unfinished", "2"]));
+        assert_eq!(replay_data("unfinished", &data), (3, valid));
+        assert_eq!(run(&["GET", "aof:unfinished"]), resp::bulk_string("1"));
+    }
+}
@@ -0,0 +1,249 @@
+// Checks an AOF, either a single file or every file listed in a manifest:
+// the framing of each command, transactions left without their EXEC, and
+// the RDB base. With `--fix` the last file is truncated to its last valid
+// command.
+
+#[path = "../aof/format.rs"]
+mod format;
+#[allow(dead_code)]
+#[path = "../aof/manifest.rs"]
+mod manifest;
+#[allow(dead_code, unused_imports)]
+#[path = "../rdb/mod.rs"]
+mod rdb;
+
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+
+use format::read_command;
+use manifest::{Manifest, BASE_RDB_SUFFIX};
+
+/// Offset up to which `data` holds whole commands and whole transactions,
+/// and the first error after it.
+fn check_commands(data: &[u8]) -> (usize, Option<String>) {
+    let mut multi_start = None;
+    let mut pos = 0;
+    while pos < data.len() {
+        match read_command(data, pos) {
+            Ok((args, end)) => {
+                if args[0].eq_ignore_ascii_case(b"MULTI") {
+                    multi_start = Some(pos);
+                } else if args[0].eq_ignore_ascii_case(b"EXEC") {
+                    multi_start = None;
+                }
+                pos = end;
+            }
+            // Commands of an unfinished transaction never ran, the valid
+            // part ends before its MULTI.
+            Err(err) => return (multi_start.unwrap_or(pos), Some(err.to_string())),
+        }
+    }
+    match multi_start {
+        Some(start) => (start, Some(format!("reached EOF before reading EXEC for the MULTI at offset {}", start))),
+        None => (pos, None),
+    }
+}
+
+/// Checks one file of commands and truncates it when `fix` is set, returning
+/// whether it is valid in the end.
+fn check_aof_file(path: &Path, fix: bool) -> bool {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("Cannot open {}: {}", path.display(), err);
+            return false;
+        }
+    };
+    let (valid, error) = check_commands(&data);
+    if let Some(error) = &error {
+        println!("{}", error);
+    }
+    let lines = data[..valid].iter().filter(|byte| **byte == b'\n').count();
+    println!(
+        "AOF analyzed: filename={}, size={}, ok_up_to={}, ok_up_to_line={}, diff={}",
+        path.display(),
+        data.len(),
+        valid,
+        lines + 1,
+        data.len() - valid
+    );
+    if error.is_none() {
+        println!("AOF {} is valid", path.display());
+        return true;
+    }
+    if !fix {
+        println!("AOF {} is not valid. Use the --fix option to try fixing it.", path.display());
+        return false;
+    }
+    println!(
+        "This will shrink the AOF {} from {} bytes, with {} bytes, to {} bytes",
+        path.display(),
+        data.len(),
+        data.len() - valid,
+        valid
+    );
+    match OpenOptions::new().write(true).open(path).and_then(|file| file.set_len(valid as u64)) {
+        Ok(()) => {
+            println!("Successfully truncated AOF {}", path.display());
+            true
+        }
+        Err(err) => {
+            println!("Failed to truncate AOF {}: {}", path.display(), err);
+            false
+        }
+    }
+}
+
+fn check_rdb_file(path: &Path) -> bool {
+    let result = fs::read(path).map_err(|err| err.to_string()).and_then(|data| rdb::parse(&data).map_err(|err| err.to_string()));
+    match result {
+        Ok(snapshot) => {
+            println!("RDB {} is valid, {} keys", path.display(), snapshot.entries.len());
+            true
+        }
+        Err(err) => {
+            println!("RDB {} is not valid: {}", path.display(), err);
+            false
+        }
+    }
+}
+
+/// Checks every file of the manifest at `path`. Only the last one may be
+/// fixed, a cut in any other one lost writes for good.
+fn check_manifest(path: &Path, fix: bool) -> bool {
+    let manifest = match fs::read_to_string(path).map_err(|err| err.to_string()).and_then(|text| Manifest::parse(&text)) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            println!("Invalid AOF manifest {}: {}", path.display(), err);
+            return false;
+        }
+    };
+    println!("Start checking Multi Part AOF");
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let count = manifest.files().count();
+    for (idx, file) in manifest.files().enumerate() {
+        let file_path = dir.join(&file.name);
+        let last = idx + 1 == count;
+        let valid = if file.name.ends_with(BASE_RDB_SUFFIX) {
+            check_rdb_file(&file_path)
+        } else {
+            check_aof_file(&file_path, fix && last)
+        };
+        if !valid {
+            if fix && !last {
+                println!("Only the last file of the AOF can be fixed");
+            }
+            return false;
+        }
+    }
+    println!("All AOF files and manifest are valid");
+    true
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (fix, path) = match args.as_slice() {
+        [_, path] => (false, path),
+        [_, flag, path] if flag == "--fix" => (true, path),
+        _ => {
+            eprintln!("Usage: {} [--fix] <file.manifest|file.aof>", args[0]);
+            std::process::exit(1);
+        }
+    };
+    let path = Path::new(path);
+    let valid = if path.to_string_lossy().ends_with(".manifest") {
+        check_manifest(path, fix)
+    } else if path.to_string_lossy().ends_with(BASE_RDB_SUFFIX) {
+        check_rdb_file(path)
+    } else {
+        check_aof_file(path, fix)
+    };
+    if !valid {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(args: &[&str]) -> Vec<u8> {
+        let mut frame = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            frame.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+        }
+        frame
+    }
+
+    fn commands(commands: &[&[&str]]) -> Vec<u8> {
+        commands.iter().flat_map(|args| frame(args)).collect()
+    }
+
+    /// A directory of its own for each test, removed once done.
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("rudis-check-aof-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn valid_up_to_the_first_error() {
+        let data = commands(&[&["SET", "k", "v"], &["MULTI"], &["SET", "k", "w"], &["EXEC"]]);
+        assert_eq!(check_commands(&data), (data.len(), None));
+
+        let mut cut = data.clone();
+        cut.extend(&frame(&["SET", "k", "x"])[..10]);
+        assert_eq!(check_commands(&cut).0, data.len());
+        // A transaction without its EXEC is invalid from its MULTI on.
+        let multi = frame(&["SET", "k", "v"]).len();
+        let (valid, error) = check_commands(&data[..data.len() - frame(&["EXEC"]).len()]);
+        assert_eq!(valid, multi);
+        assert_eq!(error.unwrap(), format!("reached EOF before reading EXEC for the MULTI at offset {}", multi));
+        let (valid, error) = check_commands(b"*1\r\n$4\r\nPING\r\nGARBAGE");
+        assert_eq!((valid, error.unwrap()), (14, "expected '*' at offset 14".to_string()));
+    }
+
+    #[test]
+    fn fixing_truncates_the_file() {
+        let dir = TempDir::new("fix");
+        let path = dir.0.join("appendonly.aof");
+        let valid = commands(&[&["SET", "k", "v"]]);
+        let mut data = valid.clone();
+        data.extend(&frame(&["SET", "k", "w"])[..12]);
+        fs::write(&path, &data).unwrap();
+        assert!(!check_aof_file(&path, false));
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert!(check_aof_file(&path, true));
+        assert_eq!(fs::read(&path).unwrap(), valid);
+    }
+
+    #[test]
+    fn only_the_last_file_of_a_manifest_is_fixed() {
+        let dir = TempDir::new("manifest");
+        let cut = &frame(&["SET", "k", "w"])[..12];
+        let manifest = "file appendonly.aof.1.incr.aof seq 1 type i\nfile appendonly.aof.2.incr.aof seq 2 type i\n";
+        fs::write(dir.0.join("appendonly.aof.manifest"), manifest).unwrap();
+        fs::write(dir.0.join("appendonly.aof.1.incr.aof"), cut).unwrap();
+        fs::write(dir.0.join("appendonly.aof.2.incr.aof"), cut).unwrap();
+        let path = dir.0.join("appendonly.aof.manifest");
+        assert!(!check_manifest(&path, true));
+        assert_eq!(fs::read(dir.0.join("appendonly.aof.2.incr.aof")).unwrap(), cut);
+
+        fs::write(dir.0.join("appendonly.aof.1.incr.aof"), frame(&["SET", "k", "v"])).unwrap();
+        assert!(check_manifest(&path, true));
+        assert!(fs::read(dir.0.join("appendonly.aof.2.incr.aof")).unwrap().is_empty());
+
+        fs::write(&path, "file appendonly.aof.1.incr.aof seq 1\n").unwrap();
+        assert!(!check_manifest(&path, false));
+    }
+}
@@ -0,0 +1,175 @@
+// Checks an RDB file: the header, every opcode, the value of every key in
+// whatever encoding it has, and the checksum. On corruption it reports the
+// offset where the first error is and the key being read.
+
+#[allow(dead_code, unused_imports)]
+#[path = "../rdb/mod.rs"]
+mod rdb;
+
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rdb::{RdbError, RdbValue};
+
+fn type_name(kind: u8) -> &'static str {
+    match kind {
+        0 => "string",
+        1 => "list",
+        2 => "set",
+        3 => "zset",
+        4 => "hash",
+        5 => "zset-v2",
+        9 => "hash-zipmap",
+        10 => "list-ziplist",
+        11 => "set-intset",
+        12 => "zset-ziplist",
+        13 => "hash-ziplist",
+        14 => "list-quicklist",
+        15 => "stream",
+        16 => "hash-listpack",
+        17 => "zset-listpack",
+        18 => "list-quicklist-v2",
+        19 => "stream-v2",
+        20 => "set-listpack",
+        21 => "stream-v3",
+        24 => "hash-metadata",
+        25 => "hash-listpack-ex",
+        _ => "unknown",
+    }
+}
+
+fn value_name(value: &RdbValue) -> &'static str {
+    match value {
+        RdbValue::String(_) => "string",
+        RdbValue::List(_) => "list",
+        RdbValue::Set(_) => "set",
+        RdbValue::SortedSet(_) => "zset",
+        RdbValue::Hash(_) => "hash",
+        RdbValue::Stream(_) => "stream",
+    }
+}
+
+/// Prints where `err` happened, and the key being read if any.
+fn report(err: &RdbError) {
+    println!("--- RDB ERROR DETECTED ---");
+    let (inner, key) = match err {
+        RdbError::Key { offset, key, kind, source } => (source.as_ref(), Some((*offset, key, *kind))),
+        other => (other, None),
+    };
+    match inner {
+        RdbError::UnexpectedEof(offset) => println!("[offset {}] Unexpected EOF reading RDB file", offset),
+        RdbError::Invalid { offset, message } => println!("[offset {}] {}", offset, message),
+        RdbError::Key { .. } => println!("{}", inner),
+    }
+    if let Some((offset, key, kind)) = key {
+        println!(
+            "[additional info] While reading key '{}' of type {} ({}) starting at offset {}",
+            String::from_utf8_lossy(key),
+            kind,
+            type_name(kind),
+            offset
+        );
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 2 {
+        eprintln!("Usage: {} <rdb-file-name>", args[0]);
+        std::process::exit(1);
+    }
+    let path = &args[1];
+    println!("[offset 0] Checking RDB file {}", path);
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("Cannot open {}: {}", path, err);
+            std::process::exit(1);
+        }
+    };
+    let snapshot = match rdb::parse(&data) {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            report(&err);
+            std::process::exit(1);
+        }
+    };
+
+    println!("[info] RDB version {}", snapshot.version);
+    for (field, value) in &snapshot.aux {
+        println!("[info] AUX FIELD {} = '{}'", String::from_utf8_lossy(field), String::from_utf8_lossy(value));
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_millis() as u64).unwrap_or(0);
+    let mut types: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut dbs: BTreeMap<u64, usize> = BTreeMap::new();
+    for entry in &snapshot.entries {
+        *types.entry(value_name(&entry.value)).or_default() += 1;
+        *dbs.entry(entry.db).or_default() += 1;
+    }
+    for (db, keys) in &dbs {
+        println!("[info] DB {}: {} keys", db, keys);
+    }
+    for (name, keys) in &types {
+        println!("[info] {} keys of type {}", keys, name);
+    }
+    println!("[info] {} keys read", snapshot.entries.len());
+    println!("[info] {} expires", snapshot.entries.iter().filter(|entry| entry.expire.is_some()).count());
+    println!(
+        "[info] {} already expired",
+        snapshot.entries.iter().filter(|entry| entry.expire.is_some_and(|expire| expire < now)).count()
+    );
+    if snapshot.version >= 5 {
+        let offset = data.len() - 8;
+        if data[offset..].iter().all(|byte| *byte == 0) {
+            println!("[offset {}] RDB file was saved with checksum disabled: no check performed.", offset);
+        } else {
+            println!("[offset {}] Checksum OK", offset);
+        }
+    }
+    println!("[offset {}] \\o/ RDB looks OK! \\o/", data.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdb::{Rdb, RdbEntry, RDB_VERSION};
+
+    fn entry(key: &str, value: RdbValue) -> RdbEntry {
+        RdbEntry { db: 0, key: key.as_bytes().to_vec(), expire: None, value }
+    }
+
+    /// The key and the type of the value a cut at `len` bytes lands in.
+    fn cut_key(rdb: &Rdb, len: usize) -> Option<(String, &'static str)> {
+        let data = rdb::serialize(rdb, false);
+        match rdb::parse(&data[..len]) {
+            Err(RdbError::Key { key, kind, .. }) => Some((String::from_utf8(key).unwrap(), type_name(kind))),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn names_the_type_of_the_broken_key() {
+        let field = |expire| (b"field".to_vec(), b"value".to_vec(), expire);
+        let mut rdb = Rdb {
+            version: RDB_VERSION,
+            aux: Vec::new(),
+            entries: vec![entry("string", RdbValue::String(b"value".to_vec()))],
+        };
+        // Cuts the last 3 bytes of the value, before the EOF opcode and the
+        // 8 byte checksum.
+        let cut = |rdb: &Rdb| cut_key(rdb, rdb::serialize(rdb, false).len() - 12);
+        assert_eq!(cut(&rdb), Some(("string".to_string(), "string")));
+
+        rdb.entries = vec![entry("hash", RdbValue::Hash(vec![field(None)]))];
+        assert_eq!(cut(&rdb), Some(("hash".to_string(), "hash")));
+        rdb.entries = vec![entry("ttls", RdbValue::Hash(vec![field(Some(1))]))];
+        assert_eq!(cut(&rdb), Some(("ttls".to_string(), "hash-metadata")));
+    }
+
+    #[test]
+    fn names_values_by_type() {
+        assert_eq!(value_name(&RdbValue::SortedSet(Vec::new())), "zset");
+        assert_eq!(value_name(&RdbValue::List(Vec::new())), "list");
+        assert_eq!(type_name(200), "unknown");
+    }
+}
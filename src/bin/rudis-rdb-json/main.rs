@@ -0,0 +1,444 @@
+// Converts an RDB file to JSON lines and back, one line per key:
+//
+//   {"db":0,"key":"k","type":"hash","ttl":null,"value":[["field","value"]]}
+//
+// `ttl` is the absolute expiry time of the key in milliseconds since the
+// epoch, so a dump converts back to the same snapshot whenever it is done.
+// Keys and values that aren't UTF-8 are written as {"hex":"..."}. Values
+// are strings for strings, arrays of elements for lists and sets, arrays of
+// [member, score] for sorted sets and of [field, value] or
+// [field, value, ttl] for hashes, and an object mirroring the RDB layout for
+// streams.
+
+#[allow(dead_code)]
+#[path = "../../rdb/mod.rs"]
+mod rdb;
+mod json;
+
+use std::fs;
+use std::io::{self, Write};
+
+use json::Json;
+use rdb::{Rdb, RdbConsumer, RdbEntry, RdbGroup, RdbPending, RdbStream, RdbStreamEntry, RdbStreamId, RdbValue};
+
+fn number(value: u64) -> Json {
+    Json::Number(value.to_string())
+}
+
+fn optional(value: Option<u64>) -> Json {
+    match value {
+        Some(value) => number(value),
+        None => Json::Null,
+    }
+}
+
+fn bytes(value: &[u8]) -> Json {
+    match std::str::from_utf8(value) {
+        Ok(text) => Json::String(text.to_string()),
+        Err(_) => Json::Object(vec![("hex".to_string(), Json::String(hex::encode(value)))]),
+    }
+}
+
+fn score(value: f64) -> Json {
+    if value.is_infinite() {
+        Json::String(if value > 0.0 { "inf" } else { "-inf" }.to_string())
+    } else {
+        Json::Number(value.to_string())
+    }
+}
+
+fn stream_id((ms, seq): RdbStreamId) -> Json {
+    Json::String(format!("{}-{}", ms, seq))
+}
+
+fn object(fields: Vec<(&str, Json)>) -> Json {
+    Json::Object(fields.into_iter().map(|(name, value)| (name.to_string(), value)).collect())
+}
+
+fn stream_json(stream: &RdbStream) -> Json {
+    let entries = stream
+        .entries
+        .iter()
+        .map(|(id, fields)| {
+            let fields = fields.iter().map(|(field, value)| Json::Array(vec![bytes(field), bytes(value)])).collect();
+            Json::Array(vec![stream_id(*id), Json::Array(fields)])
+        })
+        .collect();
+    let groups = stream
+        .groups
+        .iter()
+        .map(|group| {
+            let pending = group
+                .pending
+                .iter()
+                .map(|pending| {
+                    object(vec![
+                        ("id", stream_id(pending.id)),
+                        ("delivery_time", number(pending.delivery_time)),
+                        ("delivery_count", number(pending.delivery_count)),
+                    ])
+                })
+                .collect();
+            let consumers = group
+                .consumers
+                .iter()
+                .map(|consumer| {
+                    object(vec![
+                        ("name", bytes(&consumer.name)),
+                        ("seen_time", number(consumer.seen_time)),
+                        ("active_time", optional(consumer.active_time)),
+                        ("pending", Json::Array(consumer.pending.iter().map(|id| stream_id(*id)).collect())),
+                    ])
+                })
+                .collect();
+            object(vec![
+                ("name", bytes(&group.name)),
+                ("last_id", stream_id(group.last_id)),
+                ("entries_read", optional(group.entries_read)),
+                ("pending", Json::Array(pending)),
+                ("consumers", Json::Array(consumers)),
+            ])
+        })
+        .collect();
+    object(vec![
+        ("entries", Json::Array(entries)),
+        ("last_id", stream_id(stream.last_id)),
+        ("max_deleted_id", stream_id(stream.max_deleted_id)),
+        ("entries_added", number(stream.entries_added)),
+        ("groups", Json::Array(groups)),
+    ])
+}
+
+fn entry_json(entry: &RdbEntry) -> Json {
+    let (kind, value) = match &entry.value {
+        RdbValue::String(value) => ("string", bytes(value)),
+        RdbValue::List(items) => ("list", Json::Array(items.iter().map(|item| bytes(item)).collect())),
+        RdbValue::Set(items) => ("set", Json::Array(items.iter().map(|item| bytes(item)).collect())),
+        RdbValue::SortedSet(entries) => (
+            "zset",
+            Json::Array(entries.iter().map(|(member, value)| Json::Array(vec![bytes(member), score(*value)])).collect()),
+        ),
+        RdbValue::Hash(fields) => (
+            "hash",
+            Json::Array(
+                fields
+                    .iter()
+                    .map(|(field, value, expire)| {
+                        let mut items = vec![bytes(field), bytes(value)];
+                        items.extend(expire.map(number));
+                        Json::Array(items)
+                    })
+                    .collect(),
+            ),
+        ),
+        RdbValue::Stream(stream) => ("stream", stream_json(stream)),
+    };
+    object(vec![
+        ("db", number(entry.db)),
+        ("key", bytes(&entry.key)),
+        ("type", Json::String(kind.to_string())),
+        ("ttl", optional(entry.expire)),
+        ("value", value),
+    ])
+}
+
+fn field<'a>(value: &'a Json, name: &str) -> Result<&'a Json, String> {
+    value.get(name).ok_or_else(|| format!("missing field '{}'", name))
+}
+
+fn to_u64(value: &Json) -> Result<u64, String> {
+    match value {
+        Json::Number(text) => text.parse::<u64>().map_err(|_| format!("invalid integer {}", text)),
+        _ => Err("expected an integer".to_string()),
+    }
+}
+
+fn to_optional(value: &Json) -> Result<Option<u64>, String> {
+    match value {
+        Json::Null => Ok(None),
+        value => to_u64(value).map(Some),
+    }
+}
+
+fn to_bytes(value: &Json) -> Result<Vec<u8>, String> {
+    match value {
+        Json::String(text) => Ok(text.as_bytes().to_vec()),
+        Json::Object(_) => match value.get("hex") {
+            Some(Json::String(text)) => hex::decode(text).map_err(|_| format!("invalid hex string {}", text)),
+            _ => Err("expected a string or {\"hex\": ...}".to_string()),
+        },
+        _ => Err("expected a string".to_string()),
+    }
+}
+
+fn to_score(value: &Json) -> Result<f64, String> {
+    match value {
+        Json::Number(text) => text.parse::<f64>().map_err(|_| format!("invalid score {}", text)),
+        Json::String(text) if text == "inf" => Ok(f64::INFINITY),
+        Json::String(text) if text == "-inf" => Ok(f64::NEG_INFINITY),
+        _ => Err("expected a score".to_string()),
+    }
+}
+
+fn to_array(value: &Json) -> Result<&[Json], String> {
+    match value {
+        Json::Array(items) => Ok(items),
+        _ => Err("expected an array".to_string()),
+    }
+}
+
+fn to_stream_id(value: &Json) -> Result<RdbStreamId, String> {
+    let text = match value {
+        Json::String(text) => text,
+        _ => return Err("expected a stream ID".to_string()),
+    };
+    let parsed = text.split_once('-').and_then(|(ms, seq)| Some((ms.parse::<u64>().ok()?, seq.parse::<u64>().ok()?)));
+    parsed.ok_or_else(|| format!("invalid stream ID {}", text))
+}
+
+fn to_stream(value: &Json) -> Result<RdbStream, String> {
+    let mut entries: Vec<RdbStreamEntry> = Vec::new();
+    for entry in to_array(field(value, "entries")?)? {
+        let [id, fields] = to_array(entry)? else {
+            return Err("expected [id, fields] for a stream entry".to_string());
+        };
+        let mut pairs = Vec::new();
+        for pair in to_array(fields)? {
+            let [field, value] = to_array(pair)? else {
+                return Err("expected [field, value] in a stream entry".to_string());
+            };
+            pairs.push((to_bytes(field)?, to_bytes(value)?));
+        }
+        entries.push((to_stream_id(id)?, pairs));
+    }
+    let mut groups = Vec::new();
+    for group in to_array(field(value, "groups")?)? {
+        let mut pending = Vec::new();
+        for entry in to_array(field(group, "pending")?)? {
+            pending.push(RdbPending {
+                id: to_stream_id(field(entry, "id")?)?,
+                delivery_time: to_u64(field(entry, "delivery_time")?)?,
+                delivery_count: to_u64(field(entry, "delivery_count")?)?,
+            });
+        }
+        let mut consumers = Vec::new();
+        for consumer in to_array(field(group, "consumers")?)? {
+            consumers.push(RdbConsumer {
+                name: to_bytes(field(consumer, "name")?)?,
+                seen_time: to_u64(field(consumer, "seen_time")?)?,
+                active_time: to_optional(field(consumer, "active_time")?)?,
+                pending: to_array(field(consumer, "pending")?)?.iter().map(to_stream_id).collect::<Result<_, _>>()?,
+            });
+        }
+        groups.push(RdbGroup {
+            name: to_bytes(field(group, "name")?)?,
+            last_id: to_stream_id(field(group, "last_id")?)?,
+            entries_read: to_optional(field(group, "entries_read")?)?,
+            pending,
+            consumers,
+        });
+    }
+    Ok(RdbStream {
+        entries,
+        last_id: to_stream_id(field(value, "last_id")?)?,
+        max_deleted_id: to_stream_id(field(value, "max_deleted_id")?)?,
+        entries_added: to_u64(field(value, "entries_added")?)?,
+        groups,
+    })
+}
+
+fn to_entry(line: &Json) -> Result<RdbEntry, String> {
+    let value = field(line, "value")?;
+    let kind = match field(line, "type")? {
+        Json::String(kind) => kind.as_str(),
+        _ => return Err("expected a string for 'type'".to_string()),
+    };
+    let value = match kind {
+        "string" => RdbValue::String(to_bytes(value)?),
+        "list" => RdbValue::List(to_array(value)?.iter().map(to_bytes).collect::<Result<_, _>>()?),
+        "set" => RdbValue::Set(to_array(value)?.iter().map(to_bytes).collect::<Result<_, _>>()?),
+        "zset" => {
+            let mut entries = Vec::new();
+            for pair in to_array(value)? {
+                let [member, score] = to_array(pair)? else {
+                    return Err("expected [member, score] in a zset".to_string());
+                };
+                entries.push((to_bytes(member)?, to_score(score)?));
+            }
+            RdbValue::SortedSet(entries)
+        }
+        "hash" => {
+            let mut fields = Vec::new();
+            for items in to_array(value)? {
+                match to_array(items)? {
+                    [field, value] => fields.push((to_bytes(field)?, to_bytes(value)?, None)),
+                    [field, value, expire] => fields.push((to_bytes(field)?, to_bytes(value)?, Some(to_u64(expire)?))),
+                    _ => return Err("expected [field, value] or [field, value, ttl] in a hash".to_string()),
+                }
+            }
+            RdbValue::Hash(fields)
+        }
+        "stream" => RdbValue::Stream(to_stream(value)?),
+        other => return Err(format!("unknown type '{}'", other)),
+    };
+    Ok(RdbEntry {
+        db: to_u64(field(line, "db")?)?,
+        key: to_bytes(field(line, "key")?)?,
+        expire: to_optional(field(line, "ttl")?)?,
+        value,
+    })
+}
+
+fn to_json(input: &str, output: Option<&String>) -> Result<(), String> {
+    let data = fs::read(input).map_err(|err| format!("Cannot open {}: {}", input, err))?;
+    let snapshot = rdb::parse(&data).map_err(|err| format!("Invalid RDB {}: {}", input, err))?;
+    let mut text = String::new();
+    for entry in &snapshot.entries {
+        entry_json(entry).write(&mut text);
+        text.push('\n');
+    }
+    let written = match output {
+        Some(output) => fs::write(output, text),
+        None => io::stdout().write_all(text.as_bytes()),
+    };
+    written.map_err(|err| format!("Cannot write the dump: {}", err))
+}
+
+fn to_rdb(input: &str, output: &str) -> Result<(), String> {
+    let text = fs::read_to_string(input).map_err(|err| format!("Cannot open {}: {}", input, err))?;
+    let mut entries = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = json::parse(line).and_then(|line| to_entry(&line));
+        entries.push(entry.map_err(|err| format!("{}:{}: {}", input, idx + 1, err))?);
+    }
+    // The writer starts a new database section whenever the database changes.
+    entries.sort_by_key(|entry| entry.db);
+    let ctime = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0);
+    let aux = [("redis-ver", "7.2.0".to_string()), ("redis-bits", "64".to_string()), ("ctime", ctime.to_string())];
+    let snapshot = Rdb {
+        version: rdb::RDB_VERSION,
+        aux: aux.into_iter().map(|(field, value)| (field.as_bytes().to_vec(), value.into_bytes())).collect(),
+        entries,
+    };
+    fs::write(output, rdb::serialize(&snapshot, true)).map_err(|err| format!("Cannot write {}: {}", output, err))
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let result = match args.get(1).map(String::as_str) {
+        Some("to-json") if (3..=4).contains(&args.len()) => to_json(&args[2], args.get(3)),
+        Some("to-rdb") if args.len() == 4 => to_rdb(&args[2], &args[3]),
+        _ => {
+            eprintln!("Usage: {} to-json <file.rdb> [file.jsonl]", args[0]);
+            eprintln!("       {} to-rdb <file.jsonl> <file.rdb>", args[0]);
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: &[u8], expire: Option<u64>, value: RdbValue) -> RdbEntry {
+        RdbEntry { db: 0, key: key.to_vec(), expire, value }
+    }
+
+    fn line(entry: &RdbEntry) -> String {
+        let mut text = String::new();
+        entry_json(entry).write(&mut text);
+        text
+    }
+
+    fn sample() -> Vec<RdbEntry> {
+        let stream = RdbStream {
+            entries: vec![((1, 0), vec![(b"f".to_vec(), b"v".to_vec())]), ((2, 5), vec![(b"g".to_vec(), vec![0xff])])],
+            last_id: (2, 5),
+            max_deleted_id: (1, 1),
+            entries_added: 3,
+            groups: vec![RdbGroup {
+                name: b"readers".to_vec(),
+                last_id: (2, 5),
+                entries_read: None,
+                pending: vec![RdbPending { id: (1, 0), delivery_time: u64::MAX, delivery_count: 2 }],
+                consumers: vec![RdbConsumer { name: b"alice".to_vec(), seen_time: 7, active_time: Some(9), pending: vec![(1, 0)] }],
+            }],
+        };
+        vec![
+            entry(b"string", Some(1_900_000_000_000), RdbValue::String(b"value".to_vec())),
+            RdbEntry { db: 3, ..entry(&[0xc3, 0x28], None, RdbValue::String(vec![0, 0xfe])) },
+            entry(b"list", None, RdbValue::List(vec![b"a".to_vec(), b"a".to_vec()])),
+            entry(b"set", None, RdbValue::Set(vec![b"x".to_vec()])),
+            entry(b"zset", None, RdbValue::SortedSet(vec![(b"a".to_vec(), -0.5), (b"b".to_vec(), f64::NEG_INFINITY)])),
+            entry(b"hash", None, RdbValue::Hash(vec![(b"f".to_vec(), b"v".to_vec(), None), (b"g".to_vec(), b"w".to_vec(), Some(5))])),
+            entry(b"stream", None, RdbValue::Stream(stream)),
+        ]
+    }
+
+    #[test]
+    fn entries_survive_a_dump() {
+        for original in sample() {
+            let parsed = to_entry(&json::parse(&line(&original)).unwrap()).unwrap();
+            assert_eq!(parsed, original);
+        }
+    }
+
+    #[test]
+    fn dump_format() {
+        let entries = sample();
+        assert_eq!(
+            line(&entries[0]),
+            r#"{"db":0,"key":"string","type":"string","ttl":1900000000000,"value":"value"}"#
+        );
+        assert_eq!(line(&entries[1]), r#"{"db":3,"key":{"hex":"c328"},"type":"string","ttl":null,"value":{"hex":"00fe"}}"#);
+        assert!(line(&entries[4]).ends_with(r#""value":[["a",-0.5],["b","-inf"]]}"#));
+        assert!(line(&entries[5]).ends_with(r#""value":[["f","v"],["g","w",5]]}"#));
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        let error = |text: &str| to_entry(&json::parse(text).unwrap()).err().unwrap();
+        assert_eq!(error(r#"{"db":0,"key":"k","ttl":null,"value":1}"#), "missing field 'type'");
+        assert_eq!(error(r#"{"db":0,"key":"k","type":"list","ttl":null,"value":1}"#), "expected an array");
+        assert_eq!(error(r#"{"db":0,"key":"k","type":"json","ttl":null,"value":1}"#), "unknown type 'json'");
+        assert_eq!(error(r#"{"db":-1,"key":"k","type":"string","ttl":null,"value":"v"}"#), "invalid integer -1");
+        assert_eq!(error(r#"{"db":0,"key":{"hex":"zz"},"type":"string","ttl":null,"value":"v"}"#), "invalid hex string zz");
+        assert_eq!(
+            error(r#"{"db":0,"key":"k","type":"zset","ttl":null,"value":[["a"]]}"#),
+            "expected [member, score] in a zset"
+        );
+        assert_eq!(
+            error(r#"{"db":0,"key":"k","type":"stream","ttl":null,"value":{"entries":[["1",[]]]}}"#),
+            "invalid stream ID 1"
+        );
+    }
+
+    #[test]
+    fn files_convert_both_ways() {
+        let dir = std::env::temp_dir().join(format!("rudis-rdb-json-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).display().to_string();
+        let jsonl: String = sample().iter().map(|entry| line(entry) + "\n\n").collect();
+        fs::write(path("dump.jsonl"), &jsonl).unwrap();
+
+        to_rdb(&path("dump.jsonl"), &path("dump.rdb")).unwrap();
+        let snapshot = rdb::parse(&fs::read(path("dump.rdb")).unwrap()).unwrap();
+        // Keys are grouped by database.
+        assert_eq!(snapshot.entries.last().unwrap().db, 3);
+        to_json(&path("dump.rdb"), Some(&path("back.jsonl"))).unwrap();
+        let back = fs::read_to_string(path("back.jsonl")).unwrap();
+        assert_eq!(back.lines().count(), sample().len());
+        assert!(back.lines().all(|line| jsonl.contains(line)));
+
+        fs::write(path("bad.jsonl"), "\n{\"db\":0}\n").unwrap();
+        assert_eq!(to_rdb(&path("bad.jsonl"), &path("bad.rdb")).err().unwrap(), format!("{}:2: missing field 'value'", path("bad.jsonl")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::commands::lock_execution;
+use crate::error::CommandError;
+use crate::get_blocking_instance;
+use crate::util::parse_f64;
+
+/// Clients blocked on keys, e.g. by `BZPOPMIN`. Writers call `signal` on the
+/// keys they touched and every client waiting on one of them retries its
+/// command.
+pub struct Blocking {
+    waiters: HashMap<String, Vec<Arc<Notify>>>,
+}
+
+impl Blocking {
+    pub fn new() -> Self {
+        Blocking {
+            waiters: HashMap::new(),
+        }
+    }
+
+    fn register(&mut self, keys: &[String], notify: &Arc<Notify>) {
+        for key in keys {
+            self.waiters.entry(key.to_string()).or_default().push(notify.clone());
+        }
+    }
+
+    fn unregister(&mut self, keys: &[String], notify: &Arc<Notify>) {
+        for key in keys {
+            if let Some(waiters) = self.waiters.get_mut(key) {
+                waiters.retain(|w| !Arc::ptr_eq(w, notify));
+                if waiters.is_empty() {
+                    self.waiters.remove(key);
+                }
+            }
+        }
+    }
+
+    pub fn signal(&mut self, key: &str) {
+        if let Some(waiters) = self.waiters.get(key) {
+            for waiter in waiters {
+                waiter.notify_one();
+            }
+        }
+    }
+}
+
+/// Parses a blocking timeout in seconds, where 0 means wait forever.
+pub fn parse_timeout(value: &str) -> Result<Option<Duration>, CommandError> {
+    match parse_f64(value) {
+        Some(timeout) if timeout < 0.0 => Err(CommandError::Other("timeout is negative".to_string())),
+        Some(0.0) => Ok(None),
+        Some(timeout) if timeout.is_finite() => Ok(Some(Duration::from_secs_f64(timeout))),
+        _ => Err(CommandError::Other("timeout is not a float or out of range".to_string())),
+    }
+}
+
+/// Runs `attempt` until it produces a result, sleeping between tries until one
+/// of `keys` is signaled. Returns `None` once `timeout` elapses.
+pub async fn block_on_keys<T, F>(keys: &[String], timeout: Option<Duration>, mut attempt: F) -> Result<Option<T>, CommandError>
+where
+    F: FnMut() -> Result<Option<T>, CommandError>,
+{
+    let deadline = timeout.map(|t| Instant::now() + t);
+    loop {
+        let notify = Arc::new(Notify::new());
+        let result = {
+            let _guard = lock_execution();
+            let result = attempt();
+            // Registering in the same locked section as the failed attempt
+            // means any later write signals it. A signal arriving before the
+            // wait below is kept by `Notify` as a permit.
+            if matches!(result, Ok(None)) {
+                get_blocking_instance().register(keys, &notify);
+            }
+            result
+        };
+        if !matches!(result, Ok(None)) {
+            return result;
+        }
+
+        let woken = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, notify.notified()).await.is_ok(),
+            None => {
+                notify.notified().await;
+                true
+            }
+        };
+        {
+            let _guard = lock_execution();
+            get_blocking_instance().unregister(keys, &notify);
+        }
+        if !woken {
+            return Ok(None);
+        }
+    }
+}
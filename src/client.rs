@@ -0,0 +1,103 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::commands::lock_execution;
+use crate::error::CommandError;
+use crate::pubsub::{self, Message};
+use crate::transaction::Transaction;
+use crate::{get_clients_instance, resp, tracking};
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// State a connection keeps across commands: its negotiated protocol, an
+/// ongoing transaction and its pub/sub subscriptions. Messages published to
+/// the client are sent through `sender` and written out by `handle`.
+pub struct Client {
+    pub id: u64,
+    pub resp3: bool,
+    pub transaction: Transaction,
+    pub channels: BTreeSet<String>,
+    pub patterns: BTreeSet<String>,
+    pub shard_channels: BTreeSet<String>,
+    pub sender: UnboundedSender<Message>,
+}
+
+/// Senders of every connected client by ID, for messages addressed to
+/// another connection such as redirected invalidations.
+pub struct Clients {
+    senders: HashMap<u64, UnboundedSender<Message>>,
+}
+
+impl Clients {
+    pub fn new() -> Self {
+        Clients {
+            senders: HashMap::new(),
+        }
+    }
+
+    pub fn sender(&self, id: u64) -> Option<&UnboundedSender<Message>> {
+        self.senders.get(&id)
+    }
+}
+
+impl Client {
+    pub fn new() -> (Self, UnboundedReceiver<Message>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let client = Client {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            resp3: false,
+            transaction: Transaction::new(),
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+            shard_channels: BTreeSet::new(),
+            sender,
+        };
+        let _guard = lock_execution();
+        get_clients_instance().senders.insert(client.id, client.sender.clone());
+        (client, receiver)
+    }
+
+    pub fn subscription_count(&self) -> usize {
+        self.channels.len() + self.patterns.len() + self.shard_channels.len()
+    }
+
+    /// RESP2 connections with subscriptions can only run the subscribe
+    /// family of commands.
+    pub fn in_subscribed_mode(&self) -> bool {
+        !self.resp3 && self.subscription_count() > 0
+    }
+
+    /// `RESET`: back to a fresh RESP2 connection without subscriptions,
+    /// transaction nor tracking.
+    pub fn reset(&mut self) {
+        pubsub::unsubscribe_all(self);
+        tracking::disable(self.id);
+        self.transaction = Transaction::new();
+        self.resp3 = false;
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        pubsub::unsubscribe_all(self);
+        tracking::disable(self.id);
+        let _guard = lock_execution();
+        get_clients_instance().senders.remove(&self.id);
+    }
+}
+
+/// `CLIENT ID|TRACKING|CACHING`
+pub fn process_command(client: &mut Client, commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 2 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    match commands[1].to_ascii_uppercase().as_str() {
+        "ID" if commands.len() == 2 => Ok(resp::integer(client.id as i64)),
+        "ID" => Err(CommandError::arity("client|id")),
+        "TRACKING" => tracking::client_tracking(client, commands),
+        "CACHING" => tracking::client_caching(client, commands),
+        _ => Err(CommandError::Other(format!("unknown subcommand '{}'. Try CLIENT HELP.", commands[1]))),
+    }
+}
@@ -1,70 +1,480 @@
 
-use std::sync::Arc;
+use std::cell::{Cell, RefCell};
+use std::sync::{Arc, Mutex as StdMutex, MutexGuard};
 
 use tokio::sync::Mutex;
 
-use crate::{get_current_time, get_memory_instance, get_options_instance, get_replicas_instance, Connection, ReplicasList};
+use crate::error::CommandError;
+use crate::parser::Command;
+use crate::{aof, get_blocking_instance, get_current_time, get_memory_instance, get_options_instance, get_replicas_instance, get_replication_instance, consumer_group, geo, hash, hyperloglog, notify, persistence, pubsub, resp, set, stream, zset, Connection, ReplicasList};
+use crate::client::{self, Client};
+use crate::tracking;
+use crate::util::parse_memory;
+
+thread_local! {
+    static PROPAGATED: RefCell<Option<Vec<Vec<String>>>> = const { RefCell::new(None) };
+    static REPLICAS_ONLY: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Where a propagated command goes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Target {
+    /// The AOF and the replicas, for commands that change the keyspace.
+    All,
+    /// Only the replicas, for commands like `PUBLISH` that don't touch the
+    /// keyspace but whose effect replica clients expect to see.
+    Replicas,
+}
+
+/// Lets a command replace what gets propagated for it, for commands whose
+/// effect depends on state replicas don't share, e.g. the time a relative
+/// `HEXPIRE` ends at. An empty command propagates nothing.
+pub fn set_propagated(command: Vec<String>) {
+    let commands = if command.is_empty() { Vec::new() } else { vec![command] };
+    PROPAGATED.with(|propagated| *propagated.borrow_mut() = Some(commands));
+}
+
+/// Like `set_propagated`, for commands replicas need several commands to
+/// replay, e.g. one `XCLAIM` per entry `XAUTOCLAIM` claimed. Such commands
+/// start with `set_propagated(Vec::new())` to propagate nothing by default.
+pub fn push_propagated(command: Vec<String>) {
+    PROPAGATED.with(|propagated| propagated.borrow_mut().get_or_insert_with(Vec::new).push(command));
+}
+
+/// Propagates the running command to the replicas only, as it arrived
+/// unless `set_propagated` replaces it, e.g. for `PUBLISH`.
+pub fn set_replicas_only() {
+    REPLICAS_ONLY.with(|replicas_only| replicas_only.set(true));
+}
+
+fn take_propagated() -> Option<Vec<Vec<String>>> {
+    PROPAGATED.with(|propagated| propagated.borrow_mut().take())
+}
+
+fn take_target() -> Target {
+    match REPLICAS_ONLY.with(|replicas_only| replicas_only.replace(false)) {
+        true => Target::Replicas,
+        false => Target::All,
+    }
+}
+
+/// What replicas and the AOF receive for `command`, which just succeeded,
+/// and which of them receive it.
+pub fn propagated_frame(command: &Command) -> Option<(Vec<u8>, Target)> {
+    let target = take_target();
+    let frame = match take_propagated() {
+        Some(propagated) if propagated.is_empty() => None,
+        Some(propagated) => Some(propagated.iter().flat_map(|command| resp::bulk_string_array(command)).collect()),
+        None if target == Target::Replicas || is_write_command(&command.args[0]) => Some(command.frame()),
+        None => None,
+    };
+    frame.map(|frame| (frame, target))
+}
+
+/// Serializes command execution across connections, so a transaction runs
+/// without commands of other clients in between.
+static EXECUTION: StdMutex<()> = StdMutex::new(());
+
+pub fn lock_execution() -> MutexGuard<'static, ()> {
+    EXECUTION.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
 
 fn response_parser(res: String) -> Vec<u8> {
     let formatted_response = format!("+{}\r\n", res);
     formatted_response.as_bytes().to_vec()
 }
 
-pub async fn process_commands(commands: Vec<String>, buff: Vec<u8>, stream: &Connection, replicas_list: &Arc<Mutex<ReplicasList>>, replica_status: &mut bool) -> (Vec<Vec<u8>>, bool) {
-    let mut raw_response = "";
-    if let Some(first_element) = commands.first() {
-        match first_element.as_str() {
-            "PING" => {
-                raw_response = "PONG";
+/// Commands that modify the keyspace and therefore have to reach replicas.
+pub fn is_write_command(name: &str) -> bool {
+    matches!(
+        name,
+        "SET" | "HSET" | "HMSET" | "HSETNX" | "HDEL" | "HINCRBY" | "HINCRBYFLOAT"
+            | "HEXPIRE" | "HPEXPIRE" | "HEXPIREAT" | "HPEXPIREAT" | "HPERSIST" | "HGETEX"
+            | "SADD" | "SREM" | "SPOP" | "SMOVE" | "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE"
+            | "ZADD" | "ZINCRBY" | "ZREM" | "ZRANGESTORE" | "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE"
+            | "ZPOPMIN" | "ZPOPMAX" | "ZMPOP" | "XADD" | "XDEL" | "XTRIM" | "XGROUP" | "XREADGROUP" | "XACK"
+            | "XCLAIM" | "XAUTOCLAIM" | "PFADD" | "PFMERGE" | "GEOADD" | "GEOSEARCHSTORE" | "DEL" | "FLUSHALL"
+    )
+}
+
+/// Arity of the known commands, following the Redis convention: a negative
+/// value `-n` means at least `n` arguments, the command name included.
+fn command_arity(name: &str) -> Option<i32> {
+    let arity = match name {
+        "PING" | "INFO" | "REPLCONF" | "FLUSHALL" | "UNSUBSCRIBE" | "PUNSUBSCRIBE" | "SUNSUBSCRIBE" | "HELLO"
+        | "BGSAVE" => -1,
+        "MULTI" | "EXEC" | "DISCARD" | "UNWATCH" | "RESET" | "SAVE" | "LASTSAVE" | "BGREWRITEAOF" => 1,
+        "GET" | "TYPE" | "ECHO" | "HGETALL" | "HKEYS" | "HVALS" | "HLEN" | "SMEMBERS" | "SCARD" | "ZCARD" | "XLEN" => 2,
+        "HGET" | "HEXISTS" | "HSTRLEN" | "SISMEMBER" | "ZSCORE" | "PUBLISH" | "SPUBLISH" => 3,
+        "HSETNX" | "HINCRBY" | "HINCRBYFLOAT" | "SMOVE" | "ZINCRBY" | "ZCOUNT" | "ZLEXCOUNT" => 4,
+        "DEL" | "WATCH" | "OBJECT" | "CONFIG" | "HRANDFIELD" | "SPOP" | "SRANDMEMBER" | "SINTER" | "SUNION" | "SDIFF" | "ZPOPMIN"
+        | "ZPOPMAX" | "ZRANDMEMBER" | "XGROUP" | "XINFO" | "PFADD" | "PFCOUNT" | "PFMERGE" | "GEOPOS" | "GEOHASH"
+        | "SUBSCRIBE" | "PSUBSCRIBE" | "SSUBSCRIBE" | "PUBSUB" | "CLIENT" => -2,
+        "SET" | "HMGET" | "HDEL" | "HSCAN" | "SADD" | "SREM" | "SMISMEMBER" | "SINTERSTORE" | "SUNIONSTORE"
+        | "SDIFFSTORE" | "SINTERCARD" | "SSCAN" | "ZREM" | "ZMSCORE" | "ZRANK" | "ZREVRANK" | "ZUNION" | "ZINTER"
+        | "ZDIFF" | "ZINTERCARD" | "BZPOPMIN" | "BZPOPMAX" | "XDEL" | "XPENDING" | "PSYNC" => -3,
+        "HSET" | "HMSET" | "ZADD" | "ZRANGE" | "ZREVRANGE" | "ZRANGEBYSCORE" | "ZREVRANGEBYSCORE" | "ZRANGEBYLEX"
+        | "ZREVRANGEBYLEX" | "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE" | "ZMPOP" | "XRANGE" | "XREVRANGE"
+        | "XTRIM" | "XREAD" | "XACK" | "GEODIST" => -4,
+        "HTTL" | "HPTTL" | "HEXPIRETIME" | "HPEXPIRETIME" | "HPERSIST" | "HGETEX" | "ZRANGESTORE" | "BZMPOP" | "XADD"
+        | "GEOADD" => -5,
+        "HEXPIRE" | "HPEXPIRE" | "HEXPIREAT" | "HPEXPIREAT" | "XCLAIM" | "XAUTOCLAIM" => -6,
+        "XREADGROUP" | "GEOSEARCH" => -7,
+        "GEOSEARCHSTORE" => -8,
+        _ => return None,
+    };
+    Some(arity)
+}
+
+/// Rejects unknown commands and wrong argument counts before running them.
+pub fn check_command(commands: &[String]) -> Result<(), CommandError> {
+    let arity = match command_arity(&commands[0]) {
+        Some(arity) => arity,
+        None => {
+            let args: String = commands[1..].iter().map(|arg| format!("'{}' ", arg)).collect();
+            return Err(CommandError::Other(format!(
+                "unknown command '{}', with args beginning with: {}",
+                commands[0].to_ascii_lowercase(),
+                args
+            )));
+        }
+    };
+    let len = commands.len() as i32;
+    if (arity > 0 && len != arity) || len < -arity {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    Ok(())
+}
+
+/// `INFO [section]`, with every section when none is given.
+fn info(commands: &[String]) -> Vec<u8> {
+    let section = commands.get(1).map(|section| section.to_ascii_lowercase());
+    let all = matches!(section.as_deref(), None | Some("all") | Some("everything") | Some("default"));
+    let mut response = String::new();
+    if all || section.as_deref() == Some("replication") {
+        let master_replid = get_options_instance().get("master_replid").unwrap();
+        let port = get_options_instance().get("role").unwrap();
+        let master_repl_offset = get_options_instance().get("master_repl_offset").unwrap();
+        response.push_str(&format!(
+            "role:{port}\n\rmaster_replid:{master_replid}\n\rmaster_repl_offset:{master_repl_offset}\n\r"
+        ));
+    }
+    if all || section.as_deref() == Some("persistence") {
+        response.push_str("# Persistence\r\n");
+        for (name, value) in persistence::info().into_iter().chain(aof::info()) {
+            response.push_str(&format!("{}:{}\r\n", name, value));
+        }
+    }
+    format!("${}\r\n{response}\r\n", response.len()).into_bytes()
+}
+
+fn set(command: &Command) -> Result<Vec<u8>, CommandError> {
+    let commands = &command.args;
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let memory = get_memory_instance();
+    memory.set(commands[1].to_string(), command.raw_arg(2).to_vec());
+
+    if commands.len() > 4 && commands[3].eq_ignore_ascii_case("PX") {
+        let ttl = commands[4].parse::<u128>().map_err(|_| CommandError::NotInteger)?;
+        memory.expire(commands[1].to_string(), get_current_time() + ttl);
+    }
+    Ok(resp::simple_string("OK"))
+}
+
+fn object(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 3 || !commands[1].eq_ignore_ascii_case("ENCODING") {
+        return Err(CommandError::Syntax);
+    }
+    match get_memory_instance().lookup(&commands[2]) {
+        Some(value) => Ok(resp::bulk_string(value.encoding())),
+        None => Ok(resp::null_bulk_string()),
+    }
+}
+
+fn config(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let options = get_options_instance();
+    match commands[1].to_ascii_uppercase().as_str() {
+        "GET" => {
+            let mut elements = Vec::new();
+            for (name, value) in options.matching(&commands[2]) {
+                elements.push(resp::bulk_string(&name));
+                elements.push(resp::bulk_string(&value));
             }
-            "ECHO" => {
-                raw_response = &commands[1];
+            Ok(resp::array(elements))
+        }
+        "SET" => {
+            if commands.len() % 2 == 1 {
+                return Err(CommandError::arity(&commands[0]));
             }
-            "GET" => match get_memory_instance().get(&commands[1]) {
-                Some(value) => {
-                    raw_response = value;
+            for pair in commands[2..].chunks(2) {
+                if pair[0].eq_ignore_ascii_case("notify-keyspace-events") && !notify::is_valid(&pair[1]) {
+                    return Err(CommandError::Other(
+                        "CONFIG SET failed (possibly related to argument 'notify-keyspace-events') - Invalid event class character. Use 'KEg$lshzxetmnA'.".to_string(),
+                    ));
+                }
+                if pair[0].eq_ignore_ascii_case("save") && persistence::parse_save_points(&pair[1]).is_none() {
+                    return Err(CommandError::Other(
+                        "CONFIG SET failed (possibly related to argument 'save') - Invalid save parameters".to_string(),
+                    ));
                 }
-                None => {
-                    return (vec![b"$-1\r\n".to_vec()], false);
+                if pair[0].eq_ignore_ascii_case("auto-aof-rewrite-min-size") && parse_memory(&pair[1]).is_none() {
+                    return Err(CommandError::Other(
+                        "CONFIG SET failed (possibly related to argument 'auto-aof-rewrite-min-size') - argument must be a memory value".to_string(),
+                    ));
                 }
-            },
-            "SET" => {
-                let memory = get_memory_instance();
-                memory.set(commands[1].to_string(), commands[2].to_string());
-
-                if commands.len() > 3 {
-                    match commands[3].to_ascii_uppercase().as_str() {
-                        "PX" => {
-                            let ttl = commands[4].parse::<u128>().unwrap();
-                            memory.expire(commands[1].to_string(), get_current_time() + ttl);
+                if pair[0].eq_ignore_ascii_case("auto-aof-rewrite-percentage") && pair[1].parse::<u64>().is_err() {
+                    return Err(CommandError::Other(
+                        "CONFIG SET failed (possibly related to argument 'auto-aof-rewrite-percentage') - argument couldn't be parsed into an integer".to_string(),
+                    ));
+                }
+                if pair[0].eq_ignore_ascii_case("appendonly") {
+                    let enabled = match pair[1].to_ascii_lowercase().as_str() {
+                        "yes" => true,
+                        "no" => false,
+                        _ => {
+                            return Err(CommandError::Other(
+                                "CONFIG SET failed (possibly related to argument 'appendonly') - argument must be 'yes' or 'no'".to_string(),
+                            ))
                         }
-                        _ => {}
+                    };
+                    let previous = options.get("appendonly").cloned().unwrap_or_else(|| "no".to_string());
+                    options.set("appendonly", if enabled { "yes" } else { "no" });
+                    if let Err(err) = aof::set_enabled(enabled) {
+                        options.set("appendonly", &previous);
+                        return Err(err);
                     }
+                    continue;
                 }
+                options.set(&pair[0].to_ascii_lowercase(), &pair[1]);
+            }
+            Ok(resp::simple_string("OK"))
+        }
+        _ => Err(CommandError::Syntax),
+    }
+}
 
-                let replicas = &replicas_list.lock().await;
+/// Runs a command against the keyspace. These commands don't depend on the
+/// connection they arrive on, so the same path serves clients and the
+/// replication stream.
+pub fn execute(command: &Command) -> Result<Vec<u8>, CommandError> {
+    let _guard = lock_execution();
+    let response = execute_unlocked(command);
+    tracking::remember_reads(&command.args[0]);
+    let response = response?;
+    if let Some((frame, target)) = propagated_frame(command) {
+        propagate_to(&frame, target);
+    }
+    Ok(response)
+}
 
-                println!("# replicas: {}", replicas.handles.lock().await.len());
-                if replicas.handles.lock().await.len() > 0 {
-                    // replication_queue.lock().await.tasks.push(buff.to_vec());
-                    for replica in replicas.handles.lock().await.iter() {
-                        println!("send task to debug");
-                        _  = replica.sender.send(crate::ReplicaCommand { message: buff.to_vec() }).await;
-                    }
-                }
-                raw_response = "OK";
+/// Appends a write to the AOF and queues it for the replicas. Callers hold
+/// the execution lock, so both get writes in the order they ran, and a
+/// rewrite or a full sync never starts between a write and its propagation.
+pub fn propagate(frame: &[u8]) {
+    propagate_to(frame, Target::All);
+}
+
+pub fn propagate_to(frame: &[u8], target: Target) {
+    if target == Target::All {
+        aof::feed(frame);
+    }
+    get_replication_instance().feed(frame);
+}
+
+/// `execute` for callers already holding the execution lock, which also
+/// propagate the command themselves.
+pub fn execute_unlocked(command: &Command) -> Result<Vec<u8>, CommandError> {
+    let commands = &command.args;
+    take_propagated();
+    let response = notify::with_events(commands, || execute_command(command))?;
+    if is_write_command(&commands[0]) && commands.len() > 1 {
+        get_blocking_instance().signal(&commands[1]);
+    }
+    Ok(response)
+}
+
+fn execute_command(command: &Command) -> Result<Vec<u8>, CommandError> {
+    let commands = &command.args;
+    match commands[0].as_str() {
+        "PING" => Ok(resp::simple_string("PONG")),
+        "ECHO" => {
+            if commands.len() != 2 {
+                return Err(CommandError::arity(&commands[0]));
+            }
+            Ok(resp::simple_string(&commands[1]))
+        }
+        "INFO" => Ok(info(commands)),
+        "GET" => {
+            if commands.len() != 2 {
+                return Err(CommandError::arity(&commands[0]));
+            }
+            match get_memory_instance().get(&commands[1])? {
+                Some(value) => Ok(resp::bulk_bytes(value)),
+                None => Ok(resp::null_bulk_string()),
+            }
+        }
+        "SET" => set(command),
+        "DEL" => {
+            if commands.len() < 2 {
+                return Err(CommandError::arity(&commands[0]));
+            }
+            let memory = get_memory_instance();
+            let removed = commands[1..].iter().filter(|key| memory.remove(key)).count();
+            Ok(resp::integer(removed as i64))
+        }
+        "FLUSHALL" => {
+            match commands.get(1).map(|mode| mode.to_ascii_uppercase()) {
+                None => {}
+                Some(mode) if mode == "SYNC" || mode == "ASYNC" => {}
+                Some(_) => return Err(CommandError::Syntax),
+            }
+            get_memory_instance().flush();
+            Ok(resp::simple_string("OK"))
+        }
+        "TYPE" => {
+            if commands.len() != 2 {
+                return Err(CommandError::arity(&commands[0]));
             }
-            "INFO" => {
-                let master_replid = get_options_instance().get("master_replid").unwrap();
-                let port = get_options_instance().get("role").unwrap();
-                let master_repl_offset = get_options_instance().get("master_repl_offset").unwrap();
+            let value = get_memory_instance().lookup(&commands[1]);
+            Ok(resp::simple_string(value.map(|v| v.type_name()).unwrap_or("none")))
+        }
+        "OBJECT" => object(commands),
+        "CONFIG" => config(commands),
+        "HSET" | "HMSET" | "HSETNX" | "HGET" | "HMGET" | "HGETALL" | "HDEL" | "HEXISTS" | "HINCRBY"
+        | "HINCRBYFLOAT" | "HKEYS" | "HVALS" | "HLEN" | "HSTRLEN" | "HSCAN" | "HRANDFIELD" | "HEXPIRE"
+        | "HPEXPIRE" | "HEXPIREAT" | "HPEXPIREAT" | "HTTL" | "HPTTL" | "HEXPIRETIME" | "HPEXPIRETIME"
+        | "HPERSIST" | "HGETEX" => {
+            hash::process_command(commands)
+        }
+        "SADD" | "SREM" | "SMEMBERS" | "SISMEMBER" | "SMISMEMBER" | "SCARD" | "SPOP" | "SRANDMEMBER"
+        | "SMOVE" | "SINTER" | "SUNION" | "SDIFF" | "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE"
+        | "SINTERCARD" | "SSCAN" => set::process_command(commands),
+        "ZADD" | "ZINCRBY" | "ZREM" | "ZSCORE" | "ZMSCORE" | "ZCARD" | "ZRANK" | "ZREVRANK" | "ZCOUNT"
+        | "ZLEXCOUNT" | "ZRANGE" | "ZREVRANGE" | "ZRANGEBYSCORE" | "ZREVRANGEBYSCORE" | "ZRANGEBYLEX"
+        | "ZREVRANGEBYLEX" | "ZRANGESTORE" | "ZUNION" | "ZINTER" | "ZDIFF" | "ZUNIONSTORE" | "ZINTERSTORE"
+        | "ZDIFFSTORE" | "ZINTERCARD" | "ZPOPMIN" | "ZPOPMAX" | "ZMPOP" | "ZRANDMEMBER" | "BZPOPMIN" | "BZPOPMAX" | "BZMPOP" => {
+            zset::process_command(commands)
+        }
+        "XADD" | "XRANGE" | "XREVRANGE" | "XLEN" | "XDEL" | "XTRIM" | "XREAD" => stream::process_command(commands),
+        "XGROUP" | "XREADGROUP" | "XACK" | "XPENDING" | "XCLAIM" | "XAUTOCLAIM" | "XINFO" => {
+            consumer_group::process_command(commands)
+        }
+        "PFADD" | "PFCOUNT" | "PFMERGE" => hyperloglog::process_command(command),
+        "GEOADD" | "GEOPOS" | "GEODIST" | "GEOHASH" | "GEOSEARCH" | "GEOSEARCHSTORE" => geo::process_command(commands),
+        "PUBLISH" | "SPUBLISH" => pubsub::publish(command),
+        "PUBSUB" => pubsub::pubsub(commands),
+        "SAVE" | "BGSAVE" | "LASTSAVE" => persistence::process_command(commands),
+        "BGREWRITEAOF" => aof::process_command(commands),
+        _ => {
+            println!("Unrecognized command {:?}", commands);
+            Err(CommandError::Other(format!("unknown command '{}'", commands[0])))
+        }
+    }
+}
+
+/// `HELLO [protover]`: switches the connection protocol and describes the
+/// server, as a map under RESP3.
+fn hello(client: &mut Client, commands: &[String]) -> Vec<u8> {
+    match commands.get(1) {
+        None => {}
+        Some(_) if commands.len() > 2 => return resp::error(&CommandError::Syntax.to_string()),
+        Some(version) => match version.parse::<i64>() {
+            Ok(2) => client.resp3 = false,
+            Ok(3) => client.resp3 = true,
+            Ok(_) => return resp::error("NOPROTO unsupported protocol version"),
+            Err(_) => return resp::error("ERR Protocol version is not an integer or out of range"),
+        },
+    }
+    let role = match get_options_instance().get("role").unwrap().as_str() {
+        "slave" => "replica",
+        _ => "master",
+    };
+    let fields = vec![
+        ("server", resp::bulk_string("redis")),
+        ("version", resp::bulk_string("7.2.0")),
+        ("proto", resp::integer(if client.resp3 { 3 } else { 2 })),
+        ("id", resp::integer(client.id as i64)),
+        ("mode", resp::bulk_string("standalone")),
+        ("role", resp::bulk_string(role)),
+        ("modules", resp::array(Vec::new())),
+    ];
+    let fields = fields.into_iter().map(|(name, value)| (resp::bulk_string(name), value)).collect();
+    if client.resp3 {
+        resp::map(fields)
+    } else {
+        resp::array(fields.into_iter().flat_map(|(name, value)| [name, value]).collect())
+    }
+}
+
+/// Stream reads only need the async path when they may block.
+fn is_blocking_read(commands: &[String]) -> bool {
+    commands.iter().any(|arg| arg.eq_ignore_ascii_case("BLOCK"))
+}
 
-                let response = format!(
-                    "role:{port}\n\rmaster_replid:{master_replid}\n\rmaster_repl_offset:{master_repl_offset}\n\r"
-                );
-                return (vec![format!("${}\r\n{response}\r\n", response.len())
-                    .as_bytes()
-                    .to_vec()], false);
+pub async fn process_commands(command: Command, stream: &Connection, replicas_list: &Arc<Mutex<ReplicasList>>, replica_status: &mut bool, client: &mut Client) -> (Vec<Vec<u8>>, bool) {
+    let commands = &command.args;
+    let raw_response;
+    if let Some(first_element) = commands.first() {
+        if client.in_subscribed_mode() && !pubsub::allowed_in_subscribed_mode(first_element) {
+            let message = format!(
+                "Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                first_element.to_ascii_lowercase()
+            );
+            return (vec![resp::error(&CommandError::Other(message).to_string())], false);
+        }
+        if client.transaction.is_active() && !matches!(first_element.as_str(), "MULTI" | "EXEC" | "DISCARD" | "WATCH" | "RESET") {
+            return (vec![client.transaction.queue(command)], false);
+        }
+        match first_element.as_str() {
+            "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "SSUBSCRIBE" | "SUNSUBSCRIBE" => {
+                return match pubsub::process_command(client, commands) {
+                    Ok(responses) => (responses, false),
+                    Err(err) => (vec![resp::error(&err.to_string())], false),
+                };
+            }
+            "PING" if client.in_subscribed_mode() => {
+                let message = commands.get(1).map(String::as_str).unwrap_or("");
+                return (vec![resp::bulk_string_array(&["pong", message])], false);
+            }
+            "HELLO" => {
+                return (vec![hello(client, commands)], false);
+            }
+            "CLIENT" => {
+                let response = match client::process_command(client, commands) {
+                    Ok(response) => response,
+                    Err(err) => resp::error(&err.to_string()),
+                };
+                return (vec![response], false);
+            }
+            "RESET" => {
+                client.reset();
+                return (vec![resp::simple_string("RESET")], false);
+            }
+            "MULTI" => {
+                return (vec![client.transaction.begin()], false);
+            }
+            "DISCARD" => {
+                return (vec![client.transaction.discard()], false);
+            }
+            "WATCH" => {
+                if commands.len() < 2 {
+                    return (vec![resp::error(&CommandError::arity(&commands[0]).to_string())], false);
+                }
+                return (vec![client.transaction.watch(&commands[1..])], false);
+            }
+            "UNWATCH" => {
+                let _guard = lock_execution();
+                client.transaction.unwatch();
+                return (vec![resp::simple_string("OK")], false);
+            }
+            "EXEC" => {
+                let response = tracking::track(client.id, || client.transaction.exec());
+                return (vec![response], false);
             }
             "REPLCONF" => {
                 match commands[1].as_str() {
@@ -85,11 +495,71 @@ pub async fn process_commands(commands: Vec<String>, buff: Vec<u8>, stream: &Con
                     response_parser(format!("FULLRESYNC {} 0", idl))
                 ], false);
             }
+            "BZPOPMIN" | "BZPOPMAX" | "BZMPOP" => {
+                let response = match zset::blocking_pop(commands).await {
+                    Ok(response) => response,
+                    Err(err) => resp::error(&err.to_string()),
+                };
+                return (vec![response], false);
+            }
+            "XREAD" if is_blocking_read(commands) => {
+                let response = match stream::xread(commands).await {
+                    Ok(response) => response,
+                    Err(err) => resp::error(&err.to_string()),
+                };
+                return (vec![response], false);
+            }
+            "XREADGROUP" if is_blocking_read(commands) => {
+                let response = match consumer_group::xreadgroup(commands).await {
+                    Ok(response) => response,
+                    Err(err) => resp::error(&err.to_string()),
+                };
+                return (vec![response], false);
+            }
             _ => {
-                println!("Unrecognized command {:?}", commands);
+                let response = match tracking::track(client.id, || execute(&command)) {
+                    Ok(response) => response,
+                    Err(err) => resp::error(&err.to_string()),
+                };
+                return (vec![response], false);
             }
         }
     } else {
+        return (Vec::new(), false);
+    }
+    (vec![response_parser(raw_response.to_string())], false)
+}
+
+/// Runs a command the way a client's command runs and returns what the
+/// client reads, errors included, for the tests of the command modules.
+#[cfg(test)]
+pub fn run(args: &[&str]) -> Vec<u8> {
+    let command = Command::new(args.iter().map(|arg| arg.as_bytes().to_vec()).collect());
+    execute(&command).unwrap_or_else(|err| resp::error(&err.to_string()))
+}
+
+/// Runs a command and returns what it propagates and where, for tests.
+#[cfg(test)]
+pub fn run_propagated(args: &[&str]) -> Option<(Vec<String>, Target)> {
+    let command = Command::new(args.iter().map(|arg| arg.as_bytes().to_vec()).collect());
+    let _guard = lock_execution();
+    execute_unlocked(&command).ok()?;
+    propagated_frame(&command).map(|(frame, target)| (strings(&frame), target))
+}
+
+/// The bulk strings of an array reply in order, nested arrays flattened,
+/// for tests.
+#[cfg(test)]
+pub fn strings(reply: &[u8]) -> Vec<String> {
+    let reply = String::from_utf8_lossy(reply);
+    let mut lines = reply.split("\r\n");
+    let mut strings = Vec::new();
+    while let Some(line) = lines.next() {
+        match line.strip_prefix('$') {
+            Some("-1") => strings.push(String::new()),
+            Some(_) => strings.extend(lines.next().map(str::to_string)),
+            None => {}
+        }
     }
-    return (vec![response_parser(raw_response.to_string())], false);
+    strings
 }
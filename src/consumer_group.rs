@@ -0,0 +1,1240 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use crate::blocking::block_on_keys;
+use crate::commands::{lock_execution, propagate, push_propagated, set_propagated};
+use crate::error::CommandError;
+use crate::resp;
+use crate::stream::{entry_response, Stream, StreamId};
+use crate::util::parse_i64;
+use crate::{get_current_time, get_memory_instance};
+
+#[derive(Clone)]
+pub struct PendingEntry {
+    pub consumer: String,
+    pub delivery_time: u128,
+    pub delivery_count: u64,
+}
+
+#[derive(Clone)]
+pub struct Consumer {
+    pub seen_time: u128,
+    pub active_time: Option<u128>,
+    pub pending: BTreeSet<StreamId>,
+}
+
+impl Consumer {
+    fn new() -> Self {
+        Consumer {
+            seen_time: get_current_time(),
+            active_time: None,
+            pending: BTreeSet::new(),
+        }
+    }
+}
+
+/// A consumer group of a stream with its pending entries list (PEL): every
+/// entry delivered to a consumer and not yet acknowledged.
+#[derive(Clone)]
+pub struct ConsumerGroup {
+    pub last_delivered: StreamId,
+    pub entries_read: Option<u64>,
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<String, Consumer>,
+}
+
+impl ConsumerGroup {
+    pub fn new(last_delivered: StreamId, entries_read: Option<u64>) -> Self {
+        ConsumerGroup {
+            last_delivered,
+            entries_read,
+            pending: BTreeMap::new(),
+            consumers: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the consumer, creating it if needed, and marks it as seen.
+    fn consumer(&mut self, name: &str) -> &mut Consumer {
+        let consumer = self.consumers.entry(name.to_string()).or_insert_with(Consumer::new);
+        consumer.seen_time = get_current_time();
+        consumer
+    }
+
+    /// Assigns `id` to `consumer`, taking it away from its previous owner.
+    fn assign(&mut self, id: StreamId, consumer: &str, delivery_time: u128, delivery_count: u64) {
+        if let Some(previous) = self.pending.get(&id) {
+            if let Some(owner) = self.consumers.get_mut(&previous.consumer) {
+                owner.pending.remove(&id);
+            }
+        }
+        self.consumer(consumer).pending.insert(id);
+        self.pending.insert(
+            id,
+            PendingEntry { consumer: consumer.to_string(), delivery_time, delivery_count },
+        );
+    }
+
+    pub fn acknowledge(&mut self, id: StreamId) -> bool {
+        match self.pending.remove(&id) {
+            Some(entry) => {
+                if let Some(consumer) = self.consumers.get_mut(&entry.consumer) {
+                    consumer.pending.remove(&id);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Stream {
+    /// Whether an entry in `(after, last_id]` was deleted, which makes counting
+    /// entries by their position unreliable.
+    fn has_tombstones_after(&self, after: StreamId) -> bool {
+        self.max_deleted_id != StreamId::MIN && self.max_deleted_id > after
+    }
+
+    /// Estimates how many entries were ever added up to `id`, if that can be
+    /// known without walking the stream.
+    pub fn estimate_entries_read(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        if self.len() == 0 || id >= self.last_id {
+            return Some(self.entries_added);
+        }
+        let first_id = self.first_entry().map(|entry| entry.id)?;
+        if id < first_id && self.max_deleted_id < first_id {
+            return Some(self.entries_added - self.len() as u64);
+        }
+        None
+    }
+
+    /// Number of entries the group has yet to read, when it can be computed.
+    pub fn group_lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        let entries_read = match group.entries_read {
+            Some(entries_read) if !self.has_tombstones_after(group.last_delivered) => Some(entries_read),
+            _ => self.estimate_entries_read(group.last_delivered),
+        }?;
+        Some(self.entries_added.saturating_sub(entries_read))
+    }
+
+    fn record_delivery(&self, group: &mut ConsumerGroup, id: StreamId) {
+        group.last_delivered = id;
+        group.entries_read = match group.entries_read {
+            Some(entries_read) if !self.has_tombstones_after(id) => Some(entries_read + 1),
+            _ => self.estimate_entries_read(id),
+        };
+    }
+}
+
+fn no_group(key: &str, group: &str, command: &str) -> CommandError {
+    CommandError::Custom(format!(
+        "NOGROUP No such key '{}' or consumer group '{}' in {} command",
+        key, group, command
+    ))
+}
+
+/// Resolves the ID of `XGROUP CREATE/SETID`, where `$` stands for the last ID.
+fn parse_group_id(stream: &Stream, id: &str) -> Result<StreamId, CommandError> {
+    if id == "$" {
+        return Ok(stream.last_id);
+    }
+    StreamId::parse(id, 0)
+}
+
+fn parse_entries_read(args: &[String]) -> Result<Option<u64>, CommandError> {
+    match args {
+        [] => Ok(None),
+        [option, value] if option.eq_ignore_ascii_case("ENTRIESREAD") => match parse_i64(value) {
+            Some(value) if value >= 0 => Ok(Some(value as u64)),
+            Some(-1) => Ok(None),
+            _ => Err(CommandError::Other("value for ENTRIESREAD must be positive or -1".to_string())),
+        },
+        _ => Err(CommandError::Syntax),
+    }
+}
+
+fn xgroup(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let subcommand = commands[1].to_ascii_uppercase();
+    let (key, group_name) = (&commands[2], &commands[3]);
+    let memory = get_memory_instance();
+
+    if subcommand == "CREATE" {
+        if commands.len() < 5 {
+            return Err(CommandError::arity("xgroup|create"));
+        }
+        let mut options = &commands[5..];
+        let mkstream = options.first().map(|o| o.eq_ignore_ascii_case("MKSTREAM")).unwrap_or(false);
+        if mkstream {
+            options = &options[1..];
+        }
+        let entries_read = parse_entries_read(options)?;
+        if memory.get_stream(key)?.is_none() {
+            if !mkstream {
+                return Err(CommandError::Other(
+                    "The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.".to_string(),
+                ));
+            }
+            memory.get_or_create_stream(key)?;
+        }
+        let stream = memory.get_stream_mut(key)?.unwrap();
+        if stream.groups.contains_key(group_name) {
+            return Err(CommandError::Custom("BUSYGROUP Consumer Group name already exists".to_string()));
+        }
+        let id = parse_group_id(stream, &commands[4])?;
+        let entries_read = entries_read.or_else(|| stream.estimate_entries_read(id));
+        stream.groups.insert(group_name.to_string(), ConsumerGroup::new(id, entries_read));
+        memory.touch(key);
+        return Ok(resp::simple_string("OK"));
+    }
+
+    let stream = match memory.get_stream_mut(key)? {
+        Some(stream) => stream,
+        None => {
+            return Err(CommandError::Other(
+                "The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.".to_string(),
+            ))
+        }
+    };
+
+    match subcommand.as_str() {
+        "DESTROY" => {
+            let destroyed = stream.groups.remove(group_name).is_some();
+            if destroyed {
+                memory.touch(key);
+            }
+            Ok(resp::integer(destroyed as i64))
+        }
+        "SETID" => {
+            if commands.len() < 5 {
+                return Err(CommandError::arity("xgroup|setid"));
+            }
+            let id = parse_group_id(stream, &commands[4])?;
+            let entries_read = parse_entries_read(&commands[5..])?;
+            let group = stream.groups.get_mut(group_name).ok_or_else(|| no_group(key, group_name, "XGROUP"))?;
+            group.last_delivered = id;
+            group.entries_read = entries_read;
+            memory.touch(key);
+            Ok(resp::simple_string("OK"))
+        }
+        "CREATECONSUMER" | "DELCONSUMER" => {
+            if commands.len() != 5 {
+                return Err(CommandError::arity(&format!("xgroup|{}", subcommand.to_ascii_lowercase())));
+            }
+            let group = stream.groups.get_mut(group_name).ok_or_else(|| no_group(key, group_name, "XGROUP"))?;
+            let consumer_name = &commands[4];
+            if subcommand == "CREATECONSUMER" {
+                if group.consumers.contains_key(consumer_name) {
+                    return Ok(resp::integer(0));
+                }
+                group.consumer(consumer_name);
+                memory.touch(key);
+                return Ok(resp::integer(1));
+            }
+            let pending: Vec<StreamId> = match group.consumers.get(consumer_name) {
+                Some(consumer) => consumer.pending.iter().copied().collect(),
+                None => return Ok(resp::integer(0)),
+            };
+            for id in &pending {
+                group.acknowledge(*id);
+            }
+            group.consumers.remove(consumer_name);
+            memory.touch(key);
+            Ok(resp::integer(pending.len() as i64))
+        }
+        _ => Err(CommandError::Other(format!(
+            "unknown subcommand '{}'. Try XGROUP HELP.",
+            commands[1]
+        ))),
+    }
+}
+
+struct ReadGroup<'a> {
+    group: &'a str,
+    consumer: &'a str,
+    count: Option<usize>,
+    block: Option<u64>,
+    no_ack: bool,
+    keys: &'a [String],
+    ids: &'a [String],
+}
+
+impl<'a> ReadGroup<'a> {
+    /// Parses `GROUP group consumer [COUNT count] [BLOCK ms] [NOACK] STREAMS key [key ...] id [id ...]`.
+    fn parse(commands: &'a [String]) -> Result<Self, CommandError> {
+        if commands.len() < 7 || !commands[1].eq_ignore_ascii_case("GROUP") {
+            return Err(CommandError::Syntax);
+        }
+        let mut read = ReadGroup {
+            group: &commands[2],
+            consumer: &commands[3],
+            count: None,
+            block: None,
+            no_ack: false,
+            keys: &[],
+            ids: &[],
+        };
+        let mut idx = 4;
+        while idx < commands.len() {
+            match commands[idx].to_ascii_uppercase().as_str() {
+                "COUNT" if idx + 1 < commands.len() => {
+                    let count = parse_i64(&commands[idx + 1]).ok_or(CommandError::NotInteger)?;
+                    read.count = if count > 0 { Some(count as usize) } else { None };
+                    idx += 2;
+                }
+                "BLOCK" if idx + 1 < commands.len() => {
+                    match parse_i64(&commands[idx + 1]) {
+                        Some(timeout) if timeout >= 0 => read.block = Some(timeout as u64),
+                        Some(_) => return Err(CommandError::Other("timeout is negative".to_string())),
+                        None => return Err(CommandError::Other("timeout is not an integer or out of range".to_string())),
+                    }
+                    idx += 2;
+                }
+                "NOACK" => {
+                    read.no_ack = true;
+                    idx += 1;
+                }
+                "STREAMS" => {
+                    idx += 1;
+                    break;
+                }
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+
+        let streams = &commands[idx.min(commands.len())..];
+        if streams.is_empty() || streams.len() % 2 == 1 {
+            return Err(CommandError::Other(
+                "Unbalanced 'xreadgroup' list of streams: for each stream key an ID or '>' must be specified.".to_string(),
+            ));
+        }
+        let (keys, ids) = streams.split_at(streams.len() / 2);
+        read.keys = keys;
+        read.ids = ids;
+        for id in ids {
+            if id != ">" {
+                StreamId::parse(id, 0)?;
+            }
+        }
+        Ok(read)
+    }
+
+    /// Command replicas run to reach the same group state: the read itself,
+    /// without blocking.
+    fn propagated(commands: &[String]) -> Vec<String> {
+        let mut propagated = Vec::new();
+        let mut idx = 0;
+        while idx < commands.len() {
+            if commands[idx].eq_ignore_ascii_case("BLOCK") {
+                idx += 2;
+                continue;
+            }
+            if commands[idx].eq_ignore_ascii_case("STREAMS") {
+                propagated.extend(commands[idx..].iter().cloned());
+                break;
+            }
+            propagated.push(commands[idx].to_string());
+            idx += 1;
+        }
+        propagated
+    }
+
+    /// Delivers entries of every stream, returning `None` when there was
+    /// nothing to deliver.
+    fn read(&self) -> Result<Option<Vec<u8>>, CommandError> {
+        let memory = get_memory_instance();
+        let now = get_current_time();
+        let mut streams = Vec::new();
+
+        // Check every stream before delivering from any, so a missing group
+        // fails the read without having delivered from the earlier streams.
+        for key in self.keys {
+            let stream = memory.get_stream_mut(key)?.ok_or_else(|| no_group(key, self.group, "XREADGROUP"))?;
+            if !stream.groups.contains_key(self.group) {
+                return Err(no_group(key, self.group, "XREADGROUP"));
+            }
+        }
+
+        for (key, id) in self.keys.iter().zip(self.ids) {
+            let stream = memory.get_stream_mut(key)?.ok_or_else(|| no_group(key, self.group, "XREADGROUP"))?;
+            let mut group = stream.groups.remove(self.group).ok_or_else(|| no_group(key, self.group, "XREADGROUP"))?;
+            group.consumer(self.consumer);
+
+            let mut entries = Vec::new();
+            if id == ">" {
+                let start = group.last_delivered.next().unwrap_or(StreamId::MAX);
+                let delivered: Vec<StreamId> = stream
+                    .range(start, StreamId::MAX, self.count, false)
+                    .iter()
+                    .map(|entry| {
+                        entries.push(entry_response(entry));
+                        entry.id
+                    })
+                    .collect();
+                for id in delivered {
+                    stream.record_delivery(&mut group, id);
+                    if !self.no_ack {
+                        group.assign(id, self.consumer, now, 1);
+                    }
+                }
+                if !entries.is_empty() {
+                    group.consumer(self.consumer).active_time = Some(now);
+                }
+            } else {
+                // Reading history: entries pending for this consumer after `id`.
+                let start = StreamId::parse(id, 0)?.next().unwrap_or(StreamId::MAX);
+                let pending: Vec<StreamId> = group.consumers[self.consumer]
+                    .pending
+                    .range(start..)
+                    .take(self.count.unwrap_or(usize::MAX))
+                    .copied()
+                    .collect();
+                for id in pending {
+                    let range = stream.range(id, id, Some(1), false);
+                    match range.first() {
+                        Some(entry) => entries.push(entry_response(entry)),
+                        None => entries.push(resp::array(vec![resp::bulk_string(&id.to_string()), resp::null_array()])),
+                    }
+                    if let Some(pending) = group.pending.get_mut(&id) {
+                        pending.delivery_time = now;
+                        pending.delivery_count += 1;
+                    }
+                }
+            }
+            stream.groups.insert(self.group.to_string(), group);
+            if !entries.is_empty() {
+                memory.touch(key);
+            }
+
+            // History reads always answer for every stream, even if empty.
+            if !entries.is_empty() || id != ">" {
+                streams.push(resp::array(vec![resp::bulk_string(key), resp::array(entries)]));
+            }
+        }
+
+        if streams.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(resp::array(streams)))
+    }
+}
+
+/// `XREADGROUP ... BLOCK`. A read that delivers entries propagates the
+/// command a replica has to run to deliver the same ones, from the locked
+/// section that made it.
+pub async fn xreadgroup(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    let read = ReadGroup::parse(commands)?;
+    let can_block = read.ids.iter().all(|id| id == ">");
+    let attempt = || {
+        let response = read.read()?;
+        if response.is_some() {
+            propagate(&resp::bulk_string_array(&ReadGroup::propagated(commands)));
+        }
+        Ok(response)
+    };
+    let response = match read.block {
+        Some(timeout) if can_block => {
+            let timeout = if timeout == 0 { None } else { Some(Duration::from_millis(timeout)) };
+            block_on_keys(read.keys, timeout, attempt).await?
+        }
+        _ => {
+            let _guard = lock_execution();
+            attempt()?
+        }
+    };
+    Ok(response.unwrap_or_else(resp::null_array))
+}
+
+fn xack(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let mut ids = Vec::new();
+    for id in &commands[3..] {
+        ids.push(StreamId::parse(id, 0)?);
+    }
+    let memory = get_memory_instance();
+    let group = match memory.get_stream_mut(&commands[1])? {
+        Some(stream) => stream.groups.get_mut(&commands[2]),
+        None => None,
+    };
+    let acknowledged = match group {
+        Some(group) => ids.into_iter().filter(|id| group.acknowledge(*id)).count(),
+        None => 0,
+    };
+    if acknowledged > 0 {
+        memory.touch(&commands[1]);
+    }
+    Ok(resp::integer(acknowledged as i64))
+}
+
+/// `XPENDING key group [[IDLE min-idle-time] start end count [consumer]]`
+fn xpending(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let (key, group_name) = (&commands[1], &commands[2]);
+    let stream = get_memory_instance().get_stream(key)?.ok_or_else(|| no_group(key, group_name, "XPENDING"))?;
+    let group = stream.groups.get(group_name).ok_or_else(|| no_group(key, group_name, "XPENDING"))?;
+
+    if commands.len() == 3 {
+        if group.pending.is_empty() {
+            return Ok(resp::array(vec![
+                resp::integer(0),
+                resp::null_bulk_string(),
+                resp::null_bulk_string(),
+                resp::null_array(),
+            ]));
+        }
+        let first = group.pending.keys().next().unwrap();
+        let last = group.pending.keys().next_back().unwrap();
+        let consumers = group
+            .consumers
+            .iter()
+            .filter(|(_, consumer)| !consumer.pending.is_empty())
+            .map(|(name, consumer)| {
+                resp::array(vec![resp::bulk_string(name), resp::bulk_string(&consumer.pending.len().to_string())])
+            })
+            .collect();
+        return Ok(resp::array(vec![
+            resp::integer(group.pending.len() as i64),
+            resp::bulk_string(&first.to_string()),
+            resp::bulk_string(&last.to_string()),
+            resp::array(consumers),
+        ]));
+    }
+
+    let mut args = &commands[3..];
+    let mut min_idle = 0;
+    if args[0].eq_ignore_ascii_case("IDLE") {
+        if args.len() < 2 {
+            return Err(CommandError::Syntax);
+        }
+        min_idle = parse_i64(&args[1]).ok_or(CommandError::NotInteger)?.max(0) as u128;
+        args = &args[2..];
+    }
+    if args.len() < 3 || args.len() > 4 {
+        return Err(CommandError::Syntax);
+    }
+    let start = StreamId::parse_range_bound(&args[0], true)?;
+    let end = StreamId::parse_range_bound(&args[1], false)?;
+    let count = parse_i64(&args[2]).ok_or(CommandError::NotInteger)?.max(0) as usize;
+    let consumer = args.get(3);
+
+    let now = get_current_time();
+    let entries = if start > end {
+        Vec::new()
+    } else {
+        group
+            .pending
+            .range(start..=end)
+            .filter(|(_, pending)| consumer.map(|c| *c == pending.consumer).unwrap_or(true))
+            .filter(|(_, pending)| now.saturating_sub(pending.delivery_time) >= min_idle)
+            .take(count)
+            .map(|(id, pending)| {
+                resp::array(vec![
+                    resp::bulk_string(&id.to_string()),
+                    resp::bulk_string(&pending.consumer),
+                    resp::integer(now.saturating_sub(pending.delivery_time) as i64),
+                    resp::integer(pending.delivery_count as i64),
+                ])
+            })
+            .collect()
+    };
+    Ok(resp::array(entries))
+}
+
+struct ClaimOptions {
+    idle: Option<u128>,
+    time: Option<u128>,
+    retry_count: Option<u64>,
+    force: bool,
+    just_id: bool,
+    last_id: Option<StreamId>,
+}
+
+/// `XCLAIM key group consumer min-idle-time id [id ...] [IDLE ms] [TIME unix-ms]
+/// [RETRYCOUNT count] [FORCE] [JUSTID] [LASTID id]`
+fn xclaim(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 6 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let (key, group_name, consumer) = (&commands[1], &commands[2], &commands[3]);
+    let min_idle = parse_i64(&commands[4])
+        .ok_or_else(|| CommandError::Other("Invalid min-idle-time argument for XCLAIM".to_string()))?
+        .max(0) as u128;
+
+    let mut ids = Vec::new();
+    let mut idx = 5;
+    while idx < commands.len() {
+        match StreamId::parse(&commands[idx], 0) {
+            Ok(id) => ids.push(id),
+            Err(_) => break,
+        }
+        idx += 1;
+    }
+    let mut options = ClaimOptions { idle: None, time: None, retry_count: None, force: false, just_id: false, last_id: None };
+    while idx < commands.len() {
+        let value = commands.get(idx + 1);
+        match commands[idx].to_ascii_uppercase().as_str() {
+            "FORCE" => options.force = true,
+            "JUSTID" => options.just_id = true,
+            "IDLE" if value.is_some() => {
+                options.idle = Some(parse_i64(value.unwrap()).ok_or(CommandError::NotInteger)?.max(0) as u128);
+                idx += 1;
+            }
+            "TIME" if value.is_some() => {
+                options.time = Some(parse_i64(value.unwrap()).ok_or(CommandError::NotInteger)?.max(0) as u128);
+                idx += 1;
+            }
+            "RETRYCOUNT" if value.is_some() => {
+                options.retry_count = Some(parse_i64(value.unwrap()).ok_or(CommandError::NotInteger)?.max(0) as u64);
+                idx += 1;
+            }
+            "LASTID" if value.is_some() => {
+                options.last_id = Some(StreamId::parse(value.unwrap(), 0)?);
+                idx += 1;
+            }
+            _ => return Err(CommandError::Other(format!("Unrecognized XCLAIM option '{}'", commands[idx]))),
+        }
+        idx += 1;
+    }
+
+    let now = get_current_time();
+    let delivery_time = match (options.idle, options.time) {
+        (Some(idle), _) => now.saturating_sub(idle),
+        (None, Some(time)) => time,
+        (None, None) => now,
+    };
+
+    let memory = get_memory_instance();
+    let stream = memory.get_stream_mut(key)?.ok_or_else(|| no_group(key, group_name, "XCLAIM"))?;
+    let mut group = stream.groups.remove(group_name.as_str()).ok_or_else(|| no_group(key, group_name, "XCLAIM"))?;
+    set_propagated(Vec::new());
+    let mut changed = false;
+    let mut moved_last_id = false;
+    if let Some(last_id) = options.last_id {
+        if last_id > group.last_delivered {
+            group.last_delivered = last_id;
+            changed = true;
+            moved_last_id = true;
+        }
+    }
+
+    let mut claimed = Vec::new();
+    for id in ids {
+        let exists = !stream.range(id, id, Some(1), false).is_empty();
+        let delivery_count = match group.pending.get(&id) {
+            Some(pending) => {
+                if now.saturating_sub(pending.delivery_time) < min_idle {
+                    continue;
+                }
+                pending.delivery_count
+            }
+            None if options.force && exists => 0,
+            None => continue,
+        };
+        if !exists {
+            // The entry was deleted in the meantime, drop it from the PEL.
+            group.acknowledge(id);
+            push_propagated(acknowledge_command(key, group_name, id));
+            changed = true;
+            continue;
+        }
+        let delivery_count = match options.retry_count {
+            Some(retry_count) => retry_count,
+            None if options.just_id => delivery_count,
+            None => delivery_count + 1,
+        };
+        group.assign(id, consumer, delivery_time, delivery_count);
+        push_propagated(claim_command(key, group_name, consumer, id, &group));
+        claimed.push(id);
+    }
+    if moved_last_id && claimed.is_empty() {
+        push_propagated(set_id_command(key, group_name, &group));
+    }
+    group.consumer(consumer).active_time = Some(now);
+
+    let response = claimed
+        .iter()
+        .map(|id| {
+            if options.just_id {
+                resp::bulk_string(&id.to_string())
+            } else {
+                entry_response(stream.range(*id, *id, Some(1), false)[0])
+            }
+        })
+        .collect();
+    stream.groups.insert(group_name.to_string(), group);
+    if changed || !claimed.is_empty() {
+        memory.touch(key);
+    }
+    Ok(resp::array(response))
+}
+
+/// The `XCLAIM` a replica runs to reach the state a claim of `id` left in
+/// `group`. Idle checks and relative times would depend on the replica's
+/// clock, so it forces the claim with the absolute delivery time and count.
+fn claim_command(key: &str, group_name: &str, consumer: &str, id: StreamId, group: &ConsumerGroup) -> Vec<String> {
+    let pending = &group.pending[&id];
+    [
+        "XCLAIM",
+        key,
+        group_name,
+        consumer,
+        "0",
+        &id.to_string(),
+        "TIME",
+        &pending.delivery_time.to_string(),
+        "RETRYCOUNT",
+        &pending.delivery_count.to_string(),
+        "FORCE",
+        "JUSTID",
+        "LASTID",
+        &group.last_delivered.to_string(),
+    ]
+    .iter()
+    .map(|arg| arg.to_string())
+    .collect()
+}
+
+/// Drops an entry that was deleted from the stream from the PEL of replicas.
+fn acknowledge_command(key: &str, group_name: &str, id: StreamId) -> Vec<String> {
+    vec!["XACK".to_string(), key.to_string(), group_name.to_string(), id.to_string()]
+}
+
+/// Moves the last delivered ID of replicas when a claim did nothing else.
+fn set_id_command(key: &str, group_name: &str, group: &ConsumerGroup) -> Vec<String> {
+    let entries_read = group.entries_read.map_or(-1, |entries_read| entries_read as i64);
+    vec![
+        "XGROUP".to_string(),
+        "SETID".to_string(),
+        key.to_string(),
+        group_name.to_string(),
+        group.last_delivered.to_string(),
+        "ENTRIESREAD".to_string(),
+        entries_read.to_string(),
+    ]
+}
+
+/// `XAUTOCLAIM key group consumer min-idle-time start [COUNT count] [JUSTID]`
+fn xautoclaim(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 6 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let (key, group_name, consumer) = (&commands[1], &commands[2], &commands[3]);
+    let min_idle = parse_i64(&commands[4])
+        .ok_or_else(|| CommandError::Other("Invalid min-idle-time argument for XAUTOCLAIM".to_string()))?
+        .max(0) as u128;
+    let start = StreamId::parse_range_bound(&commands[5], true)?;
+    let mut count = 100;
+    let mut just_id = false;
+    let mut idx = 6;
+    while idx < commands.len() {
+        match commands[idx].to_ascii_uppercase().as_str() {
+            "COUNT" if idx + 1 < commands.len() => {
+                count = match parse_i64(&commands[idx + 1]) {
+                    Some(count) if (1..=i64::MAX / 10).contains(&count) => count as usize,
+                    _ => return Err(CommandError::Other("COUNT must be > 0".to_string())),
+                };
+                idx += 1;
+            }
+            "JUSTID" => just_id = true,
+            _ => return Err(CommandError::Syntax),
+        }
+        idx += 1;
+    }
+
+    let now = get_current_time();
+    let memory = get_memory_instance();
+    let stream = memory.get_stream_mut(key)?.ok_or_else(|| no_group(key, group_name, "XAUTOCLAIM"))?;
+    let mut group = stream.groups.remove(group_name.as_str()).ok_or_else(|| no_group(key, group_name, "XAUTOCLAIM"))?;
+    set_propagated(Vec::new());
+
+    // Like Redis, scan at most ten times COUNT entries of the PEL per call.
+    let scanned: Vec<(StreamId, u128, u64)> = group
+        .pending
+        .range(start..)
+        .take(count * 10 + 1)
+        .map(|(id, pending)| (*id, pending.delivery_time, pending.delivery_count))
+        .collect();
+    let mut claimed = Vec::new();
+    let mut deleted = Vec::new();
+    let mut next = StreamId::MIN;
+    for (position, (id, delivery_time, delivery_count)) in scanned.iter().enumerate() {
+        if position == count * 10 || claimed.len() == count {
+            next = *id;
+            break;
+        }
+        if now.saturating_sub(*delivery_time) < min_idle {
+            continue;
+        }
+        if stream.range(*id, *id, Some(1), false).is_empty() {
+            group.acknowledge(*id);
+            push_propagated(acknowledge_command(key, group_name, *id));
+            deleted.push(resp::bulk_string(&id.to_string()));
+            continue;
+        }
+        let delivery_count = if just_id { *delivery_count } else { delivery_count + 1 };
+        group.assign(*id, consumer, now, delivery_count);
+        push_propagated(claim_command(key, group_name, consumer, *id, &group));
+        claimed.push(*id);
+    }
+    group.consumer(consumer).active_time = Some(now);
+
+    let entries = claimed
+        .iter()
+        .map(|id| {
+            if just_id {
+                resp::bulk_string(&id.to_string())
+            } else {
+                entry_response(stream.range(*id, *id, Some(1), false)[0])
+            }
+        })
+        .collect();
+    stream.groups.insert(group_name.to_string(), group);
+    if !claimed.is_empty() || !deleted.is_empty() {
+        memory.touch(key);
+    }
+    Ok(resp::array(vec![resp::bulk_string(&next.to_string()), resp::array(entries), resp::array(deleted)]))
+}
+
+fn optional_integer(value: Option<u64>) -> Vec<u8> {
+    match value {
+        Some(value) => resp::integer(value as i64),
+        None => resp::null_bulk_string(),
+    }
+}
+
+fn xinfo_groups(stream: &Stream) -> Vec<u8> {
+    resp::array(
+        stream
+            .groups
+            .iter()
+            .map(|(name, group)| {
+                resp::array(vec![
+                    resp::bulk_string("name"),
+                    resp::bulk_string(name),
+                    resp::bulk_string("consumers"),
+                    resp::integer(group.consumers.len() as i64),
+                    resp::bulk_string("pending"),
+                    resp::integer(group.pending.len() as i64),
+                    resp::bulk_string("last-delivered-id"),
+                    resp::bulk_string(&group.last_delivered.to_string()),
+                    resp::bulk_string("entries-read"),
+                    optional_integer(group.entries_read),
+                    resp::bulk_string("lag"),
+                    optional_integer(stream.group_lag(group)),
+                ])
+            })
+            .collect(),
+    )
+}
+
+fn xinfo_consumers(group: &ConsumerGroup) -> Vec<u8> {
+    let now = get_current_time();
+    resp::array(
+        group
+            .consumers
+            .iter()
+            .map(|(name, consumer)| {
+                let inactive = match consumer.active_time {
+                    Some(active_time) => now.saturating_sub(active_time) as i64,
+                    None => -1,
+                };
+                resp::array(vec![
+                    resp::bulk_string("name"),
+                    resp::bulk_string(name),
+                    resp::bulk_string("pending"),
+                    resp::integer(consumer.pending.len() as i64),
+                    resp::bulk_string("idle"),
+                    resp::integer(now.saturating_sub(consumer.seen_time) as i64),
+                    resp::bulk_string("inactive"),
+                    resp::integer(inactive),
+                ])
+            })
+            .collect(),
+    )
+}
+
+fn xinfo_stream(stream: &Stream, full: Option<usize>) -> Vec<u8> {
+    let mut fields = vec![
+        resp::bulk_string("length"),
+        resp::integer(stream.len() as i64),
+        resp::bulk_string("radix-tree-keys"),
+        resp::integer(stream.node_count() as i64),
+        resp::bulk_string("radix-tree-nodes"),
+        resp::integer(stream.node_count() as i64 + 1),
+        resp::bulk_string("last-generated-id"),
+        resp::bulk_string(&stream.last_id.to_string()),
+        resp::bulk_string("max-deleted-entry-id"),
+        resp::bulk_string(&stream.max_deleted_id.to_string()),
+        resp::bulk_string("entries-added"),
+        resp::integer(stream.entries_added as i64),
+        resp::bulk_string("recorded-first-entry-id"),
+        resp::bulk_string(&stream.first_entry().map(|e| e.id).unwrap_or(StreamId::MIN).to_string()),
+    ];
+
+    let count = match full {
+        None => {
+            fields.push(resp::bulk_string("groups"));
+            fields.push(resp::integer(stream.groups.len() as i64));
+            fields.push(resp::bulk_string("first-entry"));
+            fields.push(stream.first_entry().map(entry_response).unwrap_or_else(resp::null_bulk_string));
+            fields.push(resp::bulk_string("last-entry"));
+            fields.push(stream.last_entry().map(entry_response).unwrap_or_else(resp::null_bulk_string));
+            return resp::array(fields);
+        }
+        Some(0) => None,
+        Some(count) => Some(count),
+    };
+
+    let entries: Vec<Vec<u8>> = stream
+        .range(StreamId::MIN, StreamId::MAX, count, false)
+        .into_iter()
+        .map(entry_response)
+        .collect();
+    fields.push(resp::bulk_string("entries"));
+    fields.push(resp::array(entries));
+
+    let pending_entry = |id: &StreamId, pending: &PendingEntry, with_consumer: bool| {
+        let mut entry = vec![resp::bulk_string(&id.to_string())];
+        if with_consumer {
+            entry.push(resp::bulk_string(&pending.consumer));
+        }
+        entry.push(resp::integer(pending.delivery_time as i64));
+        entry.push(resp::integer(pending.delivery_count as i64));
+        resp::array(entry)
+    };
+    let limit = count.unwrap_or(usize::MAX);
+    let groups = stream
+        .groups
+        .iter()
+        .map(|(name, group)| {
+            let pel = group.pending.iter().take(limit).map(|(id, p)| pending_entry(id, p, true)).collect();
+            let consumers = group
+                .consumers
+                .iter()
+                .map(|(consumer_name, consumer)| {
+                    let pel = consumer
+                        .pending
+                        .iter()
+                        .take(limit)
+                        .filter_map(|id| group.pending.get(id).map(|p| pending_entry(id, p, false)))
+                        .collect();
+                    resp::array(vec![
+                        resp::bulk_string("name"),
+                        resp::bulk_string(consumer_name),
+                        resp::bulk_string("seen-time"),
+                        resp::integer(consumer.seen_time as i64),
+                        resp::bulk_string("active-time"),
+                        resp::integer(consumer.active_time.map(|t| t as i64).unwrap_or(-1)),
+                        resp::bulk_string("pel-count"),
+                        resp::integer(consumer.pending.len() as i64),
+                        resp::bulk_string("pending"),
+                        resp::array(pel),
+                    ])
+                })
+                .collect();
+            resp::array(vec![
+                resp::bulk_string("name"),
+                resp::bulk_string(name),
+                resp::bulk_string("last-delivered-id"),
+                resp::bulk_string(&group.last_delivered.to_string()),
+                resp::bulk_string("entries-read"),
+                optional_integer(group.entries_read),
+                resp::bulk_string("lag"),
+                optional_integer(stream.group_lag(group)),
+                resp::bulk_string("pel-count"),
+                resp::integer(group.pending.len() as i64),
+                resp::bulk_string("pending"),
+                resp::array(pel),
+                resp::bulk_string("consumers"),
+                resp::array(consumers),
+            ])
+        })
+        .collect();
+    fields.push(resp::bulk_string("groups"));
+    fields.push(resp::array(groups));
+    resp::array(fields)
+}
+
+/// `XINFO STREAM key [FULL [COUNT count]]`, `XINFO GROUPS key` and
+/// `XINFO CONSUMERS key group`.
+fn xinfo(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let subcommand = commands[1].to_ascii_uppercase();
+    let key = &commands[2];
+    let stream = get_memory_instance()
+        .get_stream(key)?
+        .ok_or_else(|| CommandError::Other("no such key".to_string()))?;
+
+    match subcommand.as_str() {
+        "STREAM" => {
+            let full = match &commands[3..] {
+                [] => None,
+                [full] if full.eq_ignore_ascii_case("FULL") => Some(10),
+                [full, option, count] if full.eq_ignore_ascii_case("FULL") && option.eq_ignore_ascii_case("COUNT") => {
+                    Some(parse_i64(count).ok_or(CommandError::NotInteger)?.max(0) as usize)
+                }
+                _ => return Err(CommandError::Syntax),
+            };
+            Ok(xinfo_stream(stream, full))
+        }
+        "GROUPS" => Ok(xinfo_groups(stream)),
+        "CONSUMERS" => {
+            if commands.len() != 4 {
+                return Err(CommandError::arity("xinfo|consumers"));
+            }
+            let group = stream.groups.get(&commands[3]).ok_or_else(|| no_group(key, &commands[3], "XINFO"))?;
+            Ok(xinfo_consumers(group))
+        }
+        _ => Err(CommandError::Other(format!(
+            "unknown subcommand '{}'. Try XINFO HELP.",
+            commands[1]
+        ))),
+    }
+}
+
+pub fn process_command(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    match commands[0].as_str() {
+        "XGROUP" => xgroup(commands),
+        // Reached from the replication stream, where reads never block.
+        "XREADGROUP" => Ok(ReadGroup::parse(commands)?.read()?.unwrap_or_else(resp::null_array)),
+        "XACK" => xack(commands),
+        "XPENDING" => xpending(commands),
+        "XCLAIM" => xclaim(commands),
+        "XAUTOCLAIM" => xautoclaim(commands),
+        "XINFO" => xinfo(commands),
+        _ => Err(CommandError::Other(format!("unknown command '{}'", commands[0]))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{run, run_propagated, strings};
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// Owner and delivery count of every pending entry of `group`.
+    fn pending(key: &str, group: &str) -> Vec<(String, String, u64)> {
+        let _guard = lock_execution();
+        let stream = get_memory_instance().get_stream(key).unwrap().unwrap();
+        let group = &stream.groups[group];
+        for (name, consumer) in &group.consumers {
+            for id in &consumer.pending {
+                assert_eq!(&group.pending[id].consumer, name);
+            }
+        }
+        let pending = group.pending.iter();
+        pending.map(|(id, entry)| (id.to_string(), entry.consumer.clone(), entry.delivery_count)).collect()
+    }
+
+    fn owned(ids: &[(&str, &str, u64)]) -> Vec<(String, String, u64)> {
+        ids.iter().map(|(id, consumer, count)| (id.to_string(), consumer.to_string(), *count)).collect()
+    }
+
+    fn add_entries(key: &str, count: u64) {
+        for ms in 1..=count {
+            run(&["XADD", key, &format!("{}-0", ms), "n", &ms.to_string()]);
+        }
+    }
+
+    #[test]
+    fn assigning_moves_ownership() {
+        let mut group = ConsumerGroup::new(StreamId::MIN, Some(0));
+        group.assign(id(1, 0), "alice", 10, 1);
+        group.assign(id(2, 0), "alice", 10, 1);
+        group.assign(id(1, 0), "bob", 20, 2);
+        assert_eq!(group.consumers["alice"].pending.iter().collect::<Vec<_>>(), [&id(2, 0)]);
+        assert_eq!(group.consumers["bob"].pending.iter().collect::<Vec<_>>(), [&id(1, 0)]);
+        assert_eq!(group.pending[&id(1, 0)].delivery_count, 2);
+        assert!(group.acknowledge(id(1, 0)));
+        assert!(!group.acknowledge(id(1, 0)));
+        assert!(group.consumers["bob"].pending.is_empty());
+    }
+
+    #[test]
+    fn read_arguments() {
+        let commands = args(&["XREADGROUP", "GROUP", "g", "c", "COUNT", "2", "BLOCK", "100", "NOACK", "STREAMS", "k", ">"]);
+        let read = ReadGroup::parse(&commands).unwrap();
+        assert_eq!((read.group, read.consumer, read.count, read.block, read.no_ack), ("g", "c", Some(2), Some(100), true));
+        // Replicas run the read without blocking.
+        let propagated = ReadGroup::propagated(&commands);
+        assert_eq!(propagated, args(&["XREADGROUP", "GROUP", "g", "c", "COUNT", "2", "NOACK", "STREAMS", "k", ">"]));
+
+        let error = |read: &[&str]| ReadGroup::parse(&args(read)).err().unwrap().to_string();
+        assert_eq!(error(&["XREADGROUP", "GROUP", "g", "c", "STREAMS", "k"]), "ERR syntax error");
+        assert_eq!(
+            error(&["XREADGROUP", "GROUP", "g", "c", "STREAMS", "k", "l", ">"]),
+            "ERR Unbalanced 'xreadgroup' list of streams: for each stream key an ID or '>' must be specified."
+        );
+        assert_eq!(error(&["XREADGROUP", "GROUP", "g", "c", "BLOCK", "-5", "STREAMS", "k", ">"]), "ERR timeout is negative");
+    }
+
+    #[test]
+    fn entries_read_and_lag() {
+        let _guard = lock_execution();
+        let mut stream = Stream::new();
+        assert_eq!(stream.estimate_entries_read(StreamId::MIN), Some(0));
+        for ms in 1..=5 {
+            stream.append(id(ms, 0), Vec::new());
+        }
+        assert_eq!(stream.estimate_entries_read(id(9, 0)), Some(5));
+        assert_eq!(stream.estimate_entries_read(StreamId::MIN), Some(0));
+        assert_eq!(stream.estimate_entries_read(id(3, 0)), None);
+        let mut group = ConsumerGroup::new(id(2, 0), Some(2));
+        assert_eq!(stream.group_lag(&group), Some(3));
+
+        // A deletion after the last delivered ID makes the lag unknown.
+        stream.remove(id(4, 0));
+        assert_eq!(stream.group_lag(&group), None);
+        stream.record_delivery(&mut group, id(3, 0));
+        stream.record_delivery(&mut group, id(5, 0));
+        assert_eq!((group.last_delivered, group.entries_read), (id(5, 0), Some(5)));
+        assert_eq!(stream.group_lag(&group), Some(0));
+    }
+
+    #[test]
+    fn groups() {
+        let missing = "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.";
+        assert_eq!(run(&["XGROUP", "CREATE", "group:create", "g", "$"]), resp::error(missing));
+        assert_eq!(run(&["XGROUP", "CREATE", "group:create", "g", "$", "MKSTREAM"]), resp::simple_string("OK"));
+        assert_eq!(
+            run(&["XGROUP", "CREATE", "group:create", "g", "0"]),
+            resp::error("BUSYGROUP Consumer Group name already exists")
+        );
+        assert_eq!(run(&["XGROUP", "CREATECONSUMER", "group:create", "g", "c"]), resp::integer(1));
+        assert_eq!(run(&["XGROUP", "CREATECONSUMER", "group:create", "g", "c"]), resp::integer(0));
+        assert_eq!(
+            run(&["XGROUP", "SETID", "group:create", "nope", "0"]),
+            resp::error("NOGROUP No such key 'group:create' or consumer group 'nope' in XGROUP command")
+        );
+        assert_eq!(
+            run(&["XGROUP", "CREATE", "group:create", "h", "0", "ENTRIESREAD", "-2"]),
+            resp::error("ERR value for ENTRIESREAD must be positive or -1")
+        );
+        assert_eq!(run(&["XGROUP", "DESTROY", "group:create", "g"]), resp::integer(1));
+        assert_eq!(run(&["XGROUP", "DESTROY", "group:create", "g"]), resp::integer(0));
+    }
+
+    #[test]
+    fn reads_fill_the_pending_entries_list() {
+        add_entries("group:read", 3);
+        run(&["XGROUP", "CREATE", "group:read", "g", "0"]);
+        let read = |consumer: &str, id: &str| strings(&run(&["XREADGROUP", "GROUP", "g", consumer, "COUNT", "2", "STREAMS", "group:read", id]));
+        // The nested reply flattens to the key, then each ID and its fields.
+        assert_eq!(read("alice", ">"), ["group:read", "1-0", "n", "1", "2-0", "n", "2"]);
+        assert_eq!(read("bob", ">"), ["group:read", "3-0", "n", "3"]);
+        assert_eq!(run(&["XREADGROUP", "GROUP", "g", "bob", "STREAMS", "group:read", ">"]), resp::null_array());
+        assert_eq!(pending("group:read", "g"), owned(&[("1-0", "alice", 1), ("2-0", "alice", 1), ("3-0", "bob", 1)]));
+
+        // History reads return the consumer's own pending entries again.
+        assert_eq!(read("alice", "1-0"), ["group:read", "2-0", "n", "2"]);
+        assert_eq!(pending("group:read", "g")[1], owned(&[("2-0", "alice", 2)])[0]);
+
+        assert_eq!(run(&["XACK", "group:read", "g", "1-0", "3-0", "9-0"]), resp::integer(2));
+        let summary = resp::array(vec![
+            resp::integer(1),
+            resp::bulk_string("2-0"),
+            resp::bulk_string("2-0"),
+            resp::array(vec![resp::bulk_string_array(&["alice", "1"])]),
+        ]);
+        assert_eq!(run(&["XPENDING", "group:read", "g"]), summary);
+
+        // NOACK reads don't add to the PEL.
+        run(&["XADD", "group:read", "4-0", "n", "4"]);
+        run(&["XREADGROUP", "GROUP", "g", "bob", "NOACK", "STREAMS", "group:read", ">"]);
+        assert_eq!(pending("group:read", "g").len(), 1);
+        assert_eq!(
+            run(&["XREADGROUP", "GROUP", "nope", "bob", "STREAMS", "group:read", ">"]),
+            resp::error("NOGROUP No such key 'group:read' or consumer group 'nope' in XREADGROUP command")
+        );
+    }
+
+    #[test]
+    fn claims() {
+        add_entries("group:claim", 3);
+        run(&["XGROUP", "CREATE", "group:claim", "g", "0"]);
+        run(&["XREADGROUP", "GROUP", "g", "alice", "STREAMS", "group:claim", ">"]);
+
+        // Not idle long enough.
+        assert_eq!(run(&["XCLAIM", "group:claim", "g", "bob", "100000", "1-0"]), resp::array(Vec::new()));
+        assert_eq!(run(&["XCLAIM", "group:claim", "g", "bob", "0", "1-0", "JUSTID"]), resp::bulk_string_array(&["1-0"]));
+        let claimed = strings(&run(&["XCLAIM", "group:claim", "g", "bob", "0", "2-0", "RETRYCOUNT", "7"]));
+        assert_eq!(claimed, ["2-0", "n", "2"]);
+        assert_eq!(
+            pending("group:claim", "g"),
+            owned(&[("1-0", "bob", 1), ("2-0", "bob", 7), ("3-0", "alice", 1)])
+        );
+
+        // A deleted entry is dropped from the PEL instead of being claimed.
+        run(&["XDEL", "group:claim", "3-0"]);
+        assert_eq!(run(&["XCLAIM", "group:claim", "g", "bob", "0", "3-0"]), resp::array(Vec::new()));
+        assert_eq!(pending("group:claim", "g").len(), 2);
+
+        // FORCE claims entries that were never delivered.
+        run(&["XADD", "group:claim", "4-0", "n", "4"]);
+        assert_eq!(run(&["XCLAIM", "group:claim", "g", "carol", "0", "4-0", "JUSTID"]), resp::array(Vec::new()));
+        let (propagated, _) = run_propagated(&["XCLAIM", "group:claim", "g", "carol", "0", "4-0", "FORCE", "JUSTID"]).unwrap();
+        let time = pending_time("group:claim", "g", id(4, 0));
+        let expected = [
+            "XCLAIM", "group:claim", "g", "carol", "0", "4-0", "TIME", &time, "RETRYCOUNT", "0", "FORCE", "JUSTID",
+            "LASTID", "3-0",
+        ];
+        assert_eq!(propagated, expected);
+        assert_eq!(
+            run(&["XCLAIM", "group:claim", "g", "carol", "0", "4-0", "BOGUS"]),
+            resp::error("ERR Unrecognized XCLAIM option 'BOGUS'")
+        );
+    }
+
+    fn pending_time(key: &str, group: &str, id: StreamId) -> String {
+        let _guard = lock_execution();
+        let stream = get_memory_instance().get_stream(key).unwrap().unwrap();
+        stream.groups[group].pending[&id].delivery_time.to_string()
+    }
+
+    #[test]
+    fn autoclaim_pages_through_the_pending_entries_list() {
+        add_entries("group:autoclaim", 5);
+        run(&["XGROUP", "CREATE", "group:autoclaim", "g", "0"]);
+        run(&["XREADGROUP", "GROUP", "g", "alice", "STREAMS", "group:autoclaim", ">"]);
+        run(&["XDEL", "group:autoclaim", "2-0"]);
+
+        let reply = run(&["XAUTOCLAIM", "group:autoclaim", "g", "bob", "0", "-", "COUNT", "2", "JUSTID"]);
+        let expected = resp::array(vec![
+            resp::bulk_string("4-0"),
+            resp::bulk_string_array(&["1-0", "3-0"]),
+            resp::bulk_string_array(&["2-0"]),
+        ]);
+        assert_eq!(reply, expected);
+        let (propagated, _) = run_propagated(&["XAUTOCLAIM", "group:autoclaim", "g", "bob", "0", "4-0", "JUSTID"]).unwrap();
+        // One forced XCLAIM per claimed entry, flattened here.
+        assert_eq!(propagated.iter().filter(|arg| *arg == "XCLAIM").count(), 2);
+        assert_eq!(
+            pending("group:autoclaim", "g"),
+            owned(&[("1-0", "bob", 1), ("3-0", "bob", 1), ("4-0", "bob", 1), ("5-0", "bob", 1)])
+        );
+        assert_eq!(
+            run(&["XAUTOCLAIM", "group:autoclaim", "g", "bob", "0", "-", "COUNT", "0"]),
+            resp::error("ERR COUNT must be > 0")
+        );
+    }
+}
@@ -0,0 +1,28 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    WrongArity(String),
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
+    #[error("ERR value is not a valid float")]
+    NotFloat,
+    #[error("ERR increment or decrement would overflow")]
+    Overflow,
+    #[error("ERR {0}")]
+    Other(String),
+    /// Errors carrying their own code, such as `NOGROUP` or `BUSYGROUP`.
+    #[error("{0}")]
+    Custom(String),
+}
+
+impl CommandError {
+    pub fn arity(command: &str) -> Self {
+        CommandError::WrongArity(command.to_ascii_lowercase())
+    }
+}
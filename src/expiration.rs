@@ -1,5 +1,10 @@
+use crate::commands::lock_execution;
 use crate::{get_current_time, get_memory_instance};
 
 pub fn ttl() {
-  get_memory_instance().remove_expired(get_current_time());
-}
\ No newline at end of file
+  // Keys must not expire in the middle of a transaction.
+  let _guard = lock_execution();
+  let current_time = get_current_time();
+  get_memory_instance().remove_expired(current_time);
+  get_memory_instance().remove_expired_fields(current_time);
+}
@@ -0,0 +1,490 @@
+use crate::error::CommandError;
+use crate::geohash::{self, Shape, LAT_MAX, LAT_MIN, LONG_MAX, LONG_MIN, STEP_MAX};
+use crate::get_memory_instance;
+use crate::resp;
+use crate::util::{format_f64, parse_f64, parse_i64};
+use crate::zset::{self, ScoreRange, SortedSet};
+
+fn parse_coordinate(value: &str) -> Result<f64, CommandError> {
+    parse_f64(value).ok_or(CommandError::NotFloat)
+}
+
+fn parse_long_lat(longitude: &str, latitude: &str) -> Result<(f64, f64), CommandError> {
+    let longitude = parse_coordinate(longitude)?;
+    let latitude = parse_coordinate(latitude)?;
+    if !(LONG_MIN..=LONG_MAX).contains(&longitude) || !(LAT_MIN..=LAT_MAX).contains(&latitude) {
+        return Err(CommandError::Other(format!(
+            "invalid longitude,latitude pair {:.6},{:.6}",
+            longitude, latitude
+        )));
+    }
+    Ok((longitude, latitude))
+}
+
+/// Meters per unit.
+fn parse_unit(unit: &str) -> Result<f64, CommandError> {
+    match unit.to_ascii_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "ft" => Ok(0.3048),
+        "mi" => Ok(1609.34),
+        _ => Err(CommandError::Other("unsupported unit provided. please use M, KM, FT, MI".to_string())),
+    }
+}
+
+fn parse_distance(value: &str) -> Result<f64, CommandError> {
+    match parse_f64(value) {
+        Some(distance) if distance >= 0.0 => Ok(distance),
+        Some(_) => Err(CommandError::Other("radius cannot be negative".to_string())),
+        None => Err(CommandError::Other("need numeric radius".to_string())),
+    }
+}
+
+fn member_position(zset: &SortedSet, member: &str) -> Option<(f64, f64)> {
+    zset.score(member).map(|score| geohash::decode_to_long_lat(score as u64))
+}
+
+fn format_distance(meters: f64, unit: f64) -> String {
+    format!("{:.4}", meters / unit)
+}
+
+/// `GEOADD key [NX|XX] [CH] longitude latitude member [...]`, run as the
+/// equivalent `ZADD` with geohash scores.
+fn geoadd(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    let mut idx = 2;
+    let mut zadd = vec!["ZADD".to_string(), commands.get(1).cloned().unwrap_or_default()];
+    let (mut nx, mut xx) = (false, false);
+    while idx < commands.len() {
+        match commands[idx].to_ascii_uppercase().as_str() {
+            "NX" => nx = true,
+            "XX" => xx = true,
+            "CH" => {}
+            _ => break,
+        }
+        zadd.push(commands[idx].to_ascii_uppercase());
+        idx += 1;
+    }
+    if nx && xx {
+        return Err(CommandError::Other("XX and NX options at the same time are not compatible".to_string()));
+    }
+    let triples = &commands[idx.min(commands.len())..];
+    if triples.is_empty() || !triples.chunks_exact(3).remainder().is_empty() {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    for triple in triples.chunks(3) {
+        let (longitude, latitude) = parse_long_lat(&triple[0], &triple[1])?;
+        let score = geohash::encode_wgs84(longitude, latitude, STEP_MAX).bits;
+        zadd.push(score.to_string());
+        zadd.push(triple[2].to_string());
+    }
+    zset::process_command(&zadd)
+}
+
+fn geopos(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 2 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let memory = get_memory_instance();
+    let zset = memory.get_zset(&commands[1])?;
+    let positions = commands[2..]
+        .iter()
+        .map(|member| match zset.and_then(|z| member_position(z, member)) {
+            Some((longitude, latitude)) => {
+                resp::array(vec![resp::bulk_string(&format_f64(longitude)), resp::bulk_string(&format_f64(latitude))])
+            }
+            None => resp::null_array(),
+        })
+        .collect();
+    Ok(resp::array(positions))
+}
+
+fn geodist(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 4 && commands.len() != 5 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let unit = match commands.get(4) {
+        Some(unit) => parse_unit(unit)?,
+        None => 1.0,
+    };
+    let memory = get_memory_instance();
+    let zset = match memory.get_zset(&commands[1])? {
+        Some(zset) => zset,
+        None => return Ok(resp::null_bulk_string()),
+    };
+    match (member_position(zset, &commands[2]), member_position(zset, &commands[3])) {
+        (Some(from), Some(to)) => {
+            let meters = geohash::distance(from.0, from.1, to.0, to.1);
+            Ok(resp::bulk_string(&format_distance(meters, unit)))
+        }
+        _ => Ok(resp::null_bulk_string()),
+    }
+}
+
+fn geohash_command(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 2 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let memory = get_memory_instance();
+    let zset = memory.get_zset(&commands[1])?;
+    let hashes = commands[2..]
+        .iter()
+        .map(|member| match zset.and_then(|z| member_position(z, member)) {
+            Some((longitude, latitude)) => resp::bulk_string(&geohash::to_string(longitude, latitude)),
+            None => resp::null_bulk_string(),
+        })
+        .collect();
+    Ok(resp::array(hashes))
+}
+
+enum Origin {
+    Member(String),
+    LongLat(f64, f64),
+}
+
+#[derive(PartialEq)]
+enum Sort {
+    None,
+    Asc,
+    Desc,
+}
+
+struct Search {
+    origin: Origin,
+    shape: Shape,
+    unit: f64,
+    sort: Sort,
+    count: Option<usize>,
+    any: bool,
+    with_coord: bool,
+    with_dist: bool,
+    with_hash: bool,
+    store_dist: bool,
+}
+
+struct Match {
+    member: String,
+    score: f64,
+    distance: f64,
+    position: (f64, f64),
+}
+
+impl Search {
+    /// Parses the options following the source key of `GEOSEARCH` and
+    /// `GEOSEARCHSTORE`.
+    fn parse(command: &str, args: &[String], store: bool) -> Result<Self, CommandError> {
+        let mut origin = None;
+        let mut shape = None;
+        let mut search = Search {
+            origin: Origin::LongLat(0.0, 0.0),
+            shape: Shape::Radius(0.0),
+            unit: 1.0,
+            sort: Sort::None,
+            count: None,
+            any: false,
+            with_coord: false,
+            with_dist: false,
+            with_hash: false,
+            store_dist: false,
+        };
+
+        let mut idx = 0;
+        while idx < args.len() {
+            let remaining = args.len() - idx - 1;
+            match args[idx].to_ascii_uppercase().as_str() {
+                "FROMMEMBER" if remaining >= 1 => {
+                    if origin.is_some() {
+                        return Err(CommandError::Syntax);
+                    }
+                    origin = Some(Origin::Member(args[idx + 1].to_string()));
+                    idx += 1;
+                }
+                "FROMLONLAT" if remaining >= 2 => {
+                    if origin.is_some() {
+                        return Err(CommandError::Syntax);
+                    }
+                    let (longitude, latitude) = parse_long_lat(&args[idx + 1], &args[idx + 2])?;
+                    origin = Some(Origin::LongLat(longitude, latitude));
+                    idx += 2;
+                }
+                "BYRADIUS" if remaining >= 2 => {
+                    if shape.is_some() {
+                        return Err(CommandError::Syntax);
+                    }
+                    let radius = parse_distance(&args[idx + 1])?;
+                    search.unit = parse_unit(&args[idx + 2])?;
+                    shape = Some(Shape::Radius(radius * search.unit));
+                    idx += 2;
+                }
+                "BYBOX" if remaining >= 3 => {
+                    if shape.is_some() {
+                        return Err(CommandError::Syntax);
+                    }
+                    let invalid = || CommandError::Other("need numeric width and height".to_string());
+                    let width = parse_f64(&args[idx + 1]).filter(|w| *w >= 0.0).ok_or_else(invalid)?;
+                    let height = parse_f64(&args[idx + 2]).filter(|h| *h >= 0.0).ok_or_else(invalid)?;
+                    search.unit = parse_unit(&args[idx + 3])?;
+                    shape = Some(Shape::Box { width: width * search.unit, height: height * search.unit });
+                    idx += 3;
+                }
+                "ASC" => search.sort = Sort::Asc,
+                "DESC" => search.sort = Sort::Desc,
+                "COUNT" if remaining >= 1 => {
+                    match parse_i64(&args[idx + 1]) {
+                        Some(count) if count > 0 => search.count = Some(count as usize),
+                        Some(_) => return Err(CommandError::Other("COUNT must be > 0".to_string())),
+                        None => return Err(CommandError::NotInteger),
+                    }
+                    idx += 1;
+                    if args.get(idx + 1).map(|a| a.eq_ignore_ascii_case("ANY")).unwrap_or(false) {
+                        search.any = true;
+                        idx += 1;
+                    }
+                }
+                "WITHCOORD" if !store => search.with_coord = true,
+                "WITHDIST" if !store => search.with_dist = true,
+                "WITHHASH" if !store => search.with_hash = true,
+                "STOREDIST" if store => search.store_dist = true,
+                _ => return Err(CommandError::Syntax),
+            }
+            idx += 1;
+        }
+
+        let name = command.to_ascii_uppercase();
+        search.origin = origin.ok_or_else(|| {
+            CommandError::Other(format!(
+                "exactly one of FROMMEMBER or FROMLONLAT can be specified for '{}' command",
+                name
+            ))
+        })?;
+        search.shape = shape.ok_or_else(|| {
+            CommandError::Other(format!(
+                "exactly one of BYRADIUS and BYBOX arguments must be provided for '{}' command",
+                name
+            ))
+        })?;
+        if search.any && search.count.is_none() {
+            return Err(CommandError::Other("the ANY argument requires COUNT argument".to_string()));
+        }
+        // Without ANY, COUNT has to return the closest matches.
+        if search.count.is_some() && search.sort == Sort::None && !search.any {
+            search.sort = Sort::Asc;
+        }
+        Ok(search)
+    }
+
+    /// Walks the score ranges of the cells around the center, keeping the
+    /// members that actually lie in the shape.
+    fn run(&self, zset: &SortedSet) -> Result<Vec<Match>, CommandError> {
+        let center = match &self.origin {
+            Origin::LongLat(longitude, latitude) => (*longitude, *latitude),
+            Origin::Member(member) => member_position(zset, member)
+                .ok_or_else(|| CommandError::Other("could not decode requested zset member".to_string()))?,
+        };
+
+        let limit = if self.any { self.count.unwrap_or(usize::MAX) } else { usize::MAX };
+        let mut matches = Vec::new();
+        let mut visited: Vec<(u64, u64)> = Vec::new();
+        for area in geohash::search_areas(&self.shape, center.0, center.1) {
+            if area.is_zero() {
+                continue;
+            }
+            // Huge radiuses can make neighbors collapse into the same cell.
+            let (min, max) = area.score_range();
+            if visited.contains(&(min, max)) {
+                continue;
+            }
+            visited.push((min, max));
+
+            let range = ScoreRange { min: min as f64, min_exclusive: false, max: max as f64, max_exclusive: true };
+            for (member, score) in zset.range_by_score(&range, false, 0, usize::MAX) {
+                if matches.len() >= limit {
+                    return Ok(matches);
+                }
+                let position = geohash::decode_to_long_lat(score as u64);
+                if let Some(distance) = self.shape.distance_if_inside(center, position) {
+                    matches.push(Match { member, score, distance, position });
+                }
+            }
+        }
+        Ok(matches)
+    }
+
+    fn sorted(&self, mut matches: Vec<Match>) -> Vec<Match> {
+        match self.sort {
+            Sort::Asc => matches.sort_by(|a, b| a.distance.total_cmp(&b.distance)),
+            Sort::Desc => matches.sort_by(|a, b| b.distance.total_cmp(&a.distance)),
+            Sort::None => {}
+        }
+        if let Some(count) = self.count {
+            matches.truncate(count);
+        }
+        matches
+    }
+
+    fn response(&self, matches: &[Match]) -> Vec<u8> {
+        let with_extras = self.with_coord || self.with_dist || self.with_hash;
+        let elements = matches
+            .iter()
+            .map(|m| {
+                if !with_extras {
+                    return resp::bulk_string(&m.member);
+                }
+                let mut element = vec![resp::bulk_string(&m.member)];
+                if self.with_dist {
+                    element.push(resp::bulk_string(&format_distance(m.distance, self.unit)));
+                }
+                if self.with_hash {
+                    element.push(resp::integer(m.score as i64));
+                }
+                if self.with_coord {
+                    element.push(resp::array(vec![
+                        resp::bulk_string(&format_f64(m.position.0)),
+                        resp::bulk_string(&format_f64(m.position.1)),
+                    ]));
+                }
+                resp::array(element)
+            })
+            .collect();
+        resp::array(elements)
+    }
+}
+
+/// `GEOSEARCH key FROMMEMBER member|FROMLONLAT lon lat BYRADIUS radius unit|BYBOX
+/// width height unit [ASC|DESC] [COUNT count [ANY]] [WITHCOORD] [WITHDIST] [WITHHASH]`
+fn geosearch(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 7 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let search = Search::parse(&commands[0], &commands[2..], false)?;
+    let memory = get_memory_instance();
+    let matches = match memory.get_zset(&commands[1])? {
+        Some(zset) => search.run(zset)?,
+        None => Vec::new(),
+    };
+    Ok(search.response(&search.sorted(matches)))
+}
+
+/// `GEOSEARCHSTORE destination source ... [STOREDIST]`
+fn geosearchstore(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 8 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let search = Search::parse(&commands[0], &commands[3..], true)?;
+    let memory = get_memory_instance();
+    let matches = match memory.get_zset(&commands[2])? {
+        Some(zset) => search.run(zset)?,
+        None => Vec::new(),
+    };
+    let entries = search
+        .sorted(matches)
+        .into_iter()
+        .map(|m| {
+            let score = if search.store_dist { m.distance / search.unit } else { m.score };
+            (m.member, score)
+        })
+        .collect();
+    Ok(resp::integer(zset::store_entries(&commands[1], entries) as i64))
+}
+
+pub fn process_command(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    match commands[0].as_str() {
+        "GEOADD" => geoadd(commands),
+        "GEOPOS" => geopos(commands),
+        "GEODIST" => geodist(commands),
+        "GEOHASH" => geohash_command(commands),
+        "GEOSEARCH" => geosearch(commands),
+        "GEOSEARCHSTORE" => geosearchstore(commands),
+        _ => Err(CommandError::Other(format!("unknown command '{}'", commands[0]))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{run, strings};
+
+    fn add_sicily(key: &str) {
+        run(&["GEOADD", key, "13.361389", "38.115556", "Palermo", "15.087269", "37.502669", "Catania"]);
+    }
+
+    #[test]
+    fn positions_distances_and_hashes() {
+        add_sicily("geo:sicily");
+        assert_eq!(run(&["GEODIST", "geo:sicily", "Palermo", "Catania"]), resp::bulk_string("166274.1516"));
+        assert_eq!(run(&["GEODIST", "geo:sicily", "Palermo", "Catania", "km"]), resp::bulk_string("166.2742"));
+        assert_eq!(run(&["GEODIST", "geo:sicily", "Palermo", "Rome"]), resp::null_bulk_string());
+        assert_eq!(run(&["GEOHASH", "geo:sicily", "Palermo", "Rome"]), resp::array(vec![resp::bulk_string("sqc8b49rny0"), resp::null_bulk_string()]));
+
+        let position = strings(&run(&["GEOPOS", "geo:sicily", "Palermo"]));
+        let position: Vec<f64> = position.iter().map(|value| value.parse().unwrap()).collect();
+        assert!((position[0] - 13.361389).abs() < 1e-5 && (position[1] - 38.115556).abs() < 1e-5);
+        assert_eq!(run(&["GEOPOS", "geo:sicily", "Rome"]), resp::array(vec![resp::null_array()]));
+    }
+
+    #[test]
+    fn adding_validates_coordinates() {
+        assert_eq!(
+            run(&["GEOADD", "geo:invalid", "181", "0", "m"]),
+            resp::error("ERR invalid longitude,latitude pair 181.000000,0.000000")
+        );
+        assert_eq!(
+            run(&["GEOADD", "geo:invalid", "NX", "XX", "0", "0", "m"]),
+            resp::error("ERR XX and NX options at the same time are not compatible")
+        );
+        assert_eq!(run(&["GEOADD", "geo:invalid", "CH", "0", "0", "m"]), resp::integer(1));
+        assert_eq!(run(&["GEOADD", "geo:invalid", "CH", "1", "1", "m"]), resp::integer(1));
+        assert_eq!(run(&["GEOADD", "geo:invalid", "1", "1", "m"]), resp::integer(0));
+    }
+
+    #[test]
+    fn searches() {
+        add_sicily("geo:search");
+        let search = |options: &[&str]| {
+            let mut args = vec!["GEOSEARCH", "geo:search"];
+            args.extend(options);
+            strings(&run(&args))
+        };
+        assert_eq!(search(&["FROMLONLAT", "15", "37", "BYRADIUS", "200", "km", "ASC"]), ["Catania", "Palermo"]);
+        assert_eq!(search(&["FROMLONLAT", "15", "37", "BYRADIUS", "100", "km"]), ["Catania"]);
+        assert_eq!(
+            search(&["FROMLONLAT", "15", "37", "BYBOX", "400", "400", "km", "DESC", "WITHDIST"]),
+            ["Palermo", "190.4424", "Catania", "56.4413"]
+        );
+        assert_eq!(search(&["FROMMEMBER", "Palermo", "BYRADIUS", "200", "km", "COUNT", "1"]), ["Palermo"]);
+
+        let error = |options: &[&str]| {
+            let mut args = vec!["GEOSEARCH", "geo:search"];
+            args.extend(options);
+            run(&args)
+        };
+        assert_eq!(
+            error(&["BYRADIUS", "1", "km", "ASC", "COUNT", "1"]),
+            resp::error("ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for 'GEOSEARCH' command")
+        );
+        assert_eq!(
+            error(&["FROMLONLAT", "15", "37", "BYRADIUS", "1", "yd"]),
+            resp::error("ERR unsupported unit provided. please use M, KM, FT, MI")
+        );
+        assert_eq!(
+            error(&["FROMLONLAT", "15", "37", "BYRADIUS", "1", "km", "ANY"]),
+            resp::error(&CommandError::Syntax.to_string())
+        );
+    }
+
+    #[test]
+    fn stored_searches() {
+        add_sicily("geo:source");
+        let store = |options: &[&str]| {
+            let mut args = vec!["GEOSEARCHSTORE", "geo:stored", "geo:source", "FROMLONLAT", "15", "37", "BYRADIUS", "200", "km"];
+            args.extend(options);
+            run(&args)
+        };
+        assert_eq!(store(&["WITHDIST"]), resp::error(&CommandError::Syntax.to_string()));
+        assert_eq!(store(&[]), resp::integer(2));
+        assert_eq!(run(&["ZSCORE", "geo:stored", "Palermo"]), run(&["ZSCORE", "geo:source", "Palermo"]));
+        assert_eq!(store(&["STOREDIST", "COUNT", "1"]), resp::integer(1));
+        assert_eq!(strings(&run(&["ZRANGE", "geo:stored", "0", "-1", "WITHSCORES"]))[0], "Catania");
+        let distance: f64 = String::from_utf8(run(&["ZSCORE", "geo:stored", "Catania"])).unwrap().split("\r\n").nth(1).unwrap().parse().unwrap();
+        assert!((distance - 56.4413).abs() < 1e-3);
+    }
+}
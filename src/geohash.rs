@@ -0,0 +1,366 @@
+// Geohash helpers following the Redis implementation: coordinates are encoded
+// as 26 bits of latitude interleaved with 26 bits of longitude, which fits in
+// the 52-bit mantissa of a sorted set score.
+
+pub const STEP_MAX: u8 = 26;
+pub const LAT_MIN: f64 = -85.05112878;
+pub const LAT_MAX: f64 = 85.05112878;
+pub const LONG_MIN: f64 = -180.0;
+pub const LONG_MAX: f64 = 180.0;
+
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+const MERCATOR_MAX: f64 = 20037726.37;
+
+#[derive(Clone, Copy)]
+pub struct Range {
+    pub min: f64,
+    pub max: f64,
+}
+
+pub const LAT_RANGE: Range = Range { min: LAT_MIN, max: LAT_MAX };
+pub const LONG_RANGE: Range = Range { min: LONG_MIN, max: LONG_MAX };
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct HashBits {
+    pub bits: u64,
+    pub step: u8,
+}
+
+impl HashBits {
+    pub const ZERO: HashBits = HashBits { bits: 0, step: 0 };
+
+    pub fn is_zero(&self) -> bool {
+        self.bits == 0 && self.step == 0
+    }
+
+    /// Score range `[min, max)` of the sorted set covered by this box.
+    pub fn score_range(&self) -> (u64, u64) {
+        let shift = 2 * (STEP_MAX - self.step) as u32;
+        (self.bits << shift, (self.bits + 1) << shift)
+    }
+}
+
+pub struct Area {
+    pub longitude: Range,
+    pub latitude: Range,
+}
+
+fn spread(value: u32) -> u64 {
+    let mut v = value as u64;
+    v = (v | (v << 16)) & 0x0000FFFF0000FFFF;
+    v = (v | (v << 8)) & 0x00FF00FF00FF00FF;
+    v = (v | (v << 4)) & 0x0F0F0F0F0F0F0F0F;
+    v = (v | (v << 2)) & 0x3333333333333333;
+    v = (v | (v << 1)) & 0x5555555555555555;
+    v
+}
+
+fn squash(value: u64) -> u32 {
+    let mut v = value & 0x5555555555555555;
+    v = (v | (v >> 1)) & 0x3333333333333333;
+    v = (v | (v >> 2)) & 0x0F0F0F0F0F0F0F0F;
+    v = (v | (v >> 4)) & 0x00FF00FF00FF00FF;
+    v = (v | (v >> 8)) & 0x0000FFFF0000FFFF;
+    v = (v | (v >> 16)) & 0x00000000FFFFFFFF;
+    v as u32
+}
+
+/// Latitude bits go to the even positions, longitude bits to the odd ones.
+fn interleave(latitude: u32, longitude: u32) -> u64 {
+    spread(latitude) | (spread(longitude) << 1)
+}
+
+pub fn encode(long_range: Range, lat_range: Range, longitude: f64, latitude: f64, step: u8) -> HashBits {
+    let lat_offset = (latitude - lat_range.min) / (lat_range.max - lat_range.min);
+    let long_offset = (longitude - long_range.min) / (long_range.max - long_range.min);
+    let cells = (1u64 << step) as f64;
+    HashBits {
+        bits: interleave((lat_offset * cells) as u32, (long_offset * cells) as u32),
+        step,
+    }
+}
+
+pub fn encode_wgs84(longitude: f64, latitude: f64, step: u8) -> HashBits {
+    encode(LONG_RANGE, LAT_RANGE, longitude, latitude, step)
+}
+
+pub fn decode(hash: HashBits) -> Area {
+    let latitude = squash(hash.bits) as f64;
+    let longitude = squash(hash.bits >> 1) as f64;
+    let cells = (1u64 << hash.step) as f64;
+    let lat_scale = LAT_MAX - LAT_MIN;
+    let long_scale = LONG_MAX - LONG_MIN;
+    Area {
+        latitude: Range {
+            min: LAT_MIN + (latitude / cells) * lat_scale,
+            max: LAT_MIN + ((latitude + 1.0) / cells) * lat_scale,
+        },
+        longitude: Range {
+            min: LONG_MIN + (longitude / cells) * long_scale,
+            max: LONG_MIN + ((longitude + 1.0) / cells) * long_scale,
+        },
+    }
+}
+
+/// Center of the cell a full precision hash designates, as `(longitude, latitude)`.
+pub fn decode_to_long_lat(bits: u64) -> (f64, f64) {
+    let area = decode(HashBits { bits, step: STEP_MAX });
+    let longitude = ((area.longitude.min + area.longitude.max) / 2.0).clamp(LONG_MIN, LONG_MAX);
+    let latitude = ((area.latitude.min + area.latitude.max) / 2.0).clamp(LAT_MIN, LAT_MAX);
+    (longitude, latitude)
+}
+
+fn move_x(hash: &mut HashBits, direction: i8) {
+    let x = hash.bits & 0xaaaaaaaaaaaaaaaa;
+    let y = hash.bits & 0x5555555555555555;
+    let zz = 0x5555555555555555u64 >> (64 - hash.step as u32 * 2);
+    let x = if direction > 0 { x.wrapping_add(zz + 1) } else { (x | zz).wrapping_sub(zz + 1) };
+    hash.bits = (x & (0xaaaaaaaaaaaaaaaau64 >> (64 - hash.step as u32 * 2))) | y;
+}
+
+fn move_y(hash: &mut HashBits, direction: i8) {
+    let x = hash.bits & 0xaaaaaaaaaaaaaaaa;
+    let y = hash.bits & 0x5555555555555555;
+    let zz = 0xaaaaaaaaaaaaaaaau64 >> (64 - hash.step as u32 * 2);
+    let y = if direction > 0 { y.wrapping_add(zz + 1) } else { (y | zz).wrapping_sub(zz + 1) };
+    hash.bits = x | (y & (0x5555555555555555u64 >> (64 - hash.step as u32 * 2)));
+}
+
+fn moved(hash: HashBits, dx: i8, dy: i8) -> HashBits {
+    let mut neighbor = hash;
+    if dx != 0 {
+        move_x(&mut neighbor, dx);
+    }
+    if dy != 0 {
+        move_y(&mut neighbor, dy);
+    }
+    neighbor
+}
+
+fn deg_rad(degrees: f64) -> f64 {
+    degrees * std::f64::consts::PI / 180.0
+}
+
+fn rad_deg(radians: f64) -> f64 {
+    radians / (std::f64::consts::PI / 180.0)
+}
+
+pub fn lat_distance(lat1: f64, lat2: f64) -> f64 {
+    EARTH_RADIUS_IN_METERS * (deg_rad(lat2) - deg_rad(lat1)).abs()
+}
+
+/// Haversine distance in meters.
+pub fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1r, lon1r, lat2r, lon2r) = (deg_rad(lat1), deg_rad(lon1), deg_rad(lat2), deg_rad(lon2));
+    let v = ((lon2r - lon1r) / 2.0).sin();
+    if v == 0.0 {
+        return lat_distance(lat1, lat2);
+    }
+    let u = ((lat2r - lat1r) / 2.0).sin();
+    let a = u * u + lat1r.cos() * lat2r.cos() * v * v;
+    2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+/// Shape a `GEOSEARCH` looks into, with sizes in meters.
+pub enum Shape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+impl Shape {
+    fn radius(&self) -> f64 {
+        match self {
+            Shape::Radius(radius) => *radius,
+            Shape::Box { width, height } => ((width / 2.0).powi(2) + (height / 2.0).powi(2)).sqrt(),
+        }
+    }
+
+    /// Distance from the center to the point, if the point lies in the shape.
+    pub fn distance_if_inside(&self, center: (f64, f64), point: (f64, f64)) -> Option<f64> {
+        match self {
+            Shape::Radius(radius) => {
+                let distance = distance(center.0, center.1, point.0, point.1);
+                if distance <= *radius {
+                    Some(distance)
+                } else {
+                    None
+                }
+            }
+            Shape::Box { width, height } => {
+                if lat_distance(point.1, center.1) > height / 2.0 {
+                    return None;
+                }
+                if distance(point.0, point.1, center.0, point.1) > width / 2.0 {
+                    return None;
+                }
+                Some(distance(center.0, center.1, point.0, point.1))
+            }
+        }
+    }
+
+    /// `(min_lon, min_lat, max_lon, max_lat)` enclosing the shape.
+    fn bounding_box(&self, longitude: f64, latitude: f64) -> (f64, f64, f64, f64) {
+        let (height, width) = match self {
+            Shape::Radius(radius) => (*radius, *radius),
+            Shape::Box { width, height } => (height / 2.0, width / 2.0),
+        };
+        let lat_delta = rad_deg(height / EARTH_RADIUS_IN_METERS);
+        let long_delta_top = rad_deg(width / EARTH_RADIUS_IN_METERS / deg_rad(latitude + lat_delta).cos());
+        let long_delta_bottom = rad_deg(width / EARTH_RADIUS_IN_METERS / deg_rad(latitude - lat_delta).cos());
+        let long_delta = if latitude < 0.0 { long_delta_bottom } else { long_delta_top };
+        (longitude - long_delta, latitude - lat_delta, longitude + long_delta, latitude + lat_delta)
+    }
+}
+
+/// Largest step whose cells still cover the search radius.
+fn estimate_steps_by_radius(mut range_meters: f64, latitude: f64) -> u8 {
+    if range_meters == 0.0 {
+        return STEP_MAX;
+    }
+    let mut step: i32 = 1;
+    while range_meters < MERCATOR_MAX {
+        range_meters *= 2.0;
+        step += 1;
+    }
+    step -= 2;
+    // Cells are narrower near the poles.
+    if !(-66.0..=66.0).contains(&latitude) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&latitude) {
+            step -= 1;
+        }
+    }
+    step.clamp(1, STEP_MAX as i32) as u8
+}
+
+/// The cell holding the center and its eight neighbors, with the ones that
+/// cannot intersect the shape zeroed out.
+pub fn search_areas(shape: &Shape, longitude: f64, latitude: f64) -> Vec<HashBits> {
+    let radius = shape.radius();
+    let (min_lon, min_lat, max_lon, max_lat) = shape.bounding_box(longitude, latitude);
+    let mut steps = estimate_steps_by_radius(radius, latitude);
+
+    let mut hash = encode_wgs84(longitude, latitude, steps);
+    let mut area = decode(hash);
+
+    // The step may still be too coarse at the borders of the covered area.
+    let north = decode(moved(hash, 0, 1));
+    let south = decode(moved(hash, 0, -1));
+    let east = decode(moved(hash, 1, 0));
+    let west = decode(moved(hash, -1, 0));
+    let decrease_step = distance(longitude, latitude, longitude, north.latitude.max) < radius
+        || distance(longitude, latitude, longitude, south.latitude.min) < radius
+        || distance(longitude, latitude, east.longitude.max, latitude) < radius
+        || distance(longitude, latitude, west.longitude.min, latitude) < radius;
+    if steps > 1 && decrease_step {
+        steps -= 1;
+        hash = encode_wgs84(longitude, latitude, steps);
+        area = decode(hash);
+    }
+
+    // Center, north, south, east, west, north-east, north-west, south-east, south-west.
+    let mut areas: Vec<HashBits> = [(0, 0), (0, 1), (0, -1), (1, 0), (-1, 0), (1, 1), (-1, 1), (1, -1), (-1, -1)]
+        .iter()
+        .map(|(dx, dy)| moved(hash, *dx, *dy))
+        .collect();
+    if steps >= 2 {
+        let exclude = |areas: &mut Vec<HashBits>, indexes: [usize; 3]| {
+            for index in indexes {
+                areas[index] = HashBits::ZERO;
+            }
+        };
+        if area.latitude.min < min_lat {
+            exclude(&mut areas, [2, 7, 8]);
+        }
+        if area.latitude.max > max_lat {
+            exclude(&mut areas, [1, 5, 6]);
+        }
+        if area.longitude.min < min_lon {
+            exclude(&mut areas, [4, 6, 8]);
+        }
+        if area.longitude.max > max_lon {
+            exclude(&mut areas, [3, 5, 7]);
+        }
+    }
+    areas
+}
+
+/// The standard 11 character geohash, which unlike scores spans latitudes
+/// from -90 to 90.
+pub fn to_string(longitude: f64, latitude: f64) -> String {
+    const ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+    let hash = encode(LONG_RANGE, Range { min: -90.0, max: 90.0 }, longitude, latitude, STEP_MAX);
+    (0..11)
+        .map(|i| {
+            let index = if i == 10 { 0 } else { (hash.bits >> (52 - (i + 1) * 5)) & 0x1f };
+            ALPHABET[index as usize] as char
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Palermo and Catania, the examples of the Redis documentation.
+    const PALERMO: (f64, f64) = (13.361389, 38.115556);
+    const CATANIA: (f64, f64) = (15.087269, 37.502669);
+
+    #[test]
+    fn scores_match_redis() {
+        assert_eq!(encode_wgs84(PALERMO.0, PALERMO.1, STEP_MAX).bits, 3479099956230698);
+        assert_eq!(encode_wgs84(CATANIA.0, CATANIA.1, STEP_MAX).bits, 3479447370796909);
+    }
+
+    #[test]
+    fn decodes_to_the_cell_center() {
+        let (longitude, latitude) = decode_to_long_lat(3479099956230698);
+        assert!((longitude - 13.361389338970184).abs() < 1e-9);
+        assert!((latitude - 38.1155563954963).abs() < 1e-9);
+
+        let area = decode(encode_wgs84(PALERMO.0, PALERMO.1, 10));
+        assert!(area.longitude.min <= PALERMO.0 && PALERMO.0 <= area.longitude.max);
+        assert!(area.latitude.min <= PALERMO.1 && PALERMO.1 <= area.latitude.max);
+    }
+
+    #[test]
+    fn interleaves_and_splits_back() {
+        for value in [0, 1, 0x2aa_aaaa, 0x3ff_ffff] {
+            assert_eq!(squash(spread(value)), value);
+            assert_eq!(squash(interleave(value, 0)), value);
+            assert_eq!(squash(interleave(0, value) >> 1), value);
+        }
+    }
+
+    #[test]
+    fn measures_distances() {
+        // GEODIST works on the stored positions, the centers of their cells.
+        let palermo = decode_to_long_lat(3479099956230698);
+        let catania = decode_to_long_lat(3479447370796909);
+        let meters = distance(palermo.0, palermo.1, catania.0, catania.1);
+        assert!((meters - 166274.1516).abs() < 0.0001, "{}", meters);
+        assert_eq!(distance(PALERMO.0, PALERMO.1, PALERMO.0, PALERMO.1), 0.0);
+        assert!((lat_distance(0.0, 1.0) - 111226.3).abs() < 0.1);
+    }
+
+    #[test]
+    fn formats_standard_geohashes() {
+        assert_eq!(to_string(PALERMO.0, PALERMO.1), "sqc8b49rny0");
+        assert_eq!(to_string(CATANIA.0, CATANIA.1), "sqdtr74hyu0");
+    }
+
+    #[test]
+    fn search_areas_cover_the_shape() {
+        let shape = Shape::Radius(200_000.0);
+        let areas = search_areas(&shape, 15.0, 37.0);
+        assert_eq!(areas.len(), 9);
+        for point in [PALERMO, CATANIA] {
+            let score = encode_wgs84(point.0, point.1, STEP_MAX).bits;
+            let covered = areas.iter().filter(|area| !area.is_zero()).any(|area| {
+                let (min, max) = area.score_range();
+                min <= score && score < max
+            });
+            assert!(covered);
+        }
+    }
+}
@@ -0,0 +1,909 @@
+use std::collections::HashMap;
+
+use crate::commands::set_propagated;
+use crate::error::CommandError;
+use crate::resp;
+use crate::util::{check_random_count, format_f64, parse_f64, parse_i64, random_index, ScanOptions};
+use crate::{get_current_time, get_memory_instance, get_options_instance};
+
+/// Small hashes are kept as a flat list of field/value pairs, like the
+/// listpack encoding of Redis, and converted to a real hash table once they
+/// grow past `hash-max-listpack-entries` or `hash-max-listpack-value`.
+#[derive(Clone)]
+enum Encoding {
+    Listpack(Vec<(String, String)>),
+    Table(HashMap<String, String>),
+}
+
+#[derive(Clone)]
+pub struct Hash {
+    encoding: Encoding,
+    /// Absolute expiry time in milliseconds of fields that have a TTL.
+    expires: HashMap<String, u128>,
+}
+
+fn listpack_limits() -> (usize, usize) {
+    let options = get_options_instance();
+    let entries = options
+        .get("hash-max-listpack-entries")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(128);
+    let value = options
+        .get("hash-max-listpack-value")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(64);
+    (entries, value)
+}
+
+impl Hash {
+    pub fn new() -> Self {
+        Hash {
+            encoding: Encoding::Listpack(Vec::new()),
+            expires: HashMap::new(),
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match (&self.encoding, self.expires.is_empty()) {
+            (Encoding::Listpack(_), true) => "listpack",
+            (Encoding::Listpack(_), false) => "listpackex",
+            (Encoding::Table(_), _) => "hashtable",
+        }
+    }
+
+    pub fn is_compact(&self) -> bool {
+        matches!(self.encoding, Encoding::Listpack(_))
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.encoding {
+            Encoding::Listpack(entries) => entries.len(),
+            Encoding::Table(table) => table.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, field: &str) -> Option<&String> {
+        match &self.encoding {
+            Encoding::Listpack(entries) => entries.iter().find(|(f, _)| f == field).map(|(_, v)| v),
+            Encoding::Table(table) => table.get(field),
+        }
+    }
+
+    pub fn contains(&self, field: &str) -> bool {
+        self.get(field).is_some()
+    }
+
+    /// Sets `field` to `value`, returning `true` when the field is new. The
+    /// field keeps its TTL, callers overwriting the value drop it with
+    /// `persist`.
+    pub fn insert(&mut self, field: String, value: String) -> bool {
+        let (max_entries, max_value) = listpack_limits();
+        let oversized = field.len() > max_value || value.len() > max_value;
+
+        let created = match &mut self.encoding {
+            Encoding::Listpack(entries) => match entries.iter_mut().find(|(f, _)| *f == field) {
+                Some(entry) => {
+                    entry.1 = value;
+                    false
+                }
+                None => {
+                    entries.push((field, value));
+                    true
+                }
+            },
+            Encoding::Table(table) => table.insert(field, value).is_none(),
+        };
+
+        if let Encoding::Listpack(entries) = &self.encoding {
+            if oversized || entries.len() > max_entries {
+                self.convert_to_table();
+            }
+        }
+        created
+    }
+
+    pub fn remove(&mut self, field: &str) -> bool {
+        self.expires.remove(field);
+        match &mut self.encoding {
+            Encoding::Listpack(entries) => match entries.iter().position(|(f, _)| f == field) {
+                Some(index) => {
+                    entries.remove(index);
+                    true
+                }
+                None => false,
+            },
+            Encoding::Table(table) => table.remove(field).is_some(),
+        }
+    }
+
+    pub fn entries(&self) -> Vec<(&String, &String)> {
+        match &self.encoding {
+            Encoding::Listpack(entries) => entries.iter().map(|(f, v)| (f, v)).collect(),
+            Encoding::Table(table) => table.iter().collect(),
+        }
+    }
+
+    pub fn field_ttl(&self, field: &str) -> Option<u128> {
+        self.expires.get(field).copied()
+    }
+
+    pub fn has_field_ttls(&self) -> bool {
+        !self.expires.is_empty()
+    }
+
+    pub fn set_field_ttl(&mut self, field: &str, at: u128) {
+        self.expires.insert(field.to_string(), at);
+    }
+
+    /// Removes the TTL of `field`, returning `true` if it had one.
+    pub fn persist(&mut self, field: &str) -> bool {
+        self.expires.remove(field).is_some()
+    }
+
+    pub fn has_expired_fields(&self, current_time: u128) -> bool {
+        self.expires.values().any(|at| current_time > *at)
+    }
+
+    /// Deletes every field whose TTL is in the past and returns their names.
+    pub fn remove_expired_fields(&mut self, current_time: u128) -> Vec<String> {
+        let expired: Vec<String> = self
+            .expires
+            .iter()
+            .filter(|(_, at)| current_time > **at)
+            .map(|(field, _)| field.to_string())
+            .collect();
+        for field in &expired {
+            self.remove(field);
+        }
+        expired
+    }
+
+    fn convert_to_table(&mut self) {
+        if let Encoding::Listpack(entries) = &mut self.encoding {
+            let table: HashMap<String, String> = std::mem::take(entries).into_iter().collect();
+            self.encoding = Encoding::Table(table);
+        }
+    }
+}
+
+fn pairs_response(pairs: Vec<(&String, &String)>) -> Vec<u8> {
+    let mut elements = Vec::new();
+    for (field, value) in pairs {
+        elements.push(resp::bulk_string(field));
+        elements.push(resp::bulk_string(value));
+    }
+    resp::array(elements)
+}
+
+fn hset(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 4 || commands.len() % 2 == 1 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let memory = get_memory_instance();
+    let hash = memory.get_or_create_hash(&commands[1])?;
+    let mut created = 0;
+    for pair in commands[2..].chunks(2) {
+        if hash.insert(pair[0].to_string(), pair[1].to_string()) {
+            created += 1;
+        }
+        hash.persist(&pair[0]);
+    }
+    memory.touch(&commands[1]);
+    if commands[0] == "HMSET" {
+        return Ok(resp::simple_string("OK"));
+    }
+    Ok(resp::integer(created))
+}
+
+fn hsetnx(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let memory = get_memory_instance();
+    let hash = memory.get_or_create_hash(&commands[1])?;
+    if hash.contains(&commands[2]) {
+        return Ok(resp::integer(0));
+    }
+    hash.insert(commands[2].to_string(), commands[3].to_string());
+    memory.touch(&commands[1]);
+    Ok(resp::integer(1))
+}
+
+fn hget(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let hash = get_memory_instance().get_hash(&commands[1])?;
+    Ok(resp::optional_bulk_string(hash.and_then(|h| h.get(&commands[2]))))
+}
+
+fn hmget(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let hash = get_memory_instance().get_hash(&commands[1])?;
+    Ok(resp::array(
+        commands[2..]
+            .iter()
+            .map(|field| resp::optional_bulk_string(hash.and_then(|h| h.get(field))))
+            .collect(),
+    ))
+}
+
+fn hgetall(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 2 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    match get_memory_instance().get_hash(&commands[1])? {
+        Some(hash) => Ok(pairs_response(hash.entries())),
+        None => Ok(resp::array(Vec::new())),
+    }
+}
+
+fn hdel(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let memory = get_memory_instance();
+    let mut removed = 0;
+    if let Some(hash) = memory.get_hash_mut(&commands[1])? {
+        for field in &commands[2..] {
+            if hash.remove(field) {
+                removed += 1;
+            }
+        }
+    }
+    if removed > 0 {
+        memory.touch(&commands[1]);
+    }
+    memory.remove_if_empty(&commands[1]);
+    Ok(resp::integer(removed))
+}
+
+fn hexists(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let hash = get_memory_instance().get_hash(&commands[1])?;
+    let exists = hash.map(|h| h.contains(&commands[2])).unwrap_or(false);
+    Ok(resp::integer(exists as i64))
+}
+
+fn hincrby(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let increment = parse_i64(&commands[3]).ok_or(CommandError::NotInteger)?;
+    let memory = get_memory_instance();
+    let current = match memory.get_hash(&commands[1])?.and_then(|h| h.get(&commands[2])) {
+        Some(value) => parse_i64(value)
+            .ok_or_else(|| CommandError::Other("hash value is not an integer".to_string()))?,
+        None => 0,
+    };
+    let updated = current.checked_add(increment).ok_or(CommandError::Overflow)?;
+    memory.get_or_create_hash(&commands[1])?.insert(commands[2].to_string(), updated.to_string());
+    memory.touch(&commands[1]);
+    Ok(resp::integer(updated))
+}
+
+fn hincrbyfloat(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let increment = parse_f64(&commands[3]).ok_or(CommandError::NotFloat)?;
+    let memory = get_memory_instance();
+    let current = match memory.get_hash(&commands[1])?.and_then(|h| h.get(&commands[2])) {
+        Some(value) => parse_f64(value)
+            .ok_or_else(|| CommandError::Other("hash value is not a float".to_string()))?,
+        None => 0.0,
+    };
+    let updated = current + increment;
+    if updated.is_nan() || updated.is_infinite() {
+        return Err(CommandError::Other("increment would produce NaN or Infinity".to_string()));
+    }
+    let formatted = format_f64(updated);
+    memory.get_or_create_hash(&commands[1])?.insert(commands[2].to_string(), formatted.to_string());
+    memory.touch(&commands[1]);
+    Ok(resp::bulk_string(&formatted))
+}
+
+fn hkeys(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 2 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let hash = get_memory_instance().get_hash(&commands[1])?;
+    let keys: Vec<&String> = hash.map(|h| h.entries().into_iter().map(|(f, _)| f).collect()).unwrap_or_default();
+    Ok(resp::bulk_string_array(&keys))
+}
+
+fn hvals(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 2 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let hash = get_memory_instance().get_hash(&commands[1])?;
+    let values: Vec<&String> = hash.map(|h| h.entries().into_iter().map(|(_, v)| v).collect()).unwrap_or_default();
+    Ok(resp::bulk_string_array(&values))
+}
+
+fn hlen(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 2 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let hash = get_memory_instance().get_hash(&commands[1])?;
+    Ok(resp::integer(hash.map(|h| h.len()).unwrap_or(0) as i64))
+}
+
+fn hstrlen(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let hash = get_memory_instance().get_hash(&commands[1])?;
+    let len = hash.and_then(|h| h.get(&commands[2])).map(|v| v.len()).unwrap_or(0);
+    Ok(resp::integer(len as i64))
+}
+
+fn hscan(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let options = ScanOptions::parse(&commands[2], &commands[3..], true)?;
+    let hash = match get_memory_instance().get_hash(&commands[1])? {
+        Some(hash) => hash,
+        None => return Ok(ScanOptions::response(0, Vec::new())),
+    };
+
+    let entries = hash.entries();
+    // Compact hashes are returned in a single call, like Redis does. Table
+    // iteration order changes as the hash grows, so larger ones page by hash.
+    let (next, page) = if hash.is_compact() {
+        (0, entries)
+    } else {
+        options.page_by_hash(entries, |(field, _)| field.as_str())
+    };
+
+    let mut elements = Vec::new();
+    for (field, value) in page {
+        if !options.matches(field) {
+            continue;
+        }
+        elements.push(resp::bulk_string(field));
+        if !options.no_values {
+            elements.push(resp::bulk_string(value));
+        }
+    }
+    Ok(ScanOptions::response(next, elements))
+}
+
+fn hrandfield(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 2 || commands.len() > 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let count = match commands.get(2) {
+        Some(count) => Some(parse_i64(count).ok_or(CommandError::NotInteger)?),
+        None => None,
+    };
+    let with_values = match commands.get(3) {
+        Some(option) if option.eq_ignore_ascii_case("WITHVALUES") => true,
+        Some(_) => return Err(CommandError::Syntax),
+        None => false,
+    };
+    check_random_count(count)?;
+
+    let entries = match get_memory_instance().get_hash(&commands[1])? {
+        Some(hash) => hash.entries(),
+        None => Vec::new(),
+    };
+
+    let count = match count {
+        Some(count) => count,
+        None => {
+            if entries.is_empty() {
+                return Ok(resp::null_bulk_string());
+            }
+            return Ok(resp::bulk_string(entries[random_index(entries.len())].0));
+        }
+    };
+
+    let picked: Vec<(&String, &String)> = if entries.is_empty() {
+        Vec::new()
+    } else if count < 0 {
+        (0..count.unsigned_abs()).map(|_| entries[random_index(entries.len())]).collect()
+    } else {
+        let mut remaining = entries;
+        let mut picked = Vec::new();
+        while !remaining.is_empty() && picked.len() < count as usize {
+            picked.push(remaining.swap_remove(random_index(remaining.len())));
+        }
+        picked
+    };
+
+    if with_values {
+        return Ok(pairs_response(picked));
+    }
+    let fields: Vec<&String> = picked.into_iter().map(|(f, _)| f).collect();
+    Ok(resp::bulk_string_array(&fields))
+}
+
+/// Parses the trailing `FIELDS numfields field [field ...]` block.
+fn parse_fields(args: &[String]) -> Result<&[String], CommandError> {
+    if args.len() < 2 || !args[0].eq_ignore_ascii_case("FIELDS") {
+        return Err(CommandError::Other("Mandatory argument FIELDS is missing or not at the right position".to_string()));
+    }
+    let count = match parse_i64(&args[1]) {
+        Some(count) if count > 0 => count as usize,
+        _ => return Err(CommandError::Other("Parameter `numFields` should be greater than 0".to_string())),
+    };
+    if args.len() - 2 != count {
+        return Err(CommandError::Other("The `numfields` parameter must match the number of arguments".to_string()));
+    }
+    Ok(&args[2..])
+}
+
+enum ExpireCondition {
+    Always,
+    Nx,
+    Xx,
+    Gt,
+    Lt,
+}
+
+/// Sets a TTL on every field, replying per field with -2 (no such field),
+/// 0 (condition not met), 1 (TTL set) or 2 (deleted, time already past).
+fn hexpire(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 6 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let amount = parse_i64(&commands[2]).ok_or(CommandError::NotInteger)?;
+    if amount < 0 {
+        return Err(CommandError::Other(format!("invalid expire time in '{}' command", commands[0].to_ascii_lowercase())));
+    }
+    let now = get_current_time();
+    let amount = amount as u128;
+    let at = match commands[0].as_str() {
+        "HEXPIRE" => now + amount * 1000,
+        "HPEXPIRE" => now + amount,
+        "HEXPIREAT" => amount * 1000,
+        _ => amount,
+    };
+
+    let (condition, rest) = match commands[3].to_ascii_uppercase().as_str() {
+        "NX" => (ExpireCondition::Nx, &commands[4..]),
+        "XX" => (ExpireCondition::Xx, &commands[4..]),
+        "GT" => (ExpireCondition::Gt, &commands[4..]),
+        "LT" => (ExpireCondition::Lt, &commands[4..]),
+        _ => (ExpireCondition::Always, &commands[3..]),
+    };
+    let fields = parse_fields(rest)?;
+    // Replicas get the absolute time, relative ones would expire later there.
+    let mut propagated = vec!["HPEXPIREAT".to_string(), commands[1].to_string(), at.to_string()];
+    propagated.extend(commands[3..].iter().cloned());
+    set_propagated(propagated);
+
+    let memory = get_memory_instance();
+    let hash = match memory.get_hash_mut(&commands[1])? {
+        Some(hash) => hash,
+        None => return Ok(resp::array(fields.iter().map(|_| resp::integer(-2)).collect())),
+    };
+
+    let mut replies = Vec::new();
+    let mut changed = false;
+    for field in fields {
+        if !hash.contains(field) {
+            replies.push(resp::integer(-2));
+            continue;
+        }
+        let current = hash.field_ttl(field);
+        let allowed = match condition {
+            ExpireCondition::Always => true,
+            ExpireCondition::Nx => current.is_none(),
+            ExpireCondition::Xx => current.is_some(),
+            ExpireCondition::Gt => current.map(|c| at > c).unwrap_or(false),
+            ExpireCondition::Lt => current.map(|c| at < c).unwrap_or(true),
+        };
+        if !allowed {
+            replies.push(resp::integer(0));
+        } else if at <= now {
+            hash.remove(field);
+            changed = true;
+            replies.push(resp::integer(2));
+        } else {
+            hash.set_field_ttl(field, at);
+            changed = true;
+            replies.push(resp::integer(1));
+        }
+    }
+    if hash.has_field_ttls() {
+        memory.track_field_expires(&commands[1]);
+    }
+    if changed {
+        memory.touch(&commands[1]);
+    }
+    memory.remove_if_empty(&commands[1]);
+    Ok(resp::array(replies))
+}
+
+fn httl(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 5 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let fields = parse_fields(&commands[2..])?;
+    let now = get_current_time();
+    let hash = get_memory_instance().get_hash(&commands[1])?;
+
+    let replies = fields
+        .iter()
+        .map(|field| {
+            let hash = match hash {
+                Some(hash) if hash.contains(field) => hash,
+                _ => return resp::integer(-2),
+            };
+            match hash.field_ttl(field) {
+                None => resp::integer(-1),
+                Some(at) => {
+                    let value = match commands[0].as_str() {
+                        "HTTL" => (at.saturating_sub(now) + 500) / 1000,
+                        "HPTTL" => at.saturating_sub(now),
+                        "HEXPIRETIME" => at / 1000,
+                        _ => at,
+                    };
+                    resp::integer(value as i64)
+                }
+            }
+        })
+        .collect();
+    Ok(resp::array(replies))
+}
+
+fn hpersist(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 5 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let fields = parse_fields(&commands[2..])?;
+    let memory = get_memory_instance();
+    let hash = match memory.get_hash_mut(&commands[1])? {
+        Some(hash) => hash,
+        None => return Ok(resp::array(fields.iter().map(|_| resp::integer(-2)).collect())),
+    };
+    let mut changed = false;
+    let replies = fields
+        .iter()
+        .map(|field| {
+            if !hash.contains(field) {
+                resp::integer(-2)
+            } else if hash.persist(field) {
+                changed = true;
+                resp::integer(1)
+            } else {
+                resp::integer(-1)
+            }
+        })
+        .collect();
+    if changed {
+        memory.touch(&commands[1]);
+    }
+    Ok(resp::array(replies))
+}
+
+/// `HGETEX key [EX s | PX ms | EXAT ts | PXAT ts | PERSIST] FIELDS n field...`
+fn hgetex(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 5 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let now = get_current_time();
+    let (expiry, rest) = match commands[2].to_ascii_uppercase().as_str() {
+        "PERSIST" => (Some(None), &commands[3..]),
+        option @ ("EX" | "PX" | "EXAT" | "PXAT") => {
+            let amount = match commands.get(3).and_then(|v| parse_i64(v)) {
+                Some(amount) if amount > 0 => amount as u128,
+                _ => return Err(CommandError::Other("invalid expire time in 'hgetex' command".to_string())),
+            };
+            let at = match option {
+                "EX" => now + amount * 1000,
+                "PX" => now + amount,
+                "EXAT" => amount * 1000,
+                _ => amount,
+            };
+            (Some(Some(at)), &commands[4..])
+        }
+        _ => (None, &commands[2..]),
+    };
+    let fields = parse_fields(rest)?;
+    // Replicas get the absolute expiry, and nothing for a plain read.
+    let mut propagated = match expiry {
+        Some(Some(at)) => vec!["HPEXPIREAT".to_string(), commands[1].to_string(), at.to_string()],
+        Some(None) => vec!["HPERSIST".to_string(), commands[1].to_string()],
+        None => Vec::new(),
+    };
+    if !propagated.is_empty() {
+        propagated.extend(rest.iter().cloned());
+    }
+    set_propagated(propagated);
+
+    let memory = get_memory_instance();
+    let hash = match memory.get_hash_mut(&commands[1])? {
+        Some(hash) => hash,
+        None => return Ok(resp::array(fields.iter().map(|_| resp::null_bulk_string()).collect())),
+    };
+
+    let mut replies = Vec::new();
+    let mut changed = false;
+    for field in fields {
+        replies.push(resp::optional_bulk_string(hash.get(field)));
+        if !hash.contains(field) {
+            continue;
+        }
+        match expiry {
+            Some(Some(at)) if at <= now => {
+                hash.remove(field);
+                changed = true;
+            }
+            Some(Some(at)) => {
+                hash.set_field_ttl(field, at);
+                changed = true;
+            }
+            Some(None) => changed |= hash.persist(field),
+            None => {}
+        }
+    }
+    if hash.has_field_ttls() {
+        memory.track_field_expires(&commands[1]);
+    }
+    if changed {
+        memory.touch(&commands[1]);
+    }
+    memory.remove_if_empty(&commands[1]);
+    Ok(resp::array(replies))
+}
+
+pub fn process_command(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    match commands[0].as_str() {
+        "HSET" | "HMSET" => hset(commands),
+        "HSETNX" => hsetnx(commands),
+        "HGET" => hget(commands),
+        "HMGET" => hmget(commands),
+        "HGETALL" => hgetall(commands),
+        "HDEL" => hdel(commands),
+        "HEXISTS" => hexists(commands),
+        "HINCRBY" => hincrby(commands),
+        "HINCRBYFLOAT" => hincrbyfloat(commands),
+        "HKEYS" => hkeys(commands),
+        "HVALS" => hvals(commands),
+        "HLEN" => hlen(commands),
+        "HSTRLEN" => hstrlen(commands),
+        "HSCAN" => hscan(commands),
+        "HRANDFIELD" => hrandfield(commands),
+        "HEXPIRE" | "HPEXPIRE" | "HEXPIREAT" | "HPEXPIREAT" => hexpire(commands),
+        "HTTL" | "HPTTL" | "HEXPIRETIME" | "HPEXPIRETIME" => httl(commands),
+        "HPERSIST" => hpersist(commands),
+        "HGETEX" => hgetex(commands),
+        _ => Err(CommandError::Other(format!("unknown command '{}'", commands[0]))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{lock_execution, run, run_propagated, strings, Target};
+
+    fn fields(names: &[&str]) -> Vec<u8> {
+        resp::bulk_string_array(names)
+    }
+
+    #[test]
+    fn listpack_converts_to_table_past_its_limits() {
+        let _guard = lock_execution();
+        let mut hash = Hash::new();
+        for i in 0..128 {
+            assert!(hash.insert(format!("f{}", i), "v".to_string()));
+        }
+        assert_eq!(hash.encoding(), "listpack");
+        assert!(!hash.insert("f0".to_string(), "updated".to_string()));
+        assert!(hash.insert("f128".to_string(), "v".to_string()));
+        assert_eq!(hash.encoding(), "hashtable");
+        assert_eq!(hash.len(), 129);
+        assert_eq!(hash.get("f0").map(String::as_str), Some("updated"));
+
+        // Tables never shrink back, even once small again.
+        for i in 0..129 {
+            assert!(hash.remove(&format!("f{}", i)));
+        }
+        assert!(hash.is_empty());
+        assert_eq!(hash.encoding(), "hashtable");
+
+        let mut hash = Hash::new();
+        hash.insert("small".to_string(), "v".repeat(64));
+        assert_eq!(hash.encoding(), "listpack");
+        hash.insert("large".to_string(), "v".repeat(65));
+        assert_eq!(hash.encoding(), "hashtable");
+        assert_eq!(hash.get("small").map(String::len), Some(64));
+    }
+
+    #[test]
+    fn listpack_keeps_insertion_order() {
+        assert_eq!(run(&["HSET", "hash:order", "b", "1", "a", "2", "c", "3"]), resp::integer(3));
+        assert_eq!(run(&["HSET", "hash:order", "a", "4"]), resp::integer(0));
+        assert_eq!(run(&["HKEYS", "hash:order"]), fields(&["b", "a", "c"]));
+        assert_eq!(run(&["HVALS", "hash:order"]), fields(&["1", "4", "3"]));
+        assert_eq!(run(&["HGETALL", "hash:order"]), fields(&["b", "1", "a", "4", "c", "3"]));
+        assert_eq!(run(&["OBJECT", "ENCODING", "hash:order"]), resp::bulk_string("listpack"));
+    }
+
+    #[test]
+    fn field_commands() {
+        assert_eq!(run(&["HMSET", "hash:fields", "f", "hello", "g", "world"]), resp::simple_string("OK"));
+        assert_eq!(run(&["HSETNX", "hash:fields", "f", "other"]), resp::integer(0));
+        assert_eq!(run(&["HSETNX", "hash:fields", "h", "new"]), resp::integer(1));
+        assert_eq!(run(&["HGET", "hash:fields", "f"]), resp::bulk_string("hello"));
+        assert_eq!(run(&["HGET", "hash:fields", "missing"]), resp::null_bulk_string());
+        let values = vec![resp::bulk_string("world"), resp::null_bulk_string(), resp::bulk_string("new")];
+        assert_eq!(run(&["HMGET", "hash:fields", "g", "missing", "h"]), resp::array(values));
+        assert_eq!(run(&["HEXISTS", "hash:fields", "g"]), resp::integer(1));
+        assert_eq!(run(&["HSTRLEN", "hash:fields", "g"]), resp::integer(5));
+        assert_eq!(run(&["HLEN", "hash:fields"]), resp::integer(3));
+        assert_eq!(run(&["HDEL", "hash:fields", "f", "missing", "g"]), resp::integer(2));
+        assert_eq!(run(&["HDEL", "hash:fields", "h"]), resp::integer(1));
+        // The last field takes the key with it.
+        assert_eq!(run(&["TYPE", "hash:fields"]), resp::simple_string("none"));
+        assert_eq!(run(&["HLEN", "hash:fields"]), resp::integer(0));
+        assert_eq!(run(&["HGETALL", "hash:fields"]), resp::array(Vec::new()));
+    }
+
+    #[test]
+    fn increments() {
+        assert_eq!(run(&["HINCRBY", "hash:incr", "n", "5"]), resp::integer(5));
+        assert_eq!(run(&["HINCRBY", "hash:incr", "n", "-7"]), resp::integer(-2));
+        assert_eq!(run(&["HINCRBY", "hash:incr", "n", "x"]), resp::error("ERR value is not an integer or out of range"));
+        run(&["HSET", "hash:incr", "max", &i64::MAX.to_string(), "text", "abc"]);
+        assert_eq!(run(&["HINCRBY", "hash:incr", "max", "1"]), resp::error("ERR increment or decrement would overflow"));
+        assert_eq!(run(&["HINCRBY", "hash:incr", "text", "1"]), resp::error("ERR hash value is not an integer"));
+
+        assert_eq!(run(&["HINCRBYFLOAT", "hash:incr", "f", "1.5"]), resp::bulk_string("1.5"));
+        assert_eq!(run(&["HINCRBYFLOAT", "hash:incr", "f", "-0.5"]), resp::bulk_string("1"));
+        assert_eq!(run(&["HINCRBYFLOAT", "hash:incr", "text", "1"]), resp::error("ERR hash value is not a float"));
+        assert_eq!(
+            run(&["HINCRBYFLOAT", "hash:incr", "f", "inf"]),
+            resp::error("ERR increment would produce NaN or Infinity")
+        );
+        assert_eq!(run(&["HGET", "hash:incr", "f"]), resp::bulk_string("1"));
+    }
+
+    #[test]
+    fn wrong_type_and_arity() {
+        run(&["SET", "hash:string", "value"]);
+        let wrong_type = resp::error("WRONGTYPE Operation against a key holding the wrong kind of value");
+        assert_eq!(run(&["HSET", "hash:string", "f", "v"]), wrong_type);
+        assert_eq!(run(&["HGET", "hash:string", "f"]), wrong_type);
+        assert_eq!(run(&["HSET", "hash:arity", "f"]), resp::error("ERR wrong number of arguments for 'hset' command"));
+        assert_eq!(run(&["HRANDFIELD", "hash:arity", "1", "NOVALUES"]), resp::error("ERR syntax error"));
+    }
+
+    #[test]
+    fn random_fields() {
+        run(&["HSET", "hash:random", "a", "1", "b", "2", "c", "3"]);
+        assert_eq!(run(&["HRANDFIELD", "hash:missing"]), resp::null_bulk_string());
+        assert_eq!(run(&["HRANDFIELD", "hash:missing", "3"]), resp::array(Vec::new()));
+        // A positive count returns distinct fields, at most all of them.
+        let mut all = strings(&run(&["HRANDFIELD", "hash:random", "10"]));
+        all.sort();
+        assert_eq!(all, ["a", "b", "c"]);
+        let pairs = strings(&run(&["HRANDFIELD", "hash:random", "2", "WITHVALUES"]));
+        assert_eq!(pairs.len(), 4);
+        assert_ne!(pairs[0], pairs[2]);
+        assert_eq!(run(&["HGET", "hash:random", &pairs[0]]), resp::bulk_string(&pairs[1]));
+        // A negative one may repeat them, but is bounded.
+        assert_eq!(strings(&run(&["HRANDFIELD", "hash:random", "-5"])).len(), 5);
+        let out_of_range = resp::error("ERR value is out of range");
+        assert_eq!(run(&["HRANDFIELD", "hash:random", &(-(1i64 << 24) - 1).to_string()]), out_of_range);
+        assert_eq!(run(&["HRANDFIELD", "hash:random", &i64::MIN.to_string()]), out_of_range);
+    }
+
+    fn integers(values: &[i64]) -> Vec<u8> {
+        resp::array(values.iter().map(|value| resp::integer(*value)).collect())
+    }
+
+    #[test]
+    fn fields_block() {
+        let args: Vec<String> = ["FIELDS", "2", "a", "b"].iter().map(|arg| arg.to_string()).collect();
+        assert_eq!(parse_fields(&args).unwrap(), &args[2..]);
+        assert!(parse_fields(&args[..3]).is_err());
+        assert!(parse_fields(&args[1..]).is_err());
+        let zero: Vec<String> = ["FIELDS", "0"].iter().map(|arg| arg.to_string()).collect();
+        assert!(parse_fields(&zero).is_err());
+    }
+
+    #[test]
+    fn field_expiry_replies() {
+        run(&["HSET", "hash:ttl", "a", "1", "b", "2", "c", "3"]);
+        assert_eq!(run(&["HEXPIRE", "hash:ttl", "100", "FIELDS", "2", "a", "missing"]), integers(&[1, -2]));
+        assert_eq!(run(&["HEXPIRE", "hash:ttl", "200", "NX", "FIELDS", "2", "a", "b"]), integers(&[0, 1]));
+        assert_eq!(run(&["HEXPIRE", "hash:ttl", "50", "GT", "FIELDS", "2", "a", "c"]), integers(&[0, 0]));
+        assert_eq!(run(&["HEXPIRE", "hash:ttl", "50", "LT", "FIELDS", "2", "a", "c"]), integers(&[1, 1]));
+        assert_eq!(run(&["HEXPIRE", "hash:ttl", "60", "XX", "FIELDS", "1", "missing"]), integers(&[-2]));
+        assert_eq!(run(&["HTTL", "hash:ttl", "FIELDS", "3", "a", "b", "missing"]), integers(&[50, 200, -2]));
+        assert_eq!(run(&["OBJECT", "ENCODING", "hash:ttl"]), resp::bulk_string("listpackex"));
+
+        assert_eq!(run(&["HPERSIST", "hash:ttl", "FIELDS", "2", "a", "a"]), integers(&[1, -1]));
+        assert_eq!(run(&["HTTL", "hash:ttl", "FIELDS", "1", "a"]), integers(&[-1]));
+        // Overwriting a field drops its TTL too.
+        run(&["HSET", "hash:ttl", "b", "new"]);
+        assert_eq!(run(&["HTTL", "hash:ttl", "FIELDS", "1", "b"]), integers(&[-1]));
+
+        // A time in the past deletes the field right away.
+        assert_eq!(run(&["HPEXPIREAT", "hash:ttl", "1", "FIELDS", "1", "c"]), integers(&[2]));
+        assert_eq!(run(&["HEXISTS", "hash:ttl", "c"]), resp::integer(0));
+        assert_eq!(run(&["HEXPIREAT", "hash:ttl", "4102444800", "FIELDS", "1", "a"]), integers(&[1]));
+        assert_eq!(run(&["HEXPIRETIME", "hash:ttl", "FIELDS", "1", "a"]), integers(&[4102444800]));
+        assert_eq!(run(&["HPEXPIRETIME", "hash:ttl", "FIELDS", "1", "a"]), integers(&[4102444800000]));
+
+        assert_eq!(run(&["HEXPIRE", "hash:ttl", "-1", "FIELDS", "1", "a"]), resp::error("ERR invalid expire time in 'hexpire' command"));
+        assert_eq!(
+            run(&["HEXPIRE", "hash:ttl", "10", "FIELDS", "2", "a"]),
+            resp::error("ERR The `numfields` parameter must match the number of arguments")
+        );
+    }
+
+    #[test]
+    fn getex_reads_and_sets_expiry() {
+        run(&["HSET", "hash:getex", "a", "1", "b", "2"]);
+        let values = resp::array(vec![resp::bulk_string("1"), resp::null_bulk_string()]);
+        assert_eq!(run(&["HGETEX", "hash:getex", "PX", "100000", "FIELDS", "2", "a", "missing"]), values);
+        let reply = String::from_utf8(run(&["HPTTL", "hash:getex", "FIELDS", "1", "a"])).unwrap();
+        let ttl: u64 = reply.trim_start_matches("*1\r\n:").trim_end().parse().unwrap();
+        assert!(ttl > 99_000 && ttl <= 100_000);
+        assert_eq!(run(&["HGETEX", "hash:getex", "PERSIST", "FIELDS", "1", "a"]), resp::bulk_string_array(&["1"]));
+        assert_eq!(run(&["HTTL", "hash:getex", "FIELDS", "1", "a"]), integers(&[-1]));
+        assert_eq!(run(&["HGETEX", "hash:getex", "EX", "0", "FIELDS", "1", "a"]), resp::error("ERR invalid expire time in 'hgetex' command"));
+
+        // An expiry in the past returns the values and deletes the fields,
+        // the key along with the last of them.
+        let values = resp::bulk_string_array(&["1", "2"]);
+        assert_eq!(run(&["HGETEX", "hash:getex", "PXAT", "1", "FIELDS", "2", "a", "b"]), values);
+        assert_eq!(run(&["TYPE", "hash:getex"]), resp::simple_string("none"));
+    }
+
+    #[test]
+    fn expired_fields_are_removed() {
+        let mut hash = Hash::new();
+        hash.set_field_ttl("a", 100);
+        hash.set_field_ttl("b", 200);
+        assert!(!hash.has_expired_fields(100));
+        assert!(hash.has_expired_fields(101));
+
+        run(&["HSET", "hash:expiring", "a", "1", "b", "2"]);
+        run(&["HPEXPIRE", "hash:expiring", "1", "FIELDS", "1", "a"]);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        {
+            let _guard = lock_execution();
+            get_memory_instance().remove_expired_fields(get_current_time());
+        }
+        assert_eq!(run(&["HGETALL", "hash:expiring"]), resp::bulk_string_array(&["b", "2"]));
+        assert_eq!(run(&["OBJECT", "ENCODING", "hash:expiring"]), resp::bulk_string("listpack"));
+
+        run(&["HPEXPIRE", "hash:expiring", "1", "FIELDS", "1", "b"]);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        {
+            let _guard = lock_execution();
+            get_memory_instance().remove_expired_fields(get_current_time());
+        }
+        assert_eq!(run(&["TYPE", "hash:expiring"]), resp::simple_string("none"));
+    }
+
+    #[test]
+    fn expiry_propagates_as_absolute_time() {
+        run(&["HSET", "hash:propagated", "a", "1"]);
+        let (propagated, target) = run_propagated(&["HEXPIRE", "hash:propagated", "100", "NX", "FIELDS", "1", "a"]).unwrap();
+        assert_eq!(target, Target::All);
+        assert_eq!(propagated[..2], ["HPEXPIREAT", "hash:propagated"]);
+        let at: u128 = propagated[2].parse().unwrap();
+        assert!(at > get_current_time() + 99_000);
+        assert_eq!(propagated[3..], ["NX", "FIELDS", "1", "a"]);
+
+        let (propagated, _) = run_propagated(&["HGETEX", "hash:propagated", "PERSIST", "FIELDS", "1", "a"]).unwrap();
+        assert_eq!(propagated, ["HPERSIST", "hash:propagated", "FIELDS", "1", "a"]);
+        assert_eq!(run_propagated(&["HGETEX", "hash:propagated", "FIELDS", "1", "a"]), None);
+    }
+}
@@ -0,0 +1,492 @@
+use crate::error::CommandError;
+use crate::parser::Command;
+use crate::resp;
+use crate::{get_memory_instance, get_options_instance};
+
+// HyperLogLogs are stored as plain strings laid out exactly like in Redis, so
+// they survive GET/SET and can be exchanged with a real server:
+//
+// +------+---+-----+----------+
+// | HYLL | E | N/U | Cardin.  |
+// +------+---+-----+----------+
+//
+// 4 bytes of magic, one encoding byte (dense or sparse), 3 unused bytes and
+// the cached cardinality as a 64-bit little-endian integer whose most
+// significant bit flags the cache as stale. The registers follow.
+
+const P: u32 = 14;
+const Q: u32 = 64 - P;
+const REGISTERS: usize = 1 << P;
+const BITS: usize = 6;
+const REGISTER_MAX: u8 = (1 << BITS) - 1;
+const HEADER_SIZE: usize = 16;
+const DENSE_SIZE: usize = HEADER_SIZE + (REGISTERS * BITS).div_ceil(8);
+const ENCODING_DENSE: u8 = 0;
+const ENCODING_SPARSE: u8 = 1;
+const ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+
+// Sparse opcodes: ZERO 00xxxxxx, XZERO 01xxxxxx yyyyyyyy, VAL 1vvvvvxx.
+const SPARSE_VAL_MAX_VALUE: u8 = 32;
+const SPARSE_VAL_MAX_LEN: usize = 4;
+const SPARSE_ZERO_MAX_LEN: usize = 64;
+const SPARSE_XZERO_MAX_LEN: usize = 16384;
+
+fn sparse_max_bytes() -> usize {
+    get_options_instance()
+        .get("hll-sparse-max-bytes")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(3000)
+}
+
+fn invalid_hll() -> CommandError {
+    CommandError::Custom("WRONGTYPE Key is not a valid HyperLogLog string value.".to_string())
+}
+
+fn corrupted_hll() -> CommandError {
+    CommandError::Custom("INVALIDOBJ Corrupted HLL object detected".to_string())
+}
+
+/// MurmurHash2, 64-bit version by Austin Appleby, as used by Redis.
+fn murmurhash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+
+    let chunks = key.chunks_exact(8);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate() {
+            h ^= (*byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// Register index of the element and the length of the run of zeroes that
+/// follows it in its hash, plus one.
+fn pattern_len(element: &[u8]) -> (usize, u8) {
+    let hash = murmurhash64a(element, 0xadc83b19);
+    let index = (hash & (REGISTERS as u64 - 1)) as usize;
+    let rest = (hash >> P) | (1 << Q);
+    (index, rest.trailing_zeros() as u8 + 1)
+}
+
+fn dense_get(registers: &[u8], index: usize) -> u8 {
+    let byte = index * BITS / 8;
+    let shift = (index * BITS) & 7;
+    let b0 = registers[byte] as u16;
+    let b1 = registers.get(byte + 1).copied().unwrap_or(0) as u16;
+    (((b0 >> shift) | (b1 << (8 - shift))) as u8) & REGISTER_MAX
+}
+
+fn dense_set(registers: &mut [u8], index: usize, value: u8) {
+    let byte = index * BITS / 8;
+    let shift = (index * BITS) & 7;
+    let value = value as u16;
+    registers[byte] &= !((REGISTER_MAX as u16) << shift) as u8;
+    registers[byte] |= (value << shift) as u8;
+    if byte + 1 < registers.len() {
+        registers[byte + 1] &= !((REGISTER_MAX as u16) >> (8 - shift)) as u8;
+        registers[byte + 1] |= (value >> (8 - shift)) as u8;
+    }
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if previous == z {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if previous == z {
+            return z / 3.0;
+        }
+    }
+}
+
+/// Registers of a HyperLogLog unpacked to one byte each.
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+    dense: bool,
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        HyperLogLog {
+            registers: vec![0; REGISTERS],
+            dense: false,
+        }
+    }
+
+    /// Checks the header of a string value, returning its encoding.
+    fn check(bytes: &[u8]) -> Result<u8, CommandError> {
+        if bytes.len() < HEADER_SIZE || &bytes[..4] != b"HYLL" {
+            return Err(invalid_hll());
+        }
+        match bytes[4] {
+            ENCODING_DENSE if bytes.len() == DENSE_SIZE => Ok(ENCODING_DENSE),
+            ENCODING_SPARSE => Ok(ENCODING_SPARSE),
+            _ => Err(invalid_hll()),
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, CommandError> {
+        let mut registers = vec![0; REGISTERS];
+        if Self::check(bytes)? == ENCODING_DENSE {
+            for (index, register) in registers.iter_mut().enumerate() {
+                *register = dense_get(&bytes[HEADER_SIZE..], index);
+            }
+            return Ok(HyperLogLog { registers, dense: true });
+        }
+
+        let mut index = 0;
+        let mut cursor = HEADER_SIZE;
+        while cursor < bytes.len() {
+            let opcode = bytes[cursor];
+            let (value, len) = if opcode & 0x80 != 0 {
+                cursor += 1;
+                (((opcode >> 2) & 0x1f) + 1, (opcode & 0x03) as usize + 1)
+            } else if opcode & 0x40 != 0 {
+                let next = *bytes.get(cursor + 1).ok_or_else(corrupted_hll)? as usize;
+                cursor += 2;
+                (0, ((((opcode & 0x3f) as usize) << 8) | next) + 1)
+            } else {
+                cursor += 1;
+                (0, (opcode & 0x3f) as usize + 1)
+            };
+            if index + len > REGISTERS {
+                return Err(corrupted_hll());
+            }
+            registers[index..index + len].fill(value);
+            index += len;
+        }
+        if index != REGISTERS {
+            return Err(corrupted_hll());
+        }
+        Ok(HyperLogLog { registers, dense: false })
+    }
+
+    fn header(encoding: u8, cardinality: Option<u64>) -> Vec<u8> {
+        let mut bytes = b"HYLL".to_vec();
+        bytes.extend_from_slice(&[encoding, 0, 0, 0]);
+        let cached = cardinality.unwrap_or(1 << 63);
+        bytes.extend_from_slice(&cached.to_le_bytes());
+        bytes
+    }
+
+    fn encode_sparse(&self) -> Option<Vec<u8>> {
+        let mut bytes = Self::header(ENCODING_SPARSE, None);
+        let mut index = 0;
+        while index < REGISTERS {
+            let value = self.registers[index];
+            if value > SPARSE_VAL_MAX_VALUE {
+                return None;
+            }
+            let run = self.registers[index..].iter().take_while(|r| **r == value).count();
+            index += run;
+
+            let mut remaining = run;
+            while remaining > 0 {
+                if value > 0 {
+                    let len = remaining.min(SPARSE_VAL_MAX_LEN);
+                    bytes.push(0x80 | ((value - 1) << 2) | (len - 1) as u8);
+                    remaining -= len;
+                } else if remaining > SPARSE_ZERO_MAX_LEN {
+                    let len = remaining.min(SPARSE_XZERO_MAX_LEN) - 1;
+                    bytes.push(0x40 | (len >> 8) as u8);
+                    bytes.push((len & 0xff) as u8);
+                    remaining -= len + 1;
+                } else {
+                    bytes.push((remaining - 1) as u8);
+                    remaining = 0;
+                }
+            }
+        }
+        Some(bytes)
+    }
+
+    fn encode_dense(&self) -> Vec<u8> {
+        let mut bytes = Self::header(ENCODING_DENSE, None);
+        bytes.resize(DENSE_SIZE, 0);
+        for (index, register) in self.registers.iter().enumerate() {
+            dense_set(&mut bytes[HEADER_SIZE..], index, *register);
+        }
+        bytes
+    }
+
+    /// Serializes the registers with a stale cache, keeping the sparse
+    /// encoding while it fits in `hll-sparse-max-bytes`.
+    pub fn encode(&mut self) -> Vec<u8> {
+        if !self.dense {
+            match self.encode_sparse() {
+                Some(bytes) if bytes.len() <= sparse_max_bytes() => return bytes,
+                _ => self.dense = true,
+            }
+        }
+        self.encode_dense()
+    }
+
+    /// Returns whether the element changed any register.
+    pub fn add(&mut self, element: &[u8]) -> bool {
+        let (index, count) = pattern_len(element);
+        if self.registers[index] < count {
+            self.registers[index] = count;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (register, value) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(*value);
+        }
+        self.dense |= other.dense;
+    }
+
+    /// Cardinality estimate using the improved estimator by Otmar Ertl that
+    /// Redis implements.
+    pub fn count(&self) -> u64 {
+        let mut histogram = [0u32; 64];
+        for register in &self.registers {
+            histogram[*register as usize] += 1;
+        }
+        let m = REGISTERS as f64;
+        let mut z = m * tau((m - histogram[Q as usize + 1] as f64) / m);
+        for j in (1..=Q as usize).rev() {
+            z += histogram[j] as f64;
+            z *= 0.5;
+        }
+        z += m * sigma(histogram[0] as f64 / m);
+        (ALPHA_INF * m * m / z).round() as u64
+    }
+}
+
+/// Cached cardinality of a serialized HyperLogLog, unless it is stale.
+fn cached_count(bytes: &[u8]) -> Option<u64> {
+    if bytes[15] & 0x80 != 0 {
+        return None;
+    }
+    Some(u64::from_le_bytes(bytes[8..16].try_into().unwrap()))
+}
+
+fn store_count(bytes: &mut [u8], count: u64) {
+    bytes[8..16].copy_from_slice(&count.to_le_bytes());
+}
+
+fn pfadd(command: &Command) -> Result<Vec<u8>, CommandError> {
+    let commands = &command.args;
+    if commands.len() < 2 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let memory = get_memory_instance();
+    let (mut hll, mut updated) = match memory.get(&commands[1])? {
+        Some(bytes) => (HyperLogLog::decode(bytes)?, false),
+        None => (HyperLogLog::new(), true),
+    };
+    for idx in 2..commands.len() {
+        updated |= hll.add(command.raw_arg(idx));
+    }
+    if updated {
+        match memory.get_mut(&commands[1])? {
+            Some(bytes) => {
+                *bytes = hll.encode();
+                memory.touch(&commands[1]);
+            }
+            None => memory.set(commands[1].to_string(), hll.encode()),
+        }
+    }
+    Ok(resp::integer(updated as i64))
+}
+
+fn pfcount(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 2 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let memory = get_memory_instance();
+    if commands.len() == 2 {
+        let bytes = match memory.get_mut(&commands[1])? {
+            Some(bytes) => bytes,
+            None => return Ok(resp::integer(0)),
+        };
+        HyperLogLog::check(bytes)?;
+        if let Some(count) = cached_count(bytes) {
+            return Ok(resp::integer(count as i64));
+        }
+        let count = HyperLogLog::decode(bytes)?.count();
+        store_count(bytes, count);
+        memory.touch(&commands[1]);
+        return Ok(resp::integer(count as i64));
+    }
+
+    let mut union = HyperLogLog::new();
+    for key in &commands[1..] {
+        if let Some(bytes) = memory.get(key)? {
+            union.merge(&HyperLogLog::decode(bytes)?);
+        }
+    }
+    Ok(resp::integer(union.count() as i64))
+}
+
+fn pfmerge(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 2 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let memory = get_memory_instance();
+    let mut union = HyperLogLog::new();
+    for key in &commands[1..] {
+        if let Some(bytes) = memory.get(key)? {
+            union.merge(&HyperLogLog::decode(bytes)?);
+        }
+    }
+    match memory.get_mut(&commands[1])? {
+        Some(bytes) => {
+            *bytes = union.encode();
+            memory.touch(&commands[1]);
+        }
+        None => memory.set(commands[1].to_string(), union.encode()),
+    }
+    Ok(resp::simple_string("OK"))
+}
+
+pub fn process_command(command: &Command) -> Result<Vec<u8>, CommandError> {
+    let commands = &command.args;
+    match commands[0].as_str() {
+        "PFADD" => pfadd(command),
+        "PFCOUNT" => pfcount(commands),
+        "PFMERGE" => pfmerge(commands),
+        _ => Err(CommandError::Other(format!("unknown command '{}'", commands[0]))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::run;
+
+    fn filled(elements: usize) -> HyperLogLog {
+        let mut hll = HyperLogLog::new();
+        for i in 0..elements {
+            hll.add(format!("element:{}", i).as_bytes());
+        }
+        hll
+    }
+
+    #[test]
+    fn sparse_round_trip() {
+        let hll = filled(200);
+        let bytes = hll.encode_sparse().unwrap();
+        assert_eq!(&bytes[..5], b"HYLL\x01");
+        let decoded = HyperLogLog::decode(&bytes).unwrap();
+        assert!(!decoded.dense);
+        assert_eq!(decoded.registers, hll.registers);
+        assert_eq!(HyperLogLog::decode(&HyperLogLog::new().encode_sparse().unwrap()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn dense_round_trip() {
+        let hll = filled(50_000);
+        let bytes = hll.encode_dense();
+        assert_eq!(bytes.len(), DENSE_SIZE);
+        let decoded = HyperLogLog::decode(&bytes).unwrap();
+        assert!(decoded.dense);
+        assert_eq!(decoded.registers, hll.registers);
+        let count = decoded.count() as f64;
+        assert!((count - 50_000.0).abs() / 50_000.0 < 0.02, "estimate {}", count);
+    }
+
+    #[test]
+    fn registers_past_the_sparse_range_need_dense() {
+        let mut hll = filled(10);
+        hll.registers[0] = SPARSE_VAL_MAX_VALUE;
+        assert!(hll.encode_sparse().is_some());
+        hll.registers[0] = SPARSE_VAL_MAX_VALUE + 1;
+        assert!(hll.encode_sparse().is_none());
+        let decoded = HyperLogLog::decode(&hll.encode_dense()).unwrap();
+        assert_eq!(decoded.registers, hll.registers);
+    }
+
+    #[test]
+    fn sparse_and_dense_agree() {
+        let hll = filled(1_000);
+        let sparse = HyperLogLog::decode(&hll.encode_sparse().unwrap()).unwrap();
+        let dense = HyperLogLog::decode(&hll.encode_dense()).unwrap();
+        assert_eq!(sparse.registers, dense.registers);
+        assert_eq!(sparse.count(), dense.count());
+    }
+
+    #[test]
+    fn merging_a_dense_hll_makes_it_dense() {
+        let mut sparse = filled(10);
+        let dense = HyperLogLog::decode(&filled(100).encode_dense()).unwrap();
+        sparse.merge(&dense);
+        assert!(sparse.dense);
+        assert_eq!(sparse.registers, filled(100).registers);
+    }
+
+    #[test]
+    fn rejects_invalid_and_corrupted_values() {
+        assert!(HyperLogLog::decode(b"HYLL").is_err());
+        assert!(HyperLogLog::decode(b"NOPE\x01\0\0\0\0\0\0\0\0\0\0\0").is_err());
+        // A dense value must hold every register.
+        assert!(HyperLogLog::decode(&HyperLogLog::header(ENCODING_DENSE, None)).is_err());
+
+        let mut sparse = HyperLogLog::new().encode_sparse().unwrap();
+        sparse.pop();
+        assert!(matches!(HyperLogLog::decode(&sparse), Err(CommandError::Custom(message)) if message.starts_with("INVALIDOBJ")));
+        sparse.extend_from_slice(&[0x7f, 0xff, 0x00]);
+        assert!(HyperLogLog::decode(&sparse).is_err());
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(run(&["PFADD", "hll:a", "x", "y", "z"]), resp::integer(1));
+        assert_eq!(run(&["PFADD", "hll:a", "x"]), resp::integer(0));
+        assert_eq!(run(&["PFADD", "hll:empty"]), resp::integer(1));
+        assert_eq!(run(&["PFCOUNT", "hll:a"]), resp::integer(3));
+        run(&["PFADD", "hll:b", "z", "w"]);
+        // Counting several keys counts their union.
+        assert_eq!(run(&["PFCOUNT", "hll:a", "hll:b", "hll:missing"]), resp::integer(4));
+        assert_eq!(run(&["PFMERGE", "hll:merged", "hll:a", "hll:b"]), resp::simple_string("OK"));
+        assert_eq!(run(&["PFCOUNT", "hll:merged"]), resp::integer(4));
+
+        run(&["SET", "hll:string", "not an hll"]);
+        assert_eq!(run(&["PFCOUNT", "hll:string"]), resp::error(&invalid_hll().to_string()));
+        run(&["SADD", "hll:set", "x"]);
+        assert_eq!(run(&["PFADD", "hll:set", "x"]), resp::error(&CommandError::WrongType.to_string()));
+    }
+}
@@ -6,20 +6,49 @@ mod commands;
 mod options;
 mod memory;
 mod replication;
-
+mod resp;
+mod error;
+mod util;
+mod hash;
+mod set;
+mod skiplist;
+mod zset;
+mod blocking;
+mod stream;
+mod consumer_group;
+mod hyperloglog;
+mod geohash;
+mod geo;
+mod transaction;
+mod client;
+mod pubsub;
+mod notify;
+mod tracking;
+mod rdb;
+mod persistence;
+mod aof;
+
+use aof::Aof;
+use blocking::Blocking;
+use client::{Client, Clients};
 use commands::process_commands;
 use memory::MemoryStore;
 use options::Options;
-use parser::parser;
+use persistence::Persistence;
+use parser::{parse_command, Command};
+use pubsub::PubSub;
 use replica::Replicas;
+use tracking::Tracking;
+use transaction::Transaction;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
-use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::mpsc;
 use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
 use std::net::SocketAddr;
+use std::ptr::addr_of_mut;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
@@ -29,13 +58,19 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use crate::expiration::ttl;
 use crate::options::read_options;
 use crate::replication::Replication;
+use crate::commands::execute;
 
 
 static mut MEMORY_STORE_INSTANCE: Option<MemoryStore> = None;
 static mut OPTIONS: Option<Options> = None;
 static mut REPLICAS: Option<Replicas> = None;
 static mut REPLICATION: Option<Replication> = None;
-use crate::parser::parser_v2;
+static mut BLOCKING: Option<Blocking> = None;
+static mut PUBSUB: Option<PubSub> = None;
+static mut CLIENTS: Option<Clients> = None;
+static mut TRACKING: Option<Tracking> = None;
+static mut PERSISTENCE: Option<Persistence> = None;
+static mut AOF: Option<Aof> = None;
 
 fn get_current_time() -> u128 {
     let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
@@ -44,60 +79,92 @@ fn get_current_time() -> u128 {
 
 fn get_options_instance() ->  &'static mut Options {
     unsafe {
-        OPTIONS.get_or_insert_with(|| Options::new())
+        (*addr_of_mut!(OPTIONS)).get_or_insert_with(Options::new)
     }
 }
 
 fn get_memory_instance() -> &'static mut MemoryStore {
     unsafe {
-        MEMORY_STORE_INSTANCE.get_or_insert_with(|| MemoryStore::new())
+        (*addr_of_mut!(MEMORY_STORE_INSTANCE)).get_or_insert_with(MemoryStore::new)
     }
 }
 
 pub fn get_replicas_instance() -> &'static mut Replicas {
     unsafe {
-        REPLICAS.get_or_insert_with(|| Replicas::new())
+        (*addr_of_mut!(REPLICAS)).get_or_insert_with(Replicas::new)
     }
 }
 
 pub fn get_replication_instance() -> &'static mut Replication {
     unsafe {
-        REPLICATION.get_or_insert_with(|| Replication::new())
+        (*addr_of_mut!(REPLICATION)).get_or_insert_with(Replication::new)
     }
 }
 
-struct ReplicasList {
-    list: Vec<SocketAddr>,
-    handles: Mutex<Vec<ReplicaHandle>>
+pub fn get_blocking_instance() -> &'static mut Blocking {
+    unsafe {
+        (*addr_of_mut!(BLOCKING)).get_or_insert_with(Blocking::new)
+    }
 }
 
-struct ReplicaHandle {
-    pub sender: Sender<ReplicaCommand>,
+pub fn get_pubsub_instance() -> &'static mut PubSub {
+    unsafe {
+        (*addr_of_mut!(PUBSUB)).get_or_insert_with(PubSub::new)
+    }
+}
+
+pub fn get_clients_instance() -> &'static mut Clients {
+    unsafe {
+        (*addr_of_mut!(CLIENTS)).get_or_insert_with(Clients::new)
+    }
+}
+
+pub fn get_tracking_instance() -> &'static mut Tracking {
+    unsafe {
+        (*addr_of_mut!(TRACKING)).get_or_insert_with(Tracking::new)
+    }
+}
+
+pub fn get_persistence_instance() -> &'static mut Persistence {
+    unsafe {
+        (*addr_of_mut!(PERSISTENCE)).get_or_insert_with(Persistence::new)
+    }
+}
+
+pub fn get_aof_instance() -> &'static mut Aof {
+    unsafe {
+        (*addr_of_mut!(AOF)).get_or_insert_with(Aof::new)
+    }
+}
+
+struct ReplicasList {
+    list: Vec<SocketAddr>,
 }
 
 impl ReplicasList {
     pub fn new() -> Self {
         ReplicasList {
             list: Vec::new(),
-            handles: Mutex::new(Vec::new())
         }
     }
 
     pub fn add(&mut self, addr: SocketAddr) {
-        println!("Adding new replica {}", addr.to_string());
+        println!("Adding new replica {}", addr);
         self.list.push(addr);
     }
 
 }
 
 struct Connection {
-    pub stream: TcpStream
+    pub stream: TcpStream,
+    buffer: Vec<u8>,
 }
 
 impl Connection {
     pub fn bind(stream: TcpStream) -> Self {
         Self {
-            stream
+            stream,
+            buffer: Vec::new(),
         }
     }
 
@@ -106,9 +173,42 @@ impl Connection {
         self.stream.flush().await
     }
 
-    pub async fn read(&mut self) -> (usize, [u8; 255]){
-        let mut buff = [0;255];
-        (self.stream.read(&mut buff).await.unwrap(), buff)
+    /// Reads from the socket until at least one full command is buffered and
+    /// returns every complete command. An empty list means the peer closed
+    /// the connection.
+    pub async fn read_commands(&mut self) -> Vec<Command> {
+        let mut commands = Vec::new();
+        loop {
+            loop {
+                match parse_command(&self.buffer) {
+                    Ok(Some((command, consumed))) => {
+                        self.buffer.drain(..consumed);
+                        if !command.args.is_empty() {
+                            commands.push(command);
+                        }
+                    }
+                    Ok(None) => break,
+                    // Like Redis, the commands before the malformed input
+                    // still run, then the connection gets the error and is
+                    // closed: the next call fails on the same bytes.
+                    Err(err) => {
+                        if commands.is_empty() {
+                            _ = self.write(resp::error(&err.to_string())).await;
+                        }
+                        return commands;
+                    }
+                }
+            }
+            if !commands.is_empty() {
+                return commands;
+            }
+
+            let mut buff = [0; 4096];
+            match self.stream.read(&mut buff).await {
+                Ok(0) | Err(_) => return commands,
+                Ok(size) => self.buffer.extend_from_slice(&buff[..size]),
+            }
+        }
     }
 }
 
@@ -124,6 +224,11 @@ impl Connection {
 #[tokio::main]
 async fn main() {
     read_options();
+    // The AOF has the most recent data when there is one.
+    if !aof::load() {
+        persistence::load();
+    }
+    aof::open();
 
     thread::spawn(|| {
         let mut last_run = Instant::now();
@@ -136,6 +241,15 @@ async fn main() {
             ttl();
         }
     });
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_millis(100));
+        persistence::cron();
+        aof::cron();
+    });
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_secs(1));
+        aof::fsync_every_second();
+    });
     let replicas: Arc<Mutex<ReplicasList>> = Arc::new(Mutex::new(ReplicasList::new()));
     let port = get_options_instance().get("port").unwrap();
 
@@ -145,34 +259,43 @@ async fn main() {
 
     println!("[Rudis]: Server started on port {}", port);
 
-    match  get_options_instance().get("role").unwrap().as_str() {
-        "slave" => {
-            tokio::spawn(async move {
-                let mut connection = get_replicas_instance().sync_to_master().await;
-                loop {
-                    let mut replication_buff = [0; 255];
-                    connection.read(&mut replication_buff).await.unwrap();
-
-                    let commands = parser_v2(replication_buff);
-                    println!("commands to exec: {:?}", commands);
-                    for cmd in commands {
-                        match cmd[0].as_str() {
-                            "SET" => {
-                                let memory = get_memory_instance();
-                                memory.set(cmd[1].to_string(), cmd[2].to_string());
-                            }
-                            "REPLCONF" => {
-                                if cmd[1] == "GETACK" {
-                                    _ = connection.write(b"REPLCONF ACK 0").await;
-                                }
-                            }
-                            _ => {}
+    if get_options_instance().get("role").unwrap().as_str() == "slave" {
+        tokio::spawn(async move {
+            let (stream, pending) = get_replicas_instance().sync_to_master().await;
+            let mut connection = Connection::bind(stream);
+            connection.buffer = pending;
+            // Transactions of the master arrive wrapped in MULTI/EXEC.
+            let mut transaction = Transaction::new();
+            loop {
+                let commands = connection.read_commands().await;
+                if commands.is_empty() {
+                    return;
+                }
+                println!("commands to exec: {:?}", commands);
+                // Writes of the master reach the AOF, and replicas of this
+                // server, as they run.
+                for cmd in commands {
+                    match cmd.args[0].as_str() {
+                        "REPLCONF" if cmd.args[1] == "GETACK" => {
+                            _ = connection.write(b"REPLCONF ACK 0".to_vec()).await;
+                        }
+                        "REPLCONF" | "PING" => {}
+                        "MULTI" => {
+                            transaction.begin();
+                        }
+                        "EXEC" => {
+                            transaction.exec();
+                        }
+                        _ if transaction.is_active() => {
+                            transaction.queue(cmd);
+                        }
+                        _ => {
+                            _ = execute(&cmd);
                         }
                     }
                 }
-            });
-        }
-        _ => {}
+            }
+        });
     }
 
     loop {
@@ -196,25 +319,23 @@ impl ReplicaCommand {
     }
 }
 
-async fn process_sync(mut connection: Connection) -> (ReplicaHandle, JoinHandle<()>){
-    {
-        let get_empty_rdb = get_replication_instance().get_latest_rdb();
-        _ = connection.write(get_empty_rdb).await;
-    }
-    let (tx, mut rx) = mpsc::channel::<ReplicaCommand>(32);
-    let handle = tokio::spawn(async move {
-        loop {
-            while let Some(replica_command) = rx.recv().await {
-                _ = connection.write(replica_command.message).await;
-            }
+/// Sends a snapshot to a new replica, then the writes propagated since.
+/// The replica is registered along with the snapshot, so writes made while
+/// the snapshot is saved and sent are buffered in its channel, and writes
+/// already in the snapshot are not sent twice.
+async fn process_sync(mut connection: Connection) -> JoinHandle<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<ReplicaCommand>();
+    let rdb = if Replication::is_diskless() {
+        Replication::diskless_rdb(tx).await
+    } else {
+        Replication::full_sync_rdb(tx)
+    };
+    _ = connection.write(rdb).await;
+    tokio::spawn(async move {
+        while let Some(replica_command) = rx.recv().await {
+            _ = connection.write(replica_command.message).await;
         }
-    });
-    (
-        ReplicaHandle {
-            sender: tx
-        },
-        handle
-    )
+    })
 }
 
 async fn handle(
@@ -222,23 +343,35 @@ async fn handle(
     replicas: Arc<Mutex<ReplicasList>>
 ) {
     let mut is_replica = false;
+    let (mut client, mut messages) = Client::new();
 
     loop {
         if is_replica {
-            let (replica_handle, handle) = process_sync(connection).await;
-            replicas.lock().await.handles.lock().await.push(replica_handle);
+            let handle = process_sync(connection).await;
             _ = handle.await;
             return;
         }
-        let (size, buff) = connection.read().await;
-        let commands = parser_v2(buff);
+        // Published messages are written as soon as they arrive, in between
+        // the replies to the connection's own commands.
+        let commands = tokio::select! {
+            commands = connection.read_commands() => commands,
+            Some(message) = messages.recv() => {
+                if let Some(message) = message.encode(&client) {
+                    _ = connection.write(message).await;
+                }
+                continue;
+            }
+        };
+        if commands.is_empty() {
+            return;
+        }
         for cmd in commands {
             let (responses, _) = process_commands(
                 cmd,
-                buff[..size].to_vec(),
                 &connection,
                 &replicas,
-                &mut is_replica
+                &mut is_replica,
+                &mut client
             ).await;
             for response in &responses {
                 _ = connection.write(response.to_vec()).await;
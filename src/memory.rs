@@ -1,8 +1,74 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::commands::propagate;
+use crate::error::CommandError;
+use crate::get_current_time;
+use crate::hash::Hash;
+use crate::notify;
+use crate::resp;
+use crate::tracking;
+use crate::set::Set;
+use crate::stream::Stream;
+use crate::zset::SortedSet;
+
+#[derive(Clone)]
+pub enum Value {
+    String(Vec<u8>),
+    Hash(Hash),
+    Set(Set),
+    ZSet(SortedSet),
+    Stream(Stream),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::String(value) => {
+                if std::str::from_utf8(value).ok().and_then(|v| v.parse::<i64>().ok()).is_some() {
+                    "int"
+                } else if value.len() <= 44 {
+                    "embstr"
+                } else {
+                    "raw"
+                }
+            }
+            Value::Hash(hash) => hash.encoding(),
+            Value::Set(set) => set.encoding(),
+            Value::ZSet(_) => "skiplist",
+            Value::Stream(_) => "stream",
+        }
+    }
+}
+
+/// Modification version of a key some connection is watching.
+struct WatchedKey {
+    version: u64,
+    watchers: usize,
+}
 
 pub struct MemoryStore {
-    memory: HashMap<String, String>,
+    /// Values are shared with snapshots being saved, and copied on the first
+    /// write while one still holds them.
+    memory: HashMap<String, Arc<Value>>,
     expire: HashMap<String, u128>,
+    /// Hashes holding at least one field with a TTL.
+    field_expire: HashSet<String>,
+    /// Versions are only kept for watched keys and bumped on every write,
+    /// expiration or deletion of the key.
+    watched: HashMap<String, WatchedKey>,
+    /// Changes since the last successful save.
+    dirty: u64,
 }
 
 impl MemoryStore {
@@ -10,33 +76,441 @@ impl MemoryStore {
         MemoryStore {
             memory: HashMap::new(),
             expire: HashMap::new(),
+            field_expire: HashSet::new(),
+            watched: HashMap::new(),
+            dirty: 0,
+        }
+    }
+
+    /// Starts watching `key`, returning its current version.
+    pub fn watch(&mut self, key: &str) -> u64 {
+        let watched = self.watched.entry(key.to_string()).or_insert(WatchedKey { version: 0, watchers: 0 });
+        watched.watchers += 1;
+        watched.version
+    }
+
+    pub fn unwatch(&mut self, key: &str) {
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.watchers -= 1;
+            if watched.watchers == 0 {
+                self.watched.remove(key);
+            }
+        }
+    }
+
+    /// Current version of a watched key, after expiring it if it is due.
+    pub fn version(&mut self, key: &str) -> u64 {
+        self.expire_if_needed(key);
+        self.watched.get(key).map(|w| w.version).unwrap_or(0)
+    }
+
+    pub fn dirty(&self) -> u64 {
+        self.dirty
+    }
+
+    /// Records that a save wrote the first `changes` changes counted so far.
+    pub fn saved(&mut self, changes: u64) {
+        self.dirty -= changes.min(self.dirty);
+    }
+
+    /// Bumps the version of a watched key, drops client-side caches of it and
+    /// counts the change.
+    fn invalidate(&mut self, key: &str) {
+        self.dirty += 1;
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.version += 1;
+        }
+        tracking::invalidate(key);
+    }
+
+    /// Records a modification of `key` by the running command. Commands
+    /// writing through the `_mut` and `get_or_create_` accessors call it once
+    /// they actually changed something, so no-op writes don't abort
+    /// transactions watching the key, count as changes to save or send
+    /// keyspace events.
+    pub fn touch(&mut self, key: &str) {
+        self.invalidate(key);
+        notify::modified(key);
+    }
+
+    /// Removes every key, as `FLUSHALL` does.
+    pub fn flush(&mut self) {
+        for (key, watched) in self.watched.iter_mut() {
+            if self.memory.contains_key(key) {
+                watched.version += 1;
+            }
+        }
+        tracking::flush();
+        self.dirty += self.memory.len() as u64;
+        self.memory.clear();
+        self.expire.clear();
+        self.field_expire.clear();
+    }
+
+    /// Keys not yet expired at `current_time`, with their expiry time. The
+    /// values stay shared with the keyspace until a write changes them.
+    pub fn live_entries(&self, current_time: u128) -> Vec<(String, Arc<Value>, Option<u128>)> {
+        self.memory
+            .iter()
+            .map(|(key, value)| (key, value, self.expire.get(key).copied()))
+            .filter(|(_, _, expire)| !expire.is_some_and(|expire| current_time > expire))
+            .map(|(key, value, expire)| (key.clone(), Arc::clone(value), expire))
+            .collect()
+    }
+
+    pub fn set(&mut self, key: String, value: Vec<u8>) {
+        self.set_value(key, Value::String(value));
+    }
+
+    /// Stores `value` at `key`, replacing whatever was there including its TTL.
+    pub fn set_value(&mut self, key: String, value: Value) {
+        if !self.memory.contains_key(&key) {
+            notify::key_event('n', "new", &key);
+        }
+        self.touch(&key);
+        self.expire.remove(&key);
+        self.field_expire.remove(&key);
+        self.memory.insert(key, Arc::new(value));
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<&Vec<u8>>, CommandError> {
+        match self.lookup(key) {
+            None => {
+                notify::key_miss(key);
+                Ok(None)
+            }
+            Some(Value::String(value)) => Ok(Some(value)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Result<Option<&mut Vec<u8>>, CommandError> {
+        match self.lookup_mut(key) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn get_hash(&mut self, key: &str) -> Result<Option<&Hash>, CommandError> {
+        match self.lookup(key) {
+            None => {
+                notify::key_miss(key);
+                Ok(None)
+            }
+            Some(Value::Hash(hash)) => Ok(Some(hash)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn get_hash_mut(&mut self, key: &str) -> Result<Option<&mut Hash>, CommandError> {
+        match self.lookup_mut(key) {
+            None => Ok(None),
+            Some(Value::Hash(hash)) => Ok(Some(hash)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn get_or_create_hash(&mut self, key: &str) -> Result<&mut Hash, CommandError> {
+        match self.lookup(key) {
+            None => {
+                self.memory.insert(key.to_string(), Arc::new(Value::Hash(Hash::new())));
+                notify::key_event('n', "new", key);
+            }
+            Some(Value::Hash(_)) => {}
+            Some(_) => return Err(CommandError::WrongType),
+        }
+        match self.memory.get_mut(key).map(Arc::make_mut) {
+            Some(Value::Hash(hash)) => Ok(hash),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn get_set(&mut self, key: &str) -> Result<Option<&Set>, CommandError> {
+        match self.lookup(key) {
+            None => {
+                notify::key_miss(key);
+                Ok(None)
+            }
+            Some(Value::Set(set)) => Ok(Some(set)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn get_set_mut(&mut self, key: &str) -> Result<Option<&mut Set>, CommandError> {
+        match self.lookup_mut(key) {
+            None => Ok(None),
+            Some(Value::Set(set)) => Ok(Some(set)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn get_or_create_set(&mut self, key: &str) -> Result<&mut Set, CommandError> {
+        match self.lookup(key) {
+            None => {
+                self.memory.insert(key.to_string(), Arc::new(Value::Set(Set::new())));
+                notify::key_event('n', "new", key);
+            }
+            Some(Value::Set(_)) => {}
+            Some(_) => return Err(CommandError::WrongType),
+        }
+        match self.memory.get_mut(key).map(Arc::make_mut) {
+            Some(Value::Set(set)) => Ok(set),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn get_zset(&mut self, key: &str) -> Result<Option<&SortedSet>, CommandError> {
+        match self.lookup(key) {
+            None => {
+                notify::key_miss(key);
+                Ok(None)
+            }
+            Some(Value::ZSet(zset)) => Ok(Some(zset)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn get_zset_mut(&mut self, key: &str) -> Result<Option<&mut SortedSet>, CommandError> {
+        match self.lookup_mut(key) {
+            None => Ok(None),
+            Some(Value::ZSet(zset)) => Ok(Some(zset)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn get_or_create_zset(&mut self, key: &str) -> Result<&mut SortedSet, CommandError> {
+        match self.lookup(key) {
+            None => {
+                self.memory.insert(key.to_string(), Arc::new(Value::ZSet(SortedSet::new())));
+                notify::key_event('n', "new", key);
+            }
+            Some(Value::ZSet(_)) => {}
+            Some(_) => return Err(CommandError::WrongType),
+        }
+        match self.memory.get_mut(key).map(Arc::make_mut) {
+            Some(Value::ZSet(zset)) => Ok(zset),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn get_stream(&mut self, key: &str) -> Result<Option<&Stream>, CommandError> {
+        match self.lookup(key) {
+            None => {
+                notify::key_miss(key);
+                Ok(None)
+            }
+            Some(Value::Stream(stream)) => Ok(Some(stream)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn get_stream_mut(&mut self, key: &str) -> Result<Option<&mut Stream>, CommandError> {
+        match self.lookup_mut(key) {
+            None => Ok(None),
+            Some(Value::Stream(stream)) => Ok(Some(stream)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn get_or_create_stream(&mut self, key: &str) -> Result<&mut Stream, CommandError> {
+        match self.lookup(key) {
+            None => {
+                self.memory.insert(key.to_string(), Arc::new(Value::Stream(Stream::new())));
+                notify::key_event('n', "new", key);
+            }
+            Some(Value::Stream(_)) => {}
+            Some(_) => return Err(CommandError::WrongType),
+        }
+        match self.memory.get_mut(key).map(Arc::make_mut) {
+            Some(Value::Stream(stream)) => Ok(stream),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn lookup(&mut self, key: &str) -> Option<&Value> {
+        self.expire_if_needed(key);
+        tracking::read(key);
+        self.memory.get(key).map(Arc::as_ref)
+    }
+
+    /// Mutable access, callers `touch` the key if they change it.
+    pub fn lookup_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.expire_if_needed(key);
+        tracking::read(key);
+        self.memory.get_mut(key).map(Arc::make_mut)
+    }
+
+    pub fn remove(&mut self, key: &str) -> bool {
+        let removed = self.delete(key);
+        if removed {
+            notify::modified(key);
         }
+        removed
     }
 
-    pub fn set(&mut self, key: String, value: String) {
-        self.memory.insert(key, value);
+    /// Drops `key` and its TTLs without attributing it to the running command.
+    fn delete(&mut self, key: &str) -> bool {
+        self.expire.remove(key);
+        self.field_expire.remove(key);
+        let removed = self.memory.remove(key).is_some();
+        if removed {
+            self.invalidate(key);
+        }
+        removed
     }
 
-    pub fn get(&mut self, key: &str) -> Option<&String> {
-        self.memory.get(key)
+    /// Drops `key` if it holds an empty aggregate, e.g. after `HDEL` removed
+    /// its last field.
+    pub fn remove_if_empty(&mut self, key: &str) {
+        let empty = match self.memory.get(key).map(Arc::as_ref) {
+            Some(Value::Hash(hash)) => hash.is_empty(),
+            Some(Value::Set(set)) => set.is_empty(),
+            Some(Value::ZSet(zset)) => zset.is_empty(),
+            _ => false,
+        };
+        if empty {
+            self.remove(key);
+            notify::key_event('g', "del", key);
+        }
     }
 
     pub fn expire(&mut self, key: String, ttl: u128) {
+        self.touch(&key);
         self.expire.insert(key, ttl);
     }
 
+    pub fn track_field_expires(&mut self, key: &str) {
+        self.field_expire.insert(key.to_string());
+    }
+
+    fn expire_if_needed(&mut self, key: &str) {
+        let current_time = get_current_time();
+        if let Some(ttl) = self.expire.get(key) {
+            if current_time > *ttl {
+                self.delete(key);
+                notify::key_event('x', "expired", key);
+                return;
+            }
+        }
+        if self.field_expire.contains(key) {
+            self.expire_fields(key, current_time);
+        }
+    }
+
+    /// Drops expired fields of the hash at `key`, and the key itself once its
+    /// last field is gone.
+    fn expire_fields(&mut self, key: &str, current_time: u128) {
+        // Only copy a hash a snapshot still shares once a field is due.
+        match self.memory.get(key).map(Arc::as_ref) {
+            Some(Value::Hash(hash)) if hash.has_expired_fields(current_time) => {}
+            Some(Value::Hash(hash)) if hash.has_field_ttls() => return,
+            _ => {
+                self.field_expire.remove(key);
+                return;
+            }
+        }
+        let (expired, empty, has_ttls) = match self.memory.get_mut(key).map(Arc::make_mut) {
+            Some(Value::Hash(hash)) => {
+                let expired = hash.remove_expired_fields(current_time);
+                (expired, hash.is_empty(), hash.has_field_ttls())
+            }
+            _ => (Vec::new(), false, false),
+        };
+        if !expired.is_empty() {
+            self.invalidate(key);
+            notify::key_event('h', "hexpired", key);
+            // Replicas and the AOF don't expire fields on their own.
+            let mut hdel = vec!["HDEL".to_string(), key.to_string()];
+            hdel.extend(expired);
+            propagate(&resp::bulk_string_array(&hdel));
+        }
+        if empty {
+            self.delete(key);
+            notify::key_event('g', "del", key);
+        } else if !has_ttls {
+            self.field_expire.remove(key);
+        }
+    }
+
+    pub fn remove_expired_fields(&mut self, current_time: u128) {
+        let keys: Vec<String> = self.field_expire.iter().cloned().collect();
+        for key in keys {
+            self.expire_fields(&key, current_time);
+        }
+    }
+
     pub fn remove_expired(&mut self, current_time: u128) {
         let mut keys_deleted: Vec<String> = Vec::new();
-        if self.expire.keys().len() > 0 {
+        if !self.expire.is_empty() {
             for (k, ttl) in &self.expire {
                 if current_time > *ttl {
                     self.memory.remove(k);
+                    self.field_expire.remove(k);
                     keys_deleted.push(k.to_string());
                 }
             }
         }
         for key in keys_deleted {
             self.expire.remove(&key);
+            self.invalidate(&key);
+            notify::key_event('x', "expired", &key);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::lock_execution;
+
+    #[test]
+    fn writes_bump_watched_versions() {
+        let _guard = lock_execution();
+        let mut memory = MemoryStore::new();
+        assert_eq!(memory.watch("a"), 0);
+        memory.set("a".to_string(), b"1".to_vec());
+        memory.set("b".to_string(), b"1".to_vec());
+        assert_eq!(memory.version("a"), 1);
+        // Only watched keys keep a version.
+        assert_eq!(memory.version("b"), 0);
+        assert!(!memory.remove("missing"));
+        assert!(memory.remove("a"));
+        assert_eq!((memory.version("a"), memory.dirty()), (2, 3));
+
+        memory.saved(2);
+        assert_eq!(memory.dirty(), 1);
+        memory.unwatch("a");
+        assert_eq!(memory.version("a"), 0);
+    }
+
+    #[test]
+    fn flush_bumps_only_existing_watched_keys() {
+        let _guard = lock_execution();
+        let mut memory = MemoryStore::new();
+        memory.set("a".to_string(), b"1".to_vec());
+        memory.set("b".to_string(), b"1".to_vec());
+        memory.watch("a");
+        memory.watch("missing");
+        memory.saved(2);
+        memory.flush();
+        assert_eq!((memory.version("a"), memory.version("missing")), (1, 0));
+        assert_eq!(memory.dirty(), 2);
+        assert!(memory.live_entries(0).is_empty());
+    }
+
+    #[test]
+    fn expired_keys_are_gone() {
+        let _guard = lock_execution();
+        let mut memory = MemoryStore::new();
+        memory.set("a".to_string(), b"1".to_vec());
+        memory.expire("a".to_string(), 10);
+        memory.set("b".to_string(), b"1".to_vec());
+        memory.expire("b".to_string(), u128::MAX);
+        let live: Vec<String> = memory.live_entries(11).into_iter().map(|(key, _, _)| key).collect();
+        assert_eq!(live, ["b"]);
+        memory.remove_expired(11);
+        assert!(memory.lookup("a").is_none());
+        assert!(memory.lookup("b").is_some());
+    }
+}
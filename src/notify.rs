@@ -0,0 +1,211 @@
+use std::cell::RefCell;
+
+use crate::error::CommandError;
+use crate::{get_options_instance, get_pubsub_instance};
+
+/// Classes `A` stands for in `notify-keyspace-events`.
+const ALL_CLASSES: &str = "g$lshzxet";
+const VALID_FLAGS: &str = "KEg$lshzxetmnA";
+
+/// A keyspace event waiting for the command that caused it to finish.
+/// Events recorded because a key was modified are dropped when the command
+/// fails, the others, such as expirations, are sent regardless.
+struct Event {
+    class: char,
+    event: String,
+    key: String,
+    modified: bool,
+}
+
+/// The command being executed on this thread and the events it caused.
+struct Context {
+    event: Option<(char, String)>,
+    pending: Vec<Event>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+pub fn is_valid(flags: &str) -> bool {
+    flags.chars().all(|flag| VALID_FLAGS.contains(flag))
+}
+
+fn enabled(flags: &str, class: char) -> bool {
+    flags.contains(class) || (flags.contains('A') && ALL_CLASSES.contains(class))
+}
+
+/// Event sent for every key a command modifies, by class. Commands that
+/// don't modify keys, or report their events themselves, have none.
+fn command_event(commands: &[String]) -> Option<(char, String)> {
+    let (class, event) = match commands[0].as_str() {
+        "SET" => ('$', "set"),
+        "DEL" => ('g', "del"),
+        "PFADD" | "PFMERGE" => ('$', "pfadd"),
+        "HSET" | "HMSET" | "HSETNX" => ('h', "hset"),
+        "HDEL" | "HINCRBY" | "HINCRBYFLOAT" | "HPERSIST" => ('h', ""),
+        "HEXPIRE" | "HPEXPIRE" | "HEXPIREAT" | "HPEXPIREAT" | "HGETEX" => ('h', "hexpire"),
+        "SADD" | "SREM" | "SPOP" | "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE" => ('s', ""),
+        "ZADD" | "GEOADD" => ('z', "zadd"),
+        "ZINCRBY" => ('z', "zincr"),
+        "ZPOPMIN" | "BZPOPMIN" => ('z', "zpopmin"),
+        "ZPOPMAX" | "BZPOPMAX" => ('z', "zpopmax"),
+        "ZMPOP" | "BZMPOP" => {
+            let max = commands.iter().any(|arg| arg.eq_ignore_ascii_case("MAX"));
+            ('z', if max { "zpopmax" } else { "zpopmin" })
+        }
+        "ZREM" | "ZRANGESTORE" | "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE" | "GEOSEARCHSTORE" => ('z', ""),
+        "XADD" | "XDEL" | "XTRIM" => ('t', ""),
+        "XGROUP" if commands.len() > 1 => {
+            return Some(('t', format!("xgroup-{}", commands[1].to_ascii_lowercase())));
+        }
+        _ => return None,
+    };
+    // An empty event is the command name itself.
+    match event {
+        "" => Some((class, commands[0].to_ascii_lowercase())),
+        event => Some((class, event.to_string())),
+    }
+}
+
+/// Runs a command, sending the keyspace events it caused once it is done.
+pub fn with_events<T, F>(commands: &[String], run: F) -> Result<T, CommandError>
+where
+    F: FnOnce() -> Result<T, CommandError>,
+{
+    let context = Context {
+        event: command_event(commands),
+        pending: Vec::new(),
+    };
+    let outer = CONTEXT.with(|current| current.borrow_mut().replace(context));
+    let result = run();
+    let context = CONTEXT.with(|current| std::mem::replace(&mut *current.borrow_mut(), outer));
+    if let Some(context) = context {
+        for event in context.pending {
+            if result.is_ok() || !event.modified {
+                publish(event.class, &event.event, &event.key);
+            }
+        }
+    }
+    result
+}
+
+/// Called by the keyspace whenever `key` is modified, to send the event of
+/// the running command for it.
+pub fn modified(key: &str) {
+    CONTEXT.with(|current| {
+        if let Some(Context { event: Some((class, event)), pending }) = current.borrow_mut().as_mut() {
+            if !pending.iter().any(|e| e.modified && e.key == key) {
+                pending.push(Event { class: *class, event: event.clone(), key: key.to_string(), modified: true });
+            }
+        }
+    });
+}
+
+/// Misses only count for commands reading keys, not for writes checking
+/// whether a key exists.
+pub fn key_miss(key: &str) {
+    let reading = CONTEXT.with(|current| matches!(current.borrow().as_ref(), Some(Context { event: None, .. })));
+    if reading {
+        key_event('m', "keymiss", key);
+    }
+}
+
+/// Sends an event that doesn't depend on the running command, e.g. `expired`.
+/// Within a command it is sent along with the command's own events.
+pub fn key_event(class: char, event: &str, key: &str) {
+    let queued = CONTEXT.with(|current| match current.borrow_mut().as_mut() {
+        Some(context) => {
+            context.pending.push(Event { class, event: event.to_string(), key: key.to_string(), modified: false });
+            true
+        }
+        None => false,
+    });
+    if !queued {
+        publish(class, event, key);
+    }
+}
+
+fn publish(class: char, event: &str, key: &str) {
+    let flags = match get_options_instance().get("notify-keyspace-events") {
+        Some(flags) if enabled(flags, class) => flags.clone(),
+        _ => return,
+    };
+    let registry = get_pubsub_instance();
+    if flags.contains('K') {
+        registry.publish(&format!("__keyspace@0__:{}", key), event.as_bytes());
+    }
+    if flags.contains('E') {
+        registry.publish(&format!("__keyevent@0__:{}", event), key.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::commands::run;
+    use crate::pubsub::{self, Message};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn event(command: &[&str]) -> Option<(char, String)> {
+        command_event(&args(command))
+    }
+
+    #[test]
+    fn flags() {
+        assert!(is_valid("KEA"));
+        assert!(is_valid(""));
+        assert!(!is_valid("KEq"));
+        assert!(enabled("Kh", 'h'));
+        assert!(!enabled("Kh", 's'));
+        assert!(enabled("KA", 'z'));
+        // `A` leaves out key misses and new keys.
+        assert!(!enabled("KA", 'm'));
+        assert!(!enabled("KA", 'n'));
+        assert!(enabled("Kn", 'n'));
+    }
+
+    #[test]
+    fn command_events() {
+        assert_eq!(event(&["SET", "k", "v"]), Some(('$', "set".to_string())));
+        assert_eq!(event(&["HDEL", "k", "f"]), Some(('h', "hdel".to_string())));
+        assert_eq!(event(&["BZMPOP", "0", "1", "k", "MAX"]), Some(('z', "zpopmax".to_string())));
+        assert_eq!(event(&["ZMPOP", "1", "k", "MIN"]), Some(('z', "zpopmin".to_string())));
+        assert_eq!(event(&["XGROUP", "CREATE", "k", "g", "$"]), Some(('t', "xgroup-create".to_string())));
+        assert_eq!(event(&["GET", "k"]), None);
+    }
+
+    #[test]
+    fn events_follow_the_command_outcome() {
+        let (mut client, mut receiver) = Client::new();
+        pubsub::process_command(&mut client, &args(&["PSUBSCRIBE", "__keyspace@0__:notify:*"])).unwrap();
+        run(&["CONFIG", "SET", "notify-keyspace-events", "Kh$g"]);
+        run(&["SET", "notify:string", "v"]);
+        run(&["HSET", "notify:hash", "a", "1", "b", "2"]);
+        run(&["HDEL", "notify:hash", "a", "b"]);
+        // Failing commands and classes left out send nothing.
+        run(&["HSET", "notify:string", "f", "v"]);
+        run(&["SADD", "notify:set", "a"]);
+        run(&["CONFIG", "SET", "notify-keyspace-events", ""]);
+
+        let events: Vec<(String, Vec<u8>)> = std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|message| match message {
+                Message::Published { channel, payload, .. } => (channel, payload),
+                Message::Invalidate(_) => unreachable!(),
+            })
+            .collect();
+        let expected = [
+            ("__keyspace@0__:notify:string", "set"),
+            ("__keyspace@0__:notify:hash", "hset"),
+            ("__keyspace@0__:notify:hash", "hdel"),
+            ("__keyspace@0__:notify:hash", "del"),
+        ];
+        let expected: Vec<(String, Vec<u8>)> =
+            expected.iter().map(|(channel, event)| (channel.to_string(), event.as_bytes().to_vec())).collect();
+        assert_eq!(events, expected);
+    }
+}
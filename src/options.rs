@@ -1,6 +1,7 @@
-use std::{collections::HashMap, env::args_os};
+use std::{collections::HashMap, env::{args_os, current_dir}};
 
 use crate::get_options_instance;
+use crate::util::glob_match;
 
 pub struct Options  {
   options: HashMap<String, String>,
@@ -20,6 +21,16 @@ impl Options {
   pub fn set(&mut self, key: &str, value: &str) {
     self.options.insert(key.to_string(), value.to_string());
   }
+
+  pub fn matching(&self, pattern: &str) -> Vec<(String, String)> {
+    let mut matched: Vec<(String, String)> = self.options
+      .iter()
+      .filter(|(k, _)| glob_match(&pattern.to_ascii_lowercase(), k))
+      .map(|(k, v)| (k.to_string(), v.to_string()))
+      .collect();
+    matched.sort();
+    matched
+  }
 }
 
 
@@ -28,6 +39,26 @@ fn load_basic_options() {
   get_options_instance().set("port", "6379");
   get_options_instance().set("master_repl_offset", "0");
   get_options_instance().set("master_replid", "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb");
+  get_options_instance().set("hash-max-listpack-entries", "128");
+  get_options_instance().set("hash-max-listpack-value", "64");
+  get_options_instance().set("set-max-intset-entries", "512");
+  get_options_instance().set("stream-node-max-entries", "100");
+  get_options_instance().set("hll-sparse-max-bytes", "3000");
+  get_options_instance().set("notify-keyspace-events", "");
+  let dir = current_dir().map(|dir| dir.display().to_string()).unwrap_or_else(|_| ".".to_string());
+  get_options_instance().set("dir", &dir);
+  get_options_instance().set("dbfilename", "dump.rdb");
+  get_options_instance().set("rdbcompression", "yes");
+  get_options_instance().set("save", "3600 1 300 100 60 10000");
+  get_options_instance().set("repl-diskless-sync", "no");
+  get_options_instance().set("appendonly", "no");
+  get_options_instance().set("appendfilename", "appendonly.aof");
+  get_options_instance().set("appendfsync", "everysec");
+  get_options_instance().set("aof-load-truncated", "yes");
+  get_options_instance().set("appenddirname", "appendonlydir");
+  get_options_instance().set("auto-aof-rewrite-percentage", "100");
+  get_options_instance().set("auto-aof-rewrite-min-size", "64mb");
+  get_options_instance().set("repl-diskless-sync-delay", "5");
 }
 
 pub fn read_options() {
@@ -49,7 +80,18 @@ pub fn read_options() {
                       panic!("Missing arguments for [port]");
                   }
                   get_options_instance()
-                      .set("port", &args[2]);
+                      .set("port", &args[idx + 1]);
+              }
+              "hash-max-listpack-entries" | "hash-max-listpack-value" | "set-max-intset-entries"
+              | "notify-keyspace-events" | "dir" | "dbfilename" | "rdbcompression" | "save"
+              | "repl-diskless-sync" | "repl-diskless-sync-delay" | "appendonly" | "appendfilename"
+              | "appendfsync" | "aof-load-truncated" | "appenddirname" | "auto-aof-rewrite-percentage"
+              | "auto-aof-rewrite-min-size" => {
+                  if sz <= idx + 1 {
+                      panic!("Missing arguments for [{}]", option[1]);
+                  }
+                  get_options_instance()
+                      .set(&option[1], &args[idx + 1]);
               }
               "replicaof" => {
                   if sz <= 3 {
@@ -1,11 +1,13 @@
+use crate::error::CommandError;
+use crate::resp;
+
 // 36 ===> $
 // 42 ===> *
 // 43 ===> +
 
 fn parse_u8(st: &[u8]) -> u8 {
-  let x = &*st;
-  let parsed = std::str::from_utf8(x).expect("failed on extract");
-  return parsed.parse::<u8>().expect("failed in conversion");
+  let parsed = std::str::from_utf8(st).expect("failed on extract");
+  parsed.parse::<u8>().expect("failed in conversion")
 }
 
 
@@ -26,10 +28,10 @@ fn get_header(cursor: &mut usize, buff: Vec<u8>) -> (usize, u8) {
   }
 
   let header_buffer = &buff[(*cursor + 1)..(*cursor + delta - 2)];
-  return (delta, parse_u8(header_buffer))
+  (delta, parse_u8(header_buffer))
 }
 
-pub fn parser(buff: [u8; 255]) -> Vec<String> {
+pub fn parser_v3(buff: &[u8]) -> Vec<String> {
   let mut cursor: usize = 0;
   let mut commands: Vec<String> = Vec::new();
 
@@ -43,29 +45,7 @@ pub fn parser(buff: [u8; 255]) -> Vec<String> {
     let data_buffer = &buff[cursor..(cursor + data_buffer_size as usize)];
     cursor += data_buffer_size as usize + 2;
 
-    let _c = std::str::from_utf8(&data_buffer).unwrap();
-    commands.push(_c.to_string());
-  }
-  commands[0] = commands[0].to_ascii_uppercase();
-  commands
-}
-
-
-pub fn parser_v3(buff: &Vec<u8>) -> Vec<String> {
-  let mut cursor: usize = 0;
-  let mut commands: Vec<String> = Vec::new();
-
-  let (cursor_delta, header_size) = get_header(&mut cursor, buff.to_vec());
-  cursor += cursor_delta;
-
-  for _i in 0..header_size {
-    let (cursor_delta, data_buffer_size) = get_header(&mut cursor, buff.to_vec());
-    cursor += cursor_delta;
-
-    let data_buffer = &buff[cursor..(cursor + data_buffer_size as usize)];
-    cursor += data_buffer_size as usize + 2;
-
-    let _c = std::str::from_utf8(&data_buffer).unwrap();
+    let _c = std::str::from_utf8(data_buffer).unwrap();
     commands.push(_c.to_string());
   }
   commands[0] = commands[0].to_ascii_uppercase();
@@ -102,4 +82,112 @@ pub fn parser_v2(bytes: [u8; 255]) -> Vec<Vec<String>> {
   }
 
   section_commands
-}
\ No newline at end of file
+}
+
+fn find_crlf(buff: &[u8], from: usize) -> Option<usize> {
+  (from..buff.len().saturating_sub(1)).find(|&i| buff[i] == b'\r' && buff[i + 1] == b'\n')
+}
+
+fn parse_length(buff: &[u8]) -> Option<i64> {
+  std::str::from_utf8(buff).ok()?.parse::<i64>().ok()
+}
+
+/// Arguments of a command and the amount of bytes they took.
+type Frame = (Vec<Vec<u8>>, usize);
+
+/// Parses a single command from the start of `buff`, either a RESP array of
+/// bulk strings or an inline command. Returns the binary-safe arguments and
+/// the amount of bytes consumed, `None` when the buffer does not hold a full
+/// command yet, or an error for input that can never become one.
+pub fn parse_frame(buff: &[u8]) -> Result<Option<Frame>, CommandError> {
+  if buff.is_empty() {
+    return Ok(None);
+  }
+
+  if buff[0] != b'*' {
+    let Some(end) = find_crlf(buff, 0) else {
+      return Ok(None);
+    };
+    let args = buff[..end]
+      .split(|b| b.is_ascii_whitespace())
+      .filter(|arg| !arg.is_empty())
+      .map(|arg| arg.to_vec())
+      .collect();
+    return Ok(Some((args, end + 2)));
+  }
+
+  let Some(header_end) = find_crlf(buff, 0) else {
+    return Ok(None);
+  };
+  let elements = buff
+    .get(1..header_end)
+    .and_then(parse_length)
+    .ok_or_else(|| protocol_error("invalid multibulk length"))?;
+  let mut cursor = header_end + 2;
+  let mut args = Vec::new();
+
+  for _ in 0..elements {
+    let Some(&kind) = buff.get(cursor) else {
+      return Ok(None);
+    };
+    if kind != b'$' {
+      return Err(protocol_error(&format!("expected '$', got '{}'", kind as char)));
+    }
+    let Some(line_end) = find_crlf(buff, cursor) else {
+      return Ok(None);
+    };
+    let size = buff
+      .get((cursor + 1)..line_end)
+      .and_then(parse_length)
+      .filter(|size| *size >= 0)
+      .ok_or_else(|| protocol_error("invalid bulk length"))? as usize;
+    cursor = line_end + 2;
+
+    if buff.len() < cursor + size + 2 {
+      return Ok(None);
+    }
+    args.push(buff[cursor..(cursor + size)].to_vec());
+    cursor += size + 2;
+  }
+  Ok(Some((args, cursor)))
+}
+
+fn protocol_error(message: &str) -> CommandError {
+  CommandError::Other(format!("Protocol error: {}", message))
+}
+
+/// A command as read from a client, the replication stream or the AOF: the
+/// arguments decoded as (lossy) UTF-8 with the name uppercased, which is what
+/// the handlers match on, along with the bytes that were sent for each.
+#[derive(Debug)]
+pub struct Command {
+  pub args: Vec<String>,
+  pub raw: Vec<Vec<u8>>,
+}
+
+impl Command {
+  pub fn new(raw: Vec<Vec<u8>>) -> Self {
+    let mut args: Vec<String> = raw.iter().map(|arg| String::from_utf8_lossy(arg).to_string()).collect();
+    if let Some(name) = args.first_mut() {
+      *name = name.to_ascii_uppercase();
+    }
+    Command { args, raw }
+  }
+
+  /// Argument `idx` exactly as it was sent.
+  pub fn raw_arg(&self, idx: usize) -> &[u8] {
+    &self.raw[idx]
+  }
+
+  /// The command as a RESP array of bulk strings, the form replicas and the
+  /// AOF receive it in.
+  pub fn frame(&self) -> Vec<u8> {
+    resp::array(self.raw.iter().map(|arg| resp::bulk_bytes(arg)).collect())
+  }
+}
+
+/// Parses a single command from the start of `buff`, see `parse_frame`.
+pub fn parse_command(buff: &[u8]) -> Result<Option<(Command, usize)>, CommandError> {
+  let frame = parse_frame(buff)?;
+  Ok(frame.map(|(args, consumed)| (Command::new(args), consumed)))
+}
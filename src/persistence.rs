@@ -0,0 +1,587 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use crate::commands::lock_execution;
+use crate::consumer_group::{Consumer, ConsumerGroup, PendingEntry};
+use crate::error::CommandError;
+use crate::hash::Hash;
+use crate::memory::Value;
+use crate::rdb::{self, Rdb, RdbConsumer, RdbEntry, RdbGroup, RdbPending, RdbStream, RdbValue};
+use crate::resp;
+use crate::set::Set;
+use crate::stream::{Stream, StreamId};
+use crate::zset::SortedSet;
+use crate::{get_current_time, get_memory_instance, get_options_instance, get_persistence_instance};
+
+/// Seconds before retrying a background save triggered by a save point
+/// after the previous one failed.
+const BGSAVE_RETRY_DELAY: u64 = 5;
+
+/// Snapshotting state, guarded by the execution lock.
+pub struct Persistence {
+    /// Unix time in seconds of the last successful save, or of the start.
+    last_save: u64,
+    /// Start time in seconds of the background save in progress.
+    bgsave_started: Option<u64>,
+    last_bgsave_ok: bool,
+    last_bgsave_duration: Option<u64>,
+    last_bgsave_try: u64,
+}
+
+impl Persistence {
+    pub fn new() -> Self {
+        Persistence {
+            last_save: now_secs(),
+            bgsave_started: None,
+            last_bgsave_ok: true,
+            last_bgsave_duration: None,
+            last_bgsave_try: 0,
+        }
+    }
+}
+
+pub fn now_secs() -> u64 {
+    (get_current_time() / 1000) as u64
+}
+
+fn text(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned())
+}
+
+fn stream_id((ms, seq): rdb::RdbStreamId) -> StreamId {
+    StreamId { ms, seq }
+}
+
+/// Path of the snapshot, from `dir` and `dbfilename`.
+pub fn rdb_path() -> PathBuf {
+    let dir = get_options_instance().get("dir").cloned().unwrap_or_else(|| ".".to_string());
+    let dbfilename = get_options_instance().get("dbfilename").cloned().unwrap_or_else(|| "dump.rdb".to_string());
+    PathBuf::from(dir).join(dbfilename)
+}
+
+fn stream_value(rdb_stream: RdbStream) -> Stream {
+    let mut stream = Stream::new();
+    for (id, fields) in rdb_stream.entries {
+        let fields = fields.into_iter().map(|(field, value)| (text(field), text(value))).collect();
+        stream.append(stream_id(id), fields);
+    }
+    stream.last_id = stream_id(rdb_stream.last_id);
+    stream.max_deleted_id = stream_id(rdb_stream.max_deleted_id);
+    stream.entries_added = rdb_stream.entries_added;
+
+    for rdb_group in rdb_stream.groups {
+        let mut group = ConsumerGroup::new(stream_id(rdb_group.last_id), rdb_group.entries_read);
+        // The owner of each pending entry is only known from the consumers.
+        let mut owners = BTreeMap::new();
+        for rdb_consumer in rdb_group.consumers {
+            let name = text(rdb_consumer.name);
+            let pending: BTreeSet<StreamId> = rdb_consumer.pending.into_iter().map(stream_id).collect();
+            for id in &pending {
+                owners.insert(*id, name.clone());
+            }
+            let consumer = Consumer {
+                seen_time: rdb_consumer.seen_time as u128,
+                active_time: rdb_consumer.active_time.map(|time| time as u128),
+                pending,
+            };
+            group.consumers.insert(name, consumer);
+        }
+        let mut pending = BTreeMap::new();
+        for entry in rdb_group.pending {
+            let id = stream_id(entry.id);
+            if let Some(consumer) = owners.remove(&id) {
+                pending.insert(
+                    id,
+                    PendingEntry {
+                        consumer,
+                        delivery_time: entry.delivery_time as u128,
+                        delivery_count: entry.delivery_count,
+                    },
+                );
+            }
+        }
+        group.pending = pending;
+        stream.groups.insert(text(rdb_group.name), group);
+    }
+    stream
+}
+
+/// Converts a value read from a snapshot, flagging hashes with field TTLs.
+/// Lists have no counterpart here.
+fn memory_value(value: RdbValue) -> Result<(Value, bool), usize> {
+    let value = match value {
+        RdbValue::String(bytes) => Value::String(bytes),
+        RdbValue::List(items) => return Err(items.len()),
+        RdbValue::Set(members) => Value::Set(Set::from_members(members.into_iter().map(text).collect())),
+        RdbValue::SortedSet(entries) => {
+            Value::ZSet(SortedSet::from_entries(entries.into_iter().map(|(member, score)| (text(member), score)).collect()))
+        }
+        RdbValue::Hash(fields) => {
+            let mut hash = Hash::new();
+            let mut field_ttls = false;
+            for (field, value, expire) in fields {
+                let field = text(field);
+                if let Some(expire) = expire {
+                    hash.set_field_ttl(&field, expire as u128);
+                    field_ttls = true;
+                }
+                hash.insert(field, text(value));
+            }
+            return Ok((Value::Hash(hash), field_ttls));
+        }
+        RdbValue::Stream(stream) => Value::Stream(stream_value(stream)),
+    };
+    Ok((value, false))
+}
+
+fn rdb_stream_id(id: StreamId) -> rdb::RdbStreamId {
+    (id.ms, id.seq)
+}
+
+fn rdb_stream(stream: &Stream) -> RdbStream {
+    let entries = stream
+        .range(StreamId::MIN, StreamId::MAX, None, false)
+        .into_iter()
+        .map(|entry| {
+            let fields = entry.fields.iter().map(|(field, value)| (field.clone().into_bytes(), value.clone().into_bytes()));
+            (rdb_stream_id(entry.id), fields.collect())
+        })
+        .collect();
+    let groups = stream
+        .groups
+        .iter()
+        .map(|(name, group)| RdbGroup {
+            name: name.clone().into_bytes(),
+            last_id: rdb_stream_id(group.last_delivered),
+            entries_read: group.entries_read,
+            pending: group
+                .pending
+                .iter()
+                .map(|(id, entry)| RdbPending {
+                    id: rdb_stream_id(*id),
+                    delivery_time: entry.delivery_time as u64,
+                    delivery_count: entry.delivery_count,
+                })
+                .collect(),
+            consumers: group
+                .consumers
+                .iter()
+                .map(|(name, consumer)| RdbConsumer {
+                    name: name.clone().into_bytes(),
+                    seen_time: consumer.seen_time as u64,
+                    active_time: consumer.active_time.map(|time| time as u64),
+                    pending: consumer.pending.iter().copied().map(rdb_stream_id).collect(),
+                })
+                .collect(),
+        })
+        .collect();
+    RdbStream {
+        entries,
+        last_id: rdb_stream_id(stream.last_id),
+        max_deleted_id: rdb_stream_id(stream.max_deleted_id),
+        entries_added: stream.entries_added,
+        groups,
+    }
+}
+
+/// Copies a value of the keyspace, leaving out hash fields already expired.
+fn rdb_value(value: &Value, now: u128) -> RdbValue {
+    match value {
+        Value::String(bytes) => RdbValue::String(bytes.clone()),
+        Value::Set(set) => RdbValue::Set(set.members().into_iter().map(String::into_bytes).collect()),
+        Value::ZSet(zset) => RdbValue::SortedSet(zset.entries().into_iter().map(|(member, score)| (member.into_bytes(), score)).collect()),
+        Value::Hash(hash) => RdbValue::Hash(
+            hash.entries()
+                .into_iter()
+                .map(|(field, value)| (field, value, hash.field_ttl(field)))
+                .filter(|(_, _, expire)| !expire.is_some_and(|expire| now > expire))
+                .map(|(field, value, expire)| (field.clone().into_bytes(), value.clone().into_bytes(), expire.map(|expire| expire as u64)))
+                .collect(),
+        ),
+        Value::Stream(stream) => RdbValue::Stream(rdb_stream(stream)),
+    }
+}
+
+/// The keyspace at the time the snapshot was taken. Values are shared with
+/// the keyspace, which copies them before changing them, so taking one only
+/// holds the execution lock briefly and encoding it happens outside of it.
+pub struct Snapshot {
+    time: u128,
+    entries: Vec<(String, Arc<Value>, Option<u128>)>,
+}
+
+impl Snapshot {
+    fn to_rdb(&self) -> Rdb {
+        let entries = self
+            .entries
+            .iter()
+            .map(|(key, value, expire)| RdbEntry {
+                db: 0,
+                key: key.clone().into_bytes(),
+                expire: expire.map(|expire| expire as u64),
+                value: rdb_value(value, self.time),
+            })
+            .collect();
+        let aux = [
+            ("redis-ver", "7.2.0".to_string()),
+            ("redis-bits", "64".to_string()),
+            ("ctime", (self.time / 1000).to_string()),
+        ];
+        Rdb {
+            version: rdb::RDB_VERSION,
+            aux: aux.into_iter().map(|(field, value)| (field.as_bytes().to_vec(), value.into_bytes())).collect(),
+            entries,
+        }
+    }
+}
+
+/// Takes a snapshot of the keyspace. Callers hold the execution lock so the
+/// snapshot is consistent.
+pub fn snapshot() -> Snapshot {
+    let time = get_current_time();
+    Snapshot { time, entries: get_memory_instance().live_entries(time) }
+}
+
+/// Serializes `snapshot`, compressing strings unless `rdbcompression` is off.
+pub fn serialize(snapshot: &Snapshot) -> Vec<u8> {
+    let compress = get_options_instance().get("rdbcompression").map(|value| value != "no").unwrap_or(true);
+    rdb::serialize(&snapshot.to_rdb(), compress)
+}
+
+/// Writes `snapshot` to a temporary file next to `path` and renames it over
+/// `path` once synced, so a crash never leaves a partial snapshot behind.
+fn write_snapshot(snapshot: &Snapshot, path: &Path) -> io::Result<()> {
+    write_atomically(&serialize(snapshot), path)
+}
+
+/// Writes `data` to `path` through a synced temporary file in the same
+/// directory, so `path` holds either its old or its new contents.
+pub fn write_atomically(data: &[u8], path: &Path) -> io::Result<()> {
+    // Saves for replicas may run alongside a BGSAVE, each needs its own file.
+    static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+    let temp = path.with_file_name(format!("temp-{}-{}.rdb", std::process::id(), TEMP_FILES.fetch_add(1, Ordering::Relaxed)));
+    let result = File::create(&temp).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_all()
+    });
+    match result.and_then(|_| fs::rename(&temp, path)) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            _ = fs::remove_file(&temp);
+            Err(err)
+        }
+    }
+}
+
+/// Saves the snapshot of a disk-based replica sync, taken when the keyspace
+/// had `dirty` changes, and returns it. Like in Redis, this counts as a save
+/// of the dataset.
+pub fn save_for_sync(snapshot: &Snapshot, dirty: u64) -> Vec<u8> {
+    let data = serialize(snapshot);
+    let path = rdb_path();
+    match write_atomically(&data, &path) {
+        Ok(()) => {
+            let _guard = lock_execution();
+            get_persistence_instance().last_save = now_secs();
+            get_memory_instance().saved(dirty);
+        }
+        Err(err) => eprintln!("[Rudis]: Failed saving the DB for a replica to {}: {}", path.display(), err),
+    }
+    data
+}
+
+/// `SAVE`, with the execution lock held for the whole write.
+fn save() -> Result<Vec<u8>, CommandError> {
+    let persistence = get_persistence_instance();
+    if persistence.bgsave_started.is_some() {
+        return Err(CommandError::Other("Background save already in progress".to_string()));
+    }
+    let path = rdb_path();
+    let dirty = get_memory_instance().dirty();
+    match write_snapshot(&snapshot(), &path) {
+        Ok(()) => {
+            persistence.last_save = now_secs();
+            get_memory_instance().saved(dirty);
+            println!("[Rudis]: DB saved on disk");
+            Ok(resp::simple_string("OK"))
+        }
+        Err(err) => {
+            eprintln!("[Rudis]: Failed saving the DB to {}: {}", path.display(), err);
+            Err(CommandError::Other(format!("Failed saving the DB: {}", err)))
+        }
+    }
+}
+
+/// Copies the keyspace and writes the copy from another thread, so clients
+/// only wait for the copy. Callers hold the execution lock.
+fn start_bgsave() {
+    let persistence = get_persistence_instance();
+    let started = now_secs();
+    persistence.bgsave_started = Some(started);
+    persistence.last_bgsave_try = started;
+    let dirty = get_memory_instance().dirty();
+    let snapshot = snapshot();
+    let path = rdb_path();
+    thread::spawn(move || {
+        let result = write_snapshot(&snapshot, &path);
+        let _guard = lock_execution();
+        let persistence = get_persistence_instance();
+        let finished = now_secs();
+        persistence.bgsave_started = None;
+        persistence.last_bgsave_duration = Some(finished - started);
+        persistence.last_bgsave_ok = result.is_ok();
+        match result {
+            Ok(()) => {
+                persistence.last_save = finished;
+                // Changes made while saving are still unsaved.
+                get_memory_instance().saved(dirty);
+                println!("[Rudis]: Background saving terminated with success");
+            }
+            Err(err) => eprintln!("[Rudis]: Background saving to {} failed: {}", path.display(), err),
+        }
+    });
+}
+
+/// `BGSAVE [SCHEDULE]`
+fn bgsave(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    match commands.get(1) {
+        None => {}
+        Some(arg) if commands.len() == 2 && arg.eq_ignore_ascii_case("SCHEDULE") => {}
+        Some(_) => return Err(CommandError::Syntax),
+    }
+    if get_persistence_instance().bgsave_started.is_some() {
+        return Err(CommandError::Other("Background save already in progress".to_string()));
+    }
+    start_bgsave();
+    Ok(resp::simple_string("Background saving started"))
+}
+
+/// Parses the `save` option: pairs of seconds and number of changes.
+pub fn parse_save_points(value: &str) -> Option<Vec<(u64, u64)>> {
+    let numbers: Vec<u64> = value.split_whitespace().map(|number| number.parse().ok()).collect::<Option<_>>()?;
+    if !numbers.chunks_exact(2).remainder().is_empty() {
+        return None;
+    }
+    Some(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+/// Starts a background save once any save point is reached: at least
+/// `changes` changes and `seconds` seconds since the last save. Failed saves
+/// are only retried after `BGSAVE_RETRY_DELAY`.
+pub fn cron() {
+    let _guard = lock_execution();
+    let persistence = get_persistence_instance();
+    if persistence.bgsave_started.is_some() {
+        return;
+    }
+    let save_points = get_options_instance().get("save").and_then(|value| parse_save_points(value)).unwrap_or_default();
+    let dirty = get_memory_instance().dirty();
+    let now = now_secs();
+    let elapsed = now.saturating_sub(persistence.last_save);
+    let can_retry = persistence.last_bgsave_ok || now.saturating_sub(persistence.last_bgsave_try) > BGSAVE_RETRY_DELAY;
+    let reached = save_points.into_iter().find(|(seconds, changes)| dirty >= *changes && elapsed >= *seconds);
+    if let Some((seconds, changes)) = reached {
+        if can_retry {
+            println!("[Rudis]: {} changes in {} seconds. Saving...", changes, seconds);
+            start_bgsave();
+        }
+    }
+}
+
+/// Fields of the persistence section of `INFO`.
+pub fn info() -> Vec<(&'static str, String)> {
+    let persistence = get_persistence_instance();
+    let now = now_secs();
+    let duration = |seconds: Option<u64>| seconds.map(|seconds| seconds as i64).unwrap_or(-1).to_string();
+    vec![
+        ("loading", "0".to_string()),
+        ("rdb_changes_since_last_save", get_memory_instance().dirty().to_string()),
+        ("rdb_bgsave_in_progress", (persistence.bgsave_started.is_some() as u8).to_string()),
+        ("rdb_last_save_time", persistence.last_save.to_string()),
+        ("rdb_last_bgsave_status", if persistence.last_bgsave_ok { "ok" } else { "err" }.to_string()),
+        ("rdb_last_bgsave_time_sec", duration(persistence.last_bgsave_duration)),
+        ("rdb_current_bgsave_time_sec", duration(persistence.bgsave_started.map(|started| now - started))),
+    ]
+}
+
+pub fn process_command(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    match commands[0].as_str() {
+        "SAVE" => save(),
+        "BGSAVE" => bgsave(commands),
+        "LASTSAVE" => Ok(resp::integer(get_persistence_instance().last_save as i64)),
+        _ => Err(CommandError::Other(format!("unknown command '{}'", commands[0]))),
+    }
+}
+
+/// Loads the snapshot at `dir`/`dbfilename` into the keyspace, if there is
+/// one.
+pub fn load() {
+    let path = rdb_path();
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(_) => return,
+    };
+    let snapshot = match rdb::parse(&data) {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            eprintln!("[Rudis]: Failed to load {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    };
+    let version = snapshot.version;
+    let loaded = load_snapshot(snapshot);
+    // What was just loaded is already on disk.
+    let memory = get_memory_instance();
+    let dirty = memory.dirty();
+    memory.saved(dirty);
+    println!("[Rudis]: Loaded {} keys from {} (RDB version {})", loaded, path.display(), version);
+}
+
+/// Adds the keys of `snapshot` to the keyspace and returns how many were
+/// loaded. Keys already expired are skipped, like Redis does on a master.
+pub fn load_snapshot(snapshot: Rdb) -> usize {
+    let now = get_current_time();
+    let memory = get_memory_instance();
+    let mut loaded = 0;
+    for entry in snapshot.entries {
+        if entry.db != 0 {
+            println!("[Rudis]: Skipping key in DB {}, only DB 0 is supported", entry.db);
+            continue;
+        }
+        if entry.expire.is_some_and(|expire| (expire as u128) < now) {
+            continue;
+        }
+        let key = text(entry.key);
+        let (value, field_ttls) = match memory_value(entry.value) {
+            Ok(value) => value,
+            Err(len) => {
+                println!("[Rudis]: Skipping list {} of {} elements, lists are not supported", key, len);
+                continue;
+            }
+        };
+        memory.set_value(key.clone(), value);
+        if field_ttls {
+            memory.track_field_expires(&key);
+        }
+        if let Some(expire) = entry.expire {
+            memory.expire(key, expire as u128);
+        }
+        loaded += 1;
+    }
+    loaded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::run;
+
+    fn bytes(value: &str) -> Vec<u8> {
+        value.as_bytes().to_vec()
+    }
+
+    fn entry(key: &str, expire: Option<u64>, value: RdbValue) -> RdbEntry {
+        RdbEntry { db: 0, key: bytes(key), expire, value }
+    }
+
+    /// The value at `key` as a snapshot holds it, set members sorted.
+    fn saved(key: &str) -> RdbValue {
+        let _guard = lock_execution();
+        let value = get_memory_instance().lookup(key).unwrap().clone();
+        sorted(rdb_value(&value, get_current_time()))
+    }
+
+    fn sorted(value: RdbValue) -> RdbValue {
+        match value {
+            RdbValue::Set(mut members) => {
+                members.sort();
+                RdbValue::Set(members)
+            }
+            value => value,
+        }
+    }
+
+    #[test]
+    fn save_points() {
+        assert_eq!(parse_save_points("3600 1 300 100"), Some(vec![(3600, 1), (300, 100)]));
+        assert_eq!(parse_save_points(""), Some(Vec::new()));
+        assert_eq!(parse_save_points("3600"), None);
+        assert_eq!(parse_save_points("3600 -1"), None);
+        assert_eq!(parse_save_points("60 x"), None);
+    }
+
+    #[test]
+    fn values_survive_a_snapshot() {
+        run(&["SADD", "persist:set", "1", "2", "three"]);
+        run(&["ZADD", "persist:zset", "1.5", "a", "-2", "b"]);
+        run(&["HSET", "persist:hash", "f", "v", "g", "w"]);
+        run(&["HPEXPIREAT", "persist:hash", "99999999999999", "FIELDS", "1", "g"]);
+        run(&["XADD", "persist:stream", "1-0", "f", "v"]);
+        run(&["XADD", "persist:stream", "2-0", "f", "w"]);
+        run(&["XDEL", "persist:stream", "2-0"]);
+        run(&["XGROUP", "CREATE", "persist:stream", "g", "0"]);
+        run(&["XREADGROUP", "GROUP", "g", "alice", "STREAMS", "persist:stream", ">"]);
+
+        for key in ["persist:set", "persist:zset", "persist:hash", "persist:stream"] {
+            let (loaded, field_ttls) = memory_value(saved(key)).unwrap();
+            assert_eq!(field_ttls, key == "persist:hash");
+            assert_eq!(sorted(rdb_value(&loaded, get_current_time())), saved(key), "{}", key);
+        }
+        let RdbValue::Stream(stream) = saved("persist:stream") else { panic!() };
+        assert_eq!((stream.last_id, stream.max_deleted_id, stream.entries_added), ((2, 0), (2, 0), 2));
+        assert_eq!(stream.groups[0].consumers[0].pending, [(1, 0)]);
+        assert_eq!(memory_value(RdbValue::List(vec![bytes("a")])).err(), Some(1));
+    }
+
+    #[test]
+    fn expired_fields_are_left_out() {
+        let mut hash = Hash::new();
+        hash.insert("f".to_string(), "v".to_string());
+        hash.insert("g".to_string(), "w".to_string());
+        hash.set_field_ttl("g", 10);
+        let RdbValue::Hash(fields) = rdb_value(&Value::Hash(hash), 11) else { panic!() };
+        assert_eq!(fields, [(bytes("f"), bytes("v"), None)]);
+    }
+
+    #[test]
+    fn loading_skips_expired_keys_and_other_dbs() {
+        let snapshot = Rdb {
+            version: rdb::RDB_VERSION,
+            aux: Vec::new(),
+            entries: vec![
+                entry("load:live", Some(u64::MAX / 2), RdbValue::String(bytes("1"))),
+                entry("load:expired", Some(1), RdbValue::String(bytes("1"))),
+                RdbEntry { db: 1, ..entry("load:other", None, RdbValue::String(bytes("1"))) },
+                entry("load:list", None, RdbValue::List(vec![bytes("a")])),
+                entry("load:hash", None, RdbValue::Hash(vec![(bytes("f"), bytes("v"), Some(u64::MAX / 2))])),
+            ],
+        };
+        let loaded = {
+            let _guard = lock_execution();
+            load_snapshot(snapshot)
+        };
+        assert_eq!(loaded, 2);
+        assert_eq!(run(&["GET", "load:live"]), resp::bulk_string("1"));
+        assert_eq!(run(&["GET", "load:expired"]), resp::null_bulk_string());
+        assert_eq!(run(&["HGET", "load:hash", "f"]), resp::bulk_string("v"));
+    }
+
+    #[test]
+    fn writes_replace_files_whole() {
+        let dir = std::env::temp_dir().join(format!("rudis-persistence-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dump.rdb");
+        write_atomically(b"first", &path).unwrap();
+        write_atomically(b"second", &path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        // No temporary file is left behind.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(write_atomically(b"x", &dir.join("missing").join("dump.rdb")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,436 @@
+use std::collections::{BTreeSet, HashMap};
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::client::Client;
+use crate::commands::{lock_execution, set_replicas_only};
+use crate::error::CommandError;
+use crate::parser::Command;
+use crate::resp;
+use crate::util::glob_match;
+use crate::get_pubsub_instance;
+
+/// Channel client-side caching invalidations go to for RESP2 connections.
+pub const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+/// A message delivered to a connection out of band, encoded once the
+/// connection's protocol is known.
+pub enum Message {
+    Published {
+        kind: &'static str,
+        pattern: Option<String>,
+        channel: String,
+        payload: Vec<u8>,
+    },
+    /// Keys cached by a tracking client that changed, `None` meaning all.
+    Invalidate(Option<Vec<String>>),
+}
+
+impl Message {
+    /// `None` when the connection can't receive the message, as RESP2
+    /// connections not subscribed to invalidations.
+    pub fn encode(&self, client: &Client) -> Option<Vec<u8>> {
+        match self {
+            Message::Published { kind, pattern, channel, payload } => {
+                let mut elements = vec![resp::bulk_string(kind)];
+                if let Some(pattern) = pattern {
+                    elements.push(resp::bulk_string(pattern));
+                }
+                elements.push(resp::bulk_string(channel));
+                elements.push(resp::bulk_bytes(payload));
+                Some(push_or_array(elements, client.resp3))
+            }
+            Message::Invalidate(keys) => {
+                let keys = match keys {
+                    Some(keys) => resp::bulk_string_array(keys),
+                    None => resp::null_array(),
+                };
+                if client.resp3 {
+                    Some(resp::push(vec![resp::bulk_string("invalidate"), keys]))
+                } else if client.channels.contains(INVALIDATE_CHANNEL) {
+                    let elements = vec![resp::bulk_string("message"), resp::bulk_string(INVALIDATE_CHANNEL), keys];
+                    Some(resp::array(elements))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Out-of-band replies are push messages under RESP3 and plain arrays
+/// under RESP2.
+pub fn push_or_array(elements: Vec<Vec<u8>>, resp3: bool) -> Vec<u8> {
+    if resp3 {
+        resp::push(elements)
+    } else {
+        resp::array(elements)
+    }
+}
+
+/// The three kinds of subscriptions, each with its own registry.
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Channel,
+    Pattern,
+    Shard,
+}
+
+impl Kind {
+    fn of(command: &str) -> Self {
+        match command {
+            "PSUBSCRIBE" | "PUNSUBSCRIBE" => Kind::Pattern,
+            "SSUBSCRIBE" | "SUNSUBSCRIBE" => Kind::Shard,
+            _ => Kind::Channel,
+        }
+    }
+}
+
+type Subscribers = HashMap<u64, UnboundedSender<Message>>;
+
+/// Channel and pattern subscriptions of every connection, by client ID.
+pub struct PubSub {
+    channels: HashMap<String, Subscribers>,
+    patterns: HashMap<String, Subscribers>,
+    shard_channels: HashMap<String, Subscribers>,
+}
+
+fn add(registry: &mut HashMap<String, Subscribers>, name: &str, id: u64, sender: &UnboundedSender<Message>) {
+    registry.entry(name.to_string()).or_default().insert(id, sender.clone());
+}
+
+fn remove(registry: &mut HashMap<String, Subscribers>, name: &str, id: u64) {
+    if let Some(subscribers) = registry.get_mut(name) {
+        subscribers.remove(&id);
+        if subscribers.is_empty() {
+            registry.remove(name);
+        }
+    }
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        PubSub {
+            channels: HashMap::new(),
+            patterns: HashMap::new(),
+            shard_channels: HashMap::new(),
+        }
+    }
+
+    fn registry(&mut self, kind: Kind) -> &mut HashMap<String, Subscribers> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::Shard => &mut self.shard_channels,
+        }
+    }
+
+    /// Sends `payload` to the subscribers of `channel` and of every pattern
+    /// matching it, returning how many connections received it.
+    pub fn publish(&self, channel: &str, payload: &[u8]) -> usize {
+        let mut receivers = 0;
+        if let Some(subscribers) = self.channels.get(channel) {
+            for sender in subscribers.values() {
+                let message = Message::Published {
+                    kind: "message",
+                    pattern: None,
+                    channel: channel.to_string(),
+                    payload: payload.to_vec(),
+                };
+                if sender.send(message).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+        for (pattern, subscribers) in &self.patterns {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            for sender in subscribers.values() {
+                let message = Message::Published {
+                    kind: "pmessage",
+                    pattern: Some(pattern.to_string()),
+                    channel: channel.to_string(),
+                    payload: payload.to_vec(),
+                };
+                if sender.send(message).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+        receivers
+    }
+
+    /// Shard channels have no pattern subscriptions.
+    pub fn publish_shard(&self, channel: &str, payload: &[u8]) -> usize {
+        let Some(subscribers) = self.shard_channels.get(channel) else {
+            return 0;
+        };
+        let mut receivers = 0;
+        for sender in subscribers.values() {
+            let message = Message::Published {
+                kind: "smessage",
+                pattern: None,
+                channel: channel.to_string(),
+                payload: payload.to_vec(),
+            };
+            if sender.send(message).is_ok() {
+                receivers += 1;
+            }
+        }
+        receivers
+    }
+}
+
+/// Drops every subscription of a connection that went away.
+pub fn unsubscribe_all(client: &mut Client) {
+    let _guard = lock_execution();
+    let registry = get_pubsub_instance();
+    for channel in std::mem::take(&mut client.channels) {
+        remove(&mut registry.channels, &channel, client.id);
+    }
+    for pattern in std::mem::take(&mut client.patterns) {
+        remove(&mut registry.patterns, &pattern, client.id);
+    }
+    for channel in std::mem::take(&mut client.shard_channels) {
+        remove(&mut registry.shard_channels, &channel, client.id);
+    }
+}
+
+/// Commands a RESP2 connection may still run once subscribed.
+pub fn allowed_in_subscribed_mode(name: &str) -> bool {
+    matches!(
+        name,
+        "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "SSUBSCRIBE" | "SUNSUBSCRIBE" | "PING" | "QUIT" | "RESET"
+    )
+}
+
+fn subscriptions(client: &mut Client, kind: Kind) -> &mut BTreeSet<String> {
+    match kind {
+        Kind::Channel => &mut client.channels,
+        Kind::Pattern => &mut client.patterns,
+        Kind::Shard => &mut client.shard_channels,
+    }
+}
+
+/// Shard subscriptions are counted apart from channels and patterns.
+fn confirmation(client: &Client, command: &str, name: Option<&str>) -> Vec<u8> {
+    let name = match name {
+        Some(name) => resp::bulk_string(name),
+        None => resp::null_bulk_string(),
+    };
+    let count = match Kind::of(command) {
+        Kind::Shard => client.shard_channels.len(),
+        _ => client.channels.len() + client.patterns.len(),
+    };
+    let elements = vec![resp::bulk_string(&command.to_ascii_lowercase()), name, resp::integer(count as i64)];
+    push_or_array(elements, client.resp3)
+}
+
+/// `(P|S)SUBSCRIBE` and `(P|S)UNSUBSCRIBE`, which reply once per channel or
+/// pattern.
+pub fn process_command(client: &mut Client, commands: &[String]) -> Result<Vec<Vec<u8>>, CommandError> {
+    let _guard = lock_execution();
+    let kind = Kind::of(&commands[0]);
+    let registry = get_pubsub_instance().registry(kind);
+    let mut replies = Vec::new();
+
+    if commands[0].ends_with("UNSUBSCRIBE") {
+        let names: Vec<String> = match &commands[1..] {
+            [] => subscriptions(client, kind).iter().cloned().collect(),
+            names => names.to_vec(),
+        };
+        for name in &names {
+            if subscriptions(client, kind).remove(name) {
+                remove(registry, name, client.id);
+            }
+            replies.push(confirmation(client, &commands[0], Some(name)));
+        }
+        if names.is_empty() {
+            replies.push(confirmation(client, &commands[0], None));
+        }
+    } else {
+        if commands.len() < 2 {
+            return Err(CommandError::arity(&commands[0]));
+        }
+        for name in &commands[1..] {
+            if subscriptions(client, kind).insert(name.to_string()) {
+                add(registry, name, client.id, &client.sender);
+            }
+            replies.push(confirmation(client, &commands[0], Some(name)));
+        }
+    }
+    Ok(replies)
+}
+
+pub fn publish(command: &Command) -> Result<Vec<u8>, CommandError> {
+    let commands = &command.args;
+    if commands.len() != 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let registry = get_pubsub_instance();
+    let payload = command.raw_arg(2);
+    let receivers = match commands[0].as_str() {
+        "SPUBLISH" => registry.publish_shard(&commands[1], payload),
+        _ => registry.publish(&commands[1], payload),
+    };
+    set_replicas_only();
+    Ok(resp::integer(receivers as i64))
+}
+
+fn active_channels(registry: &HashMap<String, Subscribers>, pattern: Option<&String>) -> Vec<u8> {
+    let mut channels: Vec<&String> = registry
+        .keys()
+        .filter(|channel| match pattern {
+            Some(pattern) => glob_match(pattern, channel),
+            None => true,
+        })
+        .collect();
+    channels.sort();
+    resp::bulk_string_array(&channels)
+}
+
+fn subscriber_counts(registry: &HashMap<String, Subscribers>, channels: &[String]) -> Vec<u8> {
+    let mut elements = Vec::new();
+    for channel in channels {
+        elements.push(resp::bulk_string(channel));
+        elements.push(resp::integer(registry.get(channel).map_or(0, |subscribers| subscribers.len()) as i64));
+    }
+    resp::array(elements)
+}
+
+/// `PUBSUB CHANNELS|NUMSUB|NUMPAT|SHARDCHANNELS|SHARDNUMSUB`.
+pub fn pubsub(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 2 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let registry = get_pubsub_instance();
+    let subcommand = commands[1].to_ascii_uppercase();
+    match subcommand.as_str() {
+        "CHANNELS" | "SHARDCHANNELS" if commands.len() <= 3 => {
+            let kind = if subcommand == "CHANNELS" { Kind::Channel } else { Kind::Shard };
+            Ok(active_channels(registry.registry(kind), commands.get(2)))
+        }
+        "NUMSUB" => Ok(subscriber_counts(&registry.channels, &commands[2..])),
+        "SHARDNUMSUB" => Ok(subscriber_counts(&registry.shard_channels, &commands[2..])),
+        "NUMPAT" if commands.len() == 2 => Ok(resp::integer(registry.patterns.len() as i64)),
+        "CHANNELS" | "SHARDCHANNELS" | "NUMPAT" => Err(CommandError::Other(format!(
+            "wrong number of arguments for 'pubsub|{}' command",
+            subcommand.to_ascii_lowercase()
+        ))),
+        _ => Err(CommandError::Other(format!(
+            "unknown subcommand '{}'. Try PUBSUB HELP.",
+            commands[1]
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{run, run_propagated, Target};
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn received(receiver: &mut UnboundedReceiver<Message>, client: &Client) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        while let Ok(message) = receiver.try_recv() {
+            messages.extend(message.encode(client));
+        }
+        messages
+    }
+
+    #[test]
+    fn patterns_match_channels() {
+        let mut registry = PubSub::new();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        add(&mut registry.channels, "news.sport", 1, &sender);
+        add(&mut registry.patterns, "news.*", 1, &sender);
+        add(&mut registry.patterns, "h?llo", 1, &sender);
+        add(&mut registry.shard_channels, "news.sport", 1, &sender);
+        assert_eq!(registry.publish("news.sport", b"goal"), 2);
+        assert_eq!(registry.publish("hallo", b"hi"), 1);
+        assert_eq!(registry.publish("news", b"none"), 0);
+        assert_eq!(registry.publish_shard("news.sport", b"shard"), 1);
+        assert_eq!(registry.publish_shard("news.weather", b"shard"), 0);
+
+        let kinds: Vec<(&str, Option<String>)> = std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|message| match message {
+                Message::Published { kind, pattern, .. } => (kind, pattern),
+                Message::Invalidate(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(kinds[0], ("message", None));
+        assert_eq!(kinds[1], ("pmessage", Some("news.*".to_string())));
+        assert_eq!(kinds[2], ("pmessage", Some("h?llo".to_string())));
+        assert_eq!(kinds[3], ("smessage", None));
+
+        remove(&mut registry.patterns, "news.*", 1);
+        assert_eq!(registry.publish("news.sport", b"goal"), 1);
+        assert!(!registry.patterns.contains_key("news.*"));
+    }
+
+    #[test]
+    fn subscriptions_receive_published_messages() {
+        let (mut client, mut receiver) = Client::new();
+        let replies = process_command(&mut client, &args(&["SUBSCRIBE", "pubsub:a", "pubsub:b"])).unwrap();
+        assert_eq!(replies[1], resp::array(vec![resp::bulk_string("subscribe"), resp::bulk_string("pubsub:b"), resp::integer(2)]));
+        process_command(&mut client, &args(&["PSUBSCRIBE", "pubsub:*"])).unwrap();
+        let replies = process_command(&mut client, &args(&["SSUBSCRIBE", "pubsub:s"])).unwrap();
+        // Shard channels are counted on their own.
+        assert_eq!(replies[0], resp::array(vec![resp::bulk_string("ssubscribe"), resp::bulk_string("pubsub:s"), resp::integer(1)]));
+        assert!(client.in_subscribed_mode());
+
+        assert_eq!(run(&["PUBLISH", "pubsub:a", "hello"]), resp::integer(2));
+        assert_eq!(run(&["SPUBLISH", "pubsub:s", "shard"]), resp::integer(1));
+        let expected = vec![
+            resp::bulk_string_array(&["message", "pubsub:a", "hello"]),
+            resp::bulk_string_array(&["pmessage", "pubsub:*", "pubsub:a", "hello"]),
+            resp::bulk_string_array(&["smessage", "pubsub:s", "shard"]),
+        ];
+        assert_eq!(received(&mut receiver, &client), expected);
+        client.resp3 = true;
+        run(&["PUBLISH", "pubsub:b", "hello"]);
+        assert!(received(&mut receiver, &client)[0].starts_with(b">3\r\n"));
+
+        let replies = process_command(&mut client, &args(&["UNSUBSCRIBE"])).unwrap();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(run(&["PUBLISH", "pubsub:a", "hello"]), resp::integer(1));
+        drop(client);
+        assert_eq!(run(&["PUBLISH", "pubsub:a", "hello"]), resp::integer(0));
+    }
+
+    #[test]
+    fn publish_propagates_to_replicas_only() {
+        let (published, target) = run_propagated(&["PUBLISH", "pubsub:replicated", "hello"]).unwrap();
+        assert_eq!((published, target), (args(&["PUBLISH", "pubsub:replicated", "hello"]), Target::Replicas));
+        let (_, target) = run_propagated(&["SPUBLISH", "pubsub:replicated", "hello"]).unwrap();
+        assert_eq!(target, Target::Replicas);
+    }
+
+    #[test]
+    fn introspection() {
+        let (mut client, _receiver) = Client::new();
+        process_command(&mut client, &args(&["SUBSCRIBE", "intro:a", "intro:b"])).unwrap();
+        process_command(&mut client, &args(&["SSUBSCRIBE", "intro:s"])).unwrap();
+        assert_eq!(run(&["PUBSUB", "CHANNELS", "intro:*"]), resp::bulk_string_array(&["intro:a", "intro:b"]));
+        assert_eq!(run(&["PUBSUB", "SHARDCHANNELS", "intro:*"]), resp::bulk_string_array(&["intro:s"]));
+        let counts = resp::array(vec![
+            resp::bulk_string("intro:a"),
+            resp::integer(1),
+            resp::bulk_string("intro:c"),
+            resp::integer(0),
+        ]);
+        assert_eq!(run(&["PUBSUB", "NUMSUB", "intro:a", "intro:c"]), counts);
+        assert_eq!(
+            run(&["PUBSUB", "NUMPAT", "extra"]),
+            resp::error("ERR wrong number of arguments for 'pubsub|numpat' command")
+        );
+        assert_eq!(run(&["PUBSUB", "NOPE"]), resp::error("ERR unknown subcommand 'NOPE'. Try PUBSUB HELP."));
+        assert!(allowed_in_subscribed_mode("PING"));
+        assert!(!allowed_in_subscribed_mode("GET"));
+    }
+}
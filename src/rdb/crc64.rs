@@ -0,0 +1,48 @@
+/// Reflected polynomial of the Jones CRC-64 variant Redis checksums RDB
+/// files with.
+const POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+const fn table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut idx = 0;
+    while idx < 256 {
+        let mut crc = idx as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[idx] = crc;
+        idx += 1;
+    }
+    table
+}
+
+static TABLE: [u64; 256] = table();
+
+/// Continues the checksum `crc` over `data`, starting from 0.
+pub fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    for byte in data {
+        crc = TABLE[((crc ^ *byte as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_redis_check_value() {
+        // From the self test of crc64.c in Redis.
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+        assert_eq!(crc64(0, b""), 0);
+    }
+
+    #[test]
+    fn continues_over_chunks() {
+        let data = b"This is a test of the emergency broadcast system.";
+        let (head, tail) = data.split_at(17);
+        assert_eq!(crc64(crc64(0, head), tail), crc64(0, data));
+    }
+}
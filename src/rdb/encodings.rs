@@ -0,0 +1,254 @@
+// Compact encodings Redis serializes as an opaque string inside the RDB:
+// listpacks, the older ziplists, and intsets. Integers are returned in their
+// decimal form, as Redis does when reading them back. Only listpacks are
+// written, which is all current Redis versions produce.
+
+fn slice(data: &[u8], start: usize, len: usize) -> Option<&[u8]> {
+    data.get(start..start.checked_add(len)?)
+}
+
+fn int_le(bytes: &[u8]) -> i64 {
+    let mut value: i64 = 0;
+    for (idx, byte) in bytes.iter().enumerate() {
+        value |= (*byte as i64) << (8 * idx);
+    }
+    // Sign extend from the width of `bytes`.
+    let shift = 64 - 8 * bytes.len() as u32;
+    (value << shift) >> shift
+}
+
+/// Size of the back length that follows every listpack entry.
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+/// Value of `item` if it is an integer in canonical decimal form.
+fn canonical_int(item: &[u8]) -> Option<i64> {
+    let value = std::str::from_utf8(item).ok()?.parse::<i64>().ok()?;
+    if value.to_string().as_bytes() == item {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+fn push_backlen(data: &mut Vec<u8>, len: usize) {
+    let size = backlen_size(len);
+    for idx in (0..size).rev() {
+        let bits = ((len >> (7 * idx)) & 0x7f) as u8;
+        // Every byte but the first one written has its high bit set.
+        data.push(if idx == size - 1 { bits } else { bits | 0x80 });
+    }
+}
+
+/// Encodes `items` as a listpack, using the smallest integer encoding for
+/// items that are integers.
+pub fn encode_listpack(items: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = vec![0; 6];
+    for item in items {
+        let start = data.len();
+        match canonical_int(item) {
+            Some(value @ 0..=127) => data.push(value as u8),
+            Some(value @ -4096..=4095) => {
+                let raw = (value as u16) & 0x1fff;
+                data.extend_from_slice(&[0xc0 | (raw >> 8) as u8, raw as u8]);
+            }
+            Some(value) => {
+                let (tag, width) = match value {
+                    -32768..=32767 => (0xf1, 2),
+                    -8388608..=8388607 => (0xf2, 3),
+                    -2147483648..=2147483647 => (0xf3, 4),
+                    _ => (0xf4, 8),
+                };
+                data.push(tag);
+                data.extend_from_slice(&value.to_le_bytes()[..width]);
+            }
+            None => {
+                match item.len() {
+                    0..=63 => data.push(0x80 | item.len() as u8),
+                    64..=4095 => data.extend_from_slice(&[0xe0 | (item.len() >> 8) as u8, item.len() as u8]),
+                    _ => {
+                        data.push(0xf0);
+                        data.extend_from_slice(&(item.len() as u32).to_le_bytes());
+                    }
+                }
+                data.extend_from_slice(item);
+            }
+        }
+        let len = data.len() - start;
+        push_backlen(&mut data, len);
+    }
+    data.push(0xff);
+    let total = data.len() as u32;
+    let count = u16::try_from(items.len()).unwrap_or(u16::MAX);
+    data[..4].copy_from_slice(&total.to_le_bytes());
+    data[4..6].copy_from_slice(&count.to_le_bytes());
+    data
+}
+
+pub fn listpack(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let total = u32::from_le_bytes(slice(data, 0, 4)?.try_into().ok()?) as usize;
+    if total != data.len() {
+        return None;
+    }
+    let mut items = Vec::new();
+    let mut pos = 6;
+    loop {
+        let byte = *data.get(pos)?;
+        if byte == 0xff {
+            break;
+        }
+        let (item, len) = match byte {
+            _ if byte & 0x80 == 0 => ((byte as i64).to_string().into_bytes(), 1),
+            _ if byte & 0xc0 == 0x80 => {
+                let size = (byte & 0x3f) as usize;
+                (slice(data, pos + 1, size)?.to_vec(), 1 + size)
+            }
+            _ if byte & 0xe0 == 0xc0 => {
+                let raw = (((byte & 0x1f) as i64) << 8) | *data.get(pos + 1)? as i64;
+                let value = if raw >= 1 << 12 { raw - (1 << 13) } else { raw };
+                (value.to_string().into_bytes(), 2)
+            }
+            _ if byte & 0xf0 == 0xe0 => {
+                let size = (((byte & 0x0f) as usize) << 8) | *data.get(pos + 1)? as usize;
+                (slice(data, pos + 2, size)?.to_vec(), 2 + size)
+            }
+            0xf0 => {
+                let size = u32::from_le_bytes(slice(data, pos + 1, 4)?.try_into().ok()?) as usize;
+                (slice(data, pos + 5, size)?.to_vec(), 5 + size)
+            }
+            0xf1..=0xf4 => {
+                let width = match byte {
+                    0xf1 => 2,
+                    0xf2 => 3,
+                    0xf3 => 4,
+                    _ => 8,
+                };
+                (int_le(slice(data, pos + 1, width)?).to_string().into_bytes(), 1 + width)
+            }
+            _ => return None,
+        };
+        items.push(item);
+        pos += len + backlen_size(len);
+    }
+    Some(items)
+}
+
+pub fn ziplist(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut items = Vec::new();
+    let mut pos = 10;
+    loop {
+        let byte = *data.get(pos)?;
+        if byte == 0xff {
+            break;
+        }
+        // Length of the previous entry, in one byte or 0xfe and four more.
+        pos += if byte < 0xfe { 1 } else { 5 };
+        let encoding = *data.get(pos)?;
+        let (item, len) = match encoding >> 6 {
+            0 => {
+                let size = (encoding & 0x3f) as usize;
+                (slice(data, pos + 1, size)?.to_vec(), 1 + size)
+            }
+            1 => {
+                let size = (((encoding & 0x3f) as usize) << 8) | *data.get(pos + 1)? as usize;
+                (slice(data, pos + 2, size)?.to_vec(), 2 + size)
+            }
+            2 => {
+                let size = u32::from_be_bytes(slice(data, pos + 1, 4)?.try_into().ok()?) as usize;
+                (slice(data, pos + 5, size)?.to_vec(), 5 + size)
+            }
+            _ => {
+                let width = match encoding {
+                    0xc0 => 2,
+                    0xd0 => 4,
+                    0xe0 => 8,
+                    0xf0 => 3,
+                    0xfe => 1,
+                    0xf1..=0xfd => 0,
+                    _ => return None,
+                };
+                let value = match width {
+                    0 => (encoding & 0x0f) as i64 - 1,
+                    _ => int_le(slice(data, pos + 1, width)?),
+                };
+                (value.to_string().into_bytes(), 1 + width)
+            }
+        };
+        items.push(item);
+        pos += len;
+    }
+    Some(items)
+}
+
+pub fn intset(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let width = u32::from_le_bytes(slice(data, 0, 4)?.try_into().ok()?) as usize;
+    let len = u32::from_le_bytes(slice(data, 4, 4)?.try_into().ok()?) as usize;
+    if !matches!(width, 2 | 4 | 8) || data.len() != 8 + width * len {
+        return None;
+    }
+    Some(data[8..].chunks(width).map(|value| int_le(value).to_string().into_bytes()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(items: &[&str]) -> Vec<Vec<u8>> {
+        items.iter().map(|item| item.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn listpacks_round_trip() {
+        let long = "x".repeat(5000);
+        let medium = "y".repeat(200);
+        let original = items(&[
+            "0", "127", "128", "-1", "-4096", "4095", "32767", "-32769", "8388608", "-2147483649", "9223372036854775807", "",
+            "text", "007", "-0", &medium, &long,
+        ]);
+        let data = encode_listpack(&original);
+        assert_eq!(listpack(&data), Some(original));
+        assert_eq!(encode_listpack(&items(&["1"])), [9, 0, 0, 0, 1, 0, 1, 1, 0xff]);
+    }
+
+    #[test]
+    fn rejects_corrupt_listpacks() {
+        let mut data = encode_listpack(&items(&["text", "12345"]));
+        assert_eq!(listpack(&data[..data.len() - 1]), None);
+        // The last entry runs past the end marker.
+        let len = data.len();
+        data[len - 5] = 0xf4;
+        assert_eq!(listpack(&data), None);
+    }
+
+    #[test]
+    fn ziplists() {
+        // Header, then "ab", 12 as a 16 bit integer, 5 as an immediate and -2
+        // as an 8 bit integer, each after the length of the previous entry.
+        let mut data = vec![0; 10];
+        data.extend_from_slice(&[0, 0x02, b'a', b'b']);
+        data.extend_from_slice(&[4, 0xc0, 12, 0]);
+        data.extend_from_slice(&[4, 0xf6]);
+        data.extend_from_slice(&[2, 0xfe, 0xfe]);
+        data.push(0xff);
+        assert_eq!(ziplist(&data), Some(items(&["ab", "12", "5", "-2"])));
+        assert_eq!(ziplist(&data[..data.len() - 1]), None);
+    }
+
+    #[test]
+    fn intsets() {
+        let mut data = vec![2, 0, 0, 0, 2, 0, 0, 0];
+        data.extend_from_slice(&(-3i16).to_le_bytes());
+        data.extend_from_slice(&300i16.to_le_bytes());
+        assert_eq!(intset(&data), Some(items(&["-3", "300"])));
+        assert_eq!(intset(&data[..10]), None);
+        data[0] = 3;
+        assert_eq!(intset(&data), None);
+    }
+}
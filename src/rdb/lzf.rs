@@ -0,0 +1,140 @@
+/// Decompresses LZF data, as used for compressed RDB strings. Returns `None`
+/// when the data is corrupt or doesn't expand to `expected` bytes.
+pub fn decompress(input: &[u8], expected: usize) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut idx = 0;
+    while idx < input.len() {
+        let ctrl = input[idx] as usize;
+        idx += 1;
+        if ctrl < 32 {
+            // Literal run of ctrl + 1 bytes.
+            let run = input.get(idx..idx + ctrl + 1)?;
+            output.extend_from_slice(run);
+            idx += ctrl + 1;
+        } else {
+            // Back reference: length in the top 3 bits, extended by one byte
+            // when they are all set, then a 13-bit offset.
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(idx)? as usize;
+                idx += 1;
+            }
+            let back = ((ctrl & 0x1f) << 8) + *input.get(idx)? as usize + 1;
+            idx += 1;
+            let start = output.len().checked_sub(back)?;
+            for offset in 0..len + 2 {
+                let byte = output[start + offset];
+                output.push(byte);
+            }
+        }
+    }
+    if output.len() == expected {
+        Some(output)
+    } else {
+        None
+    }
+}
+
+/// Largest back reference offset and length LZF can encode.
+const MAX_OFFSET: usize = 1 << 13;
+const MAX_REFERENCE: usize = 7 + 255 + 2;
+
+fn push_literals(output: &mut Vec<u8>, literals: &[u8]) {
+    for run in literals.chunks(32) {
+        output.push((run.len() - 1) as u8);
+        output.extend_from_slice(run);
+    }
+}
+
+/// Compresses `input` with LZF, finding back references through a hash of
+/// the next three bytes. Returns `None` when that doesn't make it smaller.
+pub fn compress(input: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len());
+    // Last position + 1 of each hashed triple, so zero means none.
+    let mut table = vec![0usize; 1 << 14];
+    let mut literal_start = 0;
+    let mut idx = 0;
+    while idx + 2 < input.len() {
+        let triple = (input[idx] as usize) << 16 | (input[idx + 1] as usize) << 8 | input[idx + 2] as usize;
+        let hash = (triple.wrapping_mul(2654435761) >> 10) & ((1 << 14) - 1);
+        let candidate = table[hash];
+        table[hash] = idx + 1;
+        if candidate > 0 && idx - candidate < MAX_OFFSET && input[candidate - 1..candidate + 2] == input[idx..idx + 3] {
+            let reference = candidate - 1;
+            let max = (input.len() - idx).min(MAX_REFERENCE);
+            let mut len = 3;
+            while len < max && input[reference + len] == input[idx + len] {
+                len += 1;
+            }
+            push_literals(&mut output, &input[literal_start..idx]);
+            let offset = idx - reference - 1;
+            let encoded = len - 2;
+            if encoded < 7 {
+                output.push((encoded << 5 | offset >> 8) as u8);
+            } else {
+                output.push((7 << 5 | offset >> 8) as u8);
+                output.push((encoded - 7) as u8);
+            }
+            output.push((offset & 0xff) as u8);
+            idx += len;
+            literal_start = idx;
+        } else {
+            idx += 1;
+        }
+    }
+    push_literals(&mut output, &input[literal_start..]);
+    if output.len() < input.len() {
+        Some(output)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8]) {
+        let compressed = compress(input).unwrap();
+        assert!(compressed.len() < input.len());
+        assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+    }
+
+    #[test]
+    fn round_trips_repetitive_data() {
+        round_trip(&[b'a'; 1000]);
+        round_trip(b"abcabcabcabcabcabcabcabcabcabcabcabc");
+        round_trip("hello world, ".repeat(200).as_bytes());
+        // A repeat further back than the window can't be referenced.
+        let block: Vec<u8> = (0..200u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let mut far = block.clone();
+        far.resize(far.len() + MAX_OFFSET + 100, b'z');
+        far.extend_from_slice(&block);
+        round_trip(&far);
+    }
+
+    #[test]
+    fn skips_incompressible_data() {
+        assert_eq!(compress(b""), None);
+        assert_eq!(compress(b"abcdefgh"), None);
+    }
+
+    #[test]
+    fn decompresses_long_references() {
+        // One literal, then a reference one byte back of 7 + 11 + 2 bytes.
+        assert_eq!(decompress(&[0x00, b'a', 0xe0, 0x0b, 0x00], 21).as_deref(), Some(&[b'a'; 21][..]));
+        // A short reference three bytes back, of 2 + 2 bytes.
+        assert_eq!(decompress(&[0x02, b'a', b'b', b'c', 0x40, 0x02], 7).as_deref(), Some(&b"abcabca"[..]));
+    }
+
+    #[test]
+    fn rejects_corrupt_data() {
+        let compressed = compress(&[b'x'; 100]).unwrap();
+        assert_eq!(decompress(&compressed, 99), None);
+        assert_eq!(decompress(&compressed[..compressed.len() - 1], 100), None);
+        // A back reference before the start of the output.
+        assert_eq!(decompress(&[0x20, 0x05], 3), None);
+        // A literal run past the end of the input.
+        assert_eq!(decompress(&[0x05, b'a'], 6), None);
+    }
+}
@@ -0,0 +1,638 @@
+// RDB snapshots, as written by Redis. This module only depends on std so the
+// offline tools can share it with the server.
+
+mod crc64;
+mod encodings;
+mod lzf;
+mod writer;
+
+pub use writer::serialize;
+
+use thiserror::Error;
+
+const OPCODE_FUNCTION2: u8 = 0xf5;
+const OPCODE_IDLE: u8 = 0xf8;
+const OPCODE_FREQ: u8 = 0xf9;
+const OPCODE_AUX: u8 = 0xfa;
+const OPCODE_RESIZEDB: u8 = 0xfb;
+const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const OPCODE_EXPIRETIME: u8 = 0xfd;
+const OPCODE_SELECTDB: u8 = 0xfe;
+const OPCODE_EOF: u8 = 0xff;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+const TYPE_HASH_METADATA: u8 = 24;
+const TYPE_HASH_LISTPACK_EX: u8 = 25;
+
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+
+/// Most recent RDB version this module understands.
+pub const RDB_VERSION: u32 = 12;
+
+#[derive(Debug, Error)]
+pub enum RdbError {
+    #[error("unexpected end of file at offset {0}")]
+    UnexpectedEof(usize),
+    #[error("{message} at offset {offset}")]
+    Invalid { offset: usize, message: String },
+    /// An error in the value of the key at `offset`, of RDB type `kind`.
+    #[error("{source} in the value of key '{}' of type {kind} at offset {offset}", String::from_utf8_lossy(.key))]
+    Key { offset: usize, key: Vec<u8>, kind: u8, source: Box<RdbError> },
+}
+
+impl RdbError {
+    fn invalid(offset: usize, message: &str) -> Self {
+        RdbError::Invalid { offset, message: message.to_string() }
+    }
+}
+
+/// A stream ID as `(ms, seq)`.
+pub type RdbStreamId = (u64, u64);
+
+/// A stream entry with its fields and values.
+pub type RdbStreamEntry = (RdbStreamId, Vec<(Vec<u8>, Vec<u8>)>);
+
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub struct RdbPending {
+    pub id: RdbStreamId,
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub struct RdbConsumer {
+    pub name: Vec<u8>,
+    pub seen_time: u64,
+    pub active_time: Option<u64>,
+    pub pending: Vec<RdbStreamId>,
+}
+
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub struct RdbGroup {
+    pub name: Vec<u8>,
+    pub last_id: RdbStreamId,
+    pub entries_read: Option<u64>,
+    pub pending: Vec<RdbPending>,
+    pub consumers: Vec<RdbConsumer>,
+}
+
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub struct RdbStream {
+    pub entries: Vec<RdbStreamEntry>,
+    pub last_id: RdbStreamId,
+    pub max_deleted_id: RdbStreamId,
+    pub entries_added: u64,
+    pub groups: Vec<RdbGroup>,
+}
+
+/// A value as stored in the snapshot, whatever its encoding was.
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub enum RdbValue {
+    String(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    Set(Vec<Vec<u8>>),
+    SortedSet(Vec<(Vec<u8>, f64)>),
+    /// Fields with their value and absolute expiry time in milliseconds.
+    Hash(Vec<(Vec<u8>, Vec<u8>, Option<u64>)>),
+    Stream(RdbStream),
+}
+
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub struct RdbEntry {
+    pub db: u64,
+    pub key: Vec<u8>,
+    /// Absolute expiry time in milliseconds.
+    pub expire: Option<u64>,
+    pub value: RdbValue,
+}
+
+pub struct Rdb {
+    pub version: u32,
+    pub aux: Vec<(Vec<u8>, Vec<u8>)>,
+    pub entries: Vec<RdbEntry>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], RdbError> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len());
+        match end {
+            Some(end) => {
+                let bytes = &self.data[self.pos..end];
+                self.pos = end;
+                Ok(bytes)
+            }
+            None => Err(RdbError::UnexpectedEof(self.pos)),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, RdbError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32_le(&mut self) -> Result<u32, RdbError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64_le(&mut self) -> Result<u64, RdbError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Stream IDs outside of listpacks are two big endian integers.
+    fn raw_stream_id(&mut self) -> Result<RdbStreamId, RdbError> {
+        let bytes = self.bytes(16)?;
+        Ok((u64::from_be_bytes(bytes[..8].try_into().unwrap()), u64::from_be_bytes(bytes[8..].try_into().unwrap())))
+    }
+
+    /// Reads a length, or the kind of a specially encoded string, flagged
+    /// with `true`.
+    fn length_or_encoding(&mut self) -> Result<(u64, bool), RdbError> {
+        let offset = self.pos;
+        let first = self.byte()?;
+        match first >> 6 {
+            0 => Ok(((first & 0x3f) as u64, false)),
+            1 => Ok(((((first & 0x3f) as u64) << 8) | self.byte()? as u64, false)),
+            2 => match first {
+                0x80 => Ok((u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()) as u64, false)),
+                0x81 => Ok((u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()), false)),
+                _ => Err(RdbError::invalid(offset, "invalid length encoding")),
+            },
+            _ => Ok(((first & 0x3f) as u64, true)),
+        }
+    }
+
+    fn length(&mut self) -> Result<u64, RdbError> {
+        let offset = self.pos;
+        match self.length_or_encoding()? {
+            (len, false) => Ok(len),
+            (_, true) => Err(RdbError::invalid(offset, "unexpected string encoding instead of a length")),
+        }
+    }
+
+    /// A length used to size allocations, which can't exceed what is left.
+    fn count(&mut self) -> Result<usize, RdbError> {
+        let len = self.length()?;
+        if len > (self.data.len() - self.pos) as u64 {
+            return Err(RdbError::UnexpectedEof(self.pos));
+        }
+        Ok(len as usize)
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, RdbError> {
+        let offset = self.pos;
+        let (len, encoded) = self.length_or_encoding()?;
+        if !encoded {
+            let len = usize::try_from(len).map_err(|_| RdbError::UnexpectedEof(self.pos))?;
+            return Ok(self.bytes(len)?.to_vec());
+        }
+        match len {
+            0 => Ok((self.byte()? as i8).to_string().into_bytes()),
+            1 => Ok(i16::from_le_bytes(self.bytes(2)?.try_into().unwrap()).to_string().into_bytes()),
+            2 => Ok(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()).to_string().into_bytes()),
+            3 => {
+                let compressed = self.count()?;
+                let len = self.length()? as usize;
+                let data = self.bytes(compressed)?;
+                lzf::decompress(data, len).ok_or_else(|| RdbError::invalid(offset, "invalid LZF compressed string"))
+            }
+            _ => Err(RdbError::invalid(offset, "unknown string encoding")),
+        }
+    }
+
+    /// Zset scores of the oldest format, as a length prefixed string.
+    fn string_score(&mut self) -> Result<f64, RdbError> {
+        let offset = self.pos;
+        match self.byte()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => {
+                let text = self.bytes(len as usize)?;
+                parse_score(text).ok_or_else(|| RdbError::invalid(offset, "invalid zset score"))
+            }
+        }
+    }
+
+    /// Reads a string holding one of the compact encodings.
+    fn encoded<F>(&mut self, decode: F, what: &str) -> Result<Vec<Vec<u8>>, RdbError>
+    where
+        F: Fn(&[u8]) -> Option<Vec<Vec<u8>>>,
+    {
+        let offset = self.pos;
+        let blob = self.string()?;
+        decode(&blob).ok_or_else(|| RdbError::invalid(offset, &format!("invalid {}", what)))
+    }
+
+    fn value(&mut self, kind: u8, offset: usize) -> Result<RdbValue, RdbError> {
+        let value = match kind {
+            TYPE_STRING => RdbValue::String(self.string()?),
+            TYPE_LIST | TYPE_SET => {
+                let len = self.count()?;
+                let mut items = Vec::with_capacity(len);
+                for _ in 0..len {
+                    items.push(self.string()?);
+                }
+                if kind == TYPE_LIST {
+                    RdbValue::List(items)
+                } else {
+                    RdbValue::Set(items)
+                }
+            }
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let len = self.count()?;
+                let mut entries = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let member = self.string()?;
+                    let score = match kind {
+                        TYPE_ZSET => self.string_score()?,
+                        _ => f64::from_le_bytes(self.bytes(8)?.try_into().unwrap()),
+                    };
+                    entries.push((member, score));
+                }
+                RdbValue::SortedSet(entries)
+            }
+            TYPE_HASH => {
+                let len = self.count()?;
+                let mut fields = Vec::with_capacity(len);
+                for _ in 0..len {
+                    fields.push((self.string()?, self.string()?, None));
+                }
+                RdbValue::Hash(fields)
+            }
+            TYPE_HASH_METADATA => {
+                // Field TTLs are stored relative to the smallest one, plus one
+                // so that zero means no TTL.
+                let min_expire = self.u64_le()?;
+                let len = self.count()?;
+                let mut fields = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let ttl = self.length()?;
+                    let expire = if ttl == 0 { None } else { Some(min_expire + ttl - 1) };
+                    fields.push((self.string()?, self.string()?, expire));
+                }
+                RdbValue::Hash(fields)
+            }
+            TYPE_LIST_ZIPLIST => RdbValue::List(self.encoded(encodings::ziplist, "ziplist")?),
+            TYPE_SET_INTSET => RdbValue::Set(self.encoded(encodings::intset, "intset")?),
+            TYPE_SET_LISTPACK => RdbValue::Set(self.encoded(encodings::listpack, "listpack")?),
+            TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+                let items = match kind {
+                    TYPE_ZSET_ZIPLIST => self.encoded(encodings::ziplist, "ziplist")?,
+                    _ => self.encoded(encodings::listpack, "listpack")?,
+                };
+                let mut entries = Vec::with_capacity(items.len() / 2);
+                for pair in items.chunks(2) {
+                    let score = pair.get(1).and_then(|score| parse_score(score));
+                    match score {
+                        Some(score) => entries.push((pair[0].clone(), score)),
+                        None => return Err(RdbError::invalid(offset, "invalid zset score")),
+                    }
+                }
+                RdbValue::SortedSet(entries)
+            }
+            TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => {
+                let items = match kind {
+                    TYPE_HASH_ZIPLIST => self.encoded(encodings::ziplist, "ziplist")?,
+                    _ => self.encoded(encodings::listpack, "listpack")?,
+                };
+                if !items.chunks_exact(2).remainder().is_empty() {
+                    return Err(RdbError::invalid(offset, "hash with an odd number of elements"));
+                }
+                RdbValue::Hash(items.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone(), None)).collect())
+            }
+            TYPE_HASH_LISTPACK_EX => {
+                self.u64_le()?;
+                let items = self.encoded(encodings::listpack, "listpack")?;
+                if !items.chunks_exact(3).remainder().is_empty() {
+                    return Err(RdbError::invalid(offset, "hash with an invalid number of elements"));
+                }
+                let mut fields = Vec::with_capacity(items.len() / 3);
+                for triple in items.chunks(3) {
+                    let expire = match parse_integer(&triple[2]) {
+                        Some(0) => None,
+                        Some(expire) => Some(expire as u64),
+                        None => return Err(RdbError::invalid(offset, "invalid hash field TTL")),
+                    };
+                    fields.push((triple[0].clone(), triple[1].clone(), expire));
+                }
+                RdbValue::Hash(fields)
+            }
+            TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.count()?;
+                let mut items = Vec::new();
+                for _ in 0..nodes {
+                    if kind == TYPE_LIST_QUICKLIST {
+                        items.extend(self.encoded(encodings::ziplist, "ziplist")?);
+                        continue;
+                    }
+                    // Nodes are either a single plain element or a listpack.
+                    match self.length()? {
+                        1 => items.push(self.string()?),
+                        _ => items.extend(self.encoded(encodings::listpack, "listpack")?),
+                    }
+                }
+                RdbValue::List(items)
+            }
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => RdbValue::Stream(self.stream(kind)?),
+            _ => return Err(RdbError::invalid(offset, &format!("unknown object type {}", kind))),
+        };
+        Ok(value)
+    }
+
+    fn stream(&mut self, kind: u8) -> Result<RdbStream, RdbError> {
+        let nodes = self.count()?;
+        let mut entries = Vec::new();
+        for _ in 0..nodes {
+            let offset = self.pos;
+            let master = self.string()?;
+            if master.len() != 16 {
+                return Err(RdbError::invalid(offset, "invalid stream node key"));
+            }
+            let master = (
+                u64::from_be_bytes(master[..8].try_into().unwrap()),
+                u64::from_be_bytes(master[8..].try_into().unwrap()),
+            );
+            let items = self.encoded(encodings::listpack, "listpack")?;
+            stream_node(master, &items, &mut entries).ok_or_else(|| RdbError::invalid(offset, "invalid stream node"))?;
+        }
+        let length = self.length()?;
+        let last_id = (self.length()?, self.length()?);
+        let (max_deleted_id, entries_added) = if kind == TYPE_STREAM_LISTPACKS {
+            ((0, 0), length)
+        } else {
+            // The first ID comes first, it can be computed from the entries.
+            self.length()?;
+            self.length()?;
+            ((self.length()?, self.length()?), self.length()?)
+        };
+
+        let group_count = self.count()?;
+        let mut groups = Vec::with_capacity(group_count);
+        for _ in 0..group_count {
+            let name = self.string()?;
+            let last_id = (self.length()?, self.length()?);
+            let entries_read = match kind {
+                TYPE_STREAM_LISTPACKS => None,
+                _ => Some(self.length()?).filter(|read| *read != u64::MAX),
+            };
+            let pending_count = self.count()?;
+            let mut pending = Vec::with_capacity(pending_count);
+            for _ in 0..pending_count {
+                pending.push(RdbPending {
+                    id: self.raw_stream_id()?,
+                    delivery_time: self.u64_le()?,
+                    delivery_count: self.length()?,
+                });
+            }
+            let consumer_count = self.count()?;
+            let mut consumers = Vec::with_capacity(consumer_count);
+            for _ in 0..consumer_count {
+                let name = self.string()?;
+                let seen_time = self.u64_le()?;
+                let active_time = match kind {
+                    TYPE_STREAM_LISTPACKS_3 => Some(self.u64_le()?),
+                    _ => None,
+                };
+                let count = self.count()?;
+                let mut ids = Vec::with_capacity(count);
+                for _ in 0..count {
+                    ids.push(self.raw_stream_id()?);
+                }
+                consumers.push(RdbConsumer { name, seen_time, active_time, pending: ids });
+            }
+            groups.push(RdbGroup { name, last_id, entries_read, pending, consumers });
+        }
+
+        Ok(RdbStream { entries, last_id, max_deleted_id, entries_added, groups })
+    }
+}
+
+fn parse_integer(bytes: &[u8]) -> Option<i64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+fn parse_score(bytes: &[u8]) -> Option<f64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+/// Decodes a stream listpack: a master entry with the fields shared by the
+/// entries, followed by the entries themselves with IDs relative to `master`.
+fn stream_node(
+    master: RdbStreamId,
+    items: &[Vec<u8>],
+    entries: &mut Vec<RdbStreamEntry>,
+) -> Option<()> {
+    fn next_int(items: &mut std::slice::Iter<Vec<u8>>) -> Option<i64> {
+        parse_integer(items.next()?)
+    }
+
+    let mut items = items.iter();
+    let count = next_int(&mut items)?;
+    let deleted = next_int(&mut items)?;
+    let master_field_count = next_int(&mut items)?;
+    let master_fields: Vec<&Vec<u8>> = (0..master_field_count).map(|_| items.next()).collect::<Option<_>>()?;
+    next_int(&mut items)?;
+
+    for _ in 0..count + deleted {
+        let flags = next_int(&mut items)?;
+        let ms = master.0.wrapping_add(next_int(&mut items)? as u64);
+        let seq = master.1.wrapping_add(next_int(&mut items)? as u64);
+        let mut fields = Vec::new();
+        if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            for field in &master_fields {
+                fields.push(((*field).clone(), items.next()?.clone()));
+            }
+        } else {
+            for _ in 0..next_int(&mut items)? {
+                fields.push((items.next()?.clone(), items.next()?.clone()));
+            }
+        }
+        // The number of items of the entry, to walk the listpack backwards.
+        next_int(&mut items)?;
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            entries.push(((ms, seq), fields));
+        }
+    }
+    Some(())
+}
+
+/// Parses a whole RDB file.
+pub fn parse(data: &[u8]) -> Result<Rdb, RdbError> {
+    let mut reader = Reader { data, pos: 0 };
+    if reader.bytes(5)? != b"REDIS" {
+        return Err(RdbError::invalid(0, "wrong signature trying to load DB from file"));
+    }
+    let version = std::str::from_utf8(reader.bytes(4)?)
+        .ok()
+        .and_then(|version| version.parse::<u32>().ok())
+        .filter(|version| (1..=RDB_VERSION).contains(version))
+        .ok_or_else(|| RdbError::invalid(5, "unsupported RDB version"))?;
+
+    let mut rdb = Rdb { version, aux: Vec::new(), entries: Vec::new() };
+    let mut db = 0;
+    let mut expire = None;
+    loop {
+        let offset = reader.pos;
+        match reader.byte()? {
+            OPCODE_AUX => {
+                let field = reader.string()?;
+                let value = reader.string()?;
+                rdb.aux.push((field, value));
+            }
+            OPCODE_RESIZEDB => {
+                reader.length()?;
+                reader.length()?;
+            }
+            OPCODE_SELECTDB => db = reader.length()?,
+            OPCODE_EXPIRETIME_MS => expire = Some(reader.u64_le()?),
+            OPCODE_EXPIRETIME => expire = Some(reader.u32_le()? as u64 * 1000),
+            OPCODE_FREQ => {
+                reader.byte()?;
+            }
+            OPCODE_IDLE => {
+                reader.length()?;
+            }
+            OPCODE_FUNCTION2 => {
+                reader.string()?;
+            }
+            OPCODE_EOF => {
+                // Followed by a CRC64 checksum since version 5, zero when
+                // the writer had checksums disabled.
+                if version >= 5 {
+                    let expected = crc64::crc64(0, &data[..reader.pos]);
+                    let checksum = reader.u64_le()?;
+                    if checksum != 0 && checksum != expected {
+                        return Err(RdbError::invalid(reader.pos - 8, "wrong RDB checksum"));
+                    }
+                }
+                break;
+            }
+            kind => {
+                let key = reader.string()?;
+                let value = reader.value(kind, offset).map_err(|err| RdbError::Key {
+                    offset,
+                    key: key.clone(),
+                    kind,
+                    source: Box::new(err),
+                })?;
+                rdb.entries.push(RdbEntry { db, key, expire: expire.take(), value });
+            }
+        }
+    }
+    Ok(rdb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(value: &str) -> Vec<u8> {
+        value.as_bytes().to_vec()
+    }
+
+    fn entry(key: &str, expire: Option<u64>, value: RdbValue) -> RdbEntry {
+        RdbEntry { db: 0, key: bytes(key), expire, value }
+    }
+
+    fn sample() -> Rdb {
+        let stream = RdbStream {
+            entries: vec![
+                ((1, 0), vec![(bytes("temp"), bytes("20")), (bytes("hum"), bytes("50"))]),
+                ((1, 1), vec![(bytes("temp"), bytes("21")), (bytes("hum"), bytes("48"))]),
+                ((5, 0), vec![(bytes("note"), bytes("other fields"))]),
+            ],
+            last_id: (5, 0),
+            max_deleted_id: (0, 0),
+            entries_added: 3,
+            groups: vec![RdbGroup {
+                name: bytes("readers"),
+                last_id: (1, 1),
+                entries_read: Some(2),
+                pending: vec![RdbPending { id: (1, 1), delivery_time: 1_700_000_000_000, delivery_count: 2 }],
+                consumers: vec![RdbConsumer {
+                    name: bytes("alice"),
+                    seen_time: 1_700_000_000_000,
+                    active_time: Some(1_700_000_000_000),
+                    pending: vec![(1, 1)],
+                }],
+            }],
+        };
+        Rdb {
+            version: RDB_VERSION,
+            aux: vec![(bytes("redis-ver"), bytes("7.2.0"))],
+            entries: vec![
+                entry("string", None, RdbValue::String(bytes("value"))),
+                entry("integer", Some(1_900_000_000_000), RdbValue::String(bytes("-12345"))),
+                entry("long", None, RdbValue::String("compressible ".repeat(50).into_bytes())),
+                entry("binary", None, RdbValue::String(vec![0, 255, 13, 10])),
+                entry("list", None, RdbValue::List(vec![bytes("a"), bytes("b"), bytes("a")])),
+                entry("set", None, RdbValue::Set(vec![bytes("x"), bytes("y")])),
+                entry("zset", None, RdbValue::SortedSet(vec![(bytes("a"), -1.5), (bytes("b"), 2.0), (bytes("c"), f64::INFINITY)])),
+                entry("hash", None, RdbValue::Hash(vec![(bytes("f"), bytes("v"), None)])),
+                entry("ttls", None, RdbValue::Hash(vec![(bytes("f"), bytes("v"), Some(1_900_000_000_000)), (bytes("g"), bytes("w"), None)])),
+                entry("stream", None, RdbValue::Stream(stream)),
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trips_every_type() {
+        for compress in [false, true] {
+            let original = sample();
+            let parsed = parse(&serialize(&original, compress)).unwrap();
+            assert_eq!(parsed.version, original.version);
+            assert_eq!(parsed.aux, original.aux);
+            assert_eq!(parsed.entries, original.entries);
+        }
+    }
+
+    #[test]
+    fn compresses_long_strings() {
+        let rdb = sample();
+        assert!(serialize(&rdb, true).len() < serialize(&rdb, false).len());
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let mut data = serialize(&sample(), false);
+        let value = data.windows(5).position(|window| window == b"value").unwrap();
+        data[value] = b'V';
+        assert!(matches!(parse(&data), Err(RdbError::Invalid { message, .. }) if message == "wrong RDB checksum"));
+
+        let data = serialize(&sample(), false);
+        assert!(parse(&data[..data.len() - 20]).is_err());
+        assert!(matches!(parse(&data[..data.len() - 9]), Err(RdbError::UnexpectedEof(_))));
+        assert!(parse(b"REDIX0012").is_err());
+        assert!(parse(b"REDIS0099").is_err());
+    }
+
+    #[test]
+    fn accepts_a_disabled_checksum() {
+        let mut data = serialize(&sample(), false);
+        let len = data.len();
+        data[len - 8..].fill(0);
+        assert_eq!(parse(&data).unwrap().entries.len(), sample().entries.len());
+    }
+}
@@ -0,0 +1,239 @@
+use super::{crc64::crc64, encodings, lzf};
+use super::{Rdb, RdbStream, RdbStreamEntry, RdbStreamId, RdbValue};
+use super::{OPCODE_AUX, OPCODE_EOF, OPCODE_EXPIRETIME_MS, OPCODE_RESIZEDB, OPCODE_SELECTDB};
+use super::{STREAM_ITEM_FLAG_SAMEFIELDS, TYPE_HASH, TYPE_HASH_METADATA, TYPE_LIST, TYPE_SET, TYPE_STRING};
+use super::{TYPE_STREAM_LISTPACKS_3, TYPE_ZSET_2};
+
+/// Entries per stream listpack, Redis' default `stream-node-max-entries`.
+const STREAM_NODE_ENTRIES: usize = 100;
+
+/// Redis stores an unknown `entries_read` of a group as -1.
+const INVALID_ENTRIES_READ: u64 = u64::MAX;
+
+struct Writer {
+    data: Vec<u8>,
+    compress: bool,
+}
+
+impl Writer {
+    fn length(&mut self, len: u64) {
+        if len < 1 << 6 {
+            self.data.push(len as u8);
+        } else if len < 1 << 14 {
+            self.data.extend_from_slice(&[0x40 | (len >> 8) as u8, len as u8]);
+        } else if len <= u32::MAX as u64 {
+            self.data.push(0x80);
+            self.data.extend_from_slice(&(len as u32).to_be_bytes());
+        } else {
+            self.data.push(0x81);
+            self.data.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+
+    fn u64_le(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn raw_stream_id(&mut self, (ms, seq): RdbStreamId) {
+        self.data.extend_from_slice(&ms.to_be_bytes());
+        self.data.extend_from_slice(&seq.to_be_bytes());
+    }
+
+    /// Writes `bytes` as an integer when it is a small one in canonical form,
+    /// LZF compressed when that saves enough, and as is otherwise.
+    fn string(&mut self, bytes: &[u8]) {
+        if bytes.len() <= 11 {
+            let value = std::str::from_utf8(bytes).ok().and_then(|text| text.parse::<i32>().ok());
+            if let Some(value) = value.filter(|value| value.to_string().as_bytes() == bytes) {
+                match value {
+                    -128..=127 => self.data.extend_from_slice(&[0xc0, value as u8]),
+                    -32768..=32767 => {
+                        self.data.push(0xc1);
+                        self.data.extend_from_slice(&(value as i16).to_le_bytes());
+                    }
+                    _ => {
+                        self.data.push(0xc2);
+                        self.data.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+                return;
+            }
+        }
+        if self.compress && bytes.len() > 20 {
+            if let Some(compressed) = lzf::compress(bytes).filter(|compressed| compressed.len() + 4 <= bytes.len()) {
+                self.data.push(0xc3);
+                self.length(compressed.len() as u64);
+                self.length(bytes.len() as u64);
+                self.data.extend_from_slice(&compressed);
+                return;
+            }
+        }
+        self.length(bytes.len() as u64);
+        self.data.extend_from_slice(bytes);
+    }
+
+    fn value(&mut self, value: &RdbValue) {
+        match value {
+            RdbValue::String(bytes) => self.string(bytes),
+            RdbValue::List(items) | RdbValue::Set(items) => {
+                self.length(items.len() as u64);
+                for item in items {
+                    self.string(item);
+                }
+            }
+            RdbValue::SortedSet(entries) => {
+                self.length(entries.len() as u64);
+                for (member, score) in entries {
+                    self.string(member);
+                    self.data.extend_from_slice(&score.to_le_bytes());
+                }
+            }
+            RdbValue::Hash(fields) => {
+                // Field TTLs are written relative to the smallest one, see
+                // `Reader::value`.
+                let min_expire = fields.iter().filter_map(|(_, _, expire)| *expire).min();
+                if let Some(min_expire) = min_expire {
+                    self.u64_le(min_expire);
+                }
+                self.length(fields.len() as u64);
+                for (field, value, expire) in fields {
+                    if let Some(min_expire) = min_expire {
+                        self.length(expire.map(|expire| expire - min_expire + 1).unwrap_or(0));
+                    }
+                    self.string(field);
+                    self.string(value);
+                }
+            }
+            RdbValue::Stream(stream) => self.stream(stream),
+        }
+    }
+
+    fn stream(&mut self, stream: &RdbStream) {
+        let nodes: Vec<&[RdbStreamEntry]> = stream.entries.chunks(STREAM_NODE_ENTRIES).collect();
+        self.length(nodes.len() as u64);
+        for node in nodes {
+            let master = node[0].0;
+            let mut key = Vec::with_capacity(16);
+            key.extend_from_slice(&master.0.to_be_bytes());
+            key.extend_from_slice(&master.1.to_be_bytes());
+            self.string(&key);
+            self.string(&encodings::encode_listpack(&stream_node(master, node)));
+        }
+
+        let first_id = stream.entries.first().map(|(id, _)| *id).unwrap_or((0, 0));
+        self.length(stream.entries.len() as u64);
+        for id in [stream.last_id, first_id, stream.max_deleted_id] {
+            self.length(id.0);
+            self.length(id.1);
+        }
+        self.length(stream.entries_added);
+
+        self.length(stream.groups.len() as u64);
+        for group in &stream.groups {
+            self.string(&group.name);
+            self.length(group.last_id.0);
+            self.length(group.last_id.1);
+            self.length(group.entries_read.unwrap_or(INVALID_ENTRIES_READ));
+            self.length(group.pending.len() as u64);
+            for pending in &group.pending {
+                self.raw_stream_id(pending.id);
+                self.u64_le(pending.delivery_time);
+                self.length(pending.delivery_count);
+            }
+            self.length(group.consumers.len() as u64);
+            for consumer in &group.consumers {
+                self.string(&consumer.name);
+                self.u64_le(consumer.seen_time);
+                self.u64_le(consumer.active_time.unwrap_or(consumer.seen_time));
+                self.length(consumer.pending.len() as u64);
+                for id in &consumer.pending {
+                    self.raw_stream_id(*id);
+                }
+            }
+        }
+    }
+}
+
+fn kind(value: &RdbValue) -> u8 {
+    match value {
+        RdbValue::String(_) => TYPE_STRING,
+        RdbValue::List(_) => TYPE_LIST,
+        RdbValue::Set(_) => TYPE_SET,
+        RdbValue::SortedSet(_) => TYPE_ZSET_2,
+        RdbValue::Hash(fields) if fields.iter().any(|(_, _, expire)| expire.is_some()) => TYPE_HASH_METADATA,
+        RdbValue::Hash(_) => TYPE_HASH,
+        RdbValue::Stream(_) => TYPE_STREAM_LISTPACKS_3,
+    }
+}
+
+/// Items of a stream listpack holding `entries`: a master entry with the
+/// fields of the first entry, then every entry relative to `master`, with
+/// only its values when it has the same fields.
+fn stream_node(master: RdbStreamId, entries: &[RdbStreamEntry]) -> Vec<Vec<u8>> {
+    fn int(value: i64) -> Vec<u8> {
+        value.to_string().into_bytes()
+    }
+
+    let master_fields: Vec<&Vec<u8>> = entries[0].1.iter().map(|(field, _)| field).collect();
+    let mut items = vec![int(entries.len() as i64), int(0), int(master_fields.len() as i64)];
+    items.extend(master_fields.iter().map(|field| (*field).clone()));
+    items.push(int(0));
+
+    for ((ms, seq), fields) in entries {
+        let same_fields = fields.len() == master_fields.len()
+            && fields.iter().zip(&master_fields).all(|((field, _), master)| field == *master);
+        let flags = if same_fields { STREAM_ITEM_FLAG_SAMEFIELDS } else { 0 };
+        items.push(int(flags));
+        items.push(int(ms.wrapping_sub(master.0) as i64));
+        items.push(int(seq.wrapping_sub(master.1) as i64));
+        if same_fields {
+            items.extend(fields.iter().map(|(_, value)| value.clone()));
+            items.push(int(fields.len() as i64 + 3));
+        } else {
+            items.push(int(fields.len() as i64));
+            for (field, value) in fields {
+                items.push(field.clone());
+                items.push(value.clone());
+            }
+            items.push(int(fields.len() as i64 * 2 + 4));
+        }
+    }
+    items
+}
+
+/// Serializes `rdb`, with a SELECTDB and RESIZEDB for each run of entries of
+/// the same database and a CRC64 checksum at the end.
+pub fn serialize(rdb: &Rdb, compress: bool) -> Vec<u8> {
+    let mut writer = Writer { data: Vec::new(), compress };
+    writer.data.extend_from_slice(format!("REDIS{:04}", rdb.version).as_bytes());
+    for (field, value) in &rdb.aux {
+        writer.data.push(OPCODE_AUX);
+        writer.string(field);
+        writer.string(value);
+    }
+
+    let mut db = None;
+    for (idx, entry) in rdb.entries.iter().enumerate() {
+        if db != Some(entry.db) {
+            db = Some(entry.db);
+            let run: Vec<_> = rdb.entries[idx..].iter().take_while(|other| other.db == entry.db).collect();
+            writer.data.push(OPCODE_SELECTDB);
+            writer.length(entry.db);
+            writer.data.push(OPCODE_RESIZEDB);
+            writer.length(run.len() as u64);
+            writer.length(run.iter().filter(|other| other.expire.is_some()).count() as u64);
+        }
+        if let Some(expire) = entry.expire {
+            writer.data.push(OPCODE_EXPIRETIME_MS);
+            writer.u64_le(expire);
+        }
+        writer.data.push(kind(&entry.value));
+        writer.string(&entry.key);
+        writer.value(&entry.value);
+    }
+
+    writer.data.push(OPCODE_EOF);
+    let checksum = crc64(0, &writer.data);
+    writer.u64_le(checksum);
+    writer.data
+}
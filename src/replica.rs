@@ -3,9 +3,12 @@ use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 
+use crate::commands::lock_execution;
 use crate::get_memory_instance;
 use crate::get_options_instance;
 use crate::parser::parser_v2;
+use crate::persistence;
+use crate::rdb;
 
 struct ReplicaInfo {
   port: Option<String>,
@@ -45,7 +48,7 @@ async fn send_and_response(stream: &mut TcpStream, data: Vec<&str>) -> Option<St
     for cmd in commands {
         if cmd[0] == "SET" {
             let memory = get_memory_instance();
-            memory.set(cmd[1].to_string(), cmd[2].to_string());
+            memory.set(cmd[1].to_string(), cmd[2].as_bytes().to_vec());
         }
     }
   }
@@ -53,6 +56,84 @@ async fn send_and_response(stream: &mut TcpStream, data: Vec<&str>) -> Option<St
   None
 }
 
+/// Reads more of the master's stream into `buffer`, `None` once it closed.
+async fn read_more(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> Option<()> {
+  let mut buff = [0; 4096];
+  match stream.read(&mut buff).await {
+    Ok(0) | Err(_) => None,
+    Ok(size) => {
+      buffer.extend_from_slice(&buff[..size]);
+      Some(())
+    }
+  }
+}
+
+async fn read_line(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> Option<String> {
+  loop {
+    if let Some(end) = buffer.windows(2).position(|window| window == b"\r\n") {
+      let line: Vec<u8> = buffer.drain(..end + 2).collect();
+      return Some(String::from_utf8_lossy(&line[..end]).to_string());
+    }
+    read_more(stream, buffer).await?;
+  }
+}
+
+/// Reads the snapshot following the `+FULLRESYNC` reply, either length
+/// prefixed or delimited by an EOF mark, leaving what follows it in
+/// `buffer`. `None` when the header is unexpected, or with `buffer` emptied
+/// when the master closed the connection first.
+async fn read_snapshot(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+  let header = read_line(stream, buffer).await.unwrap_or_default();
+  if let Some(mark) = header.strip_prefix("$EOF:") {
+    // Diskless sync: the snapshot ends with the mark announced in the header.
+    let mark = mark.as_bytes();
+    loop {
+      if let Some(end) = buffer.windows(mark.len()).position(|window| window == mark) {
+        let data: Vec<u8> = buffer.drain(..end).collect();
+        buffer.drain(..mark.len());
+        return Some(data);
+      }
+      if read_more(stream, buffer).await.is_none() {
+        buffer.clear();
+        return None;
+      }
+    }
+  } else if let Some(len) = header.strip_prefix('$').and_then(|len| len.parse::<usize>().ok()) {
+    while buffer.len() < len {
+      if read_more(stream, buffer).await.is_none() {
+        buffer.clear();
+        return None;
+      }
+    }
+    Some(buffer.drain(..len).collect())
+  } else {
+    println!("[Redis][replica]: Unexpected snapshot header {:?}", header);
+    None
+  }
+}
+
+/// Reads the `+FULLRESYNC` reply to `PSYNC` and the snapshot following it,
+/// and replaces the keyspace with it. Returns what was read past the
+/// snapshot, the start of the replication stream.
+async fn full_resync(stream: &mut TcpStream) -> Vec<u8> {
+  let mut buffer = Vec::new();
+  let reply = read_line(stream, &mut buffer).await.unwrap_or_default();
+  println!("[Redis][replica]: {}", reply);
+  let Some(data) = read_snapshot(stream, &mut buffer).await else {
+    return buffer;
+  };
+  match rdb::parse(&data) {
+    Ok(snapshot) => {
+      let _guard = lock_execution();
+      get_memory_instance().flush();
+      let loaded = persistence::load_snapshot(snapshot);
+      println!("[Redis][replica]: Loaded {} keys from the master", loaded);
+    }
+    Err(err) => println!("[Redis][replica]: Failed to load the master snapshot: {}", err),
+  }
+  buffer
+}
+
 pub struct Replicas {
   replicas: HashMap<String, ReplicaInfo>,
   has_setup: bool,
@@ -79,12 +160,12 @@ impl Replicas {
   }
 
   pub fn available(&mut self) -> usize {
-    return self.replicas_conn.len()
+    self.replicas_conn.len()
   }
 
   pub fn add_replica(&mut self, port_id: &str) {
     let mut replica_data = ReplicaInfo::new();
-    replica_data.set_port(&port_id);
+    replica_data.set_port(port_id);
 
     self.replicas.insert(port_id.to_string(), replica_data);
     self.replicas_conn.push(port_id.to_string());
@@ -99,7 +180,9 @@ impl Replicas {
     println!("{}", self.replicas_list.len());
   }
 
-  pub async fn sync_to_master(&mut self) -> TcpStream {
+  /// Connects to the master and performs the handshake. Returns the
+  /// connection with what was already read of the replication stream.
+  pub async fn sync_to_master(&mut self) -> (TcpStream, Vec<u8>) {
     println!("[Redis][replica]: Attempting syncing with master");
     let master_port = get_options_instance().get("master-port").unwrap();
     let port = get_options_instance().get("port").unwrap();
@@ -115,12 +198,62 @@ impl Replicas {
     {
       _ = send_and_response(&mut listener, vec! ["REPLCONF", "capa", "psync2"]).await;
     }
-    {
-      _ = send_and_response(&mut listener, vec! ["PSYNC", "?", "-1"]).await;
-    }
-    listener
+    let _ = listener.write_all(&parse_str_to_repl(vec! ["PSYNC", "?", "-1"])).await;
+    let pending = full_resync(&mut listener).await;
+    (listener, pending)
   }
 }
 
 
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::net::TcpListener;
+
+  /// What `read_snapshot` makes of a master sending `payload` then closing
+  /// the connection, and everything left to read after the snapshot.
+  async fn read_from_master(payload: &[u8]) -> (Option<Vec<u8>>, Vec<u8>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let payload = payload.to_vec();
+    tokio::spawn(async move {
+      let (mut master, _) = listener.accept().await.unwrap();
+      // Split writes, so the framing can't rely on a single read.
+      for chunk in payload.chunks(7) {
+        _ = master.write_all(chunk).await;
+        _ = master.flush().await;
+      }
+    });
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let mut buffer = Vec::new();
+    let snapshot = read_snapshot(&mut stream, &mut buffer).await;
+    while read_more(&mut stream, &mut buffer).await.is_some() {}
+    (snapshot, buffer)
+  }
+
+  #[tokio::test]
+  async fn reads_length_prefixed_snapshots() {
+    let (snapshot, rest) = read_from_master(b"$11\r\nREDIS0012\r\n*1\r\n$4\r\nPING\r\n").await;
+    // The snapshot has no trailing CRLF, so its own bytes may look like one.
+    assert_eq!(snapshot.as_deref(), Some(&b"REDIS0012\r\n"[..]));
+    assert_eq!(rest, b"*1\r\n$4\r\nPING\r\n");
+  }
+
+  #[tokio::test]
+  async fn reads_snapshots_up_to_the_eof_mark() {
+    let mark = "0123456789abcdef0123456789abcdef01234567";
+    let payload = format!("$EOF:{}\r\nREDIS0012{}*1\r\n$4\r\nPING\r\n", mark, mark);
+    let (snapshot, rest) = read_from_master(payload.as_bytes()).await;
+    assert_eq!(snapshot.as_deref(), Some(&b"REDIS0012"[..]));
+    assert_eq!(rest, b"*1\r\n$4\r\nPING\r\n");
+  }
+
+  #[tokio::test]
+  async fn gives_up_on_truncated_snapshots() {
+    assert_eq!(read_from_master(b"$100\r\nREDIS").await, (None, Vec::new()));
+    assert_eq!(read_from_master(b"$EOF:abc\r\nREDIS").await, (None, Vec::new()));
+    assert_eq!(read_from_master(b"+OK\r\n*1\r\n").await, (None, b"*1\r\n".to_vec()));
+  }
+}
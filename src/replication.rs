@@ -1,26 +1,111 @@
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::watch;
+
+use crate::commands::lock_execution;
+use crate::util::random_u64;
+use crate::{get_memory_instance, get_options_instance, get_replication_instance, persistence, ReplicaCommand};
+
+/// Snapshot shared by the replicas of one diskless sync, once taken.
+type DisklessPayload = Option<Arc<Vec<u8>>>;
+
 pub struct Replication {
-  pending: HashMap<String, Vec<u8>>
+  pending: HashMap<String, Vec<u8>>,
+  /// Diskless sync waiting for `repl-diskless-sync-delay` to pass, which
+  /// replicas connecting meanwhile join.
+  diskless: Option<watch::Receiver<DisklessPayload>>,
+  /// Replicas waiting for the snapshot of that diskless sync.
+  diskless_replicas: Vec<UnboundedSender<ReplicaCommand>>,
+  /// Replicas fed the writes made since their snapshot was taken.
+  replicas: Vec<UnboundedSender<ReplicaCommand>>,
 }
 
 impl Replication {
   pub fn new() -> Self {
     Replication {
-      pending: HashMap::new()
+      pending: HashMap::new(),
+      diskless: None,
+      diskless_replicas: Vec::new(),
+      replicas: Vec::new(),
     }
   }
 
-  pub fn get_latest_rdb(&mut self) -> Vec<u8> {
-    let rdb_as_hex = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
-    let _hex_to_bytes = hex::decode(rdb_as_hex).unwrap();
-    let mut r = format!("${}\r\n", _hex_to_bytes.len()).into_bytes();
-    r.extend(_hex_to_bytes);
-    return r;
+  /// Queues a write for every replica. Callers hold the execution lock, so
+  /// replicas get writes in the order they ran, and none made before their
+  /// snapshot.
+  pub fn feed(&mut self, frame: &[u8]) {
+    self.replicas.retain(|replica| replica.send(ReplicaCommand::new(frame.to_vec())).is_ok());
   }
 
-  pub fn fullresync(&mut self, to: &str) {
-    let data = self.get_latest_rdb();
-    self.add_to_queue(to, data);
+  pub fn is_diskless() -> bool {
+    get_options_instance().get("repl-diskless-sync").map(|value| value == "yes").unwrap_or(false)
+  }
+
+  /// Snapshot of the keyspace for a full resync, saved to disk first and
+  /// framed like a bulk string without the trailing CRLF. `replica` is fed
+  /// the writes from the moment the snapshot is taken.
+  pub fn full_sync_rdb(replica: UnboundedSender<ReplicaCommand>) -> Vec<u8> {
+    let (snapshot, dirty) = {
+      let _guard = lock_execution();
+      get_replication_instance().replicas.push(replica);
+      (persistence::snapshot(), get_memory_instance().dirty())
+    };
+    let rdb = persistence::save_for_sync(&snapshot, dirty);
+    let mut r = format!("${}\r\n", rdb.len()).into_bytes();
+    r.extend(rdb);
+    r
+  }
+
+  /// Snapshot of the keyspace for a diskless full resync, streamed without
+  /// a length: `$EOF:<mark>` followed by the RDB and the same 40 byte mark.
+  /// The first replica starts a sync that waits `repl-diskless-sync-delay`
+  /// seconds, so replicas connecting in the meantime share its snapshot.
+  pub async fn diskless_rdb(replica: UnboundedSender<ReplicaCommand>) -> Vec<u8> {
+    let mut receiver = {
+      let _guard = lock_execution();
+      let replication = get_replication_instance();
+      replication.diskless_replicas.push(replica);
+      match &replication.diskless {
+        Some(receiver) => receiver.clone(),
+        None => {
+          let (sender, receiver) = watch::channel(None);
+          replication.diskless = Some(receiver.clone());
+          let delay = get_options_instance()
+            .get("repl-diskless-sync-delay")
+            .and_then(|delay| delay.parse::<u64>().ok())
+            .unwrap_or(5);
+          tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+            let snapshot = {
+              let _guard = lock_execution();
+              let replication = get_replication_instance();
+              replication.diskless = None;
+              let waiting = std::mem::take(&mut replication.diskless_replicas);
+              replication.replicas.extend(waiting);
+              persistence::snapshot()
+            };
+            let rdb = persistence::serialize(&snapshot);
+            let mark = format!("{:016x}{:016x}{:08x}", random_u64(), random_u64(), random_u64() as u32);
+            let mut payload = format!("$EOF:{}\r\n", mark).into_bytes();
+            payload.extend(rdb);
+            payload.extend(mark.as_bytes());
+            _ = sender.send(Some(Arc::new(payload)));
+          });
+          receiver
+        }
+      }
+    };
+    loop {
+      if let Some(payload) = receiver.borrow().as_ref() {
+        return payload.to_vec();
+      }
+      if receiver.changed().await.is_err() {
+        return Vec::new();
+      }
+    }
   }
 
   pub fn add_to_queue(&mut self, to: &str, data: Vec<u8>) {
@@ -34,4 +119,69 @@ impl Replication {
   pub fn remove(&mut self, key: &str) {
     self.pending.remove(key);
   }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::commands::run;
+  use crate::rdb;
+  use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+  fn keys(snapshot: &[u8]) -> Vec<String> {
+    let snapshot = rdb::parse(snapshot).unwrap();
+    snapshot.entries.into_iter().map(|entry| String::from_utf8(entry.key).unwrap()).collect()
+  }
+
+  /// Whether `replica` was fed a write of `key` since its snapshot.
+  fn fed(replica: &mut UnboundedReceiver<ReplicaCommand>, key: &str) -> bool {
+    std::iter::from_fn(|| replica.try_recv().ok()).any(|write| write.message.windows(key.len()).any(|window| window == key.as_bytes()))
+  }
+
+  #[test]
+  fn full_sync_sends_the_saved_snapshot() {
+    let path = std::env::temp_dir().join(format!("rudis-sync-{}.rdb", std::process::id()));
+    {
+      let _guard = lock_execution();
+      get_options_instance().set("dbfilename", &path.display().to_string());
+    }
+    run(&["SET", "sync:before", "1"]);
+    let (sender, mut replica) = mpsc::unbounded_channel();
+    let payload = Replication::full_sync_rdb(sender);
+    run(&["SET", "sync:after", "1"]);
+    {
+      let _guard = lock_execution();
+      get_options_instance().set("dbfilename", "dump.rdb");
+    }
+
+    let header_end = payload.windows(2).position(|window| window == b"\r\n").unwrap();
+    let snapshot = &payload[header_end + 2..];
+    assert_eq!(&payload[..header_end], format!("${}", snapshot.len()).as_bytes());
+    assert!(!snapshot.ends_with(b"\r\n"));
+    assert_eq!(std::fs::read(&path).unwrap(), snapshot);
+    std::fs::remove_file(&path).unwrap();
+
+    let keys = keys(snapshot);
+    assert!(keys.contains(&"sync:before".to_string()));
+    assert!(!keys.contains(&"sync:after".to_string()));
+    assert!(fed(&mut replica, "sync:after"));
+  }
+
+  #[tokio::test]
+  async fn diskless_sync_streams_the_snapshot_between_marks() {
+    {
+      let _guard = lock_execution();
+      get_options_instance().set("repl-diskless-sync-delay", "0");
+    }
+    run(&["SET", "diskless:before", "1"]);
+    let (sender, mut replica) = mpsc::unbounded_channel();
+    let payload = Replication::diskless_rdb(sender).await;
+    run(&["SET", "diskless:after", "1"]);
+
+    let header = payload.strip_prefix(b"$EOF:").unwrap();
+    let (mark, rest) = header.split_at(40);
+    assert!(mark.iter().all(u8::is_ascii_hexdigit));
+    let snapshot = rest.strip_prefix(b"\r\n").unwrap().strip_suffix(mark).unwrap();
+    assert!(keys(snapshot).contains(&"diskless:before".to_string()));
+    assert!(fed(&mut replica, "diskless:after"));
+  }
+}
@@ -0,0 +1,69 @@
+pub fn simple_string(value: &str) -> Vec<u8> {
+    format!("+{}\r\n", value).into_bytes()
+}
+
+pub fn error(message: &str) -> Vec<u8> {
+    format!("-{}\r\n", message).into_bytes()
+}
+
+pub fn integer(value: i64) -> Vec<u8> {
+    format!(":{}\r\n", value).into_bytes()
+}
+
+pub fn bulk_string(value: &str) -> Vec<u8> {
+    format!("${}\r\n{}\r\n", value.len(), value).into_bytes()
+}
+
+pub fn bulk_bytes(value: &[u8]) -> Vec<u8> {
+    let mut response = format!("${}\r\n", value.len()).into_bytes();
+    response.extend_from_slice(value);
+    response.extend_from_slice(b"\r\n");
+    response
+}
+
+pub fn null_bulk_string() -> Vec<u8> {
+    b"$-1\r\n".to_vec()
+}
+
+pub fn optional_bulk_string(value: Option<&String>) -> Vec<u8> {
+    match value {
+        Some(value) => bulk_string(value),
+        None => null_bulk_string(),
+    }
+}
+
+pub fn null_array() -> Vec<u8> {
+    b"*-1\r\n".to_vec()
+}
+
+/// Wraps already encoded elements into a RESP array.
+pub fn array(elements: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut response = format!("*{}\r\n", elements.len()).into_bytes();
+    for element in elements {
+        response.extend(element);
+    }
+    response
+}
+
+/// RESP3 out-of-band push message, such as a pub/sub message.
+pub fn push(elements: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut response = format!(">{}\r\n", elements.len()).into_bytes();
+    for element in elements {
+        response.extend(element);
+    }
+    response
+}
+
+/// RESP3 map of already encoded keys and values.
+pub fn map(entries: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<u8> {
+    let mut response = format!("%{}\r\n", entries.len()).into_bytes();
+    for (key, value) in entries {
+        response.extend(key);
+        response.extend(value);
+    }
+    response
+}
+
+pub fn bulk_string_array<S: AsRef<str>>(values: &[S]) -> Vec<u8> {
+    array(values.iter().map(|v| bulk_string(v.as_ref())).collect())
+}
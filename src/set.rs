@@ -0,0 +1,550 @@
+use std::collections::HashSet;
+
+use crate::commands::set_propagated;
+use crate::error::CommandError;
+use crate::memory::Value;
+use crate::notify;
+use crate::resp;
+use crate::util::{check_random_count, parse_i64, random_index, ScanOptions};
+use crate::{get_memory_instance, get_options_instance};
+
+/// Sets made only of integers are stored as a sorted vector, like the intset
+/// encoding of Redis, until they grow past `set-max-intset-entries` or a
+/// non-integer member is added.
+#[derive(Clone)]
+pub enum Set {
+    IntSet(Vec<i64>),
+    Table(HashSet<String>),
+}
+
+fn max_intset_entries() -> usize {
+    get_options_instance()
+        .get("set-max-intset-entries")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(512)
+}
+
+/// Only members whose string form round-trips are integer encoded, so "007"
+/// stays a string.
+fn as_intset_member(member: &str) -> Option<i64> {
+    let value = parse_i64(member)?;
+    if value.to_string() == member {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+impl Set {
+    pub fn new() -> Self {
+        Set::IntSet(Vec::new())
+    }
+
+    pub fn from_members(members: Vec<String>) -> Self {
+        let mut set = Set::new();
+        for member in members {
+            set.insert(member);
+        }
+        set
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Set::IntSet(_) => "intset",
+            Set::Table(_) => "hashtable",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Set::IntSet(values) => values.len(),
+            Set::Table(table) => table.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, member: &str) -> bool {
+        match self {
+            Set::IntSet(values) => match as_intset_member(member) {
+                Some(value) => values.binary_search(&value).is_ok(),
+                None => false,
+            },
+            Set::Table(table) => table.contains(member),
+        }
+    }
+
+    /// Adds `member`, returning `true` if it wasn't already present.
+    pub fn insert(&mut self, member: String) -> bool {
+        if let Set::IntSet(values) = self {
+            if let Some(value) = as_intset_member(&member) {
+                return match values.binary_search(&value) {
+                    Ok(_) => false,
+                    Err(position) => {
+                        values.insert(position, value);
+                        if values.len() > max_intset_entries() {
+                            self.convert_to_table();
+                        }
+                        true
+                    }
+                };
+            }
+            self.convert_to_table();
+        }
+        match self {
+            Set::Table(table) => table.insert(member),
+            Set::IntSet(_) => unreachable!(),
+        }
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self {
+            Set::IntSet(values) => match as_intset_member(member).map(|v| values.binary_search(&v)) {
+                Some(Ok(position)) => {
+                    values.remove(position);
+                    true
+                }
+                _ => false,
+            },
+            Set::Table(table) => table.remove(member),
+        }
+    }
+
+    pub fn members(&self) -> Vec<String> {
+        match self {
+            Set::IntSet(values) => values.iter().map(|v| v.to_string()).collect(),
+            Set::Table(table) => table.iter().cloned().collect(),
+        }
+    }
+
+    fn convert_to_table(&mut self) {
+        if let Set::IntSet(values) = self {
+            let table: HashSet<String> = values.iter().map(|v| v.to_string()).collect();
+            *self = Set::Table(table);
+        }
+    }
+}
+
+fn read_members(key: &str) -> Result<Vec<String>, CommandError> {
+    Ok(get_memory_instance().get_set(key)?.map(|s| s.members()).unwrap_or_default())
+}
+
+fn read_set(key: &str) -> Result<HashSet<String>, CommandError> {
+    Ok(read_members(key)?.into_iter().collect())
+}
+
+fn intersection(keys: &[String]) -> Result<Vec<String>, CommandError> {
+    let mut sets = Vec::new();
+    for key in keys {
+        sets.push(read_set(key)?);
+    }
+    sets.sort_by_key(|s| s.len());
+    let (smallest, others) = match sets.split_first() {
+        Some(split) => split,
+        None => return Ok(Vec::new()),
+    };
+    Ok(smallest
+        .iter()
+        .filter(|member| others.iter().all(|s| s.contains(*member)))
+        .cloned()
+        .collect())
+}
+
+fn union(keys: &[String]) -> Result<Vec<String>, CommandError> {
+    let mut result: HashSet<String> = HashSet::new();
+    for key in keys {
+        result.extend(read_members(key)?);
+    }
+    Ok(result.into_iter().collect())
+}
+
+fn difference(keys: &[String]) -> Result<Vec<String>, CommandError> {
+    let mut result = read_set(&keys[0])?;
+    for key in &keys[1..] {
+        for member in read_members(key)? {
+            result.remove(&member);
+        }
+    }
+    Ok(result.into_iter().collect())
+}
+
+fn algebra(name: &str, keys: &[String]) -> Result<Vec<String>, CommandError> {
+    match name {
+        "SINTER" | "SINTERSTORE" => intersection(keys),
+        "SUNION" | "SUNIONSTORE" => union(keys),
+        _ => difference(keys),
+    }
+}
+
+fn sadd(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let memory = get_memory_instance();
+    let set = memory.get_or_create_set(&commands[1])?;
+    let added = commands[2..].iter().filter(|m| set.insert(m.to_string())).count();
+    if added > 0 {
+        memory.touch(&commands[1]);
+    }
+    Ok(resp::integer(added as i64))
+}
+
+fn srem(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let memory = get_memory_instance();
+    let removed = match memory.get_set_mut(&commands[1])? {
+        Some(set) => commands[2..].iter().filter(|m| set.remove(m)).count(),
+        None => 0,
+    };
+    if removed > 0 {
+        memory.touch(&commands[1]);
+    }
+    memory.remove_if_empty(&commands[1]);
+    Ok(resp::integer(removed as i64))
+}
+
+fn smembers(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 2 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    Ok(resp::bulk_string_array(&read_members(&commands[1])?))
+}
+
+fn sismember(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let set = get_memory_instance().get_set(&commands[1])?;
+    Ok(resp::integer(set.map(|s| s.contains(&commands[2])).unwrap_or(false) as i64))
+}
+
+fn smismember(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let set = get_memory_instance().get_set(&commands[1])?;
+    Ok(resp::array(
+        commands[2..]
+            .iter()
+            .map(|m| resp::integer(set.map(|s| s.contains(m)).unwrap_or(false) as i64))
+            .collect(),
+    ))
+}
+
+fn scard(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 2 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let set = get_memory_instance().get_set(&commands[1])?;
+    Ok(resp::integer(set.map(|s| s.len()).unwrap_or(0) as i64))
+}
+
+fn parse_count(commands: &[String]) -> Result<Option<i64>, CommandError> {
+    match commands.get(2) {
+        Some(count) => Ok(Some(parse_i64(count).ok_or(CommandError::NotInteger)?)),
+        None => Ok(None),
+    }
+}
+
+fn spop(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 2 || commands.len() > 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let count = parse_count(commands)?;
+    if count.map(|c| c < 0).unwrap_or(false) {
+        return Err(CommandError::Other("value is out of range, must be positive".to_string()));
+    }
+
+    let memory = get_memory_instance();
+    let mut popped = Vec::new();
+    if let Some(set) = memory.get_set_mut(&commands[1])? {
+        let mut members = set.members();
+        while !members.is_empty() && popped.len() < count.unwrap_or(1) as usize {
+            let member = members.swap_remove(random_index(members.len()));
+            set.remove(&member);
+            popped.push(member);
+        }
+    }
+    if !popped.is_empty() {
+        memory.touch(&commands[1]);
+    }
+    memory.remove_if_empty(&commands[1]);
+
+    // Replicas remove the members picked here rather than random ones.
+    let mut propagated = Vec::new();
+    if !popped.is_empty() {
+        propagated.extend(["SREM".to_string(), commands[1].to_string()]);
+        propagated.extend(popped.iter().cloned());
+    }
+    set_propagated(propagated);
+
+    match count {
+        Some(_) => Ok(resp::bulk_string_array(&popped)),
+        None => Ok(resp::optional_bulk_string(popped.first())),
+    }
+}
+
+fn srandmember(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 2 || commands.len() > 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let count = parse_count(commands)?;
+    check_random_count(count)?;
+    let mut members = read_members(&commands[1])?;
+
+    let count = match count {
+        Some(count) => count,
+        None if members.is_empty() => return Ok(resp::null_bulk_string()),
+        None => return Ok(resp::bulk_string(&members[random_index(members.len())])),
+    };
+
+    let picked: Vec<String> = if members.is_empty() {
+        Vec::new()
+    } else if count < 0 {
+        (0..count.unsigned_abs()).map(|_| members[random_index(members.len())].to_string()).collect()
+    } else {
+        let mut picked = Vec::new();
+        while !members.is_empty() && picked.len() < count as usize {
+            picked.push(members.swap_remove(random_index(members.len())));
+        }
+        picked
+    };
+    Ok(resp::bulk_string_array(&picked))
+}
+
+fn smove(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let memory = get_memory_instance();
+    // Check the destination type before touching the source.
+    memory.get_set(&commands[2])?;
+    let moved = match memory.get_set_mut(&commands[1])? {
+        Some(set) => set.remove(&commands[3]),
+        None => false,
+    };
+    if moved {
+        memory.touch(&commands[1]);
+        notify::key_event('s', "srem", &commands[1]);
+        memory.get_or_create_set(&commands[2])?.insert(commands[3].to_string());
+        memory.touch(&commands[2]);
+        notify::key_event('s', "sadd", &commands[2]);
+        memory.remove_if_empty(&commands[1]);
+    }
+    Ok(resp::integer(moved as i64))
+}
+
+fn set_algebra(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 2 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    Ok(resp::bulk_string_array(&algebra(&commands[0], &commands[1..])?))
+}
+
+fn set_algebra_store(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let members = algebra(&commands[0], &commands[2..])?;
+    let size = members.len();
+
+    let memory = get_memory_instance();
+    memory.remove(&commands[1]);
+    if size > 0 {
+        memory.set_value(commands[1].to_string(), Value::Set(Set::from_members(members)));
+    }
+    Ok(resp::integer(size as i64))
+}
+
+/// `SINTERCARD numkeys key [key ...] [LIMIT limit]`
+fn sintercard(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let numkeys = match parse_i64(&commands[1]) {
+        Some(numkeys) if numkeys > 0 => numkeys as usize,
+        _ => return Err(CommandError::Other("numkeys should be greater than 0".to_string())),
+    };
+    if commands.len() < 2 + numkeys {
+        return Err(CommandError::Other("Number of keys can't be greater than number of args".to_string()));
+    }
+    let keys = &commands[2..2 + numkeys];
+    let limit = match &commands[2 + numkeys..] {
+        [] => 0,
+        [option, limit] if option.eq_ignore_ascii_case("LIMIT") => match parse_i64(limit) {
+            Some(limit) if limit >= 0 => limit as usize,
+            _ => return Err(CommandError::Other("LIMIT can't be negative".to_string())),
+        },
+        _ => return Err(CommandError::Syntax),
+    };
+
+    let size = intersection(keys)?.len();
+    if limit > 0 {
+        return Ok(resp::integer(size.min(limit) as i64));
+    }
+    Ok(resp::integer(size as i64))
+}
+
+fn sscan(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let options = ScanOptions::parse(&commands[2], &commands[3..], false)?;
+    let set = match get_memory_instance().get_set(&commands[1])? {
+        Some(set) => set,
+        None => return Ok(ScanOptions::response(0, Vec::new())),
+    };
+
+    let members = set.members();
+    // Table iteration order changes as the set grows, so tables page by hash.
+    let (next, page) = match set {
+        Set::IntSet(_) => (0, members),
+        Set::Table(_) => options.page_by_hash(members, |member| member.as_str()),
+    };
+    let elements = page
+        .iter()
+        .filter(|m| options.matches(m))
+        .map(|m| resp::bulk_string(m))
+        .collect();
+    Ok(ScanOptions::response(next, elements))
+}
+
+pub fn process_command(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    match commands[0].as_str() {
+        "SADD" => sadd(commands),
+        "SREM" => srem(commands),
+        "SMEMBERS" => smembers(commands),
+        "SISMEMBER" => sismember(commands),
+        "SMISMEMBER" => smismember(commands),
+        "SCARD" => scard(commands),
+        "SPOP" => spop(commands),
+        "SRANDMEMBER" => srandmember(commands),
+        "SMOVE" => smove(commands),
+        "SINTER" | "SUNION" | "SDIFF" => set_algebra(commands),
+        "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE" => set_algebra_store(commands),
+        "SINTERCARD" => sintercard(commands),
+        "SSCAN" => sscan(commands),
+        _ => Err(CommandError::Other(format!("unknown command '{}'", commands[0]))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{lock_execution, run, run_propagated, strings};
+
+    fn sorted(reply: Vec<u8>) -> Vec<String> {
+        let mut members = strings(&reply);
+        members.sort();
+        members
+    }
+
+    #[test]
+    fn intset_converts_to_table() {
+        let _guard = lock_execution();
+        let mut set = Set::from_members(vec!["3".to_string(), "-1".to_string(), "2".to_string()]);
+        assert_eq!(set.encoding(), "intset");
+        assert_eq!(set.members(), ["-1", "2", "3"]);
+        assert!(!set.insert("2".to_string()));
+        assert!(set.contains("3") && !set.contains("03"));
+
+        // Integers that don't round-trip stay strings.
+        assert!(set.insert("007".to_string()));
+        assert_eq!(set.encoding(), "hashtable");
+        assert!(set.contains("007") && set.contains("-1") && !set.contains("7"));
+
+        let mut set = Set::new();
+        for i in 0..512 {
+            set.insert(i.to_string());
+        }
+        assert_eq!(set.encoding(), "intset");
+        set.insert("512".to_string());
+        assert_eq!(set.encoding(), "hashtable");
+        assert_eq!(set.len(), 513);
+        assert!(set.remove("0") && !set.remove("0"));
+    }
+
+    #[test]
+    fn member_commands() {
+        assert_eq!(run(&["SADD", "set:members", "a", "b", "a", "c"]), resp::integer(3));
+        assert_eq!(run(&["SADD", "set:members", "a"]), resp::integer(0));
+        assert_eq!(run(&["SCARD", "set:members"]), resp::integer(3));
+        assert_eq!(run(&["SISMEMBER", "set:members", "b"]), resp::integer(1));
+        let flags = resp::array(vec![resp::integer(1), resp::integer(0)]);
+        assert_eq!(run(&["SMISMEMBER", "set:members", "c", "d"]), flags);
+        assert_eq!(run(&["SREM", "set:members", "a", "d"]), resp::integer(1));
+        assert_eq!(sorted(run(&["SMEMBERS", "set:members"])), ["b", "c"]);
+        assert_eq!(run(&["SMOVE", "set:members", "set:moved", "b"]), resp::integer(1));
+        assert_eq!(run(&["SMOVE", "set:members", "set:moved", "b"]), resp::integer(0));
+        assert_eq!(run(&["SREM", "set:members", "c"]), resp::integer(1));
+        assert_eq!(run(&["TYPE", "set:members"]), resp::simple_string("none"));
+        assert_eq!(run(&["SMEMBERS", "set:moved"]), resp::bulk_string_array(&["b"]));
+        assert_eq!(run(&["OBJECT", "ENCODING", "set:moved"]), resp::bulk_string("hashtable"));
+    }
+
+    #[test]
+    fn algebra() {
+        run(&["SADD", "set:algebra1", "a", "b", "c", "d"]);
+        run(&["SADD", "set:algebra2", "c", "d", "e"]);
+        run(&["SADD", "set:algebra3", "a", "c", "e"]);
+        assert_eq!(sorted(run(&["SINTER", "set:algebra1", "set:algebra2", "set:algebra3"])), ["c"]);
+        assert_eq!(sorted(run(&["SINTER", "set:algebra1", "set:missing"])), Vec::<String>::new());
+        assert_eq!(sorted(run(&["SUNION", "set:algebra2", "set:missing", "set:algebra3"])), ["a", "c", "d", "e"]);
+        assert_eq!(sorted(run(&["SDIFF", "set:algebra1", "set:algebra2"])), ["a", "b"]);
+        assert_eq!(sorted(run(&["SDIFF", "set:missing", "set:algebra2"])), Vec::<String>::new());
+        assert_eq!(run(&["SINTERCARD", "2", "set:algebra1", "set:algebra2"]), resp::integer(2));
+        assert_eq!(run(&["SINTERCARD", "2", "set:algebra1", "set:algebra2", "LIMIT", "1"]), resp::integer(1));
+        assert_eq!(
+            run(&["SINTERCARD", "3", "set:algebra1", "set:algebra2"]),
+            resp::error("ERR Number of keys can't be greater than number of args")
+        );
+
+        assert_eq!(run(&["SUNIONSTORE", "set:stored", "set:algebra1", "set:algebra2"]), resp::integer(5));
+        assert_eq!(run(&["SCARD", "set:stored"]), resp::integer(5));
+        // An empty result deletes the destination.
+        assert_eq!(run(&["SINTERSTORE", "set:stored", "set:algebra1", "set:missing"]), resp::integer(0));
+        assert_eq!(run(&["TYPE", "set:stored"]), resp::simple_string("none"));
+
+        run(&["SET", "set:string", "x"]);
+        let wrong_type = resp::error("WRONGTYPE Operation against a key holding the wrong kind of value");
+        assert_eq!(run(&["SUNION", "set:algebra1", "set:string"]), wrong_type);
+    }
+
+    #[test]
+    fn stored_results_pick_their_encoding() {
+        run(&["SADD", "set:ints", "1", "2", "3"]);
+        run(&["SADD", "set:mixed", "2", "x"]);
+        run(&["SINTERSTORE", "set:ints_only", "set:ints", "set:mixed"]);
+        assert_eq!(run(&["OBJECT", "ENCODING", "set:ints_only"]), resp::bulk_string("intset"));
+        run(&["SUNIONSTORE", "set:strings", "set:ints", "set:mixed"]);
+        assert_eq!(run(&["OBJECT", "ENCODING", "set:strings"]), resp::bulk_string("hashtable"));
+    }
+
+    #[test]
+    fn pops_and_random_members() {
+        run(&["SADD", "set:pop", "a", "b", "c", "d"]);
+        assert_eq!(strings(&run(&["SRANDMEMBER", "set:pop", "-6"])).len(), 6);
+        assert_eq!(sorted(run(&["SRANDMEMBER", "set:pop", "10"])), ["a", "b", "c", "d"]);
+        let out_of_range = resp::error("ERR value is out of range");
+        assert_eq!(run(&["SRANDMEMBER", "set:pop", &i64::MIN.to_string()]), out_of_range);
+        assert_eq!(run(&["SRANDMEMBER", "set:missing"]), resp::null_bulk_string());
+        assert_eq!(
+            run(&["SPOP", "set:pop", "-1"]),
+            resp::error("ERR value is out of range, must be positive")
+        );
+
+        // Replicas remove the members that were actually popped.
+        let (propagated, _) = run_propagated(&["SPOP", "set:pop", "3"]).unwrap();
+        assert_eq!(propagated[..2], ["SREM", "set:pop"]);
+        assert_eq!(propagated.len(), 5);
+        let left = strings(&run(&["SMEMBERS", "set:pop"]));
+        assert_eq!(left.len(), 1);
+        assert!(!propagated[2..].contains(&left[0]));
+        assert_eq!(run(&["SPOP", "set:pop"]), resp::bulk_string(&left[0]));
+        assert_eq!(run_propagated(&["SPOP", "set:pop"]), None);
+    }
+}
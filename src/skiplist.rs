@@ -0,0 +1,344 @@
+use crate::util::random_u64;
+
+const MAX_LEVEL: usize = 32;
+const HEADER: usize = 0;
+
+#[derive(Clone)]
+struct Level {
+    forward: Option<usize>,
+    /// Number of level-0 hops covered by `forward`, used to compute ranks.
+    span: usize,
+}
+
+#[derive(Clone)]
+struct Node {
+    member: String,
+    score: f64,
+    backward: Option<usize>,
+    levels: Vec<Level>,
+}
+
+/// Skiplist ordered by (score, member) as in the Redis zset implementation.
+/// Nodes live in an arena and link to each other by index; index 0 is the
+/// header. Ranks handed in and out are 1-based.
+#[derive(Clone)]
+pub struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    tail: Option<usize>,
+    length: usize,
+    level: usize,
+}
+
+fn random_level() -> usize {
+    let mut level = 1;
+    // Each extra level is taken with a 1/4 probability.
+    while level < MAX_LEVEL && (random_u64() & 3) == 0 {
+        level += 1;
+    }
+    level
+}
+
+impl SkipList {
+    pub fn new() -> Self {
+        SkipList {
+            nodes: vec![Node {
+                member: String::new(),
+                score: 0.0,
+                backward: None,
+                levels: vec![Level { forward: None, span: 0 }; MAX_LEVEL],
+            }],
+            free: Vec::new(),
+            tail: None,
+            length: 0,
+            level: 1,
+        }
+    }
+
+    fn is_before(&self, id: usize, score: f64, member: &str) -> bool {
+        let node = &self.nodes[id];
+        node.score < score || (node.score == score && node.member.as_str() < member)
+    }
+
+    fn allocate(&mut self, node: Node) -> usize {
+        match self.free.pop() {
+            Some(id) => {
+                self.nodes[id] = node;
+                id
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    /// Inserts a member that must not already be part of the list.
+    pub fn insert(&mut self, score: f64, member: String) {
+        let mut update = [HEADER; MAX_LEVEL];
+        let mut rank = [0usize; MAX_LEVEL];
+
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                if !self.is_before(next, score, &member) {
+                    break;
+                }
+                rank[i] += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let level = random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEADER;
+                self.nodes[HEADER].levels[i].span = self.length;
+            }
+            self.level = level;
+        }
+
+        let id = self.allocate(Node {
+            member,
+            score,
+            backward: None,
+            levels: vec![Level { forward: None, span: 0 }; level],
+        });
+        for i in 0..level {
+            let prev = update[i];
+            self.nodes[id].levels[i].forward = self.nodes[prev].levels[i].forward;
+            self.nodes[prev].levels[i].forward = Some(id);
+            self.nodes[id].levels[i].span = self.nodes[prev].levels[i].span - (rank[0] - rank[i]);
+            self.nodes[prev].levels[i].span = (rank[0] - rank[i]) + 1;
+        }
+        for (i, prev) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[*prev].levels[i].span += 1;
+        }
+
+        self.nodes[id].backward = if update[0] == HEADER { None } else { Some(update[0]) };
+        match self.nodes[id].levels[0].forward {
+            Some(next) => self.nodes[next].backward = Some(id),
+            None => self.tail = Some(id),
+        }
+        self.length += 1;
+    }
+
+    /// Removes the node matching `score` and `member`, returning whether it
+    /// was found.
+    pub fn remove(&mut self, score: f64, member: &str) -> bool {
+        let mut update = [HEADER; MAX_LEVEL];
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                if !self.is_before(next, score, member) {
+                    break;
+                }
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let target = match self.nodes[x].levels[0].forward {
+            Some(id) if self.nodes[id].score == score && self.nodes[id].member == member => id,
+            _ => return false,
+        };
+
+        for (i, prev) in update.iter().enumerate().take(self.level) {
+            if self.nodes[*prev].levels[i].forward == Some(target) {
+                self.nodes[*prev].levels[i].span += self.nodes[target].levels[i].span;
+                self.nodes[*prev].levels[i].span -= 1;
+                self.nodes[*prev].levels[i].forward = self.nodes[target].levels[i].forward;
+            } else {
+                self.nodes[*prev].levels[i].span -= 1;
+            }
+        }
+        match self.nodes[target].levels[0].forward {
+            Some(next) => self.nodes[next].backward = self.nodes[target].backward,
+            None => self.tail = self.nodes[target].backward,
+        }
+        while self.level > 1 && self.nodes[HEADER].levels[self.level - 1].forward.is_none() {
+            self.level -= 1;
+        }
+
+        self.nodes[target].member = String::new();
+        self.nodes[target].levels = Vec::new();
+        self.free.push(target);
+        self.length -= 1;
+        true
+    }
+
+    /// 1-based rank of the element, if present.
+    pub fn rank(&self, score: f64, member: &str) -> Option<usize> {
+        let mut rank = 0;
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                let node = &self.nodes[next];
+                if !(node.score < score || (node.score == score && node.member.as_str() <= member)) {
+                    break;
+                }
+                rank += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if x != HEADER && self.nodes[x].member == member {
+                return Some(rank);
+            }
+        }
+        None
+    }
+
+    /// Node at the given 1-based rank.
+    pub fn by_rank(&self, rank: usize) -> Option<usize> {
+        let mut traversed = 0;
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                if traversed + self.nodes[x].levels[i].span > rank {
+                    break;
+                }
+                traversed += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if traversed == rank && x != HEADER {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    /// First node for which `reached` holds, given that `reached` is monotonic
+    /// along the list order.
+    pub fn first_where<F: Fn(f64, &str) -> bool>(&self, reached: F) -> Option<usize> {
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                if reached(self.nodes[next].score, &self.nodes[next].member) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        self.nodes[x].levels[0].forward
+    }
+
+    /// Last node for which `within` holds, given that `within` holds for a
+    /// prefix of the list.
+    pub fn last_where<F: Fn(f64, &str) -> bool>(&self, within: F) -> Option<usize> {
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                if !within(self.nodes[next].score, &self.nodes[next].member) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        if x == HEADER {
+            None
+        } else {
+            Some(x)
+        }
+    }
+
+    pub fn first(&self) -> Option<usize> {
+        self.nodes[HEADER].levels[0].forward
+    }
+
+    pub fn last(&self) -> Option<usize> {
+        self.tail
+    }
+
+    pub fn next(&self, id: usize) -> Option<usize> {
+        self.nodes[id].levels[0].forward
+    }
+
+    pub fn prev(&self, id: usize) -> Option<usize> {
+        self.nodes[id].backward
+    }
+
+    pub fn entry(&self, id: usize) -> (&String, f64) {
+        (&self.nodes[id].member, self.nodes[id].score)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(list: &SkipList) -> Vec<(String, f64)> {
+        let mut entries = Vec::new();
+        let mut node = list.first();
+        while let Some(id) = node {
+            let (member, score) = list.entry(id);
+            entries.push((member.clone(), score));
+            node = list.next(id);
+        }
+        entries
+    }
+
+    #[test]
+    fn orders_by_score_then_member() {
+        let mut list = SkipList::new();
+        for (score, member) in [(2.0, "b"), (1.0, "z"), (2.0, "a"), (-1.5, "c")] {
+            list.insert(score, member.to_string());
+        }
+        let expected = [("c", -1.5), ("z", 1.0), ("a", 2.0), ("b", 2.0)];
+        let expected: Vec<(String, f64)> = expected.iter().map(|(m, s)| (m.to_string(), *s)).collect();
+        assert_eq!(members(&list), expected);
+        assert_eq!(list.entry(list.last().unwrap()).0, "b");
+        assert_eq!(list.entry(list.prev(list.last().unwrap()).unwrap()).0, "a");
+    }
+
+    #[test]
+    fn ranks_match_positions() {
+        let mut list = SkipList::new();
+        for i in (0..500).rev() {
+            list.insert(i as f64, format!("m{}", i));
+        }
+        for i in 0..500 {
+            assert_eq!(list.rank(i as f64, &format!("m{}", i)), Some(i + 1));
+            assert_eq!(list.entry(list.by_rank(i + 1).unwrap()).0, &format!("m{}", i));
+        }
+        assert_eq!(list.rank(1.0, "m2"), None);
+        assert_eq!(list.by_rank(0), None);
+        assert_eq!(list.by_rank(501), None);
+    }
+
+    #[test]
+    fn remove_keeps_ranks_and_reuses_nodes() {
+        let mut list = SkipList::new();
+        for i in 0..100 {
+            list.insert(i as f64, format!("m{}", i));
+        }
+        for i in (0..100).step_by(2) {
+            assert!(list.remove(i as f64, &format!("m{}", i)));
+        }
+        assert!(!list.remove(0.0, "m0"));
+        assert!(!list.remove(3.0, "m4"));
+        assert_eq!(list.length, 50);
+        assert_eq!(list.rank(51.0, "m51"), Some(26));
+
+        let allocated = list.nodes.len();
+        list.insert(0.5, "new".to_string());
+        assert_eq!(list.nodes.len(), allocated);
+        assert_eq!(list.rank(0.5, "new"), Some(1));
+        assert_eq!(list.entry(list.last().unwrap()).0, "m99");
+    }
+
+    #[test]
+    fn finds_range_bounds() {
+        let mut list = SkipList::new();
+        for i in 0..10 {
+            list.insert(i as f64, format!("m{}", i));
+        }
+        let first = list.first_where(|score, _| score >= 3.5).unwrap();
+        assert_eq!(list.entry(first).0, "m4");
+        let last = list.last_where(|score, _| score <= 6.0).unwrap();
+        assert_eq!(list.entry(last).0, "m6");
+        assert_eq!(list.first_where(|score, _| score > 9.0), None);
+        assert_eq!(list.last_where(|score, _| score < 0.0), None);
+    }
+}
@@ -0,0 +1,846 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use crate::blocking::block_on_keys;
+use crate::commands::{lock_execution, set_propagated};
+use crate::consumer_group::ConsumerGroup;
+use crate::error::CommandError;
+use crate::resp;
+use crate::util::parse_i64;
+use crate::{get_current_time, get_memory_instance, get_options_instance};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    /// Parses `ms-seq`, or a bare `ms` completed with `missing_seq`.
+    pub fn parse(value: &str, missing_seq: u64) -> Result<Self, CommandError> {
+        let invalid = || CommandError::Other("Invalid stream ID specified as stream command argument".to_string());
+        match value.split_once('-') {
+            Some((ms, seq)) => Ok(StreamId {
+                ms: ms.parse().map_err(|_| invalid())?,
+                seq: seq.parse().map_err(|_| invalid())?,
+            }),
+            None => Ok(StreamId { ms: value.parse().map_err(|_| invalid())?, seq: missing_seq }),
+        }
+    }
+
+    /// Parses a range boundary: `-`, `+`, an ID, or `(` followed by an ID
+    /// for exclusive ranges.
+    pub fn parse_range_bound(value: &str, start: bool) -> Result<Self, CommandError> {
+        match value {
+            "-" => Ok(StreamId::MIN),
+            "+" => Ok(StreamId::MAX),
+            _ => {
+                let missing_seq = if start { 0 } else { u64::MAX };
+                match value.strip_prefix('(') {
+                    Some(id) => {
+                        let id = StreamId::parse(id, missing_seq)?;
+                        let exclusive = if start { id.next() } else { id.prev() };
+                        exclusive.ok_or_else(|| {
+                            CommandError::Other("invalid start ID for the interval".to_string())
+                        })
+                    }
+                    None => StreamId::parse(value, missing_seq),
+                }
+            }
+        }
+    }
+
+    pub fn next(&self) -> Option<StreamId> {
+        if self.seq < u64::MAX {
+            Some(StreamId { ms: self.ms, seq: self.seq + 1 })
+        } else if self.ms < u64::MAX {
+            Some(StreamId { ms: self.ms + 1, seq: 0 })
+        } else {
+            None
+        }
+    }
+
+    pub fn prev(&self) -> Option<StreamId> {
+        if self.seq > 0 {
+            Some(StreamId { ms: self.ms, seq: self.seq - 1 })
+        } else if self.ms > 0 {
+            Some(StreamId { ms: self.ms - 1, seq: u64::MAX })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+#[derive(Clone)]
+pub struct StreamEntry {
+    pub id: StreamId,
+    pub fields: Vec<(String, String)>,
+}
+
+fn max_node_entries() -> usize {
+    get_options_instance()
+        .get("stream-node-max-entries")
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(100)
+}
+
+/// Entries are grouped in nodes of up to `stream-node-max-entries`, indexed
+/// by the ID of their first entry, mirroring the radix tree of listpacks
+/// Redis uses. Approximate trimming only ever drops whole nodes.
+#[derive(Clone)]
+pub struct Stream {
+    nodes: BTreeMap<StreamId, Vec<StreamEntry>>,
+    length: usize,
+    pub last_id: StreamId,
+    pub max_deleted_id: StreamId,
+    pub entries_added: u64,
+    pub groups: BTreeMap<String, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Stream {
+            nodes: BTreeMap::new(),
+            length: 0,
+            last_id: StreamId::MIN,
+            max_deleted_id: StreamId::MIN,
+            entries_added: 0,
+            groups: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Resolves the ID requested by `XADD`: `*`, `ms-*` or an explicit ID
+    /// that has to be greater than the last one.
+    pub fn next_id(&self, requested: &str) -> Result<StreamId, CommandError> {
+        let too_small = || {
+            CommandError::Other("The ID specified in XADD is equal or smaller than the target stream top item".to_string())
+        };
+        let id = if requested == "*" {
+            let ms = (get_current_time() as u64).max(self.last_id.ms);
+            if ms == self.last_id.ms {
+                if self.last_id.seq == u64::MAX {
+                    return Err(too_small());
+                }
+                StreamId { ms, seq: self.last_id.seq + 1 }
+            } else {
+                StreamId { ms, seq: 0 }
+            }
+        } else if let Some(ms) = requested.strip_suffix("-*") {
+            let ms: u64 = ms
+                .parse()
+                .map_err(|_| CommandError::Other("Invalid stream ID specified as stream command argument".to_string()))?;
+            if ms < self.last_id.ms || (ms == self.last_id.ms && self.last_id.seq == u64::MAX) {
+                return Err(too_small());
+            }
+            let seq = if ms == self.last_id.ms { self.last_id.seq + 1 } else { 0 };
+            StreamId { ms, seq }
+        } else {
+            StreamId::parse(requested, 0)?
+        };
+
+        if id == StreamId::MIN {
+            return Err(CommandError::Other("The ID specified in XADD must be greater than 0-0".to_string()));
+        }
+        if id <= self.last_id {
+            return Err(too_small());
+        }
+        Ok(id)
+    }
+
+    pub fn append(&mut self, id: StreamId, fields: Vec<(String, String)>) {
+        let max_entries = max_node_entries();
+        let entry = StreamEntry { id, fields };
+        match self.nodes.values_mut().next_back() {
+            Some(node) if node.len() < max_entries => node.push(entry),
+            _ => {
+                self.nodes.insert(id, vec![entry]);
+            }
+        }
+        self.length += 1;
+        self.entries_added += 1;
+        self.last_id = id;
+    }
+
+    pub fn first_entry(&self) -> Option<&StreamEntry> {
+        self.nodes.values().next().and_then(|node| node.first())
+    }
+
+    pub fn last_entry(&self) -> Option<&StreamEntry> {
+        self.nodes.values().next_back().and_then(|node| node.last())
+    }
+
+    /// Entries with IDs in `[start, end]`, newest first when `rev`.
+    pub fn range(&self, start: StreamId, end: StreamId, count: Option<usize>, rev: bool) -> Vec<&StreamEntry> {
+        if start > end {
+            return Vec::new();
+        }
+        let limit = count.unwrap_or(usize::MAX);
+        // The node holding `start` is keyed by an ID lower or equal to it.
+        let from = self.nodes.range(..=start).next_back().map(|(k, _)| *k).unwrap_or(start);
+        let nodes = self.nodes.range(from..=end);
+        let within = |entry: &&StreamEntry| entry.id >= start && entry.id <= end;
+        if rev {
+            nodes.rev().flat_map(|(_, node)| node.iter().rev()).filter(within).take(limit).collect()
+        } else {
+            nodes.flat_map(|(_, node)| node.iter()).filter(within).take(limit).collect()
+        }
+    }
+
+    pub fn remove(&mut self, id: StreamId) -> bool {
+        let key = match self.nodes.range(..=id).next_back() {
+            Some((key, _)) => *key,
+            None => return false,
+        };
+        let node = self.nodes.get_mut(&key).unwrap();
+        let position = match node.iter().position(|entry| entry.id == id) {
+            Some(position) => position,
+            None => return false,
+        };
+        node.remove(position);
+        if node.is_empty() {
+            self.nodes.remove(&key);
+        }
+        self.length -= 1;
+        if id > self.max_deleted_id {
+            self.max_deleted_id = id;
+        }
+        true
+    }
+
+    fn drop_first_node(&mut self) -> usize {
+        match self.nodes.pop_first() {
+            Some((_, node)) => {
+                self.length -= node.len();
+                if let Some(last) = node.last() {
+                    if last.id > self.max_deleted_id {
+                        self.max_deleted_id = last.id;
+                    }
+                }
+                node.len()
+            }
+            None => 0,
+        }
+    }
+
+    fn drop_first_entry(&mut self) -> bool {
+        match self.first_entry().map(|entry| entry.id) {
+            Some(id) => self.remove(id),
+            None => false,
+        }
+    }
+
+    /// Trims the stream according to `strategy`, returning how many entries
+    /// were removed.
+    pub fn trim(&mut self, strategy: &TrimStrategy) -> usize {
+        let mut removed = 0;
+        let limit = strategy.limit.unwrap_or(usize::MAX);
+        while let Some(node) = self.nodes.values().next() {
+            let first_node_len = node.len();
+            let first_node_last = node.last().map(|e| e.id);
+            let first_id = match node.first() {
+                Some(entry) => entry.id,
+                None => break,
+            };
+
+            if strategy.approximate {
+                let whole_node_fits = match strategy.threshold {
+                    TrimThreshold::MaxLen(max) => self.length - first_node_len >= max,
+                    TrimThreshold::MinId(min) => first_node_last.map(|id| id < min).unwrap_or(false),
+                };
+                if !whole_node_fits || removed + first_node_len > limit {
+                    break;
+                }
+                removed += self.drop_first_node();
+            } else {
+                let over = match strategy.threshold {
+                    TrimThreshold::MaxLen(max) => self.length > max,
+                    TrimThreshold::MinId(min) => first_id < min,
+                };
+                if !over || removed >= limit {
+                    break;
+                }
+                if self.drop_first_entry() {
+                    removed += 1;
+                }
+            }
+        }
+        removed
+    }
+
+    /// Exact trim arguments leaving a stream with the same history like this
+    /// one. Approximate trims depend on the node layout, which replicas and
+    /// AOF reloads don't share, so they are propagated in this form.
+    fn exact_trim_args(&self) -> Vec<String> {
+        match self.first_entry() {
+            Some(entry) => vec!["MINID".to_string(), "=".to_string(), entry.id.to_string()],
+            None => vec!["MAXLEN".to_string(), "=".to_string(), "0".to_string()],
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum TrimThreshold {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+pub struct TrimStrategy {
+    pub threshold: TrimThreshold,
+    pub approximate: bool,
+    pub limit: Option<usize>,
+}
+
+impl TrimStrategy {
+    /// Parses `MAXLEN|MINID [=|~] threshold [LIMIT count]` starting at `args[0]`,
+    /// returning the strategy and the number of arguments consumed.
+    pub fn parse(args: &[String]) -> Result<(Self, usize), CommandError> {
+        let mut idx = 1;
+        let approximate = match args.get(idx).map(|s| s.as_str()) {
+            Some("~") => {
+                idx += 1;
+                true
+            }
+            Some("=") => {
+                idx += 1;
+                false
+            }
+            _ => false,
+        };
+        let value = args.get(idx).ok_or(CommandError::Syntax)?;
+        idx += 1;
+
+        let threshold = match args[0].to_ascii_uppercase().as_str() {
+            "MAXLEN" => match parse_i64(value) {
+                Some(max) if max >= 0 => TrimThreshold::MaxLen(max as usize),
+                Some(_) => return Err(CommandError::Other("The MAXLEN argument must be >= 0.".to_string())),
+                None => return Err(CommandError::NotInteger),
+            },
+            _ => TrimThreshold::MinId(StreamId::parse(value, 0)?),
+        };
+
+        let mut limit = None;
+        if args.get(idx).map(|s| s.eq_ignore_ascii_case("LIMIT")).unwrap_or(false) {
+            if !approximate {
+                return Err(CommandError::Other(
+                    "syntax error, LIMIT cannot be used without the special ~ option".to_string(),
+                ));
+            }
+            limit = match args.get(idx + 1).and_then(|v| parse_i64(v)) {
+                Some(limit) if limit >= 0 => Some(limit as usize),
+                _ => return Err(CommandError::Other("The LIMIT argument must be >= 0.".to_string())),
+            };
+            idx += 2;
+        }
+        // Like Redis, approximate trimming is capped by default so a single
+        // call never does unbounded work. A LIMIT of 0 means no cap.
+        let limit = match limit {
+            Some(0) => None,
+            Some(limit) => Some(limit),
+            None if approximate => Some(100 * max_node_entries()),
+            None => None,
+        };
+        Ok((TrimStrategy { threshold, approximate, limit }, idx))
+    }
+}
+
+pub fn entry_response(entry: &StreamEntry) -> Vec<u8> {
+    let mut fields = Vec::new();
+    for (field, value) in &entry.fields {
+        fields.push(resp::bulk_string(field));
+        fields.push(resp::bulk_string(value));
+    }
+    resp::array(vec![resp::bulk_string(&entry.id.to_string()), resp::array(fields)])
+}
+
+pub fn entries_response(entries: &[&StreamEntry]) -> Vec<u8> {
+    resp::array(entries.iter().map(|entry| entry_response(entry)).collect())
+}
+
+fn is_trim_option(arg: &str) -> bool {
+    arg.eq_ignore_ascii_case("MAXLEN") || arg.eq_ignore_ascii_case("MINID")
+}
+
+/// `XADD key [NOMKSTREAM] [MAXLEN|MINID [=|~] threshold [LIMIT count]] *|id field value [field value ...]`
+fn xadd(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 5 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let mut idx = 2;
+    let mut no_mkstream = false;
+    let mut trim = None;
+    loop {
+        match commands.get(idx) {
+            Some(arg) if arg.eq_ignore_ascii_case("NOMKSTREAM") => {
+                no_mkstream = true;
+                idx += 1;
+            }
+            Some(arg) if is_trim_option(arg) => {
+                let (strategy, consumed) = TrimStrategy::parse(&commands[idx..])?;
+                trim = Some((strategy, idx..idx + consumed));
+                idx += consumed;
+            }
+            _ => break,
+        }
+    }
+    let pairs = commands.get(idx + 1..).unwrap_or_default();
+    if pairs.is_empty() || pairs.len() % 2 == 1 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+
+    let memory = get_memory_instance();
+    if no_mkstream && memory.get_stream(&commands[1])?.is_none() {
+        return Ok(resp::null_bulk_string());
+    }
+    let id = match memory.get_stream(&commands[1])? {
+        Some(stream) => stream.next_id(&commands[idx])?,
+        None => Stream::new().next_id(&commands[idx])?,
+    };
+    let stream = memory.get_or_create_stream(&commands[1])?;
+    stream.append(id, pairs.chunks(2).map(|p| (p[0].to_string(), p[1].to_string())).collect());
+    if let Some((strategy, _)) = &trim {
+        stream.trim(strategy);
+    }
+
+    // Replicas must store the same ID, so the resolved one is propagated.
+    let mut propagated = commands.to_vec();
+    propagated[idx] = id.to_string();
+    if let Some((strategy, args)) = trim {
+        if strategy.approximate {
+            propagated.splice(args, stream.exact_trim_args());
+        }
+    }
+    memory.touch(&commands[1]);
+    set_propagated(propagated);
+    Ok(resp::bulk_string(&id.to_string()))
+}
+
+/// `XRANGE key start end [COUNT count]`, and `XREVRANGE key end start [COUNT count]`.
+fn xrange(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 4 && commands.len() != 6 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let rev = commands[0] == "XREVRANGE";
+    let (start, end) = if rev { (&commands[3], &commands[2]) } else { (&commands[2], &commands[3]) };
+    let start = StreamId::parse_range_bound(start, true)?;
+    let end = StreamId::parse_range_bound(end, false)?;
+    let count = match commands.get(4) {
+        Some(option) if option.eq_ignore_ascii_case("COUNT") => {
+            Some(parse_i64(&commands[5]).ok_or(CommandError::NotInteger)?.max(0) as usize)
+        }
+        Some(_) => return Err(CommandError::Syntax),
+        None => None,
+    };
+
+    match get_memory_instance().get_stream(&commands[1])? {
+        Some(stream) => Ok(entries_response(&stream.range(start, end, count, rev))),
+        None => Ok(resp::array(Vec::new())),
+    }
+}
+
+fn xlen(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 2 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let stream = get_memory_instance().get_stream(&commands[1])?;
+    Ok(resp::integer(stream.map(|s| s.len()).unwrap_or(0) as i64))
+}
+
+fn xdel(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let mut ids = Vec::new();
+    for id in &commands[2..] {
+        ids.push(StreamId::parse(id, 0)?);
+    }
+    let memory = get_memory_instance();
+    let removed = match memory.get_stream_mut(&commands[1])? {
+        Some(stream) => ids.into_iter().filter(|id| stream.remove(*id)).count(),
+        None => 0,
+    };
+    if removed > 0 {
+        memory.touch(&commands[1]);
+    }
+    Ok(resp::integer(removed as i64))
+}
+
+/// `XTRIM key MAXLEN|MINID [=|~] threshold [LIMIT count]`
+fn xtrim(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    if !is_trim_option(&commands[2]) {
+        return Err(CommandError::Syntax);
+    }
+    let (strategy, consumed) = TrimStrategy::parse(&commands[2..])?;
+    if 2 + consumed != commands.len() {
+        return Err(CommandError::Syntax);
+    }
+    let memory = get_memory_instance();
+    let removed = match memory.get_stream_mut(&commands[1])? {
+        Some(stream) => {
+            let removed = stream.trim(&strategy);
+            if strategy.approximate {
+                let mut propagated = commands[..2].to_vec();
+                propagated.extend(stream.exact_trim_args());
+                set_propagated(propagated);
+            }
+            removed
+        }
+        None => 0,
+    };
+    if removed > 0 {
+        memory.touch(&commands[1]);
+    }
+    Ok(resp::integer(removed as i64))
+}
+
+/// Resolves the ID given to `XREAD` for `key` into the first ID to return:
+/// `$` only returns entries added from now on, `+` starts at the last entry
+/// and any other ID is exclusive.
+fn read_start(key: &str, requested: &str) -> Result<StreamId, CommandError> {
+    let stream = get_memory_instance().get_stream(key)?;
+    let last_id = stream.map(|s| s.last_id).unwrap_or(StreamId::MIN);
+    let start = match requested {
+        "$" => last_id.next(),
+        "+" => match stream.and_then(|s| s.last_entry()) {
+            Some(entry) => Some(entry.id),
+            None => last_id.next(),
+        },
+        _ => StreamId::parse(requested, 0)?.next(),
+    };
+    Ok(start.unwrap_or(StreamId::MAX))
+}
+
+fn read_streams(keys: &[String], starts: &[StreamId], count: Option<usize>) -> Result<Option<Vec<u8>>, CommandError> {
+    let memory = get_memory_instance();
+    let mut streams = Vec::new();
+    for (key, start) in keys.iter().zip(starts) {
+        let stream = match memory.get_stream(key)? {
+            Some(stream) => stream,
+            None => continue,
+        };
+        let entries = stream.range(*start, StreamId::MAX, count, false);
+        if !entries.is_empty() {
+            streams.push(resp::array(vec![resp::bulk_string(key), entries_response(&entries)]));
+        }
+    }
+    if streams.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(resp::array(streams)))
+}
+
+/// `XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id [id ...]`
+struct Read<'a> {
+    keys: &'a [String],
+    ids: &'a [String],
+    /// Where each stream is read from, resolved by the first attempt.
+    starts: Vec<StreamId>,
+    count: Option<usize>,
+    block: Option<u64>,
+}
+
+impl<'a> Read<'a> {
+    fn parse(commands: &'a [String]) -> Result<Self, CommandError> {
+        let mut count = None;
+        let mut block = None;
+        let mut idx = 1;
+        while idx < commands.len() {
+            match commands[idx].to_ascii_uppercase().as_str() {
+                "COUNT" if idx + 1 < commands.len() => {
+                    count = match parse_i64(&commands[idx + 1]).ok_or(CommandError::NotInteger)? {
+                        count if count > 0 => Some(count as usize),
+                        _ => None,
+                    };
+                    idx += 2;
+                }
+                "BLOCK" if idx + 1 < commands.len() => {
+                    let timeout = parse_i64(&commands[idx + 1])
+                        .ok_or_else(|| CommandError::Other("timeout is not an integer or out of range".to_string()))?;
+                    if timeout < 0 {
+                        return Err(CommandError::Other("timeout is negative".to_string()));
+                    }
+                    block = Some(timeout as u64);
+                    idx += 2;
+                }
+                "STREAMS" => {
+                    idx += 1;
+                    break;
+                }
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+
+        let streams = &commands[idx.min(commands.len())..];
+        if streams.is_empty() || streams.len() % 2 == 1 {
+            return Err(CommandError::Other(
+                "Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.".to_string(),
+            ));
+        }
+        let (keys, ids) = streams.split_at(streams.len() / 2);
+        for id in ids {
+            if id != "$" && id != "+" {
+                StreamId::parse(id, 0)?;
+            }
+        }
+        Ok(Read { keys, ids, starts: Vec::new(), count, block })
+    }
+
+    /// Reads the streams, with the execution lock held. `$` and `+` refer
+    /// to the streams as the first attempt finds them, later attempts of a
+    /// blocked read keep waiting for entries after those.
+    fn attempt(&mut self) -> Result<Option<Vec<u8>>, CommandError> {
+        if self.starts.is_empty() {
+            let starts: Result<Vec<StreamId>, CommandError> =
+                self.keys.iter().zip(self.ids).map(|(key, id)| read_start(key, id)).collect();
+            self.starts = starts?;
+        }
+        read_streams(self.keys, &self.starts, self.count)
+    }
+}
+
+pub async fn xread(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    let mut read = Read::parse(commands)?;
+    let keys = read.keys;
+    let response = match read.block {
+        None => {
+            let _guard = lock_execution();
+            read.attempt()?
+        }
+        Some(timeout) => {
+            let timeout = if timeout == 0 { None } else { Some(Duration::from_millis(timeout)) };
+            block_on_keys(keys, timeout, || read.attempt()).await?
+        }
+    };
+    Ok(response.unwrap_or_else(resp::null_array))
+}
+
+pub fn process_command(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    match commands[0].as_str() {
+        "XADD" => xadd(commands),
+        "XRANGE" | "XREVRANGE" => xrange(commands),
+        "XLEN" => xlen(commands),
+        "XDEL" => xdel(commands),
+        "XTRIM" => xtrim(commands),
+        // Inside a transaction reads never block.
+        "XREAD" => Ok(Read::parse(commands)?.attempt()?.unwrap_or_else(resp::null_array)),
+        _ => Err(CommandError::Other(format!("unknown command '{}'", commands[0]))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{run, run_propagated, strings};
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn ids(stream: &Stream) -> Vec<String> {
+        stream.range(StreamId::MIN, StreamId::MAX, None, false).iter().map(|entry| entry.id.to_string()).collect()
+    }
+
+    #[test]
+    fn ids_and_range_bounds() {
+        assert_eq!(StreamId::parse("5-3", 0).unwrap(), id(5, 3));
+        assert_eq!(StreamId::parse("5", 7).unwrap(), id(5, 7));
+        assert!(StreamId::parse("5-", 0).is_err());
+        assert!(StreamId::parse("-1", 0).is_err());
+        assert_eq!(StreamId::parse_range_bound("5", true).unwrap(), id(5, 0));
+        assert_eq!(StreamId::parse_range_bound("5", false).unwrap(), id(5, u64::MAX));
+        assert_eq!(StreamId::parse_range_bound("(5-3", true).unwrap(), id(5, 4));
+        assert_eq!(StreamId::parse_range_bound("(5-0", false).unwrap(), id(4, u64::MAX));
+        assert_eq!(StreamId::parse_range_bound("-", false).unwrap(), StreamId::MIN);
+        assert!(StreamId::parse_range_bound("(0-0", false).is_err());
+        assert_eq!(StreamId::MAX.next(), None);
+        assert_eq!(id(1, u64::MAX).next(), Some(id(2, 0)));
+        assert_eq!(id(5, 3).to_string(), "5-3");
+    }
+
+    #[test]
+    fn next_id_rules() {
+        let mut stream = Stream::new();
+        assert_eq!(
+            stream.next_id("0-0").unwrap_err().to_string(),
+            "ERR The ID specified in XADD must be greater than 0-0"
+        );
+        assert_eq!(stream.next_id("0-*").unwrap(), id(0, 1));
+        stream.last_id = id(5, 3);
+        assert_eq!(stream.next_id("5-*").unwrap(), id(5, 4));
+        assert_eq!(stream.next_id("6-*").unwrap(), id(6, 0));
+        assert_eq!(stream.next_id("6").unwrap(), id(6, 0));
+        let too_small = "ERR The ID specified in XADD is equal or smaller than the target stream top item";
+        assert_eq!(stream.next_id("5-3").unwrap_err().to_string(), too_small);
+        assert_eq!(stream.next_id("4-*").unwrap_err().to_string(), too_small);
+        let now = stream.next_id("*").unwrap();
+        assert!(now > id(5, 3) && now.seq == 0);
+
+        // `*` keeps increasing the sequence while the clock is behind.
+        stream.last_id = id(u64::MAX - 1, 9);
+        assert_eq!(stream.next_id("*").unwrap(), id(u64::MAX - 1, 10));
+        stream.last_id = id(7, u64::MAX);
+        assert_eq!(stream.next_id("7-*").unwrap_err().to_string(), too_small);
+    }
+
+    #[test]
+    fn trims() {
+        let _guard = crate::commands::lock_execution();
+        let mut stream = Stream::new();
+        for ms in 1..=250 {
+            stream.append(id(ms, 0), vec![("f".to_string(), "v".to_string())]);
+        }
+        assert_eq!(stream.node_count(), 3);
+
+        // Approximate trims only drop whole nodes.
+        let (strategy, consumed) = TrimStrategy::parse(&args(&["MAXLEN", "~", "120"])).unwrap();
+        assert_eq!(consumed, 3);
+        assert_eq!(stream.trim(&strategy), 100);
+        assert_eq!(stream.len(), 150);
+        assert_eq!(stream.exact_trim_args(), ["MINID", "=", "101-0"]);
+
+        let (strategy, _) = TrimStrategy::parse(&args(&["MINID", "=", "140"])).unwrap();
+        assert_eq!(stream.trim(&strategy), 39);
+        assert_eq!(stream.first_entry().unwrap().id, id(140, 0));
+        assert_eq!(stream.max_deleted_id, id(139, 0));
+
+        let (strategy, _) = TrimStrategy::parse(&args(&["MAXLEN", "~", "0", "LIMIT", "50"])).unwrap();
+        assert_eq!(stream.trim(&strategy), 0);
+        let (strategy, _) = TrimStrategy::parse(&args(&["MAXLEN", "2"])).unwrap();
+        assert_eq!(stream.trim(&strategy), 109);
+        assert_eq!(ids(&stream), ["249-0", "250-0"]);
+
+        let error = |trim: &[&str]| TrimStrategy::parse(&args(trim)).err().unwrap().to_string();
+        assert_eq!(error(&["MAXLEN", "-1"]), "ERR The MAXLEN argument must be >= 0.");
+        assert_eq!(error(&["MAXLEN", "1", "LIMIT", "5"]), "ERR syntax error, LIMIT cannot be used without the special ~ option");
+        assert_eq!(error(&["MINID", "~", "x"]), "ERR Invalid stream ID specified as stream command argument");
+    }
+
+    #[test]
+    fn add_range_and_delete() {
+        assert_eq!(run(&["XADD", "stream:range", "1-1", "a", "1"]), resp::bulk_string("1-1"));
+        assert_eq!(run(&["XADD", "stream:range", "1-*", "b", "2"]), resp::bulk_string("1-2"));
+        assert_eq!(run(&["XADD", "stream:range", "2-0", "c", "3", "d", "4"]), resp::bulk_string("2-0"));
+        assert_eq!(run(&["XADD", "stream:range", "2-0", "e", "5"]), resp::error("ERR The ID specified in XADD is equal or smaller than the target stream top item"));
+        assert_eq!(run(&["XADD", "stream:range", "3-0", "odd"]), resp::error("ERR wrong number of arguments for 'xadd' command"));
+        assert_eq!(run(&["XADD", "stream:missing", "NOMKSTREAM", "*", "a", "1"]), resp::null_bulk_string());
+        assert_eq!(run(&["TYPE", "stream:missing"]), resp::simple_string("none"));
+
+        let entry = |id: &str, fields: &[&str]| resp::array(vec![resp::bulk_string(id), resp::bulk_string_array(fields)]);
+        let expected = resp::array(vec![entry("1-2", &["b", "2"]), entry("2-0", &["c", "3", "d", "4"])]);
+        assert_eq!(run(&["XRANGE", "stream:range", "(1-1", "+"]), expected);
+        let expected = resp::array(vec![entry("2-0", &["c", "3", "d", "4"])]);
+        assert_eq!(run(&["XREVRANGE", "stream:range", "+", "-", "COUNT", "1"]), expected);
+        assert_eq!(run(&["XRANGE", "stream:range", "1", "1"]).len(), run(&["XRANGE", "stream:range", "1-0", "1-2"]).len());
+
+        assert_eq!(run(&["XDEL", "stream:range", "1-1", "9-9"]), resp::integer(1));
+        assert_eq!(run(&["XLEN", "stream:range"]), resp::integer(2));
+        // Deleting doesn't allow reusing IDs.
+        assert!(run(&["XADD", "stream:range", "1-5", "x", "y"]).starts_with(b"-ERR"));
+    }
+
+    #[test]
+    fn propagates_resolved_ids_and_exact_trims() {
+        let (propagated, _) = run_propagated(&["XADD", "stream:propagated", "*", "f", "v"]).unwrap();
+        let added = run(&["XRANGE", "stream:propagated", "-", "+"]);
+        assert!(String::from_utf8_lossy(&added).contains(&propagated[2]));
+        assert_eq!(propagated[3..], ["f", "v"]);
+
+        for id in ["1-0", "2-0", "3-0"] {
+            run(&["XADD", "stream:trimmed", id, "f", "v"]);
+        }
+        // Nothing fits an approximate trim yet, which replicas learn from
+        // the exact form.
+        let (propagated, _) = run_propagated(&["XADD", "stream:trimmed", "MAXLEN", "~", "1", "4-*", "f", "v"]).unwrap();
+        assert_eq!(propagated, ["XADD", "stream:trimmed", "MINID", "=", "1-0", "4-0", "f", "v"]);
+        assert_eq!(run(&["XLEN", "stream:trimmed"]), resp::integer(4));
+
+        let (propagated, _) = run_propagated(&["XTRIM", "stream:trimmed", "MAXLEN", "2"]).unwrap();
+        assert_eq!(propagated, ["XTRIM", "stream:trimmed", "MAXLEN", "2"]);
+        let (propagated, _) = run_propagated(&["XTRIM", "stream:propagated", "MAXLEN", "~", "0"]).unwrap();
+        assert_eq!(propagated, ["XTRIM", "stream:propagated", "MAXLEN", "=", "0"]);
+        assert_eq!(run(&["XLEN", "stream:propagated"]), resp::integer(0));
+        assert!(strings(&run(&["XRANGE", "stream:propagated", "-", "+"])).is_empty());
+    }
+
+    #[test]
+    fn read_arguments() {
+        let error = |read: &[&str]| Read::parse(&args(read)).err().unwrap().to_string();
+        let unbalanced = "ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.";
+        assert_eq!(error(&["XREAD", "STREAMS", "a", "b", "0"]), unbalanced);
+        assert_eq!(error(&["XREAD", "COUNT", "1"]), unbalanced);
+        assert_eq!(error(&["XREAD", "BLOCK", "-1", "STREAMS", "a", "0"]), "ERR timeout is negative");
+        assert_eq!(error(&["XREAD", "STREAMS", "a", "x"]), "ERR Invalid stream ID specified as stream command argument");
+        assert_eq!(error(&["XREAD", "LIMIT", "1", "STREAMS", "a", "0"]), "ERR syntax error");
+
+        let commands = args(&["XREAD", "COUNT", "2", "BLOCK", "0", "STREAMS", "a", "b", "$", "+"]);
+        let read = Read::parse(&commands).unwrap();
+        assert_eq!((read.keys, read.ids), (&commands[6..8], &commands[8..]));
+        assert_eq!((read.count, read.block), (Some(2), Some(0)));
+        assert!(read.starts.is_empty());
+    }
+
+    #[test]
+    fn read_special_ids() {
+        for id in ["1-0", "2-0", "3-0"] {
+            run(&["XADD", "stream:read", id, "f", id]);
+        }
+        let read = |ids: &[&str]| {
+            let mut commands = vec!["XREAD", "COUNT", "5", "STREAMS", "stream:read", "stream:read:missing"];
+            commands.extend(ids);
+            run(&commands)
+        };
+        let entry = |id: &str| resp::array(vec![resp::bulk_string(id), resp::bulk_string_array(&["f", id])]);
+        let reply = |ids: &[&str]| {
+            let entries = resp::array(ids.iter().map(|id| entry(id)).collect());
+            resp::array(vec![resp::array(vec![resp::bulk_string("stream:read"), entries])])
+        };
+        assert_eq!(read(&["1-0", "0"]), reply(&["2-0", "3-0"]));
+        assert_eq!(read(&["+", "+"]), reply(&["3-0"]));
+        assert_eq!(read(&["$", "$"]), resp::null_array());
+        assert_eq!(run(&["XREAD", "COUNT", "1", "STREAMS", "stream:read", "0"]), reply(&["1-0"]));
+    }
+
+    #[tokio::test]
+    async fn blocked_read_waits_for_new_entries() {
+        run(&["XADD", "stream:blocked", "1-0", "f", "old"]);
+        let commands = args(&["XREAD", "BLOCK", "0", "STREAMS", "stream:blocked", "$"]);
+        let waiting = tokio::spawn(async move { xread(&commands).await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+        run(&["XADD", "stream:blocked", "2-0", "f", "new"]);
+
+        let entries = resp::array(vec![resp::array(vec![resp::bulk_string("2-0"), resp::bulk_string_array(&["f", "new"])])]);
+        let expected = resp::array(vec![resp::array(vec![resp::bulk_string("stream:blocked"), entries])]);
+        assert_eq!(waiting.await.unwrap().unwrap(), expected);
+
+        let commands = args(&["XREAD", "BLOCK", "10", "STREAMS", "stream:blocked", "+"]);
+        assert_eq!(xread(&commands).await.unwrap(), expected);
+        let commands = args(&["XREAD", "BLOCK", "10", "STREAMS", "stream:blocked", "$"]);
+        assert_eq!(xread(&commands).await.unwrap(), resp::null_array());
+    }
+}
@@ -0,0 +1,359 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::client::Client;
+use crate::commands::{is_write_command, lock_execution};
+use crate::error::CommandError;
+use crate::pubsub::Message;
+use crate::resp;
+use crate::{get_clients_instance, get_tracking_instance};
+
+/// `CLIENT TRACKING` options of a client. Invalidations go to `target`,
+/// which is the client itself unless it asked for a `REDIRECT`.
+struct Tracker {
+    target: UnboundedSender<Message>,
+    bcast: bool,
+    prefixes: Vec<String>,
+    optin: bool,
+    optout: bool,
+    noloop: bool,
+    /// `CLIENT CACHING yes|no`, which only applies to the next command.
+    caching: Option<bool>,
+}
+
+/// Clients with tracking enabled, and the keys each default mode client
+/// read since they were last invalidated.
+pub struct Tracking {
+    trackers: HashMap<u64, Tracker>,
+    keys: HashMap<String, HashSet<u64>>,
+}
+
+thread_local! {
+    /// Client whose command runs on this thread, with the keys it read.
+    static CALLER: RefCell<Option<(u64, Vec<String>)>> = const { RefCell::new(None) };
+}
+
+impl Tracking {
+    pub fn new() -> Self {
+        Tracking {
+            trackers: HashMap::new(),
+            keys: HashMap::new(),
+        }
+    }
+
+    fn remember(&mut self, id: u64, name: &str, reads: Vec<String>) {
+        let Some(tracker) = self.trackers.get_mut(&id) else {
+            return;
+        };
+        let caching = tracker.caching.take();
+        if tracker.bcast || is_write_command(name) {
+            return;
+        }
+        let cache = if tracker.optin {
+            caching == Some(true)
+        } else if tracker.optout {
+            caching != Some(false)
+        } else {
+            true
+        };
+        if cache {
+            for key in reads {
+                self.keys.entry(key).or_default().insert(id);
+            }
+        }
+    }
+
+    fn invalidate(&mut self, key: &str, caller: Option<u64>) {
+        if self.trackers.is_empty() {
+            return;
+        }
+        let readers = self.keys.remove(key).unwrap_or_default();
+        for (id, tracker) in &self.trackers {
+            if tracker.noloop && caller == Some(*id) {
+                continue;
+            }
+            let interested = if tracker.bcast {
+                tracker.prefixes.is_empty() || tracker.prefixes.iter().any(|prefix| key.starts_with(prefix.as_str()))
+            } else {
+                readers.contains(id)
+            };
+            if interested {
+                _ = tracker.target.send(Message::Invalidate(Some(vec![key.to_string()])));
+            }
+        }
+    }
+
+    /// Forgets every key read, and tells every tracking client to drop its
+    /// whole cache with a single null invalidation.
+    fn flush(&mut self) {
+        self.keys.clear();
+        for tracker in self.trackers.values() {
+            _ = tracker.target.send(Message::Invalidate(None));
+        }
+    }
+}
+
+/// Runs a command of the client `id`, which calls `remember_reads` for the
+/// keys it read before releasing the execution lock.
+pub fn track<T, F>(id: u64, run: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    CALLER.with(|caller| *caller.borrow_mut() = Some((id, Vec::new())));
+    let result = run();
+    CALLER.with(|caller| caller.borrow_mut().take());
+    result
+}
+
+/// Remembers the keys read by the command `name` running on this thread, for
+/// client-side caching. Callers still hold the lock the reads were made
+/// under, so a write can't land in between and go unnoticed.
+pub fn remember_reads(name: &str) {
+    let caller = CALLER.with(|caller| caller.borrow_mut().as_mut().map(|(id, reads)| (*id, std::mem::take(reads))));
+    if let Some((id, reads)) = caller {
+        get_tracking_instance().remember(id, name, reads);
+    }
+}
+
+/// Called by the keyspace on every lookup of `key`.
+pub fn read(key: &str) {
+    CALLER.with(|caller| {
+        if let Some((_, reads)) = caller.borrow_mut().as_mut() {
+            reads.push(key.to_string());
+        }
+    });
+}
+
+/// Called by the keyspace whenever `key` changes, to invalidate the clients
+/// caching it.
+pub fn invalidate(key: &str) {
+    let caller = CALLER.with(|caller| caller.borrow().as_ref().map(|(id, _)| *id));
+    get_tracking_instance().invalidate(key, caller);
+}
+
+/// Called by the keyspace when every key is removed at once.
+pub fn flush() {
+    get_tracking_instance().flush();
+}
+
+pub fn disable(id: u64) {
+    let _guard = lock_execution();
+    get_tracking_instance().trackers.remove(&id);
+}
+
+/// `CLIENT TRACKING ON|OFF [REDIRECT id] [PREFIX prefix ...] [BCAST] [OPTIN]
+/// [OPTOUT] [NOLOOP]`
+pub fn client_tracking(client: &Client, commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity("client|tracking"));
+    }
+    let on = match commands[2].to_ascii_uppercase().as_str() {
+        "ON" => true,
+        "OFF" => false,
+        _ => return Err(CommandError::Syntax),
+    };
+    let mut redirect = None;
+    let mut tracker = Tracker {
+        target: client.sender.clone(),
+        bcast: false,
+        prefixes: Vec::new(),
+        optin: false,
+        optout: false,
+        noloop: false,
+        caching: None,
+    };
+    let mut idx = 3;
+    while idx < commands.len() {
+        match commands[idx].to_ascii_uppercase().as_str() {
+            "REDIRECT" if idx + 1 < commands.len() => {
+                let id = commands[idx + 1].parse::<u64>().map_err(|_| CommandError::NotInteger)?;
+                redirect = Some(id);
+                idx += 1;
+            }
+            "PREFIX" if idx + 1 < commands.len() => {
+                tracker.prefixes.push(commands[idx + 1].to_string());
+                idx += 1;
+            }
+            "BCAST" => tracker.bcast = true,
+            "OPTIN" => tracker.optin = true,
+            "OPTOUT" => tracker.optout = true,
+            "NOLOOP" => tracker.noloop = true,
+            _ => return Err(CommandError::Syntax),
+        }
+        idx += 1;
+    }
+
+    let _guard = lock_execution();
+    let tracking = get_tracking_instance();
+    if !on {
+        tracking.trackers.remove(&client.id);
+        return Ok(resp::simple_string("OK"));
+    }
+    if !tracker.prefixes.is_empty() && !tracker.bcast {
+        return Err(CommandError::Other("PREFIX option requires BCAST mode to be enabled".to_string()));
+    }
+    if tracker.optin && tracker.optout {
+        return Err(CommandError::Other("You can't use both OPTIN and OPTOUT".to_string()));
+    }
+    if tracker.bcast && (tracker.optin || tracker.optout) {
+        return Err(CommandError::Other("OPTIN and OPTOUT are not compatible with BCAST".to_string()));
+    }
+    if let Some(current) = tracking.trackers.get(&client.id) {
+        if current.bcast != tracker.bcast {
+            return Err(CommandError::Other(
+                "You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode.".to_string(),
+            ));
+        }
+    }
+    if let Some(id) = redirect {
+        match get_clients_instance().sender(id) {
+            Some(sender) => tracker.target = sender.clone(),
+            None => {
+                return Err(CommandError::Other("The client ID you want redirect to does not exist".to_string()));
+            }
+        }
+    }
+    tracking.trackers.insert(client.id, tracker);
+    Ok(resp::simple_string("OK"))
+}
+
+/// `CLIENT CACHING YES|NO`
+pub fn client_caching(client: &Client, commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 3 {
+        return Err(CommandError::arity("client|caching"));
+    }
+    let caching = match commands[2].to_ascii_uppercase().as_str() {
+        "YES" => true,
+        "NO" => false,
+        _ => return Err(CommandError::Syntax),
+    };
+    let _guard = lock_execution();
+    match get_tracking_instance().trackers.get_mut(&client.id) {
+        Some(tracker) if (caching && tracker.optin) || (!caching && tracker.optout) => {
+            tracker.caching = Some(caching);
+            Ok(resp::simple_string("OK"))
+        }
+        Some(tracker) if tracker.optin || tracker.optout => Err(CommandError::Other(format!(
+            "CLIENT CACHING {} is only valid when tracking is enabled in {} mode.",
+            if caching { "YES" } else { "NO" },
+            if caching { "OPTIN" } else { "OPTOUT" }
+        ))),
+        _ => Err(CommandError::Other(
+            "CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled"
+                .to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::run;
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    fn tracker(target: &UnboundedSender<Message>) -> Tracker {
+        Tracker {
+            target: target.clone(),
+            bcast: false,
+            prefixes: Vec::new(),
+            optin: false,
+            optout: false,
+            noloop: false,
+            caching: None,
+        }
+    }
+
+    fn keys(messages: &mut UnboundedReceiver<Message>) -> Vec<Option<Vec<String>>> {
+        std::iter::from_fn(|| messages.try_recv().ok())
+            .map(|message| match message {
+                Message::Invalidate(keys) => keys,
+                Message::Published { .. } => unreachable!(),
+            })
+            .collect()
+    }
+
+    fn reads(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[test]
+    fn reads_are_invalidated_once() {
+        let mut tracking = Tracking::new();
+        let (sender, mut messages) = mpsc::unbounded_channel();
+        tracking.trackers.insert(1, tracker(&sender));
+        tracking.remember(1, "GET", reads(&["a"]));
+        // Writes don't cache what they read.
+        tracking.remember(1, "SET", reads(&["b"]));
+        tracking.invalidate("a", None);
+        tracking.invalidate("a", None);
+        tracking.invalidate("b", None);
+        assert_eq!(keys(&mut messages), [Some(reads(&["a"]))]);
+
+        tracking.remember(1, "GET", reads(&["a"]));
+        tracking.flush();
+        tracking.invalidate("a", None);
+        assert_eq!(keys(&mut messages), [None]);
+    }
+
+    #[test]
+    fn optin_optout_and_noloop() {
+        let mut tracking = Tracking::new();
+        let (sender, mut messages) = mpsc::unbounded_channel();
+        tracking.trackers.insert(1, Tracker { optin: true, ..tracker(&sender) });
+        tracking.trackers.insert(2, Tracker { optout: true, noloop: true, ..tracker(&sender) });
+        tracking.remember(1, "GET", reads(&["a"]));
+        tracking.trackers.get_mut(&1).unwrap().caching = Some(true);
+        tracking.remember(1, "GET", reads(&["b"]));
+        tracking.trackers.get_mut(&2).unwrap().caching = Some(false);
+        tracking.remember(2, "GET", reads(&["c"]));
+        tracking.remember(2, "GET", reads(&["d"]));
+        for key in ["a", "b", "c"] {
+            tracking.invalidate(key, None);
+        }
+        // The writer itself isn't told with NOLOOP.
+        tracking.invalidate("d", Some(2));
+        assert_eq!(keys(&mut messages), [Some(reads(&["b"]))]);
+    }
+
+    #[test]
+    fn broadcast_prefixes() {
+        let mut tracking = Tracking::new();
+        let (sender, mut messages) = mpsc::unbounded_channel();
+        let prefixes = reads(&["user:", "session:"]);
+        tracking.trackers.insert(1, Tracker { bcast: true, prefixes, ..tracker(&sender) });
+        for key in ["user:1", "order:1", "session:1"] {
+            tracking.invalidate(key, None);
+        }
+        assert_eq!(keys(&mut messages), [Some(reads(&["user:1"])), Some(reads(&["session:1"]))]);
+    }
+
+    #[test]
+    fn clients_track_their_reads() {
+        let (client, mut messages) = Client::new();
+        let tracking = |options: &[&str]| {
+            let mut commands = reads(&["CLIENT", "TRACKING"]);
+            commands.extend(reads(options));
+            client_tracking(&client, &commands).map_err(|err| err.to_string())
+        };
+        assert_eq!(tracking(&["ON", "PREFIX", "a"]), Err("ERR PREFIX option requires BCAST mode to be enabled".to_string()));
+        assert_eq!(tracking(&["ON", "OPTIN", "OPTOUT"]), Err("ERR You can't use both OPTIN and OPTOUT".to_string()));
+        assert_eq!(tracking(&["ON", "REDIRECT", "0"]), Err("ERR The client ID you want redirect to does not exist".to_string()));
+        assert_eq!(tracking(&["ON"]), Ok(resp::simple_string("OK")));
+        assert!(tracking(&["ON", "BCAST"]).is_err());
+
+        run(&["SET", "tracking:a", "1"]);
+        track(client.id, || run(&["GET", "tracking:a"]));
+        run(&["SET", "tracking:a", "2"]);
+        run(&["SET", "tracking:a", "3"]);
+        assert_eq!(keys(&mut messages), [Some(reads(&["tracking:a"]))]);
+
+        let caching = client_caching(&client, &reads(&["CLIENT", "CACHING", "YES"]));
+        assert!(caching.is_err());
+        assert_eq!(tracking(&["OFF"]), Ok(resp::simple_string("OK")));
+        track(client.id, || run(&["GET", "tracking:a"]));
+        run(&["SET", "tracking:a", "4"]);
+        assert!(keys(&mut messages).is_empty());
+    }
+}
@@ -0,0 +1,280 @@
+use crate::commands::{check_command, execute_unlocked, lock_execution, propagate, propagate_to, propagated_frame, Target};
+use crate::aof;
+use crate::error::CommandError;
+use crate::get_memory_instance;
+use crate::parser::Command;
+use crate::resp;
+use crate::tracking;
+
+/// Commands acting on the connection itself, which can't run from `EXEC`.
+fn is_connection_command(name: &str) -> bool {
+    matches!(
+        name,
+        "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "SSUBSCRIBE" | "SUNSUBSCRIBE" | "HELLO" | "CLIENT"
+    )
+}
+
+/// State of `MULTI` on a connection: the queued commands, whether one of them was rejected while queueing, and the keys
+/// under `WATCH` with the version they had back then.
+pub struct Transaction {
+    active: bool,
+    aborted: bool,
+    queue: Vec<Command>,
+    watched: Vec<(String, u64)>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Transaction {
+            active: false,
+            aborted: false,
+            queue: Vec::new(),
+            watched: Vec::new(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    fn reset(&mut self) {
+        self.active = false;
+        self.aborted = false;
+        self.queue.clear();
+        self.unwatch();
+    }
+
+    pub fn watch(&mut self, keys: &[String]) -> Vec<u8> {
+        if self.active {
+            return resp::error("ERR WATCH inside MULTI is not allowed");
+        }
+        let _guard = lock_execution();
+        let memory = get_memory_instance();
+        for key in keys {
+            if !self.watched.iter().any(|(watched, _)| watched == key) {
+                self.watched.push((key.to_string(), memory.watch(key)));
+            }
+        }
+        resp::simple_string("OK")
+    }
+
+    pub fn unwatch(&mut self) {
+        let memory = get_memory_instance();
+        for (key, _) in self.watched.drain(..) {
+            memory.unwatch(&key);
+        }
+    }
+
+    fn watched_key_changed(&self) -> bool {
+        let memory = get_memory_instance();
+        self.watched.iter().any(|(key, version)| memory.version(key) != *version)
+    }
+
+    pub fn begin(&mut self) -> Vec<u8> {
+        if self.active {
+            return resp::error("ERR MULTI calls can not be nested");
+        }
+        self.active = true;
+        resp::simple_string("OK")
+    }
+
+    pub fn discard(&mut self) -> Vec<u8> {
+        if !self.active {
+            return resp::error("ERR DISCARD without MULTI");
+        }
+        let _guard = lock_execution();
+        self.reset();
+        resp::simple_string("OK")
+    }
+
+    /// Queues a command, flagging the transaction when the command is unknown
+    /// or called with the wrong number of arguments.
+    pub fn queue(&mut self, command: Command) -> Vec<u8> {
+        let commands = &command.args;
+        if is_connection_command(&commands[0]) {
+            self.aborted = true;
+            return resp::error(&CommandError::Other("Command not allowed inside a transaction".to_string()).to_string());
+        }
+        if let Err(err) = check_command(commands) {
+            self.aborted = true;
+            return resp::error(&err.to_string());
+        }
+        self.queue.push(command);
+        resp::simple_string("QUEUED")
+    }
+
+    /// Runs the queued commands with no other client in between, and
+    /// propagates their writes wrapped in `MULTI`/`EXEC` before letting
+    /// other clients run.
+    pub fn exec(&mut self) -> Vec<u8> {
+        let _guard = lock_execution();
+        let response = self.exec_locked();
+        tracking::remember_reads("EXEC");
+        response
+    }
+
+    fn exec_locked(&mut self) -> Vec<u8> {
+        if !self.active {
+            return resp::error("ERR EXEC without MULTI");
+        }
+        if self.aborted {
+            self.reset();
+            return resp::error("EXECABORT Transaction discarded because of previous errors.");
+        }
+        let queue = std::mem::take(&mut self.queue);
+        let changed = self.watched_key_changed();
+        self.reset();
+        if changed {
+            return resp::null_array();
+        }
+
+        let mut responses = Vec::new();
+        let mut propagated = Vec::new();
+        for command in queue {
+            match execute_unlocked(&command) {
+                Ok(response) => {
+                    propagated.extend(propagated_frame(&command));
+                    responses.push(response);
+                }
+                Err(err) => responses.push(resp::error(&err.to_string())),
+            }
+        }
+
+        // Replicas get every propagated command in order, e.g. a `PUBLISH`
+        // between two writes, while the AOF only gets the writes.
+        let replicated: Vec<&[u8]> = propagated.iter().map(|(frame, _)| frame.as_slice()).collect();
+        let writes: Vec<&[u8]> = propagated
+            .iter()
+            .filter(|(_, target)| *target == Target::All)
+            .map(|(frame, _)| frame.as_slice())
+            .collect();
+        if !writes.is_empty() && writes.len() == replicated.len() {
+            propagate(&wrap(&writes));
+        } else if !replicated.is_empty() {
+            if !writes.is_empty() {
+                aof::feed(&wrap(&writes));
+            }
+            propagate_to(&wrap(&replicated), Target::Replicas);
+        }
+        resp::array(responses)
+    }
+}
+
+/// Wraps `frames` in `MULTI`/`EXEC`, so they replay as one transaction.
+fn wrap(frames: &[&[u8]]) -> Vec<u8> {
+    let mut wrapped = resp::bulk_string_array(&["MULTI"]);
+    for frame in frames {
+        wrapped.extend_from_slice(frame);
+    }
+    wrapped.extend(resp::bulk_string_array(&["EXEC"]));
+    wrapped
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        let _guard = lock_execution();
+        self.unwatch();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{run, strings};
+
+    fn command(args: &[&str]) -> Command {
+        Command::new(args.iter().map(|arg| arg.as_bytes().to_vec()).collect())
+    }
+
+    fn queue(transaction: &mut Transaction, args: &[&str]) -> Vec<u8> {
+        transaction.queue(command(args))
+    }
+
+    #[test]
+    fn exec_runs_the_queue_in_order() {
+        let mut transaction = Transaction::new();
+        assert_eq!(transaction.exec(), resp::error("ERR EXEC without MULTI"));
+        assert_eq!(transaction.discard(), resp::error("ERR DISCARD without MULTI"));
+        assert_eq!(transaction.begin(), resp::simple_string("OK"));
+        assert_eq!(transaction.begin(), resp::error("ERR MULTI calls can not be nested"));
+        assert_eq!(queue(&mut transaction, &["SET", "multi:order", "1"]), resp::simple_string("QUEUED"));
+        queue(&mut transaction, &["SET", "multi:order", "2"]);
+        // Runtime errors don't stop the following commands.
+        queue(&mut transaction, &["HSET", "multi:order", "f", "v"]);
+        queue(&mut transaction, &["GET", "multi:order"]);
+        let expected = resp::array(vec![
+            resp::simple_string("OK"),
+            resp::simple_string("OK"),
+            resp::error(&CommandError::WrongType.to_string()),
+            resp::bulk_string("2"),
+        ]);
+        assert_eq!(transaction.exec(), expected);
+        assert!(!transaction.is_active());
+    }
+
+    #[test]
+    fn queueing_errors_abort_exec() {
+        let mut transaction = Transaction::new();
+        transaction.begin();
+        queue(&mut transaction, &["SET", "multi:abort", "1"]);
+        assert_eq!(
+            queue(&mut transaction, &["GET"]),
+            resp::error("ERR wrong number of arguments for 'get' command")
+        );
+        assert_eq!(
+            transaction.exec(),
+            resp::error("EXECABORT Transaction discarded because of previous errors.")
+        );
+        assert_eq!(run(&["GET", "multi:abort"]), resp::null_bulk_string());
+
+        transaction.begin();
+        assert_eq!(
+            queue(&mut transaction, &["SUBSCRIBE", "channel"]),
+            resp::error("ERR Command not allowed inside a transaction")
+        );
+        assert!(transaction.exec().starts_with(b"-EXECABORT"));
+
+        transaction.begin();
+        queue(&mut transaction, &["SET", "multi:abort", "1"]);
+        assert_eq!(transaction.discard(), resp::simple_string("OK"));
+        assert_eq!(run(&["GET", "multi:abort"]), resp::null_bulk_string());
+    }
+
+    #[test]
+    fn changed_watched_keys_abort_exec() {
+        run(&["SET", "watch:changed", "1"]);
+        let mut transaction = Transaction::new();
+        transaction.watch(&["watch:changed".to_string()]);
+        run(&["SET", "watch:changed", "2"]);
+        transaction.begin();
+        assert_eq!(transaction.watch(&[]), resp::error("ERR WATCH inside MULTI is not allowed"));
+        queue(&mut transaction, &["SET", "watch:changed", "3"]);
+        assert_eq!(transaction.exec(), resp::null_array());
+        assert_eq!(run(&["GET", "watch:changed"]), resp::bulk_string("2"));
+
+        // EXEC forgets the watched keys either way.
+        transaction.begin();
+        queue(&mut transaction, &["SET", "watch:changed", "3"]);
+        assert_eq!(transaction.exec(), resp::array(vec![resp::simple_string("OK")]));
+    }
+
+    #[test]
+    fn no_op_writes_keep_watched_keys() {
+        run(&["SADD", "watch:noop", "a"]);
+        let mut transaction = Transaction::new();
+        transaction.watch(&["watch:noop".to_string(), "watch:missing".to_string()]);
+        run(&["SADD", "watch:noop", "a"]);
+        run(&["SREM", "watch:noop", "b"]);
+        run(&["DEL", "watch:missing"]);
+        transaction.begin();
+        queue(&mut transaction, &["SMEMBERS", "watch:noop"]);
+        assert_eq!(strings(&transaction.exec()), ["a"]);
+
+        transaction.watch(&["watch:noop".to_string()]);
+        transaction.unwatch();
+        run(&["SADD", "watch:noop", "b"]);
+        transaction.begin();
+        queue(&mut transaction, &["SCARD", "watch:noop"]);
+        assert_eq!(transaction.exec(), resp::array(vec![resp::integer(2)]));
+    }
+}
@@ -0,0 +1,333 @@
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::CommandError;
+use crate::resp;
+
+thread_local! {
+    static RANDOM_STATE: Cell<u64> = Cell::new(seed());
+}
+
+fn seed() -> u64 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+    nanos | 1
+}
+
+/// xorshift64*, good enough for picking random members.
+pub fn random_u64() -> u64 {
+    RANDOM_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545F4914F6CDD1D)
+    })
+}
+
+pub fn random_index(len: usize) -> usize {
+    (random_u64() % len as u64) as usize
+}
+
+/// Largest amount of elements a negative `HRANDFIELD`, `SRANDMEMBER` or
+/// `ZRANDMEMBER` count may ask for.
+pub const MAX_RANDOM_COUNT: u64 = 1 << 24;
+
+/// Negative counts of random picks repeat elements, so the reply grows with
+/// the count and not with the collection: refuse counts no client could take.
+pub fn check_random_count(count: Option<i64>) -> Result<(), CommandError> {
+    match count {
+        Some(count) if count < 0 && count.unsigned_abs() > MAX_RANDOM_COUNT => {
+            Err(CommandError::Other("value is out of range".to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Redis style glob matching supporting `*`, `?`, `[...]` and `\` escapes.
+pub fn glob_match(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    glob_match_chars(&pattern, &value)
+}
+
+fn glob_match_chars(pattern: &[char], value: &[char]) -> bool {
+    let (mut p, mut v) = (0, 0);
+    while p < pattern.len() {
+        match pattern[p] {
+            '*' => {
+                while p + 1 < pattern.len() && pattern[p + 1] == '*' {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                return (v..=value.len()).any(|start| glob_match_chars(&pattern[p + 1..], &value[start..]));
+            }
+            '?' => {
+                if v >= value.len() {
+                    return false;
+                }
+                v += 1;
+            }
+            '[' => {
+                if v >= value.len() {
+                    return false;
+                }
+                p += 1;
+                let negate = p < pattern.len() && pattern[p] == '^';
+                if negate {
+                    p += 1;
+                }
+                let mut matched = false;
+                while p < pattern.len() && pattern[p] != ']' {
+                    if pattern[p] == '\\' && p + 1 < pattern.len() {
+                        p += 1;
+                        matched |= pattern[p] == value[v];
+                    } else if p + 2 < pattern.len() && pattern[p + 1] == '-' {
+                        let (start, end) = if pattern[p] <= pattern[p + 2] {
+                            (pattern[p], pattern[p + 2])
+                        } else {
+                            (pattern[p + 2], pattern[p])
+                        };
+                        matched |= value[v] >= start && value[v] <= end;
+                        p += 2;
+                    } else {
+                        matched |= pattern[p] == value[v];
+                    }
+                    p += 1;
+                }
+                if matched == negate {
+                    return false;
+                }
+                v += 1;
+            }
+            '\\' if p + 1 < pattern.len() => {
+                p += 1;
+                if v >= value.len() || pattern[p] != value[v] {
+                    return false;
+                }
+                v += 1;
+            }
+            c => {
+                if v >= value.len() || c != value[v] {
+                    return false;
+                }
+                v += 1;
+            }
+        }
+        p += 1;
+    }
+    v == value.len()
+}
+
+pub fn parse_i64(value: &str) -> Option<i64> {
+    value.parse::<i64>().ok()
+}
+
+/// Parses a size in bytes with an optional unit, as in Redis' config: `k`,
+/// `m` and `g` are powers of 1000, `kb`, `mb` and `gb` powers of 1024.
+pub fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_ascii_lowercase();
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let unit = match &value[digits..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    value[..digits].parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// Parses a float the way Redis accepts them, including `inf`/`-inf`.
+pub fn parse_f64(value: &str) -> Option<f64> {
+    match value.to_ascii_lowercase().as_str() {
+        "inf" | "+inf" => Some(f64::INFINITY),
+        "-inf" => Some(f64::NEG_INFINITY),
+        other => other.parse::<f64>().ok().filter(|v| !v.is_nan()),
+    }
+}
+
+pub fn format_f64(value: f64) -> String {
+    if value.is_infinite() {
+        return if value > 0.0 { "inf".to_string() } else { "-inf".to_string() };
+    }
+    format!("{}", value)
+}
+
+/// Position of `name` in `page_by_hash` iterations. The hasher has fixed
+/// keys, so positions hold for the lifetime of the server.
+fn scan_hash(name: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    hasher.finish() as usize
+}
+
+/// Arguments shared by the `*SCAN` family: `cursor [MATCH pattern] [COUNT count]`.
+pub struct ScanOptions {
+    pub cursor: usize,
+    pub pattern: Option<String>,
+    pub count: usize,
+    pub no_values: bool,
+}
+
+impl ScanOptions {
+    pub fn parse(cursor: &str, args: &[String], allow_no_values: bool) -> Result<Self, CommandError> {
+        let cursor = cursor
+            .parse::<usize>()
+            .map_err(|_| CommandError::Other("invalid cursor".to_string()))?;
+        let mut options = ScanOptions { cursor, pattern: None, count: 10, no_values: false };
+
+        let mut idx = 0;
+        while idx < args.len() {
+            match args[idx].to_ascii_uppercase().as_str() {
+                "MATCH" if idx + 1 < args.len() => {
+                    options.pattern = Some(args[idx + 1].to_string());
+                    idx += 2;
+                }
+                "COUNT" if idx + 1 < args.len() => {
+                    options.count = match parse_i64(&args[idx + 1]) {
+                        Some(count) if count >= 1 => count as usize,
+                        Some(_) => return Err(CommandError::Syntax),
+                        None => return Err(CommandError::NotInteger),
+                    };
+                    idx += 2;
+                }
+                "NOVALUES" if allow_no_values => {
+                    options.no_values = true;
+                    idx += 1;
+                }
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+        Ok(options)
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        match &self.pattern {
+            Some(pattern) => glob_match(pattern, value),
+            None => true,
+        }
+    }
+
+    /// Returns the next cursor and the items of the current page. Items are
+    /// ordered by a hash of `name(item)` that also serves as the cursor, so
+    /// an item present for the whole iteration is returned exactly once
+    /// however the collection grows or rehashes in between calls. Items
+    /// sharing a hash always land on the same page.
+    pub fn page_by_hash<T>(&self, items: Vec<T>, name: impl Fn(&T) -> &str) -> (usize, Vec<T>) {
+        let mut hashed: Vec<(usize, T)> = items
+            .into_iter()
+            .map(|item| (scan_hash(name(&item)), item))
+            .filter(|(hash, _)| *hash >= self.cursor)
+            .collect();
+        hashed.sort_unstable_by_key(|(hash, _)| *hash);
+
+        let mut end = self.count.min(hashed.len());
+        while end < hashed.len() && end > 0 && hashed[end].0 == hashed[end - 1].0 {
+            end += 1;
+        }
+        let next = hashed.get(end).map(|(hash, _)| *hash).unwrap_or(0);
+        let page = hashed.into_iter().take(end).map(|(_, item)| item).collect();
+        (next, page)
+    }
+
+    pub fn response(next: usize, elements: Vec<Vec<u8>>) -> Vec<u8> {
+        resp::array(vec![resp::bulk_string(&next.to_string()), resp::array(elements)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("h*llo", "heeello"));
+        assert!(glob_match("h?llo", "hallo"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("h[ae]llo", "hello"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-b]llo", "hbllo"));
+        assert!(glob_match("h[b-a]llo", "hallo"));
+        assert!(glob_match("news.*.*", "news.sport.uk"));
+        assert!(!glob_match("news.*", "news"));
+        assert!(glob_match("h\\*llo", "h*llo"));
+        assert!(!glob_match("h\\*llo", "hello"));
+        assert!(glob_match("a**b", "ab"));
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(parse_f64("+inf"), Some(f64::INFINITY));
+        assert_eq!(parse_f64("-INF"), Some(f64::NEG_INFINITY));
+        assert_eq!(parse_f64("nan"), None);
+        assert_eq!(parse_f64("1e3"), Some(1000.0));
+        assert_eq!(format_f64(1.0), "1");
+        assert_eq!(format_f64(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(format_f64(f64::NEG_INFINITY), "-inf");
+    }
+
+    fn scan_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn scan_options() {
+        let options = ScanOptions::parse("7", &scan_args(&["match", "f*", "COUNT", "3"]), false).unwrap();
+        assert_eq!((options.cursor, options.count), (7, 3));
+        assert!(options.matches("foo") && !options.matches("bar"));
+        assert!(ScanOptions::parse("-1", &[], false).is_err());
+        assert!(ScanOptions::parse("0", &scan_args(&["COUNT", "0"]), false).is_err());
+        assert!(ScanOptions::parse("0", &scan_args(&["NOVALUES"]), false).is_err());
+        assert!(ScanOptions::parse("0", &scan_args(&["NOVALUES"]), true).unwrap().no_values);
+    }
+
+    #[test]
+    fn pages_return_stable_items_exactly_once() {
+        let mut items: Vec<String> = (0..50).map(|i| format!("item{}", i)).collect();
+        let mut seen = Vec::new();
+        let mut options = ScanOptions { cursor: 0, pattern: None, count: 7, no_values: false };
+        loop {
+            let (next, page) = options.page_by_hash(items.clone(), |item| item.as_str());
+            assert!(page.len() >= 7 || next == 0);
+            seen.extend(page);
+            // Items arriving mid-iteration must not make others repeat.
+            items.push(format!("late{}", seen.len()));
+            if next == 0 {
+                break;
+            }
+            options.cursor = next;
+        }
+        for i in 0..50 {
+            assert_eq!(seen.iter().filter(|item| **item == format!("item{}", i)).count(), 1);
+        }
+    }
+
+    #[test]
+    fn random_counts_are_bounded() {
+        assert!(check_random_count(None).is_ok());
+        assert!(check_random_count(Some(i64::MAX)).is_ok());
+        assert!(check_random_count(Some(-(MAX_RANDOM_COUNT as i64))).is_ok());
+        assert!(check_random_count(Some(-(MAX_RANDOM_COUNT as i64) - 1)).is_err());
+        assert!(check_random_count(Some(i64::MIN)).is_err());
+    }
+
+    #[test]
+    fn memory_units() {
+        assert_eq!(parse_memory("64"), Some(64));
+        assert_eq!(parse_memory("64mb"), Some(64 * 1024 * 1024));
+        assert_eq!(parse_memory("1G"), Some(1000 * 1000 * 1000));
+        assert_eq!(parse_memory("2kb"), Some(2048));
+        assert_eq!(parse_memory("1tb"), None);
+        assert_eq!(parse_memory("mb"), None);
+        assert_eq!(parse_memory("99999999999gb"), None);
+    }
+}
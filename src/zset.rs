@@ -0,0 +1,1255 @@
+use std::collections::{HashMap, HashSet};
+
+use std::time::Duration;
+
+use crate::blocking::{block_on_keys, parse_timeout};
+use crate::commands::{propagate, set_propagated};
+use crate::error::CommandError;
+use crate::get_memory_instance;
+use crate::memory::Value;
+use crate::notify;
+use crate::resp;
+use crate::skiplist::SkipList;
+use crate::util::{check_random_count, format_f64, parse_f64, parse_i64, random_index};
+
+/// Sorted set kept both in a skiplist, for ordered and rank queries, and in a
+/// hash map from member to score for O(1) lookups.
+#[derive(Clone)]
+pub struct SortedSet {
+    dict: HashMap<String, f64>,
+    list: SkipList,
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        SortedSet {
+            dict: HashMap::new(),
+            list: SkipList::new(),
+        }
+    }
+
+    pub fn from_entries(entries: Vec<(String, f64)>) -> Self {
+        let mut zset = SortedSet::new();
+        for (member, score) in entries {
+            zset.insert(member, score);
+        }
+        zset
+    }
+
+    pub fn len(&self) -> usize {
+        self.dict.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dict.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.dict.get(member).copied()
+    }
+
+    /// Adds or updates `member`, returning `true` if it was added.
+    pub fn insert(&mut self, member: String, score: f64) -> bool {
+        match self.dict.get(&member).copied() {
+            Some(current) => {
+                if current != score {
+                    self.list.remove(current, &member);
+                    self.list.insert(score, member.to_string());
+                    self.dict.insert(member, score);
+                }
+                false
+            }
+            None => {
+                self.list.insert(score, member.to_string());
+                self.dict.insert(member, score);
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.dict.remove(member) {
+            Some(score) => self.list.remove(score, member),
+            None => false,
+        }
+    }
+
+    /// 0-based rank of `member`, counted from the highest score when `rev`.
+    pub fn rank(&self, member: &str, rev: bool) -> Option<usize> {
+        let score = self.score(member)?;
+        let rank = self.list.rank(score, member)? - 1;
+        if rev {
+            Some(self.len() - 1 - rank)
+        } else {
+            Some(rank)
+        }
+    }
+
+    pub fn entries(&self) -> Vec<(String, f64)> {
+        self.walk(self.list.first(), false, usize::MAX, |_, _| true)
+    }
+
+    /// Collects up to `limit` entries starting at node `from`, moving forward
+    /// or backward, while `within` holds.
+    fn walk<F: Fn(f64, &str) -> bool>(&self, from: Option<usize>, rev: bool, limit: usize, within: F) -> Vec<(String, f64)> {
+        let mut result = Vec::new();
+        let mut current = from;
+        while let Some(id) = current {
+            if result.len() >= limit {
+                break;
+            }
+            let (member, score) = self.list.entry(id);
+            if !within(score, member) {
+                break;
+            }
+            result.push((member.to_string(), score));
+            current = if rev { self.list.prev(id) } else { self.list.next(id) };
+        }
+        result
+    }
+
+    fn skip(&self, from: Option<usize>, rev: bool, offset: usize) -> Option<usize> {
+        let mut current = from;
+        for _ in 0..offset {
+            current = current.and_then(|id| if rev { self.list.prev(id) } else { self.list.next(id) });
+        }
+        current
+    }
+
+    /// Entries between the 0-based inclusive ranks, already normalized.
+    pub fn range_by_rank(&self, start: usize, stop: usize, rev: bool) -> Vec<(String, f64)> {
+        if start > stop || start >= self.len() {
+            return Vec::new();
+        }
+        let rank = if rev { self.len() - start } else { start + 1 };
+        self.walk(self.list.by_rank(rank), rev, stop - start + 1, |_, _| true)
+    }
+
+    pub fn range_by_score(&self, range: &ScoreRange, rev: bool, offset: usize, limit: usize) -> Vec<(String, f64)> {
+        if rev {
+            let from = self.list.last_where(|score, _| range.below_max(score));
+            let from = self.skip(from, true, offset);
+            self.walk(from, true, limit, |score, _| range.above_min(score))
+        } else {
+            let from = self.list.first_where(|score, _| range.above_min(score));
+            let from = self.skip(from, false, offset);
+            self.walk(from, false, limit, |score, _| range.below_max(score))
+        }
+    }
+
+    pub fn range_by_lex(&self, range: &LexRange, rev: bool, offset: usize, limit: usize) -> Vec<(String, f64)> {
+        if rev {
+            let from = self.list.last_where(|_, member| range.below_max(member));
+            let from = self.skip(from, true, offset);
+            self.walk(from, true, limit, |_, member| range.above_min(member))
+        } else {
+            let from = self.list.first_where(|_, member| range.above_min(member));
+            let from = self.skip(from, false, offset);
+            self.walk(from, false, limit, |_, member| range.below_max(member))
+        }
+    }
+
+    /// Number of elements in the range, computed from the ranks of its
+    /// boundaries.
+    fn count_between(&self, first: Option<usize>, last: Option<usize>) -> usize {
+        match (first, last) {
+            (Some(first), Some(last)) => {
+                let (first_member, first_score) = self.list.entry(first);
+                let (last_member, last_score) = self.list.entry(last);
+                let first_rank = self.list.rank(first_score, first_member).unwrap_or(0);
+                let last_rank = self.list.rank(last_score, last_member).unwrap_or(0);
+                (last_rank + 1).saturating_sub(first_rank)
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn count_by_score(&self, range: &ScoreRange) -> usize {
+        let first = self.list.first_where(|score, _| range.above_min(score));
+        let last = self.list.last_where(|score, _| range.below_max(score));
+        self.count_between(first, last)
+    }
+
+    pub fn count_by_lex(&self, range: &LexRange) -> usize {
+        let first = self.list.first_where(|_, member| range.above_min(member));
+        let last = self.list.last_where(|_, member| range.below_max(member));
+        self.count_between(first, last)
+    }
+
+    /// Removes and returns up to `count` entries from the lowest end, or the
+    /// highest when `max`.
+    pub fn pop(&mut self, count: usize, max: bool) -> Vec<(String, f64)> {
+        let start = if max { self.list.last() } else { self.list.first() };
+        let popped = self.walk(start, max, count, |_, _| true);
+        for (member, _) in &popped {
+            self.remove(member);
+        }
+        popped
+    }
+}
+
+/// `min`/`max` arguments of `ZRANGEBYSCORE` style commands, e.g. `(1.5` or `+inf`.
+pub struct ScoreRange {
+    pub min: f64,
+    pub min_exclusive: bool,
+    pub max: f64,
+    pub max_exclusive: bool,
+}
+
+fn parse_score_bound(bound: &str) -> Result<(f64, bool), CommandError> {
+    let invalid = || CommandError::Other("min or max is not a float".to_string());
+    match bound.strip_prefix('(') {
+        Some(value) => Ok((parse_f64(value).ok_or_else(invalid)?, true)),
+        None => Ok((parse_f64(bound).ok_or_else(invalid)?, false)),
+    }
+}
+
+impl ScoreRange {
+    pub fn parse(min: &str, max: &str) -> Result<Self, CommandError> {
+        let (min, min_exclusive) = parse_score_bound(min)?;
+        let (max, max_exclusive) = parse_score_bound(max)?;
+        Ok(ScoreRange { min, min_exclusive, max, max_exclusive })
+    }
+
+    pub fn above_min(&self, score: f64) -> bool {
+        if self.min_exclusive {
+            score > self.min
+        } else {
+            score >= self.min
+        }
+    }
+
+    pub fn below_max(&self, score: f64) -> bool {
+        if self.max_exclusive {
+            score < self.max
+        } else {
+            score <= self.max
+        }
+    }
+}
+
+pub enum LexBound {
+    NegativeInfinity,
+    PositiveInfinity,
+    Inclusive(String),
+    Exclusive(String),
+}
+
+/// `min`/`max` arguments of lexicographical ranges: `-`, `+`, `[a` or `(a`.
+pub struct LexRange {
+    pub min: LexBound,
+    pub max: LexBound,
+}
+
+fn parse_lex_bound(bound: &str) -> Result<LexBound, CommandError> {
+    match bound.chars().next() {
+        Some('-') if bound.len() == 1 => Ok(LexBound::NegativeInfinity),
+        Some('+') if bound.len() == 1 => Ok(LexBound::PositiveInfinity),
+        Some('[') => Ok(LexBound::Inclusive(bound[1..].to_string())),
+        Some('(') => Ok(LexBound::Exclusive(bound[1..].to_string())),
+        _ => Err(CommandError::Other("min or max not valid string range item".to_string())),
+    }
+}
+
+impl LexRange {
+    pub fn parse(min: &str, max: &str) -> Result<Self, CommandError> {
+        Ok(LexRange { min: parse_lex_bound(min)?, max: parse_lex_bound(max)? })
+    }
+
+    pub fn above_min(&self, member: &str) -> bool {
+        match &self.min {
+            LexBound::NegativeInfinity => true,
+            LexBound::PositiveInfinity => false,
+            LexBound::Inclusive(min) => member >= min.as_str(),
+            LexBound::Exclusive(min) => member > min.as_str(),
+        }
+    }
+
+    pub fn below_max(&self, member: &str) -> bool {
+        match &self.max {
+            LexBound::NegativeInfinity => false,
+            LexBound::PositiveInfinity => true,
+            LexBound::Inclusive(max) => member <= max.as_str(),
+            LexBound::Exclusive(max) => member < max.as_str(),
+        }
+    }
+}
+
+pub fn entries_response(entries: &[(String, f64)], with_scores: bool) -> Vec<u8> {
+    let mut elements = Vec::new();
+    for (member, score) in entries {
+        elements.push(resp::bulk_string(member));
+        if with_scores {
+            elements.push(resp::bulk_string(&format_f64(*score)));
+        }
+    }
+    resp::array(elements)
+}
+
+fn parse_score(value: &str) -> Result<f64, CommandError> {
+    parse_f64(value).ok_or(CommandError::NotFloat)
+}
+
+/// `ZADD key [NX|XX] [GT|LT] [CH] [INCR] score member [score member ...]`
+fn zadd(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let (mut nx, mut xx, mut gt, mut lt, mut ch, mut incr) = (false, false, false, false, false, false);
+    let mut idx = 2;
+    while idx < commands.len() {
+        match commands[idx].to_ascii_uppercase().as_str() {
+            "NX" => nx = true,
+            "XX" => xx = true,
+            "GT" => gt = true,
+            "LT" => lt = true,
+            "CH" => ch = true,
+            "INCR" => incr = true,
+            _ => break,
+        }
+        idx += 1;
+    }
+
+    let pairs = &commands[idx..];
+    if pairs.is_empty() || pairs.len() % 2 == 1 {
+        return Err(CommandError::Syntax);
+    }
+    if nx && xx {
+        return Err(CommandError::Other("XX and NX options at the same time are not compatible".to_string()));
+    }
+    if (gt && lt) || (nx && (gt || lt)) {
+        return Err(CommandError::Other("GT, LT, and/or NX options at the same time are not compatible".to_string()));
+    }
+    if incr && pairs.len() > 2 {
+        return Err(CommandError::Other("INCR option supports a single increment-element pair".to_string()));
+    }
+    let mut parsed = Vec::new();
+    for pair in pairs.chunks(2) {
+        parsed.push((parse_score(&pair[0])?, pair[1].to_string()));
+    }
+
+    let memory = get_memory_instance();
+    if xx && memory.get_zset(&commands[1])?.is_none() {
+        return Ok(if incr { resp::null_bulk_string() } else { resp::integer(0) });
+    }
+    let zset = memory.get_or_create_zset(&commands[1])?;
+
+    let (mut added, mut changed) = (0, 0);
+    let mut modified = false;
+    let mut incr_result = None;
+    for (score, member) in parsed {
+        let current = zset.score(&member);
+        if (nx && current.is_some()) || (xx && current.is_none()) {
+            continue;
+        }
+        let score = match (incr, current) {
+            (true, Some(current)) => current + score,
+            _ => score,
+        };
+        if score.is_nan() {
+            return Err(CommandError::Other("resulting score is not a number (NaN)".to_string()));
+        }
+        if let Some(current) = current {
+            if (gt && score <= current) || (lt && score >= current) {
+                continue;
+            }
+        }
+        incr_result = Some(score);
+        match current {
+            None => added += 1,
+            Some(current) if current != score => changed += 1,
+            _ => {}
+        }
+        modified |= current != Some(score);
+        zset.insert(member, score);
+    }
+    if modified {
+        memory.touch(&commands[1]);
+    }
+    memory.remove_if_empty(&commands[1]);
+
+    if incr {
+        return Ok(match incr_result {
+            Some(score) => resp::bulk_string(&format_f64(score)),
+            None => resp::null_bulk_string(),
+        });
+    }
+    Ok(resp::integer(if ch { added + changed } else { added }))
+}
+
+fn zincrby(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let increment = parse_score(&commands[2])?;
+    let memory = get_memory_instance();
+    let zset = memory.get_or_create_zset(&commands[1])?;
+    let score = zset.score(&commands[3]).unwrap_or(0.0) + increment;
+    if score.is_nan() {
+        return Err(CommandError::Other("resulting score is not a number (NaN)".to_string()));
+    }
+    zset.insert(commands[3].to_string(), score);
+    memory.touch(&commands[1]);
+    Ok(resp::bulk_string(&format_f64(score)))
+}
+
+fn zrem(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let memory = get_memory_instance();
+    let removed = match memory.get_zset_mut(&commands[1])? {
+        Some(zset) => commands[2..].iter().filter(|m| zset.remove(m)).count(),
+        None => 0,
+    };
+    if removed > 0 {
+        memory.touch(&commands[1]);
+    }
+    memory.remove_if_empty(&commands[1]);
+    Ok(resp::integer(removed as i64))
+}
+
+fn zscore(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let zset = get_memory_instance().get_zset(&commands[1])?;
+    match zset.and_then(|z| z.score(&commands[2])) {
+        Some(score) => Ok(resp::bulk_string(&format_f64(score))),
+        None => Ok(resp::null_bulk_string()),
+    }
+}
+
+fn zmscore(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let zset = get_memory_instance().get_zset(&commands[1])?;
+    Ok(resp::array(
+        commands[2..]
+            .iter()
+            .map(|member| match zset.and_then(|z| z.score(member)) {
+                Some(score) => resp::bulk_string(&format_f64(score)),
+                None => resp::null_bulk_string(),
+            })
+            .collect(),
+    ))
+}
+
+fn zcard(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 2 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let zset = get_memory_instance().get_zset(&commands[1])?;
+    Ok(resp::integer(zset.map(|z| z.len()).unwrap_or(0) as i64))
+}
+
+/// `ZRANK key member [WITHSCORE]`, also used for `ZREVRANK`.
+fn zrank(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    let with_score = match commands.len() {
+        3 => false,
+        4 if commands[3].eq_ignore_ascii_case("WITHSCORE") => true,
+        4 => return Err(CommandError::Syntax),
+        _ => return Err(CommandError::arity(&commands[0])),
+    };
+    let zset = match get_memory_instance().get_zset(&commands[1])? {
+        Some(zset) => zset,
+        None => return Ok(if with_score { resp::null_array() } else { resp::null_bulk_string() }),
+    };
+    match zset.rank(&commands[2], commands[0] == "ZREVRANK") {
+        Some(rank) if with_score => Ok(resp::array(vec![
+            resp::integer(rank as i64),
+            resp::bulk_string(&format_f64(zset.score(&commands[2]).unwrap_or(0.0))),
+        ])),
+        Some(rank) => Ok(resp::integer(rank as i64)),
+        None if with_score => Ok(resp::null_array()),
+        None => Ok(resp::null_bulk_string()),
+    }
+}
+
+fn zcount(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let range = ScoreRange::parse(&commands[2], &commands[3])?;
+    let zset = get_memory_instance().get_zset(&commands[1])?;
+    Ok(resp::integer(zset.map(|z| z.count_by_score(&range)).unwrap_or(0) as i64))
+}
+
+fn zlexcount(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() != 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let range = LexRange::parse(&commands[2], &commands[3])?;
+    let zset = get_memory_instance().get_zset(&commands[1])?;
+    Ok(resp::integer(zset.map(|z| z.count_by_lex(&range)).unwrap_or(0) as i64))
+}
+
+#[derive(PartialEq)]
+enum RangeKind {
+    Rank,
+    Score,
+    Lex,
+}
+
+struct RangeQuery {
+    kind: RangeKind,
+    start: String,
+    stop: String,
+    rev: bool,
+    offset: usize,
+    limit: Option<usize>,
+    with_scores: bool,
+}
+
+impl RangeQuery {
+    /// Parses `start stop [BYSCORE|BYLEX] [REV] [LIMIT offset count] [WITHSCORES]`.
+    fn parse(args: &[String], allow_with_scores: bool) -> Result<Self, CommandError> {
+        let mut query = RangeQuery {
+            kind: RangeKind::Rank,
+            start: args[0].to_string(),
+            stop: args[1].to_string(),
+            rev: false,
+            offset: 0,
+            limit: None,
+            with_scores: false,
+        };
+        let mut idx = 2;
+        while idx < args.len() {
+            match args[idx].to_ascii_uppercase().as_str() {
+                "BYSCORE" => query.kind = RangeKind::Score,
+                "BYLEX" => query.kind = RangeKind::Lex,
+                "REV" => query.rev = true,
+                "WITHSCORES" if allow_with_scores => query.with_scores = true,
+                "LIMIT" if idx + 2 < args.len() => {
+                    let offset = parse_i64(&args[idx + 1]).ok_or(CommandError::NotInteger)?;
+                    let count = parse_i64(&args[idx + 2]).ok_or(CommandError::NotInteger)?;
+                    query.offset = offset.max(0) as usize;
+                    query.limit = if count < 0 { None } else { Some(count as usize) };
+                    if offset < 0 {
+                        query.limit = Some(0);
+                    }
+                    idx += 2;
+                }
+                _ => return Err(CommandError::Syntax),
+            }
+            idx += 1;
+        }
+        if query.limit.is_some() && query.kind == RangeKind::Rank {
+            return Err(CommandError::Other(
+                "syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX".to_string(),
+            ));
+        }
+        if query.with_scores && query.kind == RangeKind::Lex {
+            return Err(CommandError::Other("syntax error, WITHSCORES not supported in combination with BYLEX".to_string()));
+        }
+        Ok(query)
+    }
+
+    fn run(&self, zset: &SortedSet) -> Result<Vec<(String, f64)>, CommandError> {
+        let limit = self.limit.unwrap_or(usize::MAX);
+        // With REV the first boundary is the upper one.
+        let (min, max) = if self.rev { (&self.stop, &self.start) } else { (&self.start, &self.stop) };
+        match self.kind {
+            RangeKind::Rank => {
+                let len = zset.len() as i64;
+                let start = parse_i64(&self.start).ok_or(CommandError::NotInteger)?;
+                let stop = parse_i64(&self.stop).ok_or(CommandError::NotInteger)?;
+                let start = if start < 0 { (len + start).max(0) } else { start };
+                let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+                if stop < 0 || start > stop {
+                    return Ok(Vec::new());
+                }
+                Ok(zset.range_by_rank(start as usize, stop as usize, self.rev))
+            }
+            RangeKind::Score => {
+                let range = ScoreRange::parse(min, max)?;
+                Ok(zset.range_by_score(&range, self.rev, self.offset, limit))
+            }
+            RangeKind::Lex => {
+                let range = LexRange::parse(min, max)?;
+                Ok(zset.range_by_lex(&range, self.rev, self.offset, limit))
+            }
+        }
+    }
+}
+
+fn zrange(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let query = RangeQuery::parse(&commands[2..], true)?;
+    let entries = match get_memory_instance().get_zset(&commands[1])? {
+        Some(zset) => query.run(zset)?,
+        None => Vec::new(),
+    };
+    Ok(entries_response(&entries, query.with_scores))
+}
+
+/// Rewrites the legacy range commands into their `ZRANGE` equivalent, e.g.
+/// `ZREVRANGEBYSCORE key max min` to `ZRANGE key max min BYSCORE REV`.
+fn legacy_range(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let flags: &[&str] = match commands[0].as_str() {
+        "ZREVRANGE" => &["REV"],
+        "ZRANGEBYSCORE" => &["BYSCORE"],
+        "ZREVRANGEBYSCORE" => &["BYSCORE", "REV"],
+        "ZRANGEBYLEX" => &["BYLEX"],
+        _ => &["BYLEX", "REV"],
+    };
+    let mut rewritten: Vec<String> = vec!["ZRANGE".to_string()];
+    rewritten.extend(commands[1..4].iter().cloned());
+    rewritten.extend(flags.iter().map(|f| f.to_string()));
+    rewritten.extend(commands[4..].iter().cloned());
+    zrange(&rewritten)
+}
+
+/// `ZRANGESTORE dst src min max [BYSCORE|BYLEX] [REV] [LIMIT offset count]`
+fn zrangestore(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 5 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let query = RangeQuery::parse(&commands[3..], false)?;
+    let memory = get_memory_instance();
+    let entries = match memory.get_zset(&commands[2])? {
+        Some(zset) => query.run(zset)?,
+        None => Vec::new(),
+    };
+    Ok(resp::integer(store_entries(&commands[1], entries) as i64))
+}
+
+/// Replaces `key` with a sorted set holding `entries`, deleting it when empty.
+pub fn store_entries(key: &str, entries: Vec<(String, f64)>) -> usize {
+    let memory = get_memory_instance();
+    memory.remove(key);
+    let size = entries.len();
+    if size > 0 {
+        memory.set_value(key.to_string(), Value::ZSet(SortedSet::from_entries(entries)));
+    }
+    size
+}
+
+#[derive(Clone, Copy)]
+enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(&self, current: f64, score: f64) -> f64 {
+        match self {
+            // inf + -inf is NaN in IEEE 754, Redis treats it as 0.
+            Aggregate::Sum => {
+                let sum = current + score;
+                if sum.is_nan() { 0.0 } else { sum }
+            }
+            Aggregate::Min => current.min(score),
+            Aggregate::Max => current.max(score),
+        }
+    }
+}
+
+/// Entries of a sorted set, or of a plain set with every score set to 1,
+/// as accepted by the multi-key sorted set commands.
+fn read_weighted_input(key: &str) -> Result<Vec<(String, f64)>, CommandError> {
+    match get_memory_instance().lookup(key) {
+        None => Ok(Vec::new()),
+        Some(Value::ZSet(zset)) => Ok(zset.entries()),
+        Some(Value::Set(set)) => Ok(set.members().into_iter().map(|m| (m, 1.0)).collect()),
+        Some(_) => Err(CommandError::WrongType),
+    }
+}
+
+fn parse_numkeys(args: &[String]) -> Result<(&[String], &[String]), CommandError> {
+    let numkeys = match args.first().and_then(|v| parse_i64(v)) {
+        Some(numkeys) if numkeys > 0 => numkeys as usize,
+        Some(_) => return Err(CommandError::Other("numkeys should be greater than 0".to_string())),
+        None => return Err(CommandError::NotInteger),
+    };
+    if args.len() < 1 + numkeys {
+        return Err(CommandError::Syntax);
+    }
+    Ok((&args[1..1 + numkeys], &args[1 + numkeys..]))
+}
+
+struct SetOperation<'a> {
+    keys: &'a [String],
+    weights: Vec<f64>,
+    aggregate: Aggregate,
+    with_scores: bool,
+}
+
+impl<'a> SetOperation<'a> {
+    /// Parses `numkeys key [key ...] [WEIGHTS w ...] [AGGREGATE SUM|MIN|MAX] [WITHSCORES]`.
+    fn parse(args: &'a [String], allow_weights: bool, allow_with_scores: bool) -> Result<Self, CommandError> {
+        let (keys, options) = parse_numkeys(args)?;
+        let mut operation = SetOperation {
+            keys,
+            weights: vec![1.0; keys.len()],
+            aggregate: Aggregate::Sum,
+            with_scores: false,
+        };
+        let mut idx = 0;
+        while idx < options.len() {
+            match options[idx].to_ascii_uppercase().as_str() {
+                "WEIGHTS" if allow_weights && idx + keys.len() < options.len() => {
+                    for (i, weight) in options[idx + 1..=idx + keys.len()].iter().enumerate() {
+                        operation.weights[i] = parse_f64(weight)
+                            .ok_or_else(|| CommandError::Other("weight value is not a float".to_string()))?;
+                    }
+                    idx += keys.len();
+                }
+                "AGGREGATE" if allow_weights && idx + 1 < options.len() => {
+                    operation.aggregate = match options[idx + 1].to_ascii_uppercase().as_str() {
+                        "SUM" => Aggregate::Sum,
+                        "MIN" => Aggregate::Min,
+                        "MAX" => Aggregate::Max,
+                        _ => return Err(CommandError::Syntax),
+                    };
+                    idx += 1;
+                }
+                "WITHSCORES" if allow_with_scores => operation.with_scores = true,
+                _ => return Err(CommandError::Syntax),
+            }
+            idx += 1;
+        }
+        Ok(operation)
+    }
+
+    fn weighted_inputs(&self) -> Result<Vec<Vec<(String, f64)>>, CommandError> {
+        let mut inputs = Vec::new();
+        for (key, weight) in self.keys.iter().zip(&self.weights) {
+            let entries = read_weighted_input(key)?;
+            inputs.push(
+                entries
+                    .into_iter()
+                    .map(|(member, score)| {
+                        let weighted = score * weight;
+                        (member, if weighted.is_nan() { 0.0 } else { weighted })
+                    })
+                    .collect(),
+            );
+        }
+        Ok(inputs)
+    }
+
+    fn union(&self) -> Result<Vec<(String, f64)>, CommandError> {
+        let mut result: HashMap<String, f64> = HashMap::new();
+        for input in self.weighted_inputs()? {
+            for (member, score) in input {
+                let aggregated = match result.get(&member) {
+                    Some(current) => self.aggregate.apply(*current, score),
+                    None => score,
+                };
+                result.insert(member, aggregated);
+            }
+        }
+        Ok(result.into_iter().collect())
+    }
+
+    fn intersection(&self) -> Result<Vec<(String, f64)>, CommandError> {
+        let mut inputs = self.weighted_inputs()?.into_iter();
+        let mut result: HashMap<String, f64> = inputs.next().unwrap_or_default().into_iter().collect();
+        for input in inputs {
+            let input: HashMap<String, f64> = input.into_iter().collect();
+            result = result
+                .into_iter()
+                .filter_map(|(member, current)| {
+                    let score = input.get(&member)?;
+                    Some((member, self.aggregate.apply(current, *score)))
+                })
+                .collect();
+        }
+        Ok(result.into_iter().collect())
+    }
+
+    fn difference(&self) -> Result<Vec<(String, f64)>, CommandError> {
+        let mut inputs = self.weighted_inputs()?.into_iter();
+        let first = inputs.next().unwrap_or_default();
+        let mut excluded: HashSet<String> = HashSet::new();
+        for input in inputs {
+            excluded.extend(input.into_iter().map(|(member, _)| member));
+        }
+        Ok(first.into_iter().filter(|(member, _)| !excluded.contains(member)).collect())
+    }
+
+    fn run(&self, name: &str) -> Result<Vec<(String, f64)>, CommandError> {
+        let mut entries = match name {
+            "ZUNION" | "ZUNIONSTORE" => self.union()?,
+            "ZINTER" | "ZINTERSTORE" => self.intersection()?,
+            _ => self.difference()?,
+        };
+        entries.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        Ok(entries)
+    }
+}
+
+/// `ZUNION`, `ZINTER` and `ZDIFF`.
+fn zsetop(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let operation = SetOperation::parse(&commands[1..], commands[0] != "ZDIFF", true)?;
+    let entries = operation.run(&commands[0])?;
+    Ok(entries_response(&entries, operation.with_scores))
+}
+
+/// `ZUNIONSTORE`, `ZINTERSTORE` and `ZDIFFSTORE`.
+fn zsetop_store(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let operation = SetOperation::parse(&commands[2..], commands[0] != "ZDIFFSTORE", false)?;
+    let entries = operation.run(&commands[0])?;
+    Ok(resp::integer(store_entries(&commands[1], entries) as i64))
+}
+
+fn zintercard(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let (keys, options) = parse_numkeys(&commands[1..])?;
+    let limit = match options {
+        [] => 0,
+        [option, limit] if option.eq_ignore_ascii_case("LIMIT") => match parse_i64(limit) {
+            Some(limit) if limit >= 0 => limit as usize,
+            _ => return Err(CommandError::Other("LIMIT can't be negative".to_string())),
+        },
+        _ => return Err(CommandError::Syntax),
+    };
+    let operation = SetOperation {
+        keys,
+        weights: vec![1.0; keys.len()],
+        aggregate: Aggregate::Sum,
+        with_scores: false,
+    };
+    let size = operation.intersection()?.len();
+    Ok(resp::integer(if limit > 0 { size.min(limit) } else { size } as i64))
+}
+
+/// A key together with the entries popped from it.
+type Popped = (String, Vec<(String, f64)>);
+
+/// Pops from the first non-empty sorted set among `keys`.
+fn pop_first_non_empty(keys: &[String], count: usize, max: bool) -> Result<Option<Popped>, CommandError> {
+    let memory = get_memory_instance();
+    for key in keys {
+        let popped = match memory.get_zset_mut(key)? {
+            Some(zset) => zset.pop(count, max),
+            None => continue,
+        };
+        if !popped.is_empty() {
+            memory.touch(key);
+        }
+        memory.remove_if_empty(key);
+        if !popped.is_empty() {
+            return Ok(Some((key.to_string(), popped)));
+        }
+    }
+    Ok(None)
+}
+
+fn zpop(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 2 || commands.len() > 3 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let count = match commands.get(2) {
+        Some(count) => match parse_i64(count) {
+            Some(count) if count >= 0 => count as usize,
+            _ => return Err(CommandError::Other("value is out of range, must be positive".to_string())),
+        },
+        None => 1,
+    };
+    let popped = pop_first_non_empty(&commands[1..2], count, commands[0] == "ZPOPMAX")?;
+    Ok(entries_response(&popped.map(|(_, entries)| entries).unwrap_or_default(), true))
+}
+
+struct MultiPop<'a> {
+    keys: &'a [String],
+    max: bool,
+    count: usize,
+}
+
+impl<'a> MultiPop<'a> {
+    /// Parses `numkeys key [key ...] MIN|MAX [COUNT count]`.
+    fn parse(args: &'a [String]) -> Result<Self, CommandError> {
+        let (keys, options) = parse_numkeys(args)?;
+        let max = match options.first().map(|o| o.to_ascii_uppercase()) {
+            Some(direction) if direction == "MIN" => false,
+            Some(direction) if direction == "MAX" => true,
+            _ => return Err(CommandError::Syntax),
+        };
+        let count = match &options[1..] {
+            [] => 1,
+            [option, count] if option.eq_ignore_ascii_case("COUNT") => match parse_i64(count) {
+                Some(count) if count > 0 => count as usize,
+                _ => return Err(CommandError::Other("count should be greater than 0".to_string())),
+            },
+            _ => return Err(CommandError::Syntax),
+        };
+        Ok(MultiPop { keys, max, count })
+    }
+
+    fn response(popped: Option<Popped>) -> Vec<u8> {
+        match popped {
+            Some((key, entries)) => resp::array(vec![
+                resp::bulk_string(&key),
+                resp::array(
+                    entries
+                        .iter()
+                        .map(|(member, score)| {
+                            resp::array(vec![resp::bulk_string(member), resp::bulk_string(&format_f64(*score))])
+                        })
+                        .collect(),
+                ),
+            ]),
+            None => resp::null_array(),
+        }
+    }
+}
+
+fn zmpop(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let pop = MultiPop::parse(&commands[1..])?;
+    Ok(MultiPop::response(pop_first_non_empty(pop.keys, pop.count, pop.max)?))
+}
+
+/// `BZPOPMIN`/`BZPOPMAX key [key ...] timeout` and `BZMPOP timeout numkeys
+/// key [key ...] MIN|MAX [COUNT count]`.
+struct BlockingPop<'a> {
+    multi: bool,
+    keys: &'a [String],
+    timeout: Option<Duration>,
+    max: bool,
+    count: usize,
+}
+
+impl<'a> BlockingPop<'a> {
+    fn parse(commands: &'a [String]) -> Result<Self, CommandError> {
+        if commands[0] == "BZMPOP" {
+            if commands.len() < 5 {
+                return Err(CommandError::arity(&commands[0]));
+            }
+            let timeout = parse_timeout(&commands[1])?;
+            let pop = MultiPop::parse(&commands[2..])?;
+            return Ok(BlockingPop { multi: true, keys: pop.keys, timeout, max: pop.max, count: pop.count });
+        }
+        if commands.len() < 3 {
+            return Err(CommandError::arity(&commands[0]));
+        }
+        Ok(BlockingPop {
+            multi: false,
+            keys: &commands[1..commands.len() - 1],
+            timeout: parse_timeout(&commands[commands.len() - 1])?,
+            max: commands[0] == "BZPOPMAX",
+            count: 1,
+        })
+    }
+
+    fn attempt(&self) -> Result<Option<Popped>, CommandError> {
+        pop_first_non_empty(self.keys, self.count, self.max)
+    }
+
+    /// The client response, and the command a replica has to run to pop the
+    /// same entries.
+    fn response(&self, popped: Option<Popped>) -> (Vec<u8>, Option<Vec<String>>) {
+        let direction = if self.max { "ZPOPMAX" } else { "ZPOPMIN" };
+        if self.multi {
+            let propagated = popped
+                .as_ref()
+                .map(|(key, entries)| vec![direction.to_string(), key.to_string(), entries.len().to_string()]);
+            return (MultiPop::response(popped), propagated);
+        }
+        match popped {
+            Some((key, entries)) => {
+                let (member, score) = &entries[0];
+                let response = resp::array(vec![
+                    resp::bulk_string(&key),
+                    resp::bulk_string(member),
+                    resp::bulk_string(&format_f64(*score)),
+                ]);
+                (response, Some(vec![direction.to_string(), key]))
+            }
+            None => (resp::null_array(), None),
+        }
+    }
+}
+
+pub async fn blocking_pop(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    let pop = BlockingPop::parse(commands)?;
+    // The pop is propagated in the locked section that made it.
+    let response = block_on_keys(pop.keys, pop.timeout, || {
+        let popped = notify::with_events(commands, || pop.attempt())?;
+        Ok(popped.map(|popped| {
+            let (response, propagated) = pop.response(Some(popped));
+            if let Some(propagated) = propagated {
+                propagate(&resp::bulk_string_array(&propagated));
+            }
+            response
+        }))
+    })
+    .await?;
+    Ok(response.unwrap_or_else(|| pop.response(None).0))
+}
+
+/// Blocking pops that must not block, as inside a transaction, behave like
+/// their non-blocking counterparts.
+fn blocking_pop_now(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    let pop = BlockingPop::parse(commands)?;
+    let (response, propagated) = pop.response(pop.attempt()?);
+    if let Some(propagated) = propagated {
+        set_propagated(propagated);
+    }
+    Ok(response)
+}
+
+/// `ZRANDMEMBER key [count [WITHSCORES]]`
+fn zrandmember(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    if commands.len() < 2 || commands.len() > 4 {
+        return Err(CommandError::arity(&commands[0]));
+    }
+    let count = match commands.get(2) {
+        Some(count) => Some(parse_i64(count).ok_or(CommandError::NotInteger)?),
+        None => None,
+    };
+    let with_scores = match commands.get(3) {
+        Some(option) if option.eq_ignore_ascii_case("WITHSCORES") => true,
+        Some(_) => return Err(CommandError::Syntax),
+        None => false,
+    };
+    check_random_count(count)?;
+    let mut entries = match get_memory_instance().get_zset(&commands[1])? {
+        Some(zset) => zset.entries(),
+        None => Vec::new(),
+    };
+
+    let count = match count {
+        Some(count) => count,
+        None if entries.is_empty() => return Ok(resp::null_bulk_string()),
+        None => return Ok(resp::bulk_string(&entries[random_index(entries.len())].0)),
+    };
+    let picked: Vec<(String, f64)> = if entries.is_empty() {
+        Vec::new()
+    } else if count < 0 {
+        (0..count.unsigned_abs()).map(|_| entries[random_index(entries.len())].clone()).collect()
+    } else {
+        let mut picked = Vec::new();
+        while !entries.is_empty() && picked.len() < count as usize {
+            picked.push(entries.swap_remove(random_index(entries.len())));
+        }
+        picked
+    };
+    Ok(entries_response(&picked, with_scores))
+}
+
+pub fn process_command(commands: &[String]) -> Result<Vec<u8>, CommandError> {
+    match commands[0].as_str() {
+        "ZADD" => zadd(commands),
+        "ZINCRBY" => zincrby(commands),
+        "ZREM" => zrem(commands),
+        "ZSCORE" => zscore(commands),
+        "ZMSCORE" => zmscore(commands),
+        "ZCARD" => zcard(commands),
+        "ZRANK" | "ZREVRANK" => zrank(commands),
+        "ZCOUNT" => zcount(commands),
+        "ZLEXCOUNT" => zlexcount(commands),
+        "ZRANGE" => zrange(commands),
+        "ZREVRANGE" | "ZRANGEBYSCORE" | "ZREVRANGEBYSCORE" | "ZRANGEBYLEX" | "ZREVRANGEBYLEX" => legacy_range(commands),
+        "ZRANGESTORE" => zrangestore(commands),
+        "ZUNION" | "ZINTER" | "ZDIFF" => zsetop(commands),
+        "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE" => zsetop_store(commands),
+        "ZINTERCARD" => zintercard(commands),
+        "ZPOPMIN" | "ZPOPMAX" => zpop(commands),
+        "ZMPOP" => zmpop(commands),
+        "ZRANDMEMBER" => zrandmember(commands),
+        "BZPOPMIN" | "BZPOPMAX" | "BZMPOP" => blocking_pop_now(commands),
+        _ => Err(CommandError::Other(format!("unknown command '{}'", commands[0]))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{run, run_propagated, strings};
+
+    fn zadd_sample(key: &str) {
+        run(&["ZADD", key, "1", "a", "2", "b", "2", "c", "3", "d", "-inf", "low", "+inf", "high"]);
+    }
+
+    #[test]
+    fn score_and_lex_bounds() {
+        let range = ScoreRange::parse("(1", "+inf").unwrap();
+        assert!(!range.above_min(1.0) && range.above_min(1.5) && range.below_max(f64::INFINITY));
+        let range = ScoreRange::parse("-inf", "(2.5").unwrap();
+        assert!(range.above_min(f64::NEG_INFINITY) && range.below_max(2.0) && !range.below_max(2.5));
+        assert!(ScoreRange::parse("x", "1").is_err());
+        assert!(ScoreRange::parse("((1", "1").is_err());
+
+        let range = LexRange::parse("[b", "(d").unwrap();
+        assert!(range.above_min("b") && !range.above_min("a") && range.below_max("c") && !range.below_max("d"));
+        let range = LexRange::parse("-", "+").unwrap();
+        assert!(range.above_min("") && range.below_max("zzz"));
+        assert!(LexRange::parse("b", "+").is_err());
+        assert!(!LexRange::parse("+", "-").unwrap().below_max("a"));
+    }
+
+    #[test]
+    fn add_flags() {
+        assert_eq!(run(&["ZADD", "zset:flags", "1", "a", "2", "b"]), resp::integer(2));
+        assert_eq!(run(&["ZADD", "zset:flags", "NX", "5", "a", "3", "c"]), resp::integer(1));
+        assert_eq!(run(&["ZADD", "zset:flags", "XX", "CH", "5", "a", "4", "missing"]), resp::integer(1));
+        assert_eq!(run(&["ZADD", "zset:flags", "GT", "CH", "1", "a", "3", "b"]), resp::integer(1));
+        assert_eq!(run(&["ZADD", "zset:flags", "LT", "CH", "9", "c", "0", "c"]), resp::integer(1));
+        assert_eq!(run(&["ZADD", "zset:flags", "INCR", "2.5", "a"]), resp::bulk_string("7.5"));
+        assert_eq!(run(&["ZADD", "zset:flags", "NX", "INCR", "1", "a"]), resp::null_bulk_string());
+        assert_eq!(run(&["ZSCORE", "zset:flags", "c"]), resp::bulk_string("0"));
+        assert_eq!(run(&["ZINCRBY", "zset:flags", "-1", "c"]), resp::bulk_string("-1"));
+        assert_eq!(run(&["ZCARD", "zset:flags"]), resp::integer(3));
+
+        assert_eq!(
+            run(&["ZADD", "zset:flags", "NX", "XX", "1", "a"]),
+            resp::error("ERR XX and NX options at the same time are not compatible")
+        );
+        assert_eq!(
+            run(&["ZADD", "zset:flags", "GT", "LT", "1", "a"]),
+            resp::error("ERR GT, LT, and/or NX options at the same time are not compatible")
+        );
+        assert_eq!(run(&["ZADD", "zset:flags", "NX", "1"]), resp::error("ERR syntax error"));
+        assert_eq!(run(&["ZADD", "zset:flags", "nan", "a"]), resp::error("ERR value is not a valid float"));
+        assert_eq!(run(&["ZADD", "zset:flags", "INCR", "-inf", "inf"]), resp::bulk_string("-inf"));
+        assert_eq!(run(&["ZADD", "zset:flags", "INCR", "+inf", "inf"]), resp::error("ERR resulting score is not a number (NaN)"));
+        // XX on a missing key doesn't create it.
+        assert_eq!(run(&["ZADD", "zset:none", "XX", "1", "a"]), resp::integer(0));
+        assert_eq!(run(&["TYPE", "zset:none"]), resp::simple_string("none"));
+    }
+
+    #[test]
+    fn ranges() {
+        zadd_sample("zset:ranges");
+        let all = ["low", "a", "b", "c", "d", "high"];
+        assert_eq!(strings(&run(&["ZRANGE", "zset:ranges", "0", "-1"])), all);
+        assert_eq!(strings(&run(&["ZRANGE", "zset:ranges", "-2", "100"])), ["d", "high"]);
+        assert_eq!(strings(&run(&["ZRANGE", "zset:ranges", "0", "1", "REV"])), ["high", "d"]);
+        assert_eq!(strings(&run(&["ZRANGE", "zset:ranges", "4", "2"])), Vec::<String>::new());
+        assert_eq!(strings(&run(&["ZRANGE", "zset:ranges", "(1", "2", "BYSCORE", "WITHSCORES"])), ["b", "2", "c", "2"]);
+        assert_eq!(strings(&run(&["ZRANGE", "zset:ranges", "+inf", "2", "BYSCORE", "REV", "LIMIT", "1", "2"])), ["d", "c"]);
+        assert_eq!(strings(&run(&["ZREVRANGEBYSCORE", "zset:ranges", "2", "(1"])), ["c", "b"]);
+        assert_eq!(strings(&run(&["ZRANGEBYSCORE", "zset:ranges", "-inf", "-inf", "WITHSCORES"])), ["low", "-inf"]);
+        assert_eq!(
+            run(&["ZRANGE", "zset:ranges", "0", "1", "LIMIT", "0", "1"]),
+            resp::error("ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX")
+        );
+
+        run(&["ZADD", "zset:lex", "0", "a", "0", "b", "0", "c", "0", "d"]);
+        assert_eq!(strings(&run(&["ZRANGE", "zset:lex", "[b", "(d", "BYLEX"])), ["b", "c"]);
+        assert_eq!(strings(&run(&["ZREVRANGEBYLEX", "zset:lex", "+", "[b", "LIMIT", "0", "2"])), ["d", "c"]);
+        assert_eq!(run(&["ZLEXCOUNT", "zset:lex", "-", "(c"]), resp::integer(2));
+        assert_eq!(run(&["ZCOUNT", "zset:ranges", "(1", "+inf"]), resp::integer(4));
+    }
+
+    #[test]
+    fn ranks_and_scores() {
+        zadd_sample("zset:ranks");
+        assert_eq!(run(&["ZRANK", "zset:ranks", "b"]), resp::integer(2));
+        assert_eq!(run(&["ZREVRANK", "zset:ranks", "b"]), resp::integer(3));
+        assert_eq!(run(&["ZRANK", "zset:ranks", "missing"]), resp::null_bulk_string());
+        let scores = resp::array(vec![resp::bulk_string("1"), resp::null_bulk_string(), resp::bulk_string("inf")]);
+        assert_eq!(run(&["ZMSCORE", "zset:ranks", "a", "missing", "high"]), scores);
+        assert_eq!(run(&["ZREM", "zset:ranks", "a", "missing", "b"]), resp::integer(2));
+        assert_eq!(run(&["ZRANK", "zset:ranks", "c"]), resp::integer(1));
+    }
+
+    #[test]
+    fn algebra() {
+        run(&["ZADD", "zset:algebra1", "1", "a", "2", "b", "3", "c"]);
+        run(&["ZADD", "zset:algebra2", "10", "b", "20", "c", "30", "d"]);
+        run(&["SADD", "zset:plain", "c", "d"]);
+        let union = ["a", "1", "b", "12", "c", "23", "d", "30"];
+        assert_eq!(strings(&run(&["ZUNION", "2", "zset:algebra1", "zset:algebra2", "WITHSCORES"])), union);
+        let weighted = ["a", "2", "b", "9", "d", "15", "c", "16"];
+        let args = ["ZUNION", "2", "zset:algebra1", "zset:algebra2", "WEIGHTS", "2", "0.5", "WITHSCORES"];
+        assert_eq!(strings(&run(&args)), weighted);
+        let args = ["ZINTER", "2", "zset:algebra1", "zset:algebra2", "AGGREGATE", "MAX", "WITHSCORES"];
+        assert_eq!(strings(&run(&args)), ["b", "10", "c", "20"]);
+        // Plain sets count as sorted sets with every score at 1.
+        let args = ["ZINTER", "2", "zset:algebra2", "zset:plain", "AGGREGATE", "MIN", "WITHSCORES"];
+        assert_eq!(strings(&run(&args)), ["c", "1", "d", "1"]);
+        assert_eq!(strings(&run(&["ZDIFF", "2", "zset:algebra1", "zset:algebra2"])), ["a"]);
+        assert_eq!(run(&["ZDIFF", "1", "zset:algebra1", "WEIGHTS", "1"]), resp::error("ERR syntax error"));
+        assert_eq!(run(&["ZINTERCARD", "2", "zset:algebra1", "zset:algebra2", "LIMIT", "1"]), resp::integer(1));
+        assert_eq!(run(&["ZUNION", "0", "zset:algebra1"]), resp::error("ERR numkeys should be greater than 0"));
+
+        assert_eq!(run(&["ZUNIONSTORE", "zset:stored", "2", "zset:algebra1", "zset:plain"]), resp::integer(4));
+        assert_eq!(strings(&run(&["ZRANGE", "zset:stored", "0", "-1", "WITHSCORES"])), ["a", "1", "d", "1", "b", "2", "c", "4"]);
+        assert_eq!(run(&["ZINTERSTORE", "zset:stored", "2", "zset:algebra1", "zset:missing"]), resp::integer(0));
+        assert_eq!(run(&["TYPE", "zset:stored"]), resp::simple_string("none"));
+
+        // inf - inf sums to 0, as in Redis.
+        run(&["ZADD", "zset:inf1", "inf", "x"]);
+        run(&["ZADD", "zset:inf2", "-inf", "x"]);
+        assert_eq!(strings(&run(&["ZUNION", "2", "zset:inf1", "zset:inf2", "WITHSCORES"])), ["x", "0"]);
+    }
+
+    #[test]
+    fn pops() {
+        run(&["ZADD", "zset:pop", "1", "a", "2", "b", "3", "c", "4", "d"]);
+        assert_eq!(strings(&run(&["ZPOPMIN", "zset:pop"])), ["a", "1"]);
+        assert_eq!(strings(&run(&["ZPOPMAX", "zset:pop", "2"])), ["d", "4", "c", "3"]);
+        assert_eq!(
+            run(&["ZPOPMIN", "zset:pop", "-1"]),
+            resp::error("ERR value is out of range, must be positive")
+        );
+        let popped = resp::array(vec![
+            resp::bulk_string("zset:pop"),
+            resp::array(vec![resp::bulk_string_array(&["b", "2"])]),
+        ]);
+        assert_eq!(run(&["ZMPOP", "2", "zset:missing", "zset:pop", "MIN", "COUNT", "5"]), popped);
+        assert_eq!(run(&["TYPE", "zset:pop"]), resp::simple_string("none"));
+        assert_eq!(run(&["ZMPOP", "1", "zset:pop", "MAX"]), resp::null_array());
+        assert_eq!(run(&["ZMPOP", "1", "zset:pop", "UP"]), resp::error("ERR syntax error"));
+        assert_eq!(run(&["ZPOPMIN", "zset:pop"]), resp::array(Vec::new()));
+    }
+
+    #[test]
+    fn blocking_pops_outside_a_client_do_not_block() {
+        run(&["ZADD", "zset:bpop", "1", "a", "2", "b"]);
+        let (propagated, _) = run_propagated(&["BZPOPMAX", "zset:missing", "zset:bpop", "0"]).unwrap();
+        assert_eq!(propagated, ["ZPOPMAX", "zset:bpop"]);
+        let (propagated, _) = run_propagated(&["BZMPOP", "0", "1", "zset:bpop", "MIN", "COUNT", "3"]).unwrap();
+        assert_eq!(propagated, ["ZPOPMIN", "zset:bpop", "1"]);
+        assert_eq!(run(&["BZPOPMIN", "zset:bpop", "0"]), resp::null_array());
+        assert_eq!(run_propagated(&["BZPOPMIN", "zset:bpop", "0"]), None);
+    }
+
+    #[tokio::test]
+    async fn blocking_pop_waits_for_a_write() {
+        let commands: Vec<String> = ["BZPOPMIN", "zset:blocked", "5"].iter().map(|arg| arg.to_string()).collect();
+        let waiting = tokio::spawn(async move { blocking_pop(&commands).await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+        run(&["ZADD", "zset:blocked", "7", "m"]);
+        let response = waiting.await.unwrap().unwrap();
+        assert_eq!(response, resp::bulk_string_array(&["zset:blocked", "m", "7"]));
+
+        let commands: Vec<String> = ["BZPOPMAX", "zset:blocked", "0.01"].iter().map(|arg| arg.to_string()).collect();
+        assert_eq!(blocking_pop(&commands).await.unwrap(), resp::null_array());
+    }
+
+    #[test]
+    fn random_members() {
+        run(&["ZADD", "zset:random", "1", "a", "2", "b"]);
+        assert_eq!(strings(&run(&["ZRANDMEMBER", "zset:random", "-5"])).len(), 5);
+        let mut picked = strings(&run(&["ZRANDMEMBER", "zset:random", "5", "WITHSCORES"]));
+        picked.sort();
+        assert_eq!(picked, ["1", "2", "a", "b"]);
+        assert_eq!(run(&["ZRANDMEMBER", "zset:missing"]), resp::null_bulk_string());
+        let out_of_range = resp::error("ERR value is out of range");
+        assert_eq!(run(&["ZRANDMEMBER", "zset:random", &i64::MIN.to_string()]), out_of_range);
+        assert_eq!(run(&["ZRANDMEMBER", "zset:random", "1", "WITHVALUES"]), resp::error("ERR syntax error"));
+    }
+}